@@ -4,7 +4,7 @@ use crate::delegate::{StopReceiver, VmStateDelegate, VmStopReason};
 use crate::vsock::VsockBridge;
 use async_trait::async_trait;
 use block2::RcBlock;
-use capsa_core::{AsyncPipe, BackendVmHandle, ConsoleStream, Error, Result};
+use capsa_core::{AsyncPipe, BackendVmHandle, ConsoleStream, Error, ExitReason, Result};
 use nix::fcntl::{FcntlArg, OFlag, fcntl};
 use objc2::rc::Retained;
 use objc2_foundation::NSError;
@@ -90,7 +90,7 @@ impl BackendVmHandle for NativeVmHandle {
         self.running.load(Ordering::SeqCst)
     }
 
-    async fn wait(&self) -> Result<i32> {
+    async fn wait(&self) -> Result<ExitReason> {
         let receiver = {
             let mut guard = self.stop_receiver.lock().await;
             guard.take()
@@ -102,7 +102,7 @@ impl BackendVmHandle for NativeVmHandle {
                     "wait() called multiple times".to_string(),
                 ))
             } else {
-                Ok(0)
+                Ok(ExitReason::Poweroff)
             };
         };
 
@@ -111,7 +111,7 @@ impl BackendVmHandle for NativeVmHandle {
         self.running.store(false, Ordering::SeqCst);
 
         match stop_reason {
-            Ok(Ok(VmStopReason::GuestStopped)) => Ok(0),
+            Ok(Ok(VmStopReason::GuestStopped)) => Ok(ExitReason::Poweroff),
             Ok(Ok(VmStopReason::Error(msg))) => {
                 Err(Error::Hypervisor(format!("VM stopped with error: {}", msg)))
             }
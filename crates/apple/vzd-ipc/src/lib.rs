@@ -2,7 +2,9 @@ mod transport;
 
 pub use transport::PipeTransport;
 
-pub use capsa_core::{DiskImage, MountMode, NetworkMode, ResourceConfig, SharedDir, VmConfig};
+pub use capsa_core::{
+    DiskImage, ExitReason, MountMode, NetworkMode, ResourceConfig, SharedDir, VmConfig,
+};
 
 use serde::{Deserialize, Serialize};
 
@@ -23,7 +25,7 @@ pub trait VmService {
     async fn is_available() -> bool;
     async fn start(config: VmConfig, console_socket_path: Option<String>) -> RpcResult<VmHandleId>;
     async fn is_running(handle: VmHandleId) -> RpcResult<bool>;
-    async fn wait(handle: VmHandleId) -> RpcResult<i32>;
+    async fn wait(handle: VmHandleId) -> RpcResult<ExitReason>;
     async fn shutdown(handle: VmHandleId) -> RpcResult<()>;
     async fn kill(handle: VmHandleId) -> RpcResult<()>;
     async fn release(handle: VmHandleId) -> RpcResult<()>;
@@ -1,6 +1,6 @@
 use crate::{console, network};
 use capsa_apple_vz::NativeVirtualizationBackend;
-use capsa_apple_vzd_ipc::{RpcResult, VmConfig, VmHandleId, VmService};
+use capsa_apple_vzd_ipc::{ExitReason, RpcResult, VmConfig, VmHandleId, VmService};
 use capsa_core::{BackendVmHandle, HypervisorBackend};
 use std::collections::HashMap;
 use std::os::fd::AsRawFd;
@@ -82,7 +82,7 @@ impl VmService for VzdServer {
         Ok(vm.handle.is_running().await)
     }
 
-    async fn wait(self, _: Context, handle: VmHandleId) -> RpcResult<i32> {
+    async fn wait(self, _: Context, handle: VmHandleId) -> RpcResult<ExitReason> {
         let handles = self.handles.read().await;
         let vm = handles.get(&handle).ok_or("Handle not found")?;
         vm.handle.wait().await.map_err(|e| e.to_string())
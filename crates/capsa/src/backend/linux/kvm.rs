@@ -1,11 +1,12 @@
 use crate::cluster::NetworkCluster;
 use async_trait::async_trait;
 use capsa_core::{
-    BackendCapabilities, BackendVmHandle, ConsoleStream, HostPlatform, HypervisorBackend,
-    KernelCmdline, NetworkMode, Result, VmConfig,
+    BackendCapabilities, BackendVmHandle, ConsoleStream, ExitReason, HostPlatform,
+    HypervisorBackend, KernelCmdline, NetworkMode, Result, VmConfig,
 };
 use capsa_net::SwitchPort;
 use std::os::fd::{IntoRawFd, OwnedFd};
+use std::path::Path;
 use tokio::task::JoinHandle;
 
 struct ClusterPortInfo {
@@ -62,55 +63,76 @@ impl HypervisorBackend for LinuxKvmBackend {
     }
 
     async fn start(&self, config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
-        // Create cluster port if cluster networking is configured
-        let (config, cluster_port) =
-            if let NetworkMode::Cluster(ref cluster_config) = config.network {
-                tracing::info!(
-                    cluster = %cluster_config.cluster_name,
-                    "Creating cluster port for VM"
-                );
-                let cluster = NetworkCluster::get_or_create(&cluster_config.cluster_name);
-                let port = cluster.create_port().await?;
-                tracing::info!(port_id = port.port_id, "Cluster port created");
-
-                // Transfer ownership of guest_fd to the inner backend via raw fd
-                let mut config = config.clone();
-                config.cluster_network_fd = Some(port.guest_fd.into_raw_fd());
-
-                (
-                    config,
-                    Some(ClusterPortInfo {
-                        host_fd: port.host_fd,
-                        switch_port: port.switch_port,
-                    }),
-                )
-            } else {
-                (config.clone(), None)
-            };
-
-        // Start the underlying backend
+        let (config, cluster_port) = attach_cluster_port(config).await?;
         let inner_handle = self.inner.start(&config).await?;
+        Ok(wrap_handle(inner_handle, cluster_port))
+    }
 
-        // Spawn bridge task for cluster networking
-        let bridge_task = if let Some(port) = cluster_port {
-            use capsa_net::bridge_to_switch;
-
-            Some(tokio::spawn(async move {
-                if let Err(e) = bridge_to_switch(port.host_fd, port.switch_port).await {
-                    tracing::error!(error = %e, "Cluster bridge error");
-                }
-            }))
-        } else {
-            None
-        };
-
-        Ok(Box::new(KvmVmHandle {
-            inner: inner_handle,
-            _bridge_task: bridge_task,
-        }))
+    fn snapshot_config(&self, path: &Path) -> Result<VmConfig> {
+        self.inner.snapshot_config(path)
+    }
+
+    async fn restore(&self, path: &Path, config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
+        // The restored VM joins the cluster on a fresh port
+        let (config, cluster_port) = attach_cluster_port(config).await?;
+        let inner_handle = self.inner.restore(path, &config).await?;
+        Ok(wrap_handle(inner_handle, cluster_port))
     }
 }
 
+/// Creates a cluster port if cluster networking is configured.
+///
+/// The returned config carries the guest end of the port for the inner
+/// backend.
+async fn attach_cluster_port(config: &VmConfig) -> Result<(VmConfig, Option<ClusterPortInfo>)> {
+    if let NetworkMode::Cluster(ref cluster_config) = config.network {
+        tracing::info!(
+            cluster = %cluster_config.cluster_name,
+            "Creating cluster port for VM"
+        );
+        let cluster = NetworkCluster::get_or_create(&cluster_config.cluster_name);
+        let port = cluster.create_port().await?;
+        tracing::info!(port_id = port.port_id, "Cluster port created");
+
+        // Transfer ownership of guest_fd to the inner backend via raw fd
+        let mut config = config.clone();
+        config.cluster_network_fd = Some(port.guest_fd.into_raw_fd());
+
+        Ok((
+            config,
+            Some(ClusterPortInfo {
+                host_fd: port.host_fd,
+                switch_port: port.switch_port,
+            }),
+        ))
+    } else {
+        Ok((config.clone(), None))
+    }
+}
+
+/// Spawns the bridge task for cluster networking and wraps the inner handle.
+fn wrap_handle(
+    inner: Box<dyn BackendVmHandle>,
+    cluster_port: Option<ClusterPortInfo>,
+) -> Box<dyn BackendVmHandle> {
+    let bridge_task = if let Some(port) = cluster_port {
+        use capsa_net::bridge_to_switch;
+
+        Some(tokio::spawn(async move {
+            if let Err(e) = bridge_to_switch(port.host_fd, port.switch_port).await {
+                tracing::error!(error = %e, "Cluster bridge error");
+            }
+        }))
+    } else {
+        None
+    };
+
+    Box::new(KvmVmHandle {
+        inner,
+        _bridge_task: bridge_task,
+    })
+}
+
 struct KvmVmHandle {
     inner: Box<dyn BackendVmHandle>,
     #[allow(dead_code)]
@@ -123,7 +145,7 @@ impl BackendVmHandle for KvmVmHandle {
         self.inner.is_running().await
     }
 
-    async fn wait(&self) -> Result<i32> {
+    async fn wait(&self) -> Result<ExitReason> {
         self.inner.wait().await
     }
 
@@ -138,4 +160,32 @@ impl BackendVmHandle for KvmVmHandle {
     async fn console_stream(&self) -> Result<Option<ConsoleStream>> {
         self.inner.console_stream().await
     }
+
+    async fn crash_report(&self) -> Option<String> {
+        self.inner.crash_report().await
+    }
+
+    async fn pause(&self) -> Result<()> {
+        self.inner.pause().await
+    }
+
+    async fn resume(&self) -> Result<()> {
+        self.inner.resume().await
+    }
+
+    async fn snapshot(&self, path: &Path) -> Result<()> {
+        self.inner.snapshot(path).await
+    }
+
+    async fn dump_memory(&self, path: &Path) -> Result<()> {
+        self.inner.dump_memory(path).await
+    }
+
+    async fn set_balloon_size(&self, size_mb: u32) -> Result<()> {
+        self.inner.set_balloon_size(size_mb).await
+    }
+
+    async fn balloon_size(&self) -> Result<u32> {
+        self.inner.balloon_size().await
+    }
 }
@@ -7,8 +7,8 @@ use crate::cluster::NetworkCluster;
 use async_trait::async_trait;
 use capsa_apple_vzd_ipc::{PipeTransport, VmHandleId, VmServiceClient};
 use capsa_core::{
-    BackendCapabilities, BackendVmHandle, ConsoleStream, Error, HostPlatform, HypervisorBackend,
-    KernelCmdline, NetworkMode, Result, VmConfig,
+    BackendCapabilities, BackendVmHandle, ConsoleStream, Error, ExitReason, HostPlatform,
+    HypervisorBackend, KernelCmdline, NetworkMode, Result, VmConfig,
 };
 use capsa_net::SwitchPort;
 use std::os::fd::{AsRawFd, OwnedFd};
@@ -223,7 +223,7 @@ impl BackendVmHandle for SubprocessVmHandle {
             .unwrap_or(false)
     }
 
-    async fn wait(&self) -> Result<i32> {
+    async fn wait(&self) -> Result<ExitReason> {
         self.client
             .wait(tarpc::context::current(), self.handle_id)
             .await
@@ -1,9 +1,9 @@
 use super::vm_builder::{BootConfigBuilder, VmBuilder};
 use crate::pool::No;
 use capsa_core::{
-    BackendCapabilities, BootMethod, DiskImage, Error, HypervisorBackend, KernelCmdline,
-    LinuxDirectBootConfig, NetworkMode, ResourceConfig, Result, SharedDir, UefiBootConfig,
-    VmConfig, VsockConfig,
+    BackendCapabilities, BootMethod, DiskImage, Error, GdbListener, HypervisorBackend,
+    KernelCmdline, LinuxDirectBootConfig, NetworkMode, ResourceConfig, Result, SharedDir,
+    UefiBootConfig, VirtioTransport, VmConfig, VsockConfig,
 };
 use std::path::PathBuf;
 use uuid::Uuid;
@@ -19,6 +19,9 @@ impl BootConfigBuilder for LinuxDirectBootConfig {
                 "boot method: linux direct".into(),
             ));
         }
+        if self.gdb.is_some() && !capabilities.gdb_stub {
+            return Err(Error::UnsupportedFeature("gdb stub".into()));
+        }
         Ok(())
     }
 
@@ -58,6 +61,10 @@ impl BootConfigBuilder for LinuxDirectBootConfig {
             network,
             console_enabled,
             vsock,
+            reboot_in_place: false,
+            gdb: self.gdb,
+            virtio_transport: VirtioTransport::default(),
+            watchdog: None,
             cluster_network_fd: None,
         };
         (config, None)
@@ -108,6 +115,19 @@ impl<P> VmBuilder<LinuxDirectBootConfig, P> {
     }
 }
 
+/// Linux-specific builder methods for single VMs.
+impl VmBuilder<LinuxDirectBootConfig, No> {
+    /// Serves a GDB stub for debugging the guest kernel.
+    ///
+    /// The guest starts halted and boots once the debugger attaches and
+    /// continues it. Kernels built with debug info and booted with `nokaslr`
+    /// are the easiest to debug.
+    pub fn gdb_stub(mut self, listener: GdbListener) -> Self {
+        self.boot_config.gdb = Some(listener);
+        self
+    }
+}
+
 // ============================================================================
 // UefiBootConfig implementation
 // ============================================================================
@@ -170,6 +190,10 @@ impl BootConfigBuilder for UefiBootConfig {
             network,
             console_enabled,
             vsock,
+            reboot_in_place: false,
+            gdb: None,
+            virtio_transport: VirtioTransport::default(),
+            watchdog: None,
             cluster_network_fd: None,
         };
         (config, temp_file)
@@ -197,7 +221,8 @@ fn resolve_efi_variable_store(
 // ============================================================================
 
 fn validate_disk_file(disk: &DiskImage) -> Result<()> {
-    if disk.read_only {
+    // Ephemeral disks only read the base image; writes go to an overlay
+    if disk.read_only || disk.ephemeral {
         if !disk.path.exists() {
             return Err(Error::InvalidConfig(format!(
                 "disk not found: {}",
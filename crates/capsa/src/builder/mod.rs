@@ -1,6 +1,9 @@
 mod boot_configs;
+mod overlay;
 mod vm_builder;
 
 pub(crate) use boot_configs::generate_temp_efi_store_path;
 pub use boot_configs::{LinuxVmBuilder, UefiVmBuilder};
+pub(crate) use overlay::{prepare_ephemeral_disks, remove_overlays};
+pub(crate) use vm_builder::regenerate_auto_vsock_paths;
 pub use vm_builder::{BootConfigBuilder, VmBuilder};
@@ -0,0 +1,241 @@
+//! Per-VM copy-on-write overlays for ephemeral disks.
+
+use capsa_core::{
+    BackendCapabilities, DiskImage, Error, ImageFormat, Qcow2Image, Result, VmConfig, open_disk,
+};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+/// Replaces every ephemeral disk in `config` with a freshly created overlay.
+///
+/// Returns the overlay paths, which the caller must delete once the VM stops.
+/// Read-only disks are never written, so they are attached directly.
+pub(crate) fn prepare_ephemeral_disks(
+    config: &mut VmConfig,
+    capabilities: &BackendCapabilities,
+) -> Result<Vec<PathBuf>> {
+    let mut overlays = Vec::new();
+
+    for disk in config.root_disk.iter_mut().chain(config.disks.iter_mut()) {
+        if !disk.ephemeral || disk.read_only {
+            continue;
+        }
+
+        match create_overlay(disk, capabilities) {
+            Ok(overlay) => {
+                overlays.push(overlay.path.clone());
+                *disk = overlay;
+            }
+            Err(e) => {
+                remove_overlays(&overlays);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(overlays)
+}
+
+/// Deletes overlays created by [`prepare_ephemeral_disks`].
+///
+/// Used when the VM fails to start, before a `VmHandle` takes ownership.
+pub(crate) fn remove_overlays(paths: &[PathBuf]) {
+    for path in paths {
+        if let Err(e) = std::fs::remove_file(path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            tracing::warn!("Failed to remove disk overlay {:?}: {}", path, e);
+        }
+    }
+}
+
+fn create_overlay(base: &DiskImage, capabilities: &BackendCapabilities) -> Result<DiskImage> {
+    let overlay_error = |e: std::io::Error| {
+        Error::StartFailed(format!(
+            "failed to create overlay for {}: {}",
+            base.path.display(),
+            e
+        ))
+    };
+
+    if capabilities.image_formats.qcow2 {
+        // The overlay may live in a different directory, so store an absolute
+        // backing path
+        let base_path = std::fs::canonicalize(&base.path).map_err(overlay_error)?;
+        let size = open_disk(&DiskImage::with_format(&base_path, base.format).read_only())
+            .map_err(overlay_error)?
+            .size();
+
+        let path = generate_temp_overlay_path("qcow2");
+        Qcow2Image::create(&path, size, Some((&base_path, base.format))).map_err(overlay_error)?;
+        Ok(DiskImage::with_format(path, ImageFormat::Qcow2))
+    } else {
+        // Without qcow2 support the overlay is a full copy. On APFS this is a
+        // copy-on-write clone, so it stays cheap for large images.
+        let extension = match base.format {
+            ImageFormat::Raw => "raw",
+            ImageFormat::Qcow2 => "qcow2",
+        };
+        let path = generate_temp_overlay_path(extension);
+        copy_writable(&base.path, &path).map_err(|e| {
+            let _ = std::fs::remove_file(&path);
+            overlay_error(e)
+        })?;
+        Ok(DiskImage::with_format(path, base.format))
+    }
+}
+
+fn copy_writable(from: &Path, to: &Path) -> std::io::Result<()> {
+    std::fs::copy(from, to)?;
+    // Base images often live in read-only locations like the Nix store, and
+    // the copy inherits their permissions
+    let mut perms = std::fs::metadata(to)?.permissions();
+    #[allow(clippy::permissions_set_readonly_false)]
+    perms.set_readonly(false);
+    std::fs::set_permissions(to, perms)
+}
+
+fn generate_temp_overlay_path(extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("capsa-overlay-{}.{}", Uuid::new_v4(), extension))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsa_core::{
+        BootMethod, ImageFormatSupport, NetworkMode, ResourceConfig, VirtioTransport, VsockConfig,
+    };
+    use tempfile::TempDir;
+
+    fn config_with_disks(root_disk: Option<DiskImage>, disks: Vec<DiskImage>) -> VmConfig {
+        VmConfig {
+            boot: BootMethod::LinuxDirect {
+                kernel: PathBuf::from("/kernel"),
+                initrd: PathBuf::from("/initrd"),
+                cmdline: String::new(),
+            },
+            root_disk,
+            disks,
+            resources: ResourceConfig::default(),
+            shares: Vec::new(),
+            network: NetworkMode::None,
+            console_enabled: false,
+            vsock: VsockConfig::default(),
+            reboot_in_place: false,
+            gdb: None,
+            virtio_transport: VirtioTransport::default(),
+            watchdog: None,
+            cluster_network_fd: None,
+        }
+    }
+
+    fn capabilities(qcow2: bool) -> BackendCapabilities {
+        BackendCapabilities {
+            image_formats: ImageFormatSupport { raw: true, qcow2 },
+            ..Default::default()
+        }
+    }
+
+    fn create_base(dir: &TempDir) -> PathBuf {
+        let path = dir.path().join("base.raw");
+        std::fs::write(&path, vec![0x42u8; 1024 * 1024]).unwrap();
+        path
+    }
+
+    #[test]
+    fn non_ephemeral_disks_are_untouched() {
+        let dir = TempDir::new().unwrap();
+        let base = create_base(&dir);
+        let mut config = config_with_disks(Some(DiskImage::new(&base)), Vec::new());
+
+        let overlays = prepare_ephemeral_disks(&mut config, &capabilities(true)).unwrap();
+        assert!(overlays.is_empty());
+        assert_eq!(config.root_disk.unwrap().path, base);
+    }
+
+    #[test]
+    fn read_only_ephemeral_disk_needs_no_overlay() {
+        let dir = TempDir::new().unwrap();
+        let base = create_base(&dir);
+        let disk = DiskImage::overlay_on(&base).read_only();
+        let mut config = config_with_disks(None, vec![disk]);
+
+        let overlays = prepare_ephemeral_disks(&mut config, &capabilities(true)).unwrap();
+        assert!(overlays.is_empty());
+        assert_eq!(config.disks[0].path, base);
+    }
+
+    #[test]
+    fn qcow2_overlay_leaves_base_untouched() {
+        let dir = TempDir::new().unwrap();
+        let base = create_base(&dir);
+        let mut config = config_with_disks(Some(DiskImage::overlay_on(&base)), Vec::new());
+
+        let overlays = prepare_ephemeral_disks(&mut config, &capabilities(true)).unwrap();
+        assert_eq!(overlays.len(), 1);
+
+        let root = config.root_disk.unwrap();
+        assert_eq!(root.path, overlays[0]);
+        assert_eq!(root.format, ImageFormat::Qcow2);
+        assert!(!root.ephemeral);
+        assert!(!root.read_only);
+
+        let mut overlay = open_disk(&root).unwrap();
+        assert_eq!(overlay.size(), 1024 * 1024);
+        overlay.write_at(&[0u8; 4096], 0).unwrap();
+
+        let mut buf = [0u8; 4];
+        overlay.read_at(&mut buf, 8192).unwrap();
+        assert_eq!(buf, [0x42; 4]);
+        assert!(std::fs::read(&base).unwrap().iter().all(|&b| b == 0x42));
+
+        remove_overlays(&overlays);
+        assert!(!overlays[0].exists());
+    }
+
+    #[test]
+    fn copies_base_without_qcow2_support() {
+        let dir = TempDir::new().unwrap();
+        let base = create_base(&dir);
+        let mut config = config_with_disks(None, vec![DiskImage::overlay_on(&base)]);
+
+        let overlays = prepare_ephemeral_disks(&mut config, &capabilities(false)).unwrap();
+        assert_eq!(overlays.len(), 1);
+        assert_eq!(config.disks[0].format, ImageFormat::Raw);
+        assert_eq!(
+            std::fs::read(&overlays[0]).unwrap(),
+            std::fs::read(&base).unwrap()
+        );
+
+        remove_overlays(&overlays);
+    }
+
+    #[test]
+    fn each_call_creates_a_fresh_overlay() {
+        let dir = TempDir::new().unwrap();
+        let base = create_base(&dir);
+        let config = config_with_disks(Some(DiskImage::overlay_on(&base)), Vec::new());
+
+        let mut first = config.clone();
+        let mut second = config.clone();
+        let first_overlays = prepare_ephemeral_disks(&mut first, &capabilities(true)).unwrap();
+        let second_overlays = prepare_ephemeral_disks(&mut second, &capabilities(true)).unwrap();
+        assert_ne!(first_overlays, second_overlays);
+
+        remove_overlays(&first_overlays);
+        remove_overlays(&second_overlays);
+    }
+
+    #[test]
+    fn missing_base_fails_to_start() {
+        let dir = TempDir::new().unwrap();
+        let base = create_base(&dir);
+        let mut config = config_with_disks(
+            Some(DiskImage::overlay_on(&base)),
+            vec![DiskImage::overlay_on(dir.path().join("missing.raw"))],
+        );
+
+        let err = prepare_ephemeral_disks(&mut config, &capabilities(true)).unwrap_err();
+        assert!(matches!(err, Error::StartFailed(_)));
+    }
+}
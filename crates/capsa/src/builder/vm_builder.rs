@@ -1,10 +1,12 @@
+use super::overlay::{prepare_ephemeral_disks, remove_overlays};
 use crate::backend::select_backend;
 use crate::handle::VmHandle;
 use crate::pool::{No, Poolability, VmPool, Yes};
 use capsa_core::{
-    BackendCapabilities, DiskImage, Error, GuestOs, HypervisorBackend, ImageFormat, MountMode,
-    NetworkMode, ResourceConfig, Result, ShareMechanism, SharedDir, VmConfig, VsockConfig,
-    VsockPortConfig,
+    BackendCapabilities, CpuModel, CpuTopology, DiskImage, Error, GuestOs, HugePages,
+    HypervisorBackend, ImageFormat, MemoryBacking, MountMode, NetworkMode, ResourceConfig, Result,
+    RngConfig, ShareMechanism, SharedDir, VirtioTransport, VmConfig, VsockConfig, VsockPortConfig,
+    WatchdogAction, WatchdogConfig,
 };
 use std::path::PathBuf;
 use std::time::Duration;
@@ -67,6 +69,9 @@ pub struct VmBuilder<B: BootConfigBuilder, P = No> {
     pub(crate) network: NetworkMode,
     pub(crate) console_enabled: bool,
     pub(crate) vsock: VsockConfig,
+    pub(crate) reboot_in_place: bool,
+    pub(crate) watchdog: Option<WatchdogConfig>,
+    pub(crate) virtio_transport: VirtioTransport,
     #[allow(dead_code)]
     pub(crate) timeout: Option<Duration>,
     #[allow(dead_code)]
@@ -86,6 +91,9 @@ impl<B: BootConfigBuilder> VmBuilder<B, No> {
             network: NetworkMode::default(),
             console_enabled: false,
             vsock: VsockConfig::default(),
+            reboot_in_place: false,
+            watchdog: None,
+            virtio_transport: VirtioTransport::default(),
             timeout: None,
             poolable: Poolability::new(),
         }
@@ -111,7 +119,7 @@ impl<B: BootConfigBuilder> VmBuilder<B, No> {
 
         let vsock_config = self.vsock.clone();
 
-        let (internal_config, temp_file) = self.boot_config.into_vm_config(
+        let (mut internal_config, temp_file) = self.boot_config.into_vm_config(
             self.disks,
             self.resources.clone(),
             self.shares,
@@ -120,11 +128,23 @@ impl<B: BootConfigBuilder> VmBuilder<B, No> {
             self.vsock,
             backend.as_ref(),
         );
+        internal_config.reboot_in_place = self.reboot_in_place;
+        internal_config.watchdog = self.watchdog;
+        internal_config.virtio_transport = self.virtio_transport;
 
-        let backend_handle = backend.start(&internal_config).await?;
+        let overlays = prepare_ephemeral_disks(&mut internal_config, backend.capabilities())?;
+
+        let backend_handle = match backend.start(&internal_config).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                remove_overlays(&overlays);
+                return Err(e);
+            }
+        };
 
         let mut handle = VmHandle::new(backend_handle, GuestOs::Linux, self.resources)
-            .with_vsock_config(&vsock_config);
+            .with_vsock_config(&vsock_config)
+            .with_disk_overlays(overlays);
         if let Some(path) = temp_file {
             handle = handle.with_temp_file(path);
         }
@@ -146,6 +166,9 @@ impl<B: BootConfigBuilder> VmBuilder<B, Yes> {
             network: NetworkMode::default(),
             console_enabled: false,
             vsock: VsockConfig::default(),
+            reboot_in_place: false,
+            watchdog: None,
+            virtio_transport: VirtioTransport::default(),
             timeout: None,
             poolable: Poolability::new(),
         }
@@ -155,10 +178,11 @@ impl<B: BootConfigBuilder> VmBuilder<B, Yes> {
     pub async fn build(self, size: usize) -> Result<VmPool> {
         let backend = select_backend()?;
         self.validate(backend.capabilities())?;
+        self.validate_pool_disks()?;
         self.validate_disk_files()?;
 
         // For pools, temp files are managed per-VM instance by VmPool::spawn_vm
-        let (internal_config, _) = self.boot_config.into_vm_config(
+        let (mut internal_config, _) = self.boot_config.into_vm_config(
             self.disks,
             self.resources,
             self.shares,
@@ -167,9 +191,26 @@ impl<B: BootConfigBuilder> VmBuilder<B, Yes> {
             self.vsock,
             backend.as_ref(),
         );
+        internal_config.reboot_in_place = self.reboot_in_place;
+        internal_config.watchdog = self.watchdog;
+        internal_config.virtio_transport = self.virtio_transport;
 
         VmPool::new(internal_config, size).await
     }
+
+    /// Ensures pool VMs never write to the same disk image.
+    fn validate_pool_disks(&self) -> Result<()> {
+        if let Some(disk) = self.boot_config.boot_disk()
+            && !disk.read_only
+            && !disk.ephemeral
+        {
+            return Err(Error::InvalidConfig(format!(
+                "pooled VMs cannot share writable disk {}; use DiskImage::ephemeral() or read_only()",
+                disk.path.display()
+            )));
+        }
+        Ok(())
+    }
 }
 
 impl<B: BootConfigBuilder, P> VmBuilder<B, P> {
@@ -179,12 +220,105 @@ impl<B: BootConfigBuilder, P> VmBuilder<B, P> {
         self
     }
 
+    /// Groups the vCPUs into sockets, cores and threads, and sets the number
+    /// of vCPUs to match.
+    pub fn cpu_topology(mut self, topology: CpuTopology) -> Self {
+        self.resources.cpus = topology.cpus();
+        self.resources.cpu.topology = Some(topology);
+        self
+    }
+
+    /// Pins each vCPU thread to a host CPU: the first vCPU to the first CPU in
+    /// `host_cpus`, and so on. Needs one host CPU per vCPU.
+    pub fn pin_cpus(mut self, host_cpus: impl IntoIterator<Item = usize>) -> Self {
+        self.resources.cpu.pinning = host_cpus.into_iter().collect();
+        self
+    }
+
+    /// Sets the CPU features reported to the guest. The VM fails to start on
+    /// hosts that lack a feature of the model.
+    pub fn cpu_model(mut self, model: CpuModel) -> Self {
+        self.resources.cpu.model = model;
+        self
+    }
+
+    /// Reports a CPU feature on top of the model, named as in
+    /// `/proc/cpuinfo` (e.g. `"aes"`).
+    pub fn add_cpu_feature(mut self, name: impl Into<String>) -> Self {
+        self.resources.cpu.add_features.push(name.into());
+        self
+    }
+
+    /// Hides a CPU feature from the guest, named as in `/proc/cpuinfo`.
+    pub fn remove_cpu_feature(mut self, name: impl Into<String>) -> Self {
+        self.resources.cpu.remove_features.push(name.into());
+        self
+    }
+
     /// Sets the amount of memory in megabytes for the VM.
     pub fn memory_mb(mut self, mb: u32) -> Self {
         self.resources.memory_mb = mb;
         self
     }
 
+    /// Sets how guest memory is allocated on the host.
+    pub fn memory_backing(mut self, backing: MemoryBacking) -> Self {
+        self.resources.memory_backing = backing;
+        self
+    }
+
+    /// Adds a memory balloon device to the VM.
+    ///
+    /// Use [`VmHandle::set_balloon_size_mb`](crate::VmHandle::set_balloon_size_mb)
+    /// to ask the guest to give memory back to the host.
+    pub fn balloon_enabled(mut self) -> Self {
+        self.resources.balloon = true;
+        self
+    }
+
+    /// Adds an entropy device to the VM, so the guest never blocks waiting
+    /// for randomness during boot.
+    pub fn rng_enabled(self) -> Self {
+        self.rng(RngConfig::default())
+    }
+
+    /// Adds an entropy device with the given settings, such as a limit on
+    /// how fast the guest can draw entropy.
+    pub fn rng(mut self, config: RngConfig) -> Self {
+        self.resources.rng = Some(config);
+        self
+    }
+
+    /// Restarts the guest when it reboots, instead of stopping the VM.
+    ///
+    /// The VM keeps its devices, so shared directories, vsock sockets and
+    /// network port forwards stay in place across the reboot.
+    pub fn reboot_in_place(mut self) -> Self {
+        self.reboot_in_place = true;
+        self
+    }
+
+    /// Adds a hardware watchdog, which takes `action` when the guest goes
+    /// `timeout` without pinging it.
+    ///
+    /// The guest arms the watchdog by opening `/dev/watchdog`, through the
+    /// `ib700wdt` driver on x86_64, and from then on has to keep writing to
+    /// it. A guest that hangs in userspace while its kernel keeps running is
+    /// caught this way, which nothing else on [`VmHandle`] notices.
+    pub fn watchdog(mut self, timeout: Duration, action: WatchdogAction) -> Self {
+        self.watchdog = Some(WatchdogConfig::new(timeout, action));
+        self
+    }
+
+    /// Sets how virtio devices are attached to the guest.
+    ///
+    /// [`VirtioTransport::Pci`] suits stock distribution kernels, which find
+    /// PCI devices on their own but not memory-mapped ones.
+    pub fn virtio_transport(mut self, transport: VirtioTransport) -> Self {
+        self.virtio_transport = transport;
+        self
+    }
+
     /// Sets a timeout for VM operations.
     pub fn timeout(mut self, duration: Duration) -> Self {
         self.timeout = Some(duration);
@@ -365,6 +499,50 @@ impl<B: BootConfigBuilder, P> VmBuilder<B, P> {
             )));
         }
 
+        if let Some(topology) = &self.resources.cpu.topology {
+            if !capabilities.cpu.topology {
+                return Err(Error::UnsupportedFeature("CPU topology".into()));
+            }
+            if topology.sockets == 0
+                || topology.cores_per_socket == 0
+                || topology.threads_per_core == 0
+            {
+                return Err(Error::InvalidConfig(
+                    "CPU topology needs at least one socket, core and thread".into(),
+                ));
+            }
+            if topology.cpus() != self.resources.cpus {
+                return Err(Error::InvalidConfig(format!(
+                    "CPU topology has {} vCPUs but {} were requested",
+                    topology.cpus(),
+                    self.resources.cpus
+                )));
+            }
+        }
+
+        let pinning = &self.resources.cpu.pinning;
+        if !pinning.is_empty() {
+            if !capabilities.cpu.pinning {
+                return Err(Error::UnsupportedFeature("vCPU pinning".into()));
+            }
+            if pinning.len() != self.resources.cpus as usize {
+                return Err(Error::InvalidConfig(format!(
+                    "vCPU pinning lists {} host CPUs for {} vCPUs",
+                    pinning.len(),
+                    self.resources.cpus
+                )));
+            }
+        }
+
+        let cpu = &self.resources.cpu;
+        if (cpu.model != CpuModel::Host
+            || !cpu.add_features.is_empty()
+            || !cpu.remove_features.is_empty())
+            && !capabilities.cpu.models
+        {
+            return Err(Error::UnsupportedFeature("CPU models".into()));
+        }
+
         if let Some(max) = capabilities.max_memory_mb
             && self.resources.memory_mb > max
         {
@@ -374,6 +552,58 @@ impl<B: BootConfigBuilder, P> VmBuilder<B, P> {
             )));
         }
 
+        let backing = &self.resources.memory_backing;
+        if backing.memfd && !capabilities.memory_backing.memfd {
+            return Err(Error::UnsupportedFeature("memfd-backed memory".into()));
+        }
+        if backing.hugepages != HugePages::None && !capabilities.memory_backing.hugepages {
+            return Err(Error::UnsupportedFeature("huge pages".into()));
+        }
+        if backing.prefault && !capabilities.memory_backing.prefault {
+            return Err(Error::UnsupportedFeature("memory prefaulting".into()));
+        }
+        if backing.mergeable {
+            if !capabilities.memory_backing.mergeable {
+                return Err(Error::UnsupportedFeature("kernel same-page merging".into()));
+            }
+            if backing.memfd || backing.hugepages == HugePages::Hugetlb {
+                return Err(Error::InvalidConfig(
+                    "kernel same-page merging requires private anonymous memory".into(),
+                ));
+            }
+        }
+        if self.resources.balloon && !capabilities.devices.balloon {
+            return Err(Error::UnsupportedFeature("memory balloon".into()));
+        }
+        if let Some(rng) = &self.resources.rng {
+            if !capabilities.devices.rng {
+                return Err(Error::UnsupportedFeature("entropy device".into()));
+            }
+            if let Some(limit) = &rng.rate_limit
+                && (limit.bytes == 0 || limit.period.is_zero())
+            {
+                return Err(Error::InvalidConfig(
+                    "entropy rate limit needs a nonzero byte count and period".into(),
+                ));
+            }
+        }
+        if self.reboot_in_place && !capabilities.reboot_in_place {
+            return Err(Error::UnsupportedFeature("rebooting in place".into()));
+        }
+        if let Some(watchdog) = &self.watchdog {
+            if !capabilities.devices.watchdog {
+                return Err(Error::UnsupportedFeature("watchdog".into()));
+            }
+            if watchdog.timeout.is_zero() {
+                return Err(Error::InvalidConfig(
+                    "watchdog timeout must be nonzero".into(),
+                ));
+            }
+        }
+        if self.virtio_transport == VirtioTransport::Pci && !capabilities.devices.virtio_pci {
+            return Err(Error::UnsupportedFeature("virtio-pci transport".into()));
+        }
+
         match &self.network {
             NetworkMode::None => {
                 if !capabilities.network_modes.none {
@@ -472,10 +702,10 @@ impl<B: BootConfigBuilder, P> VmBuilder<B, P> {
     }
 
     fn validate_single_disk_file(disk: &DiskImage) -> Result<()> {
-        if disk.read_only {
+        if disk.read_only || disk.ephemeral {
             if !disk.path.exists() {
                 return Err(Error::InvalidConfig(format!(
-                    "read-only disk not found: {}",
+                    "disk not found: {}",
                     disk.path.display()
                 )));
             }
@@ -495,6 +725,28 @@ impl<B: BootConfigBuilder, P> VmBuilder<B, P> {
     }
 }
 
+/// Replaces auto-generated vsock socket paths with fresh ones.
+///
+/// Used when restoring a snapshot, whose config still names the sockets of
+/// the VM it was taken from.
+pub(crate) fn regenerate_auto_vsock_paths(vsock: &VsockConfig) -> VsockConfig {
+    let mut regenerated = VsockConfig::new();
+    for config in &vsock.ports {
+        let config = if config.auto_cleanup() {
+            let path = generate_temp_vsock_path(config.port());
+            if config.is_connect() {
+                VsockPortConfig::connect(config.port(), path).with_auto_cleanup()
+            } else {
+                VsockPortConfig::listen(config.port(), path).with_auto_cleanup()
+            }
+        } else {
+            config.clone()
+        };
+        regenerated.add_port(config);
+    }
+    regenerated
+}
+
 fn generate_temp_vsock_path(port: u32) -> PathBuf {
     // Use /tmp directly to avoid macOS's long temp paths like
     // /var/folders/.../T/ which can exceed Unix socket path limits (~104 chars)
@@ -506,9 +758,9 @@ fn generate_temp_vsock_path(port: u32) -> PathBuf {
 mod tests {
     use super::*;
     use capsa_core::{
-        BackendCapabilities, BootMethodSupport, DeviceSupport, ImageFormatSupport,
-        LinuxDirectBootConfig, MountMode, NetworkModeSupport, ShareMechanismSupport,
-        UefiBootConfig, Virtio9pConfig, VirtioFsConfig,
+        BackendCapabilities, BootMethodSupport, CpuSupport, DeviceSupport, GdbListener,
+        ImageFormatSupport, LinuxDirectBootConfig, MemoryBackingSupport, MountMode,
+        NetworkModeSupport, ShareMechanismSupport, UefiBootConfig, Virtio9pConfig, VirtioFsConfig,
     };
     use std::path::PathBuf;
 
@@ -546,7 +798,20 @@ mod tests {
                 virtio_fs: true,
                 virtio_9p: true,
             },
-            devices: DeviceSupport { vsock: true },
+            devices: DeviceSupport {
+                vsock: true,
+                balloon: true,
+                rng: true,
+                watchdog: true,
+                virtio_pci: true,
+            },
+            cpu: CpuSupport {
+                topology: true,
+                pinning: true,
+                models: true,
+            },
+            reboot_in_place: true,
+            gdb_stub: true,
             ..Default::default()
         }
     }
@@ -568,6 +833,21 @@ mod tests {
             let err = builder.validate(&caps).unwrap_err();
             assert!(matches!(err, Error::UnsupportedFeature(f) if f.contains("linux direct")));
         }
+
+        #[test]
+        fn gdb_stub_supported() {
+            let builder = linux_builder().gdb_stub(GdbListener::unix("/tmp/gdb.sock"));
+            assert!(builder.validate(&all_capabilities()).is_ok());
+        }
+
+        #[test]
+        fn gdb_stub_unsupported() {
+            let builder = linux_builder().gdb_stub(GdbListener::unix("/tmp/gdb.sock"));
+            let mut caps = all_capabilities();
+            caps.gdb_stub = false;
+            let err = builder.validate(&caps).unwrap_err();
+            assert!(matches!(err, Error::UnsupportedFeature(f) if f.contains("gdb")));
+        }
     }
 
     mod uefi_boot_validation {
@@ -615,6 +895,68 @@ mod tests {
             assert!(builder.validate(&all_capabilities()).is_ok());
         }
 
+        #[test]
+        fn cpu_topology_sets_cpu_count() {
+            let builder = linux_builder().cpu_topology(CpuTopology::new(2, 4, 2));
+            assert_eq!(builder.resources.cpus, 16);
+            assert!(builder.validate(&all_capabilities()).is_ok());
+        }
+
+        #[test]
+        fn cpu_topology_must_match_cpu_count() {
+            let builder = linux_builder()
+                .cpu_topology(CpuTopology::new(1, 4, 1))
+                .cpus(2);
+            let err = builder.validate(&all_capabilities()).unwrap_err();
+            assert!(matches!(err, Error::InvalidConfig(msg) if msg.contains("topology")));
+        }
+
+        #[test]
+        fn cpu_topology_rejects_empty_levels() {
+            let builder = linux_builder().cpu_topology(CpuTopology::new(1, 0, 1));
+            let err = builder.validate(&all_capabilities()).unwrap_err();
+            assert!(matches!(err, Error::InvalidConfig(_)));
+        }
+
+        #[test]
+        fn cpu_topology_unsupported() {
+            let builder = linux_builder().cpu_topology(CpuTopology::new(1, 2, 2));
+            let mut caps = all_capabilities();
+            caps.cpu.topology = false;
+            let err = builder.validate(&caps).unwrap_err();
+            assert!(matches!(err, Error::UnsupportedFeature(msg) if msg.contains("topology")));
+        }
+
+        #[test]
+        fn pinning_needs_cpu_per_vcpu() {
+            let builder = linux_builder().cpus(2).pin_cpus([0, 1]);
+            assert!(builder.validate(&all_capabilities()).is_ok());
+
+            let builder = linux_builder().cpus(2).pin_cpus([0]);
+            let err = builder.validate(&all_capabilities()).unwrap_err();
+            assert!(matches!(err, Error::InvalidConfig(msg) if msg.contains("pinning")));
+        }
+
+        #[test]
+        fn cpu_model_supported() {
+            let builder = linux_builder()
+                .cpu_model(CpuModel::X86_64V3)
+                .remove_cpu_feature("avx2");
+            assert!(builder.validate(&all_capabilities()).is_ok());
+            assert_eq!(builder.resources.cpu.remove_features, vec!["avx2"]);
+        }
+
+        #[test]
+        fn cpu_model_unsupported() {
+            let mut caps = all_capabilities();
+            caps.cpu.models = false;
+            assert!(linux_builder().validate(&caps).is_ok());
+
+            let builder = linux_builder().add_cpu_feature("aes");
+            let err = builder.validate(&caps).unwrap_err();
+            assert!(matches!(err, Error::UnsupportedFeature(msg) if msg.contains("CPU models")));
+        }
+
         #[test]
         fn memory_within_limit() {
             let builder = linux_builder().memory_mb(4096);
@@ -637,6 +979,158 @@ mod tests {
             let builder = linux_builder().memory_mb(65536);
             assert!(builder.validate(&all_capabilities()).is_ok());
         }
+
+        #[test]
+        fn memory_backing_supported() {
+            let builder = linux_builder().memory_backing(MemoryBacking {
+                memfd: true,
+                hugepages: HugePages::Hugetlb,
+                prefault: true,
+                mergeable: false,
+            });
+            let mut caps = all_capabilities();
+            caps.memory_backing = MemoryBackingSupport {
+                memfd: true,
+                hugepages: true,
+                prefault: true,
+                mergeable: true,
+            };
+            assert!(builder.validate(&caps).is_ok());
+        }
+
+        #[test]
+        fn hugepages_unsupported() {
+            let builder = linux_builder().memory_backing(MemoryBacking {
+                hugepages: HugePages::Transparent,
+                ..Default::default()
+            });
+            let err = builder.validate(&all_capabilities()).unwrap_err();
+            assert!(matches!(err, Error::UnsupportedFeature(msg) if msg.contains("huge pages")));
+        }
+
+        #[test]
+        fn mergeable_requires_private_memory() {
+            let builder = linux_builder().memory_backing(MemoryBacking {
+                memfd: true,
+                mergeable: true,
+                ..Default::default()
+            });
+            let mut caps = all_capabilities();
+            caps.memory_backing.memfd = true;
+            caps.memory_backing.mergeable = true;
+            let err = builder.validate(&caps).unwrap_err();
+            assert!(matches!(err, Error::InvalidConfig(msg) if msg.contains("same-page merging")));
+        }
+
+        #[test]
+        fn balloon_supported() {
+            let builder = linux_builder().balloon_enabled();
+            assert!(builder.validate(&all_capabilities()).is_ok());
+        }
+
+        #[test]
+        fn balloon_unsupported() {
+            let builder = linux_builder().balloon_enabled();
+            let mut caps = all_capabilities();
+            caps.devices.balloon = false;
+            let err = builder.validate(&caps).unwrap_err();
+            assert!(matches!(err, Error::UnsupportedFeature(msg) if msg.contains("balloon")));
+        }
+
+        #[test]
+        fn rng_supported() {
+            let builder =
+                linux_builder().rng(RngConfig::rate_limited(1024, Duration::from_secs(1)));
+            assert!(builder.validate(&all_capabilities()).is_ok());
+        }
+
+        #[test]
+        fn rng_unsupported() {
+            let builder = linux_builder().rng_enabled();
+            let mut caps = all_capabilities();
+            caps.devices.rng = false;
+            let err = builder.validate(&caps).unwrap_err();
+            assert!(matches!(err, Error::UnsupportedFeature(msg) if msg.contains("entropy")));
+        }
+
+        #[test]
+        fn rng_rate_limit_must_allow_entropy() {
+            for config in [
+                RngConfig::rate_limited(0, Duration::from_secs(1)),
+                RngConfig::rate_limited(1024, Duration::ZERO),
+            ] {
+                let builder = linux_builder().rng(config);
+                let err = builder.validate(&all_capabilities()).unwrap_err();
+                assert!(matches!(err, Error::InvalidConfig(msg) if msg.contains("rate limit")));
+            }
+        }
+
+        #[test]
+        fn virtio_pci_supported() {
+            let builder = linux_builder().virtio_transport(VirtioTransport::Pci);
+            assert!(builder.validate(&all_capabilities()).is_ok());
+        }
+
+        #[test]
+        fn virtio_pci_unsupported() {
+            let builder = linux_builder().virtio_transport(VirtioTransport::Pci);
+            let mut caps = all_capabilities();
+            caps.devices.virtio_pci = false;
+            let err = builder.validate(&caps).unwrap_err();
+            assert!(matches!(err, Error::UnsupportedFeature(msg) if msg.contains("virtio-pci")));
+        }
+    }
+
+    mod reboot_validation {
+        use super::*;
+
+        #[test]
+        fn reboot_in_place_supported() {
+            let builder = linux_builder().reboot_in_place();
+            assert!(builder.validate(&all_capabilities()).is_ok());
+        }
+
+        #[test]
+        fn reboot_in_place_unsupported() {
+            let builder = linux_builder().reboot_in_place();
+            let mut caps = all_capabilities();
+            caps.reboot_in_place = false;
+            let err = builder.validate(&caps).unwrap_err();
+            assert!(matches!(err, Error::UnsupportedFeature(msg) if msg.contains("rebooting")));
+        }
+    }
+
+    mod watchdog_validation {
+        use super::*;
+
+        #[test]
+        fn watchdog_supported() {
+            let builder = linux_builder().watchdog(Duration::from_secs(30), WatchdogAction::Fail);
+            assert!(builder.validate(&all_capabilities()).is_ok());
+            assert_eq!(
+                builder.watchdog,
+                Some(WatchdogConfig::new(
+                    Duration::from_secs(30),
+                    WatchdogAction::Fail
+                ))
+            );
+        }
+
+        #[test]
+        fn watchdog_unsupported() {
+            let builder = linux_builder().watchdog(Duration::from_secs(30), WatchdogAction::Reset);
+            let mut caps = all_capabilities();
+            caps.devices.watchdog = false;
+            let err = builder.validate(&caps).unwrap_err();
+            assert!(matches!(err, Error::UnsupportedFeature(msg) if msg.contains("watchdog")));
+        }
+
+        #[test]
+        fn watchdog_timeout_must_be_nonzero() {
+            let builder = linux_builder().watchdog(Duration::ZERO, WatchdogAction::Poweroff);
+            let err = builder.validate(&all_capabilities()).unwrap_err();
+            assert!(matches!(err, Error::InvalidConfig(msg) if msg.contains("watchdog")));
+        }
     }
 
     mod network_validation {
@@ -853,6 +1347,56 @@ mod tests {
             let builder = VmBuilder::new(config).disk(DiskImage::new(temp_additional.path()));
             assert!(builder.validate_disk_files().is_ok());
         }
+
+        #[test]
+        fn ephemeral_exists() {
+            let temp_file = tempfile::NamedTempFile::new().unwrap();
+            let config = LinuxDirectBootConfig::new("/kernel", "/initrd")
+                .with_root_disk(DiskImage::overlay_on(temp_file.path()));
+            let builder = VmBuilder::new(config);
+            assert!(builder.validate_disk_files().is_ok());
+        }
+
+        #[test]
+        fn ephemeral_not_found() {
+            let builder = linux_builder().disk(DiskImage::overlay_on("/nonexistent/base.raw"));
+            let err = builder.validate_disk_files().unwrap_err();
+            assert!(matches!(err, Error::InvalidConfig(msg) if msg.contains("not found")));
+        }
+    }
+
+    mod pool_disk_validation {
+        use super::*;
+
+        fn pool_builder(root_disk: DiskImage) -> VmBuilder<LinuxDirectBootConfig, Yes> {
+            let config = LinuxDirectBootConfig::new("/kernel", "/initrd").with_root_disk(root_disk);
+            VmBuilder::new_pool(config)
+        }
+
+        #[test]
+        fn rejects_writable_root_disk() {
+            let builder = pool_builder(DiskImage::new("/disk.raw"));
+            let err = builder.validate_pool_disks().unwrap_err();
+            assert!(matches!(err, Error::InvalidConfig(msg) if msg.contains("writable disk")));
+        }
+
+        #[test]
+        fn accepts_read_only_root_disk() {
+            let builder = pool_builder(DiskImage::new("/disk.raw").read_only());
+            assert!(builder.validate_pool_disks().is_ok());
+        }
+
+        #[test]
+        fn accepts_ephemeral_root_disk() {
+            let builder = pool_builder(DiskImage::overlay_on("/disk.raw"));
+            assert!(builder.validate_pool_disks().is_ok());
+        }
+
+        #[test]
+        fn accepts_no_disks() {
+            let builder = VmBuilder::new_pool(LinuxDirectBootConfig::new("/kernel", "/initrd"));
+            assert!(builder.validate_pool_disks().is_ok());
+        }
     }
 
     mod builder_methods {
@@ -1042,6 +1586,34 @@ mod tests {
             assert!(path.to_str().unwrap().ends_with(".sock"));
         }
 
+        #[test]
+        fn regenerate_auto_vsock_paths_replaces_only_auto_paths() {
+            let mut vsock = VsockConfig::new();
+            vsock.add_port(VsockPortConfig::listen(1024, "/tmp/old.sock").with_auto_cleanup());
+            vsock.add_port(VsockPortConfig::connect(2048, "/tmp/old2.sock").with_auto_cleanup());
+            vsock.add_port(VsockPortConfig::listen(3072, "/tmp/user.sock"));
+
+            let regenerated = regenerate_auto_vsock_paths(&vsock);
+
+            let listen = &regenerated.ports[0];
+            assert_eq!(listen.port(), 1024);
+            assert!(!listen.is_connect());
+            assert!(listen.auto_cleanup());
+            assert_ne!(listen.socket_path(), std::path::Path::new("/tmp/old.sock"));
+
+            let connect = &regenerated.ports[1];
+            assert_eq!(connect.port(), 2048);
+            assert!(connect.is_connect());
+            assert_ne!(
+                connect.socket_path(),
+                std::path::Path::new("/tmp/old2.sock")
+            );
+
+            let user = &regenerated.ports[2];
+            assert!(!user.auto_cleanup());
+            assert_eq!(user.socket_path(), std::path::Path::new("/tmp/user.sock"));
+        }
+
         #[test]
         fn vsock_validation_rejects_port_zero() {
             let mut builder = linux_builder();
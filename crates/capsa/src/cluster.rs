@@ -6,7 +6,7 @@
 use capsa_core::NetworkClusterConfig;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex, OnceLock};
-use tracing::info;
+use tracing::{info, warn};
 
 #[cfg(unix)]
 use capsa_core::{Error, Result};
@@ -138,7 +138,9 @@ impl NetworkCluster {
             tokio::spawn(async move {
                 info!(cluster = %cluster_name, "Started DHCP server for cluster");
                 let stack = ClusterStack::new(port, stack_config);
-                stack.run().await;
+                if let Err(e) = stack.run().await {
+                    warn!(cluster = %cluster_name, "Cluster network stack failed: {}", e);
+                }
             });
         }
     }
@@ -1,6 +1,10 @@
-use crate::builder::{BootConfigBuilder, VmBuilder};
+use crate::backend::select_backend;
+use crate::builder::{BootConfigBuilder, VmBuilder, regenerate_auto_vsock_paths};
+use crate::handle::VmHandle;
 use crate::pool::Yes;
 use crate::sandbox::{NoMainProcess, SandboxBuilder};
+use capsa_core::{GuestOs, Result};
+use std::path::{Path, PathBuf};
 
 /// Trait for VM boot configurations.
 ///
@@ -141,6 +145,59 @@ impl Capsa {
     pub fn sandbox() -> SandboxBuilder<NoMainProcess> {
         SandboxBuilder::new()
     }
+
+    /// Starts a VM from a snapshot taken with [`VmHandle::snapshot`].
+    ///
+    /// The VM resumes exactly where the snapshot was taken, with the same
+    /// resources, devices and disks. Disk images are not part of the snapshot
+    /// and must not have changed since it was taken. Auto-generated vsock
+    /// sockets get fresh paths, so the same snapshot can be restored many
+    /// times, even while the original VM is still running.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::UnsupportedFeature`](crate::Error::UnsupportedFeature) - The
+    ///   backend does not support snapshots
+    /// - [`Error::StartFailed`](crate::Error::StartFailed) - The snapshot is
+    ///   invalid or the VM could not be restored
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// use capsa::{Capsa, LinuxDirectBootConfig};
+    ///
+    /// # async fn example() -> capsa::Result<()> {
+    /// let config = LinuxDirectBootConfig::new("./kernel", "./initrd")
+    ///     .with_root_disk("./rootfs.raw");
+    /// let vm = Capsa::vm(config).console_enabled().build().await?;
+    /// vm.console().await?.wait_for("login:").await?;
+    /// vm.snapshot("./booted.snap").await?;
+    /// vm.kill().await?;
+    ///
+    /// let vm = Capsa::restore("./booted.snap").await?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn restore(path: impl AsRef<Path>) -> Result<VmHandle> {
+        let path = path.as_ref();
+        let backend = select_backend()?;
+
+        let mut config = backend.snapshot_config(path)?;
+        config.vsock = regenerate_auto_vsock_paths(&config.vsock);
+        let vsock_cleanup_paths: Vec<PathBuf> = config
+            .vsock
+            .auto_cleanup_paths()
+            .map(|p| p.to_path_buf())
+            .collect();
+
+        let backend_handle = backend.restore(path, &config).await?;
+
+        Ok(
+            VmHandle::new(backend_handle, GuestOs::Linux, config.resources.clone())
+                .with_vsock_config(&config.vsock)
+                .with_temp_files(vsock_cleanup_paths),
+        )
+    }
 }
 
 #[cfg(test)]
@@ -5,20 +5,30 @@
 //!
 //! - Monitor VM status via [`VmHandle::status`]
 //! - Gracefully stop or forcefully kill the VM
+//! - Pause and resume the VM
 //! - Wait for the VM to exit
 //! - Access the serial console via [`VmHandle::console`]
+//! - Save the running VM to a file via [`VmHandle::snapshot`]
+//! - Reclaim guest memory via [`VmHandle::set_balloon_size_mb`]
 //!
 //! # Lifecycle
 //!
 //! VMs created via [`Capsa::vm`](crate::Capsa::vm) start in the `Running`
 //! state. Use [`stop`](VmHandle::stop) for graceful shutdown or
-//! [`kill`](VmHandle::kill) for immediate termination.
+//! [`kill`](VmHandle::kill) for immediate termination. A running VM can be
+//! frozen with [`pause`](VmHandle::pause), which moves it to `Paused` until
+//! [`resume`](VmHandle::resume) is called.
+//!
+//! A snapshot taken with [`snapshot`](VmHandle::snapshot) can be resumed later
+//! with [`Capsa::restore`](crate::Capsa::restore).
 
 use crate::console::VmConsole;
 use crate::vsock::VsockSocket;
-use capsa_core::{BackendVmHandle, Error, GuestOs, ResourceConfig, Result, VsockConfig};
+use capsa_core::{
+    BackendVmHandle, Error, ExitReason, GuestOs, ResourceConfig, Result, VsockConfig,
+};
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU8, Ordering};
 use std::time::Duration;
@@ -30,6 +40,7 @@ const STATUS_RUNNING: u8 = 2;
 const STATUS_STOPPING: u8 = 3;
 const STATUS_STOPPED: u8 = 4;
 const STATUS_FAILED: u8 = 5;
+const STATUS_PAUSED: u8 = 6;
 
 /// Current status of a virtual machine.
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -40,14 +51,17 @@ pub enum VmStatus {
     Starting,
     /// VM is running.
     Running,
+    /// VM is paused; its vCPUs and devices are stopped until resumed.
+    Paused,
     /// VM is in the process of stopping.
     Stopping,
     /// VM has stopped.
     Stopped {
-        /// Exit code from the VM, if available.
-        exit_code: Option<i32>,
+        /// Why the VM stopped, if known.
+        reason: Option<ExitReason>,
     },
-    /// VM has failed.
+    /// VM has failed, for example because the guest kernel panicked or its
+    /// watchdog expired.
     Failed {
         /// Error message describing the failure.
         message: String,
@@ -55,13 +69,14 @@ pub enum VmStatus {
 }
 
 impl VmStatus {
-    fn from_atomic(val: u8, exit_code: Option<i32>, error_msg: Option<String>) -> Self {
+    fn from_atomic(val: u8, reason: Option<ExitReason>, error_msg: Option<String>) -> Self {
         match val {
             STATUS_CREATED => VmStatus::Created,
             STATUS_STARTING => VmStatus::Starting,
             STATUS_RUNNING => VmStatus::Running,
+            STATUS_PAUSED => VmStatus::Paused,
             STATUS_STOPPING => VmStatus::Stopping,
-            STATUS_STOPPED => VmStatus::Stopped { exit_code },
+            STATUS_STOPPED => VmStatus::Stopped { reason },
             STATUS_FAILED => VmStatus::Failed {
                 message: error_msg.unwrap_or_else(|| "Unknown error".to_string()),
             },
@@ -79,7 +94,7 @@ impl VmStatus {
 pub struct VmHandle {
     backend_handle: Arc<Box<dyn BackendVmHandle>>,
     status: AtomicU8,
-    exit_code: std::sync::Mutex<Option<i32>>,
+    exit_reason: std::sync::Mutex<Option<ExitReason>>,
     error_message: std::sync::Mutex<Option<String>>,
     guest_os: GuestOs,
     resources: ResourceConfig,
@@ -87,6 +102,8 @@ pub struct VmHandle {
     temp_files: Vec<PathBuf>,
     /// Vsock port to socket mappings for easy access
     vsock_sockets: HashMap<u32, VsockSocket>,
+    /// Ephemeral overlays backing the disks, deleted when the VM stops or the handle is dropped
+    disk_overlays: Vec<PathBuf>,
 }
 
 impl VmHandle {
@@ -98,12 +115,13 @@ impl VmHandle {
         Self {
             backend_handle: Arc::new(backend_handle),
             status: AtomicU8::new(STATUS_RUNNING),
-            exit_code: std::sync::Mutex::new(None),
+            exit_reason: std::sync::Mutex::new(None),
             error_message: std::sync::Mutex::new(None),
             guest_os,
             resources,
             temp_files: Vec::new(),
             vsock_sockets: HashMap::new(),
+            disk_overlays: Vec::new(),
         }
     }
 
@@ -117,6 +135,11 @@ impl VmHandle {
         self
     }
 
+    pub(crate) fn with_disk_overlays(mut self, paths: Vec<PathBuf>) -> Self {
+        self.disk_overlays.extend(paths);
+        self
+    }
+
     pub(crate) fn with_vsock_config(mut self, config: &VsockConfig) -> Self {
         for port_config in &config.ports {
             self.vsock_sockets.insert(
@@ -130,7 +153,7 @@ impl VmHandle {
     /// Starts the VM if it's not already running.
     pub async fn start(&self) -> Result<()> {
         let current = self.status.load(Ordering::SeqCst);
-        if current == STATUS_RUNNING {
+        if current == STATUS_RUNNING || current == STATUS_PAUSED {
             return Err(Error::AlreadyRunning);
         }
 
@@ -148,9 +171,13 @@ impl VmHandle {
     }
 
     /// Gracefully stops the VM with a custom timeout.
+    ///
+    /// A paused VM is resumed first so the guest can shut down.
     pub async fn stop_with_timeout(&self, grace_period: Duration) -> Result<()> {
         let current = self.status.load(Ordering::SeqCst);
-        if current != STATUS_RUNNING {
+        if current == STATUS_PAUSED {
+            self.resume().await?;
+        } else if current != STATUS_RUNNING {
             return Err(Error::NotRunning);
         }
 
@@ -159,9 +186,8 @@ impl VmHandle {
         self.backend_handle.shutdown().await?;
 
         match timeout(grace_period, self.backend_handle.wait()).await {
-            Ok(Ok(code)) => {
-                *self.exit_code.lock().unwrap() = Some(code);
-                self.status.store(STATUS_STOPPED, Ordering::SeqCst);
+            Ok(Ok(reason)) => {
+                self.record_exit(reason).await;
                 self.cleanup_temp_files();
             }
             Ok(Err(e)) => {
@@ -172,6 +198,7 @@ impl VmHandle {
             }
             Err(_) => {
                 self.backend_handle.kill().await?;
+                *self.exit_reason.lock().unwrap() = Some(ExitReason::Killed);
                 self.status.store(STATUS_STOPPED, Ordering::SeqCst);
                 self.cleanup_temp_files();
             }
@@ -183,38 +210,170 @@ impl VmHandle {
     /// Forcefully terminates the VM immediately.
     pub async fn kill(&self) -> Result<()> {
         self.backend_handle.kill().await?;
+        *self.exit_reason.lock().unwrap() = Some(ExitReason::Killed);
         self.status.store(STATUS_STOPPED, Ordering::SeqCst);
         self.cleanup_temp_files();
         Ok(())
     }
 
-    fn cleanup_temp_files(&self) {
-        for path in &self.temp_files {
-            if let Err(e) = std::fs::remove_file(path)
-                && e.kind() != std::io::ErrorKind::NotFound
-            {
-                tracing::warn!("Failed to clean up temp file {:?}: {}", path, e);
-            }
+    /// Pauses the VM.
+    ///
+    /// All vCPUs stop and devices stop processing I/O, but the VM keeps its
+    /// memory and device state. Console input sent while paused is delivered
+    /// once the VM resumes. Pausing an already paused VM does nothing.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotRunning`] - The VM is not running
+    /// - [`Error::UnsupportedFeature`] - The backend cannot pause VMs
+    pub async fn pause(&self) -> Result<()> {
+        match self.status.load(Ordering::SeqCst) {
+            STATUS_PAUSED => return Ok(()),
+            STATUS_RUNNING => {}
+            _ => return Err(Error::NotRunning),
+        }
+
+        self.backend_handle.pause().await?;
+        self.status.store(STATUS_PAUSED, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Resumes a VM stopped by [`pause`](Self::pause).
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::NotPaused`] if the VM is not paused.
+    pub async fn resume(&self) -> Result<()> {
+        if self.status.load(Ordering::SeqCst) != STATUS_PAUSED {
+            return Err(Error::NotPaused);
+        }
+
+        self.backend_handle.resume().await?;
+        self.status.store(STATUS_RUNNING, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Saves the running VM to a snapshot file.
+    ///
+    /// The guest is paused while its memory and device state are written and
+    /// resumes once the snapshot is complete, unless it was already paused. Use
+    /// [`Capsa::restore`](crate::Capsa::restore) to start a VM from the
+    /// snapshot.
+    ///
+    /// Disk images are not part of the snapshot. They must be left unchanged
+    /// until the snapshot is restored, so VMs using ephemeral disks cannot be
+    /// snapshotted.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotRunning`] - The VM is neither running nor paused
+    /// - [`Error::UnsupportedFeature`] - The backend does not support snapshots
+    ///   or the VM uses ephemeral disks
+    pub async fn snapshot(&self, path: impl AsRef<Path>) -> Result<()> {
+        let current = self.status.load(Ordering::SeqCst);
+        if current != STATUS_RUNNING && current != STATUS_PAUSED {
+            return Err(Error::NotRunning);
         }
+        if !self.disk_overlays.is_empty() {
+            return Err(Error::UnsupportedFeature(
+                "snapshots of VMs with ephemeral disks".into(),
+            ));
+        }
+        self.backend_handle.snapshot(path.as_ref()).await
+    }
+
+    /// Writes the guest's memory and vCPU registers to an ELF core file.
+    ///
+    /// Useful when a guest hangs: the dump can be opened with `crash` or
+    /// `gdb vmlinux`. The guest is paused while the dump is written and
+    /// resumes afterwards, unless it was already paused. The file is as large
+    /// as the guest's memory.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotRunning`] - The VM is neither running nor paused
+    /// - [`Error::UnsupportedFeature`] - The backend cannot dump guest memory
+    pub async fn dump_memory(&self, path: impl AsRef<Path>) -> Result<()> {
+        self.ensure_active()?;
+        self.backend_handle.dump_memory(path.as_ref()).await
+    }
+
+    /// Asks the guest to give `mb` of its memory back to the host.
+    ///
+    /// The guest driver inflates or deflates the balloon in the background;
+    /// use [`balloon_size_mb`](Self::balloon_size_mb) to see how far it got.
+    /// Setting the size to 0 returns all memory to the guest. The guest may
+    /// deflate the balloon on its own when it runs low on memory.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotRunning`] - The VM is neither running nor paused
+    /// - [`Error::UnsupportedFeature`] - The VM was created without a balloon
+    ///   device
+    pub async fn set_balloon_size_mb(&self, mb: u32) -> Result<()> {
+        self.ensure_active()?;
+        self.backend_handle.set_balloon_size(mb).await
+    }
+
+    /// Returns how much memory the guest has given back to the host, in MB.
+    ///
+    /// # Errors
+    ///
+    /// - [`Error::NotRunning`] - The VM is neither running nor paused
+    /// - [`Error::UnsupportedFeature`] - The VM was created without a balloon
+    ///   device
+    pub async fn balloon_size_mb(&self) -> Result<u32> {
+        self.ensure_active()?;
+        self.backend_handle.balloon_size().await
+    }
+
+    fn ensure_active(&self) -> Result<()> {
+        let current = self.status.load(Ordering::SeqCst);
+        if current != STATUS_RUNNING && current != STATUS_PAUSED {
+            return Err(Error::NotRunning);
+        }
+        Ok(())
+    }
+
+    fn cleanup_temp_files(&self) {
+        remove_temp_files(self.temp_files.iter().chain(&self.disk_overlays));
     }
 
     /// Returns the current status of the VM.
     pub fn status(&self) -> VmStatus {
         let status = self.status.load(Ordering::SeqCst);
-        let exit_code = *self.exit_code.lock().unwrap();
+        let reason = self.exit_reason.lock().unwrap().clone();
         let error_msg = self.error_message.lock().unwrap().clone();
-        VmStatus::from_atomic(status, exit_code, error_msg)
+        VmStatus::from_atomic(status, reason, error_msg)
     }
 
     /// Waits for the VM to exit and returns its final status.
+    ///
+    /// A guest that crashed ends up [`VmStatus::Failed`], with a message
+    /// that includes the last console output when the backend captured it.
     pub async fn wait(&self) -> Result<VmStatus> {
-        let code = self.backend_handle.wait().await?;
-        *self.exit_code.lock().unwrap() = Some(code);
-        self.status.store(STATUS_STOPPED, Ordering::SeqCst);
+        let reason = self.backend_handle.wait().await?;
+        self.record_exit(reason).await;
         self.cleanup_temp_files();
         Ok(self.status())
     }
 
+    /// Records how the VM exited, as a failure if the guest crashed.
+    async fn record_exit(&self, reason: ExitReason) {
+        let report = match self.backend_handle.crash_report().await {
+            None if reason == ExitReason::Panic => Some("guest kernel panicked".to_string()),
+            report => report,
+        };
+        *self.exit_reason.lock().unwrap() = Some(reason);
+        match report {
+            Some(report) => {
+                *self.error_message.lock().unwrap() = Some(report);
+                self.status.store(STATUS_FAILED, Ordering::SeqCst);
+            }
+            None => self.status.store(STATUS_STOPPED, Ordering::SeqCst),
+        }
+    }
+
     /// Waits for the VM to exit with a timeout.
     ///
     /// Returns `None` if the timeout expires before the VM exits.
@@ -286,6 +445,26 @@ impl VmHandle {
     }
 }
 
+impl Drop for VmHandle {
+    fn drop(&mut self) {
+        // Discards ephemeral disk overlays even when the handle is dropped
+        // without waiting for the VM to stop. The VM may still be running:
+        // it keeps the overlays it has open, but would lose its sockets and
+        // EFI variable store, so those are left for wait and kill.
+        remove_temp_files(&self.disk_overlays);
+    }
+}
+
+fn remove_temp_files<'a>(paths: impl IntoIterator<Item = &'a PathBuf>) {
+    for path in paths {
+        if let Err(e) = std::fs::remove_file(path)
+            && e.kind() != std::io::ErrorKind::NotFound
+        {
+            tracing::warn!("Failed to clean up temp file {:?}: {}", path, e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -307,6 +486,35 @@ mod tests {
         assert!(!path.exists(), "Temp file should be deleted after cleanup");
     }
 
+    #[test]
+    fn drop_deletes_only_disk_overlays() {
+        let (_, temp_path) = NamedTempFile::new().unwrap().keep().unwrap();
+        let (_, overlay_path) = NamedTempFile::new().unwrap().keep().unwrap();
+
+        drop(
+            create_test_handle()
+                .with_temp_file(temp_path.clone())
+                .with_disk_overlays(vec![overlay_path.clone()]),
+        );
+
+        assert!(!overlay_path.exists(), "Overlay should be deleted on drop");
+        assert!(temp_path.exists(), "A running VM may still use temp files");
+        std::fs::remove_file(temp_path).unwrap();
+    }
+
+    #[test]
+    fn cleanup_temp_files_deletes_disk_overlays() {
+        let (_, overlay_path) = NamedTempFile::new().unwrap().keep().unwrap();
+
+        let handle = create_test_handle().with_disk_overlays(vec![overlay_path.clone()]);
+        handle.cleanup_temp_files();
+
+        assert!(
+            !overlay_path.exists(),
+            "Overlay should be deleted after cleanup"
+        );
+    }
+
     #[test]
     fn cleanup_temp_files_ignores_missing_file() {
         let path = std::env::temp_dir().join("nonexistent-capsa-test-file.efivarstore");
@@ -348,15 +556,20 @@ mod tests {
     }
 
     fn create_test_handle() -> VmHandle {
+        create_test_handle_with(MockBackendHandle::default())
+    }
+
+    fn create_test_handle_with(backend: MockBackendHandle) -> VmHandle {
         VmHandle {
-            backend_handle: Arc::new(Box::new(MockBackendHandle)),
+            backend_handle: Arc::new(Box::new(backend)),
             status: AtomicU8::new(STATUS_RUNNING),
-            exit_code: std::sync::Mutex::new(None),
+            exit_reason: std::sync::Mutex::new(None),
             error_message: std::sync::Mutex::new(None),
             guest_os: GuestOs::Linux,
             resources: ResourceConfig::default(),
             temp_files: Vec::new(),
             vsock_sockets: HashMap::new(),
+            disk_overlays: Vec::new(),
         }
     }
 
@@ -401,7 +614,54 @@ mod tests {
         assert_eq!(socket_b.path(), std::path::Path::new("/tmp/b.sock"));
     }
 
-    struct MockBackendHandle;
+    #[tokio::test]
+    async fn pause_and_resume_update_status() {
+        let handle = create_test_handle();
+
+        handle.pause().await.unwrap();
+        assert_eq!(handle.status(), VmStatus::Paused);
+
+        handle.pause().await.unwrap();
+        assert_eq!(handle.status(), VmStatus::Paused);
+
+        handle.resume().await.unwrap();
+        assert_eq!(handle.status(), VmStatus::Running);
+    }
+
+    #[tokio::test]
+    async fn resume_requires_paused_vm() {
+        let err = create_test_handle().resume().await.unwrap_err();
+        assert!(matches!(err, Error::NotPaused));
+    }
+
+    #[tokio::test]
+    async fn pause_requires_running_vm() {
+        let handle = create_test_handle();
+        handle.kill().await.unwrap();
+
+        let err = handle.pause().await.unwrap_err();
+        assert!(matches!(err, Error::NotRunning));
+    }
+
+    #[tokio::test]
+    async fn stop_resumes_paused_vm() {
+        let handle = create_test_handle();
+        handle.pause().await.unwrap();
+
+        handle.stop().await.unwrap();
+        assert_eq!(
+            handle.status(),
+            VmStatus::Stopped {
+                reason: Some(ExitReason::Poweroff)
+            }
+        );
+    }
+
+    #[derive(Default)]
+    struct MockBackendHandle {
+        exit_reason: Option<ExitReason>,
+        crash_report: Option<String>,
+    }
 
     #[async_trait]
     impl BackendVmHandle for MockBackendHandle {
@@ -417,13 +677,25 @@ mod tests {
             Ok(())
         }
 
-        async fn wait(&self) -> Result<i32> {
-            Ok(0)
+        async fn wait(&self) -> Result<ExitReason> {
+            Ok(self.exit_reason.clone().unwrap_or(ExitReason::Poweroff))
         }
 
         async fn console_stream(&self) -> Result<Option<ConsoleStream>> {
             Ok(None)
         }
+
+        async fn crash_report(&self) -> Option<String> {
+            self.crash_report.clone()
+        }
+
+        async fn pause(&self) -> Result<()> {
+            Ok(())
+        }
+
+        async fn resume(&self) -> Result<()> {
+            Ok(())
+        }
     }
 
     #[tokio::test]
@@ -453,4 +725,142 @@ mod tests {
 
         assert!(!path.exists(), "Temp file should be deleted after wait");
     }
+
+    #[tokio::test]
+    async fn wait_reports_guest_crash() {
+        let handle = create_test_handle_with(MockBackendHandle {
+            exit_reason: Some(ExitReason::Panic),
+            crash_report: Some("guest kernel panicked; last console output:\noops".into()),
+        });
+
+        let status = handle.wait().await.unwrap();
+        assert_eq!(
+            status,
+            VmStatus::Failed {
+                message: "guest kernel panicked; last console output:\noops".into()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_reports_panic_without_crash_report() {
+        let handle = create_test_handle_with(MockBackendHandle {
+            exit_reason: Some(ExitReason::Panic),
+            ..Default::default()
+        });
+
+        let status = handle.wait().await.unwrap();
+        assert_eq!(
+            status,
+            VmStatus::Failed {
+                message: "guest kernel panicked".into()
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_reports_exit_reason() {
+        let handle = create_test_handle_with(MockBackendHandle {
+            exit_reason: Some(ExitReason::TripleFault),
+            ..Default::default()
+        });
+
+        let status = handle.wait().await.unwrap();
+        assert_eq!(
+            status,
+            VmStatus::Stopped {
+                reason: Some(ExitReason::TripleFault)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn watchdog_poweroff_is_not_a_failure() {
+        let handle = create_test_handle_with(MockBackendHandle {
+            exit_reason: Some(ExitReason::Watchdog),
+            ..Default::default()
+        });
+
+        let status = handle.wait().await.unwrap();
+        assert_eq!(
+            status,
+            VmStatus::Stopped {
+                reason: Some(ExitReason::Watchdog)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn kill_reports_killed() {
+        let handle = create_test_handle();
+        handle.kill().await.unwrap();
+        assert_eq!(
+            handle.status(),
+            VmStatus::Stopped {
+                reason: Some(ExitReason::Killed)
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshot_requires_running_vm() {
+        let handle = create_test_handle();
+        handle.kill().await.unwrap();
+
+        let err = handle.snapshot("/tmp/snapshot").await.unwrap_err();
+        assert!(matches!(err, Error::NotRunning));
+    }
+
+    #[tokio::test]
+    async fn balloon_unsupported_by_default() {
+        let handle = create_test_handle();
+
+        let err = handle.set_balloon_size_mb(64).await.unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFeature(f) if f.contains("balloon")));
+    }
+
+    #[tokio::test]
+    async fn balloon_requires_running_vm() {
+        let handle = create_test_handle();
+        handle.kill().await.unwrap();
+
+        let err = handle.balloon_size_mb().await.unwrap_err();
+        assert!(matches!(err, Error::NotRunning));
+    }
+
+    #[tokio::test]
+    async fn dump_memory_requires_running_vm() {
+        let handle = create_test_handle();
+        handle.kill().await.unwrap();
+
+        let err = handle.dump_memory("/tmp/vmcore").await.unwrap_err();
+        assert!(matches!(err, Error::NotRunning));
+    }
+
+    #[tokio::test]
+    async fn dump_memory_is_unsupported_by_default() {
+        let err = create_test_handle()
+            .dump_memory("/tmp/vmcore")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFeature(f) if f.contains("memory dumps")));
+    }
+
+    #[tokio::test]
+    async fn snapshot_rejects_ephemeral_disks() {
+        let handle =
+            create_test_handle().with_disk_overlays(vec![PathBuf::from("/test/overlay.qcow2")]);
+
+        let err = handle.snapshot("/tmp/snapshot").await.unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFeature(_)));
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_unsupported_by_default() {
+        let err = create_test_handle()
+            .snapshot("/tmp/snapshot")
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFeature(_)));
+    }
 }
@@ -65,6 +65,7 @@ pub mod test_utils;
 // ============================================================================
 
 pub use builder::{LinuxVmBuilder, UefiVmBuilder};
+pub use capsa_core::ExitReason;
 pub use config::{BootConfig, Capsa};
 pub use console::{ConsoleReader, ConsoleWriter, VmConsole};
 pub use handle::{VmHandle, VmStatus};
@@ -94,9 +95,27 @@ pub use capsa_core::{Error, Result};
 // Advanced API - For specialized use cases
 // ============================================================================
 
+// vCPU layout and host scheduling
+pub use capsa_core::{CpuConfig, CpuModel, CpuTopology};
+
+// Guest memory allocation
+pub use capsa_core::{HugePages, MemoryBacking};
+
+// Guest entropy
+pub use capsa_core::{RngConfig, RngRateLimit};
+
+// Hang detection
+pub use capsa_core::{WatchdogAction, WatchdogConfig};
+
 // Kernel command line customization
 pub use capsa_core::KernelCmdline;
 
+// Guest kernel debugging
+pub use capsa_core::GdbListener;
+
+// Virtio device transport
+pub use capsa_core::VirtioTransport;
+
 // Fine-grained sharing configuration
 pub use capsa_core::ShareMechanism;
 
@@ -119,7 +138,7 @@ pub use capsa_core::ShareMechanism;
 pub mod capabilities {
     pub use super::backend::{HypervisorBackend, available_backends};
     pub use capsa_core::{
-        BackendCapabilities, BootMethodSupport, GuestOsSupport, HostPlatform, ImageFormatSupport,
-        NetworkModeSupport, ShareMechanismSupport,
+        BackendCapabilities, BootMethodSupport, CpuSupport, GuestOsSupport, HostPlatform,
+        ImageFormatSupport, MemoryBackingSupport, NetworkModeSupport, ShareMechanismSupport,
     };
 }
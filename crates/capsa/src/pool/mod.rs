@@ -5,19 +5,12 @@
 //!
 //! See the [VM Pools guide](crate::guides::vm_pools) for patterns and best practices.
 
-// TODO: even though VM pools don't allow for their VMs to have disks,
-// in practice, they might still allowed to have root disks (if the root
-// disk is set on LinuxDirectBootConfig, for example). however, we may want
-// to allow those with read-only access to the disk. it is also unclear
-// what happens when LinuxDirectBootConfig is setup with a root disk right now
-// and VMs are pooled using that config
-
 mod poolable;
 
 pub(crate) use poolable::{No, Poolability, Yes};
 
 use crate::backend::select_backend;
-use crate::builder::generate_temp_efi_store_path;
+use crate::builder::{generate_temp_efi_store_path, prepare_ephemeral_disks, remove_overlays};
 use crate::handle::VmHandle;
 use capsa_core::{BootMethod, Error, GuestOs, HypervisorBackend, Result, VmConfig};
 use std::ops::Deref;
@@ -76,7 +69,7 @@ impl VmPool {
     }
 
     async fn spawn_vm(base_config: &VmConfig, backend: &dyn HypervisorBackend) -> Result<VmHandle> {
-        let (vm_config, temp_file) = match &base_config.boot {
+        let (mut vm_config, temp_file) = match &base_config.boot {
             BootMethod::Uefi { .. } => {
                 let temp_efi_path = generate_temp_efi_store_path();
                 let mut uefi_config = base_config.clone();
@@ -89,8 +82,18 @@ impl VmPool {
             _ => (base_config.clone(), None),
         };
 
-        let backend_handle = backend.start(&vm_config).await?;
-        let mut handle = VmHandle::new(backend_handle, GuestOs::Linux, vm_config.resources.clone());
+        // Each VM gets its own overlay so pooled VMs never see each other's writes
+        let overlays = prepare_ephemeral_disks(&mut vm_config, backend.capabilities())?;
+
+        let backend_handle = match backend.start(&vm_config).await {
+            Ok(handle) => handle,
+            Err(e) => {
+                remove_overlays(&overlays);
+                return Err(e);
+            }
+        };
+        let mut handle = VmHandle::new(backend_handle, GuestOs::Linux, vm_config.resources.clone())
+            .with_disk_overlays(overlays);
         if let Some(path) = temp_file {
             handle = handle.with_temp_file(path);
         }
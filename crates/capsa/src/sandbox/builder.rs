@@ -4,8 +4,8 @@ use super::config::{CapsaSandboxConfig, MainProcess, ShareConfig};
 use crate::backend::select_backend;
 use crate::handle::VmHandle;
 use capsa_core::{
-    BootMethod, Error, GuestOs, MountMode, NetworkMode, ResourceConfig, Result, SharedDir,
-    VmConfig, VsockConfig,
+    BootMethod, Error, GuestOs, MountMode, NetworkMode, ResourceConfig, Result, RngConfig,
+    SharedDir, VirtioTransport, VmConfig, VsockConfig,
 };
 use capsa_sandbox_protocol::AGENT_VSOCK_PORT;
 use std::marker::PhantomData;
@@ -38,6 +38,9 @@ pub struct SandboxBuilder<M = NoMainProcess> {
     #[allow(dead_code)] // Will be used in build() implementation
     pub(crate) main_process: Option<MainProcess>,
     pub(crate) resources: ResourceConfig,
+    /// Set by `.rng()`, so a backend without an entropy device fails the
+    /// build instead of leaving it out.
+    pub(crate) rng_requested: bool,
     pub(crate) network: NetworkMode,
     pub(crate) console_enabled: bool,
     pub(crate) vsock: VsockConfig,
@@ -51,7 +54,13 @@ impl SandboxBuilder<NoMainProcess> {
             config: CapsaSandboxConfig::new(),
             shares: Vec::new(),
             main_process: None,
-            resources: ResourceConfig::default(),
+            // Minimal guests block in getrandom() at boot without an entropy
+            // source
+            resources: ResourceConfig {
+                rng: Some(RngConfig::default()),
+                ..Default::default()
+            },
+            rng_requested: false,
             network: NetworkMode::default(),
             console_enabled: true,
             vsock: VsockConfig::default(),
@@ -68,6 +77,7 @@ impl SandboxBuilder<NoMainProcess> {
             shares: self.shares,
             main_process: Some(MainProcess::run(path, args)),
             resources: self.resources,
+            rng_requested: self.rng_requested,
             network: self.network,
             console_enabled: self.console_enabled,
             vsock: self.vsock,
@@ -84,6 +94,7 @@ impl SandboxBuilder<NoMainProcess> {
             shares: self.shares,
             main_process: Some(MainProcess::oci(image, args)),
             resources: self.resources,
+            rng_requested: self.rng_requested,
             network: self.network,
             console_enabled: self.console_enabled,
             vsock: self.vsock,
@@ -140,6 +151,21 @@ impl<M> SandboxBuilder<M> {
         self.network(NetworkMode::None)
     }
 
+    /// Configures the entropy device sandboxes get by default, for example
+    /// to limit how fast the guest can draw entropy.
+    pub fn rng(mut self, config: RngConfig) -> Self {
+        self.resources.rng = Some(config);
+        self.rng_requested = true;
+        self
+    }
+
+    /// Removes the entropy device from the VM.
+    pub fn no_rng(mut self) -> Self {
+        self.resources.rng = None;
+        self.rng_requested = false;
+        self
+    }
+
     /// Overrides the default sandbox kernel.
     pub fn kernel(mut self, path: impl Into<PathBuf>) -> Self {
         self.config.kernel_override = Some(path.into());
@@ -225,6 +251,10 @@ impl SandboxBuilder<HasMainProcess> {
         ));
         let vsock_for_handle = vsock.clone();
 
+        let backend = select_backend()?;
+        let mut resources = self.resources.clone();
+        resources.rng = self.entropy_device(backend.capabilities().devices.rng)?;
+
         let config = VmConfig {
             boot: BootMethod::LinuxDirect {
                 kernel,
@@ -233,15 +263,18 @@ impl SandboxBuilder<HasMainProcess> {
             },
             root_disk: None,
             disks: Vec::new(),
-            resources: self.resources.clone(),
+            resources,
             shares,
             network: self.network,
             console_enabled: self.console_enabled,
             vsock,
+            reboot_in_place: false,
+            gdb: None,
+            virtio_transport: VirtioTransport::default(),
+            watchdog: None,
             cluster_network_fd: None,
         };
 
-        let backend = select_backend()?;
         let backend_handle = backend.start(&config).await?;
 
         Ok(
@@ -250,12 +283,24 @@ impl SandboxBuilder<HasMainProcess> {
         )
     }
 
+    /// Returns the entropy device to give the VM. The default one is left out
+    /// where the backend has none, but one asked for with `.rng()` is not.
+    fn entropy_device(&self, supported: bool) -> Result<Option<RngConfig>> {
+        match &self.resources.rng {
+            Some(_) if !supported && self.rng_requested => {
+                Err(Error::UnsupportedFeature("entropy device".into()))
+            }
+            Some(_) if !supported => Ok(None),
+            rng => Ok(rng.clone()),
+        }
+    }
+
     fn generate_cmdline(&self) -> String {
         use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
 
         let mut parts = vec![
             "console=hvc0".to_string(),
-            "reboot=t".to_string(),
+            "reboot=k".to_string(),
             "panic=-1".to_string(),
             "threadirqs".to_string(),
             "acpi=off".to_string(),
@@ -436,6 +481,41 @@ mod tests {
             let builder = SandboxBuilder::new();
             assert!(builder.console_enabled);
         }
+
+        #[test]
+        fn rng_enabled_by_default() {
+            let builder = SandboxBuilder::new();
+            assert_eq!(builder.resources.rng, Some(RngConfig::default()));
+        }
+
+        #[test]
+        fn rng_sets_rate_limit() {
+            let config = RngConfig::rate_limited(256, std::time::Duration::from_secs(1));
+            let builder = SandboxBuilder::new().rng(config.clone());
+            assert_eq!(builder.resources.rng, Some(config));
+        }
+
+        #[test]
+        fn no_rng_removes_device() {
+            let builder = SandboxBuilder::new().no_rng();
+            assert!(builder.resources.rng.is_none());
+        }
+
+        #[test]
+        fn only_requested_rng_needs_backend_support() {
+            let builder = SandboxBuilder::new().run("/bin/sh", &[]);
+            assert_eq!(builder.entropy_device(false).unwrap(), None);
+            assert_eq!(
+                builder.entropy_device(true).unwrap(),
+                Some(RngConfig::default())
+            );
+
+            let builder = builder.rng(RngConfig::default());
+            assert!(matches!(
+                builder.entropy_device(false),
+                Err(Error::UnsupportedFeature(_))
+            ));
+        }
     }
 
     mod cmdline_generation {
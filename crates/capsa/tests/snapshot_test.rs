@@ -0,0 +1,69 @@
+//! Integration tests for VM snapshot and restore.
+
+#[cfg(feature = "linux-kvm")]
+use capsa::Capsa;
+#[cfg(feature = "linux-kvm")]
+use capsa::test_utils::test_vm;
+#[cfg(feature = "linux-kvm")]
+use std::time::Duration;
+
+#[tokio::test]
+async fn test_restore_resumes_guest() {
+    #[cfg(not(feature = "linux-kvm"))]
+    {
+        eprintln!("Skipping: only the KVM backend supports snapshots");
+    }
+
+    #[cfg(feature = "linux-kvm")]
+    {
+        let vm = test_vm("default")
+            .build()
+            .await
+            .expect("Failed to build VM");
+        let console = vm.console().await.expect("Failed to get console");
+
+        console
+            .wait_for_timeout("Boot successful", Duration::from_secs(30))
+            .await
+            .expect("VM did not boot");
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        console
+            .write_line("export SNAPSHOT_MARKER=restored-guest")
+            .await
+            .expect("Failed to write");
+
+        let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+        let snapshot_path = temp_dir.path().join("vm.snap");
+        vm.snapshot(&snapshot_path)
+            .await
+            .expect("Failed to snapshot VM");
+        vm.kill().await.expect("Failed to kill VM");
+
+        let restored = Capsa::restore(&snapshot_path)
+            .await
+            .expect("Failed to restore VM");
+        let console = restored.console().await.expect("Failed to get console");
+
+        console
+            .write_line("echo marker=$SNAPSHOT_MARKER")
+            .await
+            .expect("Failed to write");
+        console
+            .wait_for_timeout("marker=restored-guest", Duration::from_secs(5))
+            .await
+            .expect("Restored guest lost its shell state");
+
+        restored.kill().await.expect("Failed to kill VM");
+    }
+}
+
+#[tokio::test]
+async fn test_restore_rejects_invalid_file() {
+    let temp_dir = tempfile::tempdir().expect("Failed to create temp dir");
+    let path = temp_dir.path().join("not-a-snapshot");
+    std::fs::write(&path, b"definitely not a snapshot").expect("Failed to write file");
+
+    assert!(capsa::Capsa::restore(&path).await.is_err());
+}
@@ -1,12 +1,15 @@
 use crate::boot::KernelCmdline;
 use crate::capabilities::BackendCapabilities;
-use crate::error::Result;
-use crate::types::{DiskImage, HostPlatform, NetworkMode, ResourceConfig, SharedDir};
+use crate::error::{Error, Result};
+use crate::types::{
+    DiskImage, GdbListener, HostPlatform, NetworkMode, ResourceConfig, SharedDir, VirtioTransport,
+    WatchdogConfig,
+};
 use crate::vsock::VsockConfig;
 use async_trait::async_trait;
 use serde::{Deserialize, Serialize};
 use std::os::fd::RawFd;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::io::{AsyncRead, AsyncWrite};
 
 /// Boot method configuration for a VM.
@@ -37,12 +40,45 @@ pub struct VmConfig {
     pub console_enabled: bool,
     #[serde(default)]
     pub vsock: VsockConfig,
+    /// Restart the guest when it reboots, instead of stopping the VM.
+    #[serde(default)]
+    pub reboot_in_place: bool,
+    /// Socket of a GDB stub for debugging the guest kernel. The guest starts
+    /// halted until a debugger attaches.
+    #[serde(default)]
+    pub gdb: Option<GdbListener>,
+    /// How virtio devices are attached to the guest.
+    #[serde(default)]
+    pub virtio_transport: VirtioTransport,
+    /// Hardware watchdog the guest has to keep pinging once it starts it.
+    #[serde(default)]
+    pub watchdog: Option<WatchdogConfig>,
     /// Pre-created network guest fd for Cluster mode.
     /// When set, the backend should use this fd instead of creating its own.
     #[serde(skip)]
     pub cluster_network_fd: Option<RawFd>,
 }
 
+/// Why a VM stopped.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExitReason {
+    /// The guest powered itself off.
+    Poweroff,
+    /// The guest asked to reboot, through the keyboard controller or an ACPI
+    /// reset. VMs that reboot in place keep running instead.
+    Reboot,
+    /// The guest triple faulted, which resets the CPU on real hardware.
+    TripleFault,
+    /// The guest kernel panicked.
+    Panic,
+    /// The guest stopped pinging its watchdog.
+    Watchdog,
+    /// The hypervisor could not keep running the guest.
+    InternalError { message: String },
+    /// The host killed the VM.
+    Killed,
+}
+
 pub type ConsoleStream = Box<dyn ConsoleIo + Send>;
 
 pub trait ConsoleIo: AsyncRead + AsyncWrite + Unpin {}
@@ -51,11 +87,52 @@ impl<T: AsyncRead + AsyncWrite + Unpin> ConsoleIo for T {}
 #[async_trait]
 pub trait BackendVmHandle: Send + Sync {
     async fn is_running(&self) -> bool;
-    async fn wait(&self) -> Result<i32>;
+    async fn wait(&self) -> Result<ExitReason>;
     // TODO: better investigate how shutdown is handling ACPI, timeouts, etc
     async fn shutdown(&self) -> Result<()>;
     async fn kill(&self) -> Result<()>;
     async fn console_stream(&self) -> Result<Option<ConsoleStream>>;
+
+    /// Describes the guest crash that stopped the VM, if it crashed.
+    ///
+    /// Only meaningful once [`wait`](Self::wait) has returned.
+    async fn crash_report(&self) -> Option<String> {
+        None
+    }
+
+    /// Stops all vCPUs and device activity, keeping the VM's state in memory.
+    async fn pause(&self) -> Result<()> {
+        Err(Error::UnsupportedFeature("pausing VMs".into()))
+    }
+
+    /// Continues a VM stopped by [`pause`](Self::pause).
+    async fn resume(&self) -> Result<()> {
+        Err(Error::UnsupportedFeature("pausing VMs".into()))
+    }
+
+    /// Saves the VM's memory and device state to `path`.
+    ///
+    /// The VM is paused while the snapshot is written. It resumes afterwards
+    /// unless it was already paused.
+    async fn snapshot(&self, _path: &Path) -> Result<()> {
+        Err(Error::UnsupportedFeature("VM snapshots".into()))
+    }
+
+    /// Writes guest memory and vCPU registers to `path` as an ELF core file.
+    async fn dump_memory(&self, _path: &Path) -> Result<()> {
+        Err(Error::UnsupportedFeature("guest memory dumps".into()))
+    }
+
+    /// Asks the guest to hand `size_mb` of its memory back to the host
+    /// through the balloon device.
+    async fn set_balloon_size(&self, _size_mb: u32) -> Result<()> {
+        Err(Error::UnsupportedFeature("memory balloon".into()))
+    }
+
+    /// Returns how much memory the guest has handed back, in MB.
+    async fn balloon_size(&self) -> Result<u32> {
+        Err(Error::UnsupportedFeature("memory balloon".into()))
+    }
 }
 
 #[async_trait]
@@ -67,4 +144,17 @@ pub trait HypervisorBackend: Send + Sync {
     async fn start(&self, config: &VmConfig) -> Result<Box<dyn BackendVmHandle>>;
     fn kernel_cmdline_defaults(&self) -> KernelCmdline;
     fn default_root_device(&self) -> &str;
+
+    /// Reads the configuration of the VM a snapshot was taken from.
+    fn snapshot_config(&self, _path: &Path) -> Result<VmConfig> {
+        Err(Error::UnsupportedFeature("VM snapshots".into()))
+    }
+
+    /// Resumes a VM from a snapshot written by [`BackendVmHandle::snapshot`].
+    ///
+    /// `config` must describe the same hardware as the snapshot; only host-side
+    /// settings such as vsock socket paths may differ.
+    async fn restore(&self, _path: &Path, _config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
+        Err(Error::UnsupportedFeature("VM snapshots".into()))
+    }
 }
@@ -0,0 +1,100 @@
+//! Storage backends for emulated block devices.
+//!
+//! Backends that emulate block devices in userspace (such as KVM) use these to
+//! access disk images independently of their on-disk format.
+
+mod qcow2;
+mod raw;
+
+pub use qcow2::Qcow2Image;
+pub use raw::RawImage;
+
+use crate::types::{DiskImage, ImageFormat};
+use std::io;
+
+/// Random-access storage behind a guest block device.
+pub trait BlockBackend: Send {
+    /// Returns the virtual disk size in bytes.
+    fn size(&self) -> u64;
+
+    /// Reads exactly `buf.len()` bytes starting at `offset`.
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()>;
+
+    /// Writes all of `buf` starting at `offset`.
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()>;
+
+    /// Flushes written data to stable storage.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Opens a disk image with the backend matching its format.
+pub fn open_disk(disk: &DiskImage) -> io::Result<Box<dyn BlockBackend>> {
+    match disk.format {
+        ImageFormat::Raw => Ok(Box::new(RawImage::open(&disk.path, disk.read_only)?)),
+        ImageFormat::Qcow2 => Ok(Box::new(Qcow2Image::open(&disk.path, disk.read_only)?)),
+    }
+}
+
+fn check_bounds(size: u64, offset: u64, len: usize) -> io::Result<()> {
+    match offset.checked_add(len as u64) {
+        Some(end) if end <= size => Ok(()),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "access of {} bytes at offset {} beyond end of {} byte disk",
+                len, offset, size
+            ),
+        )),
+    }
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "disk image is read-only")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tempfile::{NamedTempFile, TempDir};
+
+    #[test]
+    fn open_disk_raw() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&[7u8; 1024]).unwrap();
+
+        let mut disk = open_disk(&DiskImage::with_format(file.path(), ImageFormat::Raw)).unwrap();
+        assert_eq!(disk.size(), 1024);
+
+        let mut buf = [0u8; 16];
+        disk.read_at(&mut buf, 100).unwrap();
+        assert_eq!(buf, [7u8; 16]);
+    }
+
+    #[test]
+    fn open_disk_qcow2() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        Qcow2Image::create(&path, 1 << 20, None).unwrap();
+
+        let disk = open_disk(&DiskImage::new(&path)).unwrap();
+        assert_eq!(disk.size(), 1 << 20);
+    }
+
+    #[test]
+    fn open_disk_honors_read_only() {
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&[0u8; 512]).unwrap();
+
+        let mut disk = open_disk(&DiskImage::new(file.path()).read_only()).unwrap();
+        let err = disk.write_at(&[1u8; 4], 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn check_bounds_rejects_overflow() {
+        assert!(check_bounds(1024, 1000, 24).is_ok());
+        assert!(check_bounds(1024, 1000, 25).is_err());
+        assert!(check_bounds(1024, u64::MAX, 2).is_err());
+    }
+}
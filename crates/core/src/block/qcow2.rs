@@ -0,0 +1,1141 @@
+//! Native qcow2 image support.
+//!
+//! Implements the parts of the qcow2 format (versions 2 and 3) needed to back
+//! a guest block device: two-level cluster mapping, 16-bit refcounts, cluster
+//! allocation, zero clusters and backing-file chains. Compressed clusters,
+//! encryption, external data files and writes to images with internal
+//! snapshots are rejected.
+//!
+//! New clusters are always appended to the end of the file. Data is written
+//! before the metadata that references it, so an interrupted write can leak
+//! clusters but never exposes stale data to the guest.
+
+use super::raw::RawImage;
+use super::{BlockBackend, check_bounds, read_only_error};
+use crate::types::ImageFormat;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+
+const QCOW2_MAGIC: u32 = 0x5146_49fb;
+
+const V2_HEADER_LEN: usize = 72;
+const V3_HEADER_LEN: usize = 104;
+
+// Header field offsets
+const HEADER_REFCOUNT_TABLE_OFFSET: u64 = 48;
+const HEADER_AUTOCLEAR_FEATURES: u64 = 88;
+
+const HEADER_EXT_END: u32 = 0;
+const HEADER_EXT_BACKING_FORMAT: u32 = 0xe279_2aca;
+
+const MIN_CLUSTER_BITS: u32 = 9;
+const MAX_CLUSTER_BITS: u32 = 21;
+const DEFAULT_CLUSTER_BITS: u32 = 16;
+
+/// Only 16-bit refcounts (the qemu-img default) are supported.
+const REFCOUNT_ORDER: u32 = 4;
+
+const L1_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const L2_OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+const REFCOUNT_TABLE_OFFSET_MASK: u64 = 0xffff_ffff_ffff_fe00;
+
+/// Set on L1/L2 entries whose cluster has a refcount of exactly one.
+const QCOW_OFLAG_COPIED: u64 = 1 << 63;
+const QCOW_OFLAG_COMPRESSED: u64 = 1 << 62;
+/// Set on L2 entries whose cluster reads as zeroes (version 3 only).
+const QCOW_OFLAG_ZERO: u64 = 1;
+
+const INCOMPAT_DIRTY: u64 = 1 << 0;
+
+// Limits matching qemu, so a malformed header cannot trigger huge allocations
+const MAX_L1_TABLE_BYTES: u64 = 32 * 1024 * 1024;
+const MAX_REFCOUNT_TABLE_BYTES: u64 = 8 * 1024 * 1024;
+const MAX_BACKING_FILE_NAME: usize = 1023;
+const MAX_BACKING_CHAIN_DEPTH: usize = 16;
+
+/// Number of L2 tables kept in memory. The cache is dropped when full.
+const L2_CACHE_TABLES: usize = 32;
+
+/// A qcow2 disk image.
+pub struct Qcow2Image {
+    file: File,
+    read_only: bool,
+    cluster_bits: u32,
+    cluster_size: u64,
+    size: u64,
+    l1_table_offset: u64,
+    l1_table: Vec<u64>,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    l2_cache: HashMap<u64, Vec<u64>>,
+    /// Host offset where the next cluster will be allocated.
+    next_free_cluster: u64,
+    backing: Option<Box<dyn BlockBackend>>,
+}
+
+impl Qcow2Image {
+    /// Opens an existing qcow2 image.
+    ///
+    /// Backing files are opened read-only. Relative backing file paths are
+    /// resolved against the directory containing the image.
+    pub fn open(path: &Path, read_only: bool) -> io::Result<Self> {
+        Self::open_chain(path, read_only, 0)
+    }
+
+    /// Creates a new, empty qcow2 (version 3) image of `size` bytes.
+    ///
+    /// When `backing` is given, clusters that have not been written read from
+    /// that file, making the new image a copy-on-write overlay. The path is
+    /// stored as given, along with the format, so the backing file is never
+    /// probed. Fails if `path` already exists.
+    pub fn create(
+        path: &Path,
+        size: u64,
+        backing: Option<(&Path, ImageFormat)>,
+    ) -> io::Result<Self> {
+        Self::create_with_cluster_bits(path, size, backing, DEFAULT_CLUSTER_BITS)
+    }
+
+    fn create_with_cluster_bits(
+        path: &Path,
+        size: u64,
+        backing: Option<(&Path, ImageFormat)>,
+        cluster_bits: u32,
+    ) -> io::Result<Self> {
+        let cluster_size = 1u64 << cluster_bits;
+        let l1_size = l1_entries_for(size, cluster_bits);
+        let l1_clusters = (l1_size * 8).div_ceil(cluster_size).max(1);
+        if l1_size * 8 > MAX_L1_TABLE_BYTES {
+            return Err(invalid_input(format!("image size {} is too large", size)));
+        }
+
+        // Header extensions start at V3_HEADER_LEN and are terminated by an
+        // all-zero end marker, so the backing file name goes after that
+        let mut extensions = Vec::new();
+        let backing_name = match backing {
+            Some((p, format)) => {
+                let name = p
+                    .to_str()
+                    .ok_or_else(|| invalid_input("backing file path is not valid UTF-8"))?;
+                push_header_extension(
+                    &mut extensions,
+                    HEADER_EXT_BACKING_FORMAT,
+                    backing_format_name(format).as_bytes(),
+                );
+                if name.len() > MAX_BACKING_FILE_NAME
+                    || V3_HEADER_LEN + extensions.len() + 8 + name.len() > cluster_size as usize
+                {
+                    return Err(invalid_input("backing file path is too long"));
+                }
+                Some(name.as_bytes().to_vec())
+            }
+            None => None,
+        };
+
+        // Layout: header, refcount table, refcount block, L1 table
+        let refcount_table_offset = cluster_size;
+        let refcount_block_offset = 2 * cluster_size;
+        let l1_table_offset = 3 * cluster_size;
+        let metadata_clusters = 3 + l1_clusters;
+        if metadata_clusters > cluster_size * 8 / (1 << REFCOUNT_ORDER) {
+            return Err(invalid_input(format!("image size {} is too large", size)));
+        }
+
+        let mut header = vec![0u8; cluster_size as usize];
+        put_u32(&mut header, 0, QCOW2_MAGIC);
+        put_u32(&mut header, 4, 3);
+        header[V3_HEADER_LEN..V3_HEADER_LEN + extensions.len()].copy_from_slice(&extensions);
+        if let Some(name) = &backing_name {
+            let name_offset = V3_HEADER_LEN + extensions.len() + 8;
+            header[name_offset..name_offset + name.len()].copy_from_slice(name);
+            put_u64(&mut header, 8, name_offset as u64);
+            put_u32(&mut header, 16, name.len() as u32);
+        }
+        put_u32(&mut header, 20, cluster_bits);
+        put_u64(&mut header, 24, size);
+        put_u32(&mut header, 36, l1_size as u32);
+        put_u64(&mut header, 40, l1_table_offset);
+        put_u64(&mut header, 48, refcount_table_offset);
+        put_u32(&mut header, 56, 1);
+        put_u32(&mut header, 96, REFCOUNT_ORDER);
+        put_u32(&mut header, 100, V3_HEADER_LEN as u32);
+
+        let mut refcount_table = vec![0u8; cluster_size as usize];
+        put_u64(&mut refcount_table, 0, refcount_block_offset);
+
+        let mut refcount_block = vec![0u8; cluster_size as usize];
+        for i in 0..metadata_clusters as usize {
+            put_u16(&mut refcount_block, i * 2, 1);
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.write_all_at(&header, 0)?;
+        file.write_all_at(&refcount_table, refcount_table_offset)?;
+        file.write_all_at(&refcount_block, refcount_block_offset)?;
+        file.set_len(metadata_clusters * cluster_size)?;
+        file.sync_all()?;
+        drop(file);
+
+        Self::open(path, false)
+    }
+
+    fn open_chain(path: &Path, read_only: bool, depth: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(!read_only).open(path)?;
+        let file_len = file.metadata()?.len();
+
+        let mut header = [0u8; V3_HEADER_LEN];
+        if file_len < V2_HEADER_LEN as u64 {
+            return Err(invalid_data("file too small for qcow2 header"));
+        }
+        let header_len = (file_len as usize).min(V3_HEADER_LEN);
+        file.read_exact_at(&mut header[..header_len], 0)?;
+
+        if get_u32(&header, 0) != QCOW2_MAGIC {
+            return Err(invalid_data("bad magic"));
+        }
+        let version = get_u32(&header, 4);
+        if version != 2 && version != 3 {
+            return Err(unsupported(format!("version {}", version)));
+        }
+
+        let backing_file_offset = get_u64(&header, 8);
+        let backing_file_size = get_u32(&header, 16) as usize;
+        let cluster_bits = get_u32(&header, 20);
+        let size = get_u64(&header, 24);
+        let crypt_method = get_u32(&header, 32);
+        let l1_size = get_u32(&header, 36) as u64;
+        let l1_table_offset = get_u64(&header, 40);
+        let refcount_table_offset = get_u64(&header, 48);
+        let refcount_table_clusters = get_u32(&header, 56) as u64;
+        let nb_snapshots = get_u32(&header, 60);
+
+        if !(MIN_CLUSTER_BITS..=MAX_CLUSTER_BITS).contains(&cluster_bits) {
+            return Err(invalid_data(format!("cluster bits {}", cluster_bits)));
+        }
+        let cluster_size = 1u64 << cluster_bits;
+
+        if crypt_method != 0 {
+            return Err(unsupported("encrypted images"));
+        }
+
+        let mut extensions_offset = V2_HEADER_LEN as u64;
+
+        if version == 3 {
+            if header_len < V3_HEADER_LEN {
+                return Err(invalid_data("file too small for version 3 header"));
+            }
+            extensions_offset = get_u32(&header, 100) as u64;
+            if extensions_offset < V3_HEADER_LEN as u64 {
+                return Err(invalid_data(format!("header length {}", extensions_offset)));
+            }
+            let incompatible = get_u64(&header, 72);
+            if incompatible & !INCOMPAT_DIRTY != 0 {
+                return Err(unsupported(format!(
+                    "incompatible features {:#x}",
+                    incompatible & !INCOMPAT_DIRTY
+                )));
+            }
+            if incompatible & INCOMPAT_DIRTY != 0 && !read_only {
+                return Err(unsupported(
+                    "writing images with dirty refcounts (run `qemu-img check -r all` first)",
+                ));
+            }
+            let refcount_order = get_u32(&header, 96);
+            if refcount_order != REFCOUNT_ORDER {
+                return Err(unsupported(format!(
+                    "{}-bit refcounts",
+                    1u32 << refcount_order.min(31)
+                )));
+            }
+            // No autoclear feature is supported, and writers must clear the
+            // bits they don't know so other tools stop trusting that data
+            let autoclear = get_u64(&header, HEADER_AUTOCLEAR_FEATURES as usize);
+            if autoclear != 0 && !read_only {
+                file.write_all_at(&0u64.to_be_bytes(), HEADER_AUTOCLEAR_FEATURES)?;
+                file.sync_data()?;
+            }
+        }
+
+        if nb_snapshots != 0 && !read_only {
+            return Err(unsupported("writing images with internal snapshots"));
+        }
+
+        if l1_size < l1_entries_for(size, cluster_bits) {
+            return Err(invalid_data("L1 table too small for image size"));
+        }
+        if l1_size * 8 > MAX_L1_TABLE_BYTES {
+            return Err(invalid_data("L1 table too large"));
+        }
+        if refcount_table_clusters * cluster_size > MAX_REFCOUNT_TABLE_BYTES {
+            return Err(invalid_data("refcount table too large"));
+        }
+        if !l1_table_offset.is_multiple_of(cluster_size)
+            || !refcount_table_offset.is_multiple_of(cluster_size)
+        {
+            return Err(invalid_data("metadata table not cluster aligned"));
+        }
+
+        let l1_table = read_table(&file, l1_table_offset, l1_size as usize)?;
+        let refcount_table = read_table(
+            &file,
+            refcount_table_offset,
+            (refcount_table_clusters * cluster_size / 8) as usize,
+        )?;
+
+        let backing = if backing_file_offset != 0 {
+            if depth >= MAX_BACKING_CHAIN_DEPTH {
+                return Err(invalid_data("backing file chain too deep"));
+            }
+            if backing_file_size > MAX_BACKING_FILE_NAME {
+                return Err(invalid_data("backing file name too long"));
+            }
+            let mut name = vec![0u8; backing_file_size];
+            file.read_exact_at(&mut name, backing_file_offset)?;
+            let name = String::from_utf8(name)
+                .map_err(|_| invalid_data("backing file name is not valid UTF-8"))?;
+            let backing_path = resolve_backing_path(path, &name);
+            let format = read_backing_format(&file, extensions_offset, cluster_size)?;
+            Some(open_backing(&backing_path, format, depth + 1)?)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            file,
+            read_only,
+            cluster_bits,
+            cluster_size,
+            size,
+            l1_table_offset,
+            l1_table,
+            refcount_table_offset,
+            refcount_table,
+            l2_cache: HashMap::new(),
+            next_free_cluster: file_len.div_ceil(cluster_size) * cluster_size,
+            backing,
+        })
+    }
+
+    /// Splits a guest offset into L1 and L2 table indices.
+    fn table_indices(&self, guest_offset: u64) -> (usize, usize) {
+        let l2_bits = self.cluster_bits - 3;
+        let cluster = guest_offset >> self.cluster_bits;
+        (
+            (cluster >> l2_bits) as usize,
+            (cluster & ((1 << l2_bits) - 1)) as usize,
+        )
+    }
+
+    fn load_l2_table(&mut self, l2_offset: u64) -> io::Result<&mut Vec<u64>> {
+        if !self.l2_cache.contains_key(&l2_offset) {
+            if !l2_offset.is_multiple_of(self.cluster_size) {
+                return Err(invalid_data(format!(
+                    "unaligned L2 table offset {:#x}",
+                    l2_offset
+                )));
+            }
+            let table = read_table(&self.file, l2_offset, (self.cluster_size / 8) as usize)?;
+            if self.l2_cache.len() >= L2_CACHE_TABLES {
+                self.l2_cache.clear();
+            }
+            self.l2_cache.insert(l2_offset, table);
+        }
+        Ok(self.l2_cache.get_mut(&l2_offset).unwrap())
+    }
+
+    /// Returns the L2 entry mapping `guest_offset`, or 0 if unallocated.
+    fn l2_entry(&mut self, guest_offset: u64) -> io::Result<u64> {
+        let (l1_index, l2_index) = self.table_indices(guest_offset);
+        let l2_offset = self.l1_table.get(l1_index).copied().unwrap_or(0) & L1_OFFSET_MASK;
+        if l2_offset == 0 {
+            return Ok(0);
+        }
+        Ok(self.load_l2_table(l2_offset)?[l2_index])
+    }
+
+    fn set_l2_entry(&mut self, guest_offset: u64, entry: u64) -> io::Result<()> {
+        let (l1_index, l2_index) = self.table_indices(guest_offset);
+        let mut l2_offset = self.l1_table[l1_index] & L1_OFFSET_MASK;
+
+        if l2_offset == 0 {
+            l2_offset = self.allocate_clusters(1)?;
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size as usize], l2_offset)?;
+            let l1_entry = l2_offset | QCOW_OFLAG_COPIED;
+            self.file.write_all_at(
+                &l1_entry.to_be_bytes(),
+                self.l1_table_offset + l1_index as u64 * 8,
+            )?;
+            self.l1_table[l1_index] = l1_entry;
+        }
+
+        self.load_l2_table(l2_offset)?[l2_index] = entry;
+        self.file
+            .write_all_at(&entry.to_be_bytes(), l2_offset + l2_index as u64 * 8)
+    }
+
+    /// Reserves `count` contiguous clusters at the end of the file and marks
+    /// them as referenced.
+    fn allocate_clusters(&mut self, count: u64) -> io::Result<u64> {
+        let offset = self.next_free_cluster;
+        self.next_free_cluster += count * self.cluster_size;
+        for i in 0..count {
+            self.set_refcount(offset + i * self.cluster_size, 1)?;
+        }
+        Ok(offset)
+    }
+
+    fn refcount_block_entries(&self) -> u64 {
+        self.cluster_size * 8 / (1 << REFCOUNT_ORDER)
+    }
+
+    fn set_refcount(&mut self, host_offset: u64, refcount: u16) -> io::Result<()> {
+        let cluster_index = host_offset >> self.cluster_bits;
+        let table_index = (cluster_index / self.refcount_block_entries()) as usize;
+        if table_index >= self.refcount_table.len() {
+            self.grow_refcount_table(table_index + 1)?;
+        }
+
+        let mut block_offset = self.refcount_table[table_index] & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            block_offset = self.next_free_cluster;
+            self.next_free_cluster += self.cluster_size;
+            self.file
+                .write_all_at(&vec![0u8; self.cluster_size as usize], block_offset)?;
+            self.file.write_all_at(
+                &block_offset.to_be_bytes(),
+                self.refcount_table_offset + table_index as u64 * 8,
+            )?;
+            self.refcount_table[table_index] = block_offset;
+            // The new block may be covered by itself or by another block
+            self.set_refcount(block_offset, 1)?;
+        }
+
+        let entry_offset = block_offset + (cluster_index % self.refcount_block_entries()) * 2;
+        self.file
+            .write_all_at(&refcount.to_be_bytes(), entry_offset)
+    }
+
+    /// Moves the refcount table to a larger location at the end of the file.
+    fn grow_refcount_table(&mut self, min_entries: usize) -> io::Result<()> {
+        let entries_per_cluster = self.cluster_size / 8;
+        // Leave headroom so the table doesn't have to move on every growth step
+        let new_clusters = (min_entries as u64).div_ceil(entries_per_cluster) * 2;
+        if new_clusters * self.cluster_size > MAX_REFCOUNT_TABLE_BYTES {
+            return Err(io::Error::other("qcow2: refcount table limit reached"));
+        }
+
+        let new_offset = self.next_free_cluster;
+        self.next_free_cluster += new_clusters * self.cluster_size;
+
+        let mut table = self.refcount_table.clone();
+        table.resize((new_clusters * entries_per_cluster) as usize, 0);
+        self.file
+            .write_all_at(&table_to_bytes(&table), new_offset)?;
+
+        let mut header_fields = [0u8; 12];
+        put_u64(&mut header_fields, 0, new_offset);
+        put_u32(&mut header_fields, 8, new_clusters as u32);
+        self.file
+            .write_all_at(&header_fields, HEADER_REFCOUNT_TABLE_OFFSET)?;
+
+        let old_offset = self.refcount_table_offset;
+        let old_clusters = self.refcount_table.len() as u64 / entries_per_cluster;
+        self.refcount_table = table;
+        self.refcount_table_offset = new_offset;
+
+        for i in 0..new_clusters {
+            self.set_refcount(new_offset + i * self.cluster_size, 1)?;
+        }
+        for i in 0..old_clusters {
+            self.set_refcount(old_offset + i * self.cluster_size, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Reads guest data that is not allocated in this image.
+    fn read_unallocated(&mut self, buf: &mut [u8], guest_offset: u64) -> io::Result<()> {
+        let Some(backing) = self.backing.as_mut() else {
+            buf.fill(0);
+            return Ok(());
+        };
+
+        // The backing file may be smaller than this image
+        let backing_size = backing.size();
+        let available = backing_size
+            .saturating_sub(guest_offset)
+            .min(buf.len() as u64) as usize;
+        if available > 0 {
+            backing.read_at(&mut buf[..available], guest_offset)?;
+        }
+        buf[available..].fill(0);
+        Ok(())
+    }
+
+    /// Writes `data` at `offset_in_cluster` within the guest cluster starting
+    /// at `cluster_start`, allocating the cluster if needed.
+    fn write_cluster(
+        &mut self,
+        cluster_start: u64,
+        offset_in_cluster: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let entry = self.l2_entry(cluster_start)?;
+        if entry & QCOW_OFLAG_COMPRESSED != 0 {
+            return Err(unsupported("writing to compressed clusters"));
+        }
+
+        let host_offset = entry & L2_OFFSET_MASK;
+        let reads_as_zero = entry & QCOW_OFLAG_ZERO != 0;
+        if host_offset != 0 && !reads_as_zero {
+            return self
+                .file
+                .write_all_at(data, host_offset + offset_in_cluster);
+        }
+
+        // Build the full cluster so unwritten parts keep their old contents
+        let mut cluster = vec![0u8; self.cluster_size as usize];
+        if data.len() as u64 != self.cluster_size && !reads_as_zero {
+            self.read_unallocated(&mut cluster, cluster_start)?;
+        }
+        let start = offset_in_cluster as usize;
+        cluster[start..start + data.len()].copy_from_slice(data);
+
+        // Preallocated zero clusters keep their host cluster
+        let host_offset = if host_offset != 0 {
+            host_offset
+        } else {
+            self.allocate_clusters(1)?
+        };
+        self.file.write_all_at(&cluster, host_offset)?;
+        self.set_l2_entry(cluster_start, host_offset | QCOW_OFLAG_COPIED)
+    }
+
+    /// Calls `f` for each cluster-bounded piece of the range starting at
+    /// `offset`, passing the guest offset and the piece's start and end
+    /// within the range.
+    fn for_each_cluster(
+        &mut self,
+        offset: u64,
+        len: usize,
+        mut f: impl FnMut(&mut Self, u64, usize, usize) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let mut done = 0usize;
+        while done < len {
+            let guest_offset = offset + done as u64;
+            let offset_in_cluster = guest_offset & (self.cluster_size - 1);
+            let chunk = ((self.cluster_size - offset_in_cluster) as usize).min(len - done);
+            f(self, guest_offset, done, done + chunk)?;
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn refcount(&self, host_offset: u64) -> io::Result<u16> {
+        let cluster_index = host_offset >> self.cluster_bits;
+        let table_index = (cluster_index / self.refcount_block_entries()) as usize;
+        let block_offset =
+            self.refcount_table.get(table_index).copied().unwrap_or(0) & REFCOUNT_TABLE_OFFSET_MASK;
+        if block_offset == 0 {
+            return Ok(0);
+        }
+        let mut buf = [0u8; 2];
+        self.file.read_exact_at(
+            &mut buf,
+            block_offset + (cluster_index % self.refcount_block_entries()) * 2,
+        )?;
+        Ok(u16::from_be_bytes(buf))
+    }
+}
+
+impl BlockBackend for Qcow2Image {
+    fn size(&self) -> u64 {
+        self.size
+    }
+
+    fn read_at(&mut self, buf: &mut [u8], offset: u64) -> io::Result<()> {
+        check_bounds(self.size, offset, buf.len())?;
+        self.for_each_cluster(offset, buf.len(), |image, guest_offset, start, end| {
+            let out = &mut buf[start..end];
+            let entry = image.l2_entry(guest_offset)?;
+            if entry & QCOW_OFLAG_COMPRESSED != 0 {
+                return Err(unsupported("compressed clusters"));
+            }
+
+            let host_offset = entry & L2_OFFSET_MASK;
+            if entry & QCOW_OFLAG_ZERO != 0 {
+                out.fill(0);
+                Ok(())
+            } else if host_offset != 0 {
+                let offset_in_cluster = guest_offset & (image.cluster_size - 1);
+                image
+                    .file
+                    .read_exact_at(out, host_offset + offset_in_cluster)
+            } else {
+                image.read_unallocated(out, guest_offset)
+            }
+        })
+    }
+
+    fn write_at(&mut self, buf: &[u8], offset: u64) -> io::Result<()> {
+        if self.read_only {
+            return Err(read_only_error());
+        }
+        check_bounds(self.size, offset, buf.len())?;
+        self.for_each_cluster(offset, buf.len(), |image, guest_offset, start, end| {
+            let offset_in_cluster = guest_offset & (image.cluster_size - 1);
+            image.write_cluster(
+                guest_offset - offset_in_cluster,
+                offset_in_cluster,
+                &buf[start..end],
+            )
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.file.sync_data()
+    }
+}
+
+/// Opens a backing file read-only in its recorded format. Only images that
+/// don't record one, like those written by older tools, have it probed.
+fn open_backing(
+    path: &Path,
+    format: Option<ImageFormat>,
+    depth: usize,
+) -> io::Result<Box<dyn BlockBackend>> {
+    let format = match format {
+        Some(format) => format,
+        None => {
+            let file = File::open(path)?;
+            let mut magic = [0u8; 4];
+            let is_qcow2 = file.read_exact_at(&mut magic, 0).is_ok()
+                && u32::from_be_bytes(magic) == QCOW2_MAGIC;
+            if is_qcow2 {
+                ImageFormat::Qcow2
+            } else {
+                ImageFormat::Raw
+            }
+        }
+    };
+
+    match format {
+        ImageFormat::Raw => Ok(Box::new(RawImage::open(path, true)?)),
+        ImageFormat::Qcow2 => Ok(Box::new(Qcow2Image::open_chain(path, true, depth)?)),
+    }
+}
+
+/// Returns the backing file format recorded in the header extensions that
+/// start at `offset`, if any.
+fn read_backing_format(
+    file: &File,
+    mut offset: u64,
+    cluster_size: u64,
+) -> io::Result<Option<ImageFormat>> {
+    loop {
+        if offset + 8 > cluster_size {
+            return Err(invalid_data(
+                "header extensions overflow the header cluster",
+            ));
+        }
+        let mut ext = [0u8; 8];
+        file.read_exact_at(&mut ext, offset)?;
+        let ext_type = get_u32(&ext, 0);
+        let len = get_u32(&ext, 4) as u64;
+        if ext_type == HEADER_EXT_END {
+            return Ok(None);
+        }
+        if offset + 8 + len > cluster_size {
+            return Err(invalid_data(
+                "header extensions overflow the header cluster",
+            ));
+        }
+
+        if ext_type == HEADER_EXT_BACKING_FORMAT {
+            let mut name = vec![0u8; len as usize];
+            file.read_exact_at(&mut name, offset + 8)?;
+            return match name.as_slice() {
+                b"raw" => Ok(Some(ImageFormat::Raw)),
+                b"qcow2" => Ok(Some(ImageFormat::Qcow2)),
+                _ => Err(unsupported(format!(
+                    "backing file format {:?}",
+                    String::from_utf8_lossy(&name)
+                ))),
+            };
+        }
+        offset += 8 + len.next_multiple_of(8);
+    }
+}
+
+fn backing_format_name(format: ImageFormat) -> &'static str {
+    match format {
+        ImageFormat::Raw => "raw",
+        ImageFormat::Qcow2 => "qcow2",
+    }
+}
+
+/// Appends a header extension, padding its data to a multiple of 8 bytes.
+fn push_header_extension(buf: &mut Vec<u8>, ext_type: u32, data: &[u8]) {
+    buf.extend_from_slice(&ext_type.to_be_bytes());
+    buf.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(buf.len().next_multiple_of(8), 0);
+}
+
+fn resolve_backing_path(image_path: &Path, name: &str) -> PathBuf {
+    let backing = Path::new(name);
+    if backing.is_absolute() {
+        return backing.to_path_buf();
+    }
+    match image_path.parent() {
+        Some(dir) => dir.join(backing),
+        None => backing.to_path_buf(),
+    }
+}
+
+/// Number of L1 entries needed to map `size` bytes.
+fn l1_entries_for(size: u64, cluster_bits: u32) -> u64 {
+    let bytes_per_l2_table = 1u64 << (cluster_bits + cluster_bits - 3);
+    size.div_ceil(bytes_per_l2_table)
+}
+
+fn read_table(file: &File, offset: u64, entries: usize) -> io::Result<Vec<u64>> {
+    let mut buf = vec![0u8; entries * 8];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf
+        .chunks_exact(8)
+        .map(|c| u64::from_be_bytes(c.try_into().unwrap()))
+        .collect())
+}
+
+fn table_to_bytes(table: &[u64]) -> Vec<u8> {
+    table.iter().flat_map(|e| e.to_be_bytes()).collect()
+}
+
+fn get_u32(buf: &[u8], offset: usize) -> u32 {
+    u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn get_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_be_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, val: u16) {
+    buf[offset..offset + 2].copy_from_slice(&val.to_be_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, val: u32) {
+    buf[offset..offset + 4].copy_from_slice(&val.to_be_bytes());
+}
+
+fn put_u64(buf: &mut [u8], offset: usize, val: u64) {
+    buf[offset..offset + 8].copy_from_slice(&val.to_be_bytes());
+}
+
+fn invalid_data(msg: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("qcow2: {}", msg))
+}
+
+fn invalid_input(msg: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, format!("qcow2: {}", msg))
+}
+
+fn unsupported(msg: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("qcow2: unsupported {}", msg),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use tempfile::TempDir;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn pattern(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    /// Verifies that every cluster referenced by image metadata has a
+    /// refcount of one, and that no other cluster is referenced.
+    fn assert_refcounts_consistent(image: &mut Qcow2Image) {
+        let cs = image.cluster_size;
+        let mut referenced = HashSet::from([0u64]);
+        let table_clusters = (image.refcount_table.len() as u64 * 8).div_ceil(cs);
+        referenced.extend((0..table_clusters).map(|i| image.refcount_table_offset + i * cs));
+        let l1_clusters = (image.l1_table.len() as u64 * 8).div_ceil(cs).max(1);
+        referenced.extend((0..l1_clusters).map(|i| image.l1_table_offset + i * cs));
+        referenced.extend(
+            image
+                .refcount_table
+                .iter()
+                .map(|e| e & REFCOUNT_TABLE_OFFSET_MASK)
+                .filter(|&o| o != 0),
+        );
+        for l1_entry in image.l1_table.clone() {
+            let l2_offset = l1_entry & L1_OFFSET_MASK;
+            if l2_offset == 0 {
+                continue;
+            }
+            referenced.insert(l2_offset);
+            for entry in image.load_l2_table(l2_offset).unwrap().clone() {
+                if entry & L2_OFFSET_MASK != 0 {
+                    referenced.insert(entry & L2_OFFSET_MASK);
+                }
+            }
+        }
+
+        for &offset in &referenced {
+            assert_eq!(
+                image.refcount(offset).unwrap(),
+                1,
+                "cluster at {:#x} should be referenced once",
+                offset
+            );
+        }
+
+        let file_len = image.file.metadata().unwrap().len();
+        for offset in (0..file_len).step_by(cs as usize) {
+            if !referenced.contains(&offset) {
+                assert_eq!(
+                    image.refcount(offset).unwrap(),
+                    0,
+                    "unreferenced cluster at {:#x} has a refcount",
+                    offset
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn create_and_reopen() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        Qcow2Image::create(&path, 10 * MIB, None).unwrap();
+
+        let mut image = Qcow2Image::open(&path, false).unwrap();
+        assert_eq!(image.size(), 10 * MIB);
+        assert_eq!(image.cluster_size, 1 << DEFAULT_CLUSTER_BITS);
+
+        let mut buf = vec![0xffu8; 4096];
+        image.read_at(&mut buf, 5 * MIB).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+        assert_refcounts_consistent(&mut image);
+    }
+
+    #[test]
+    fn create_fails_if_file_exists() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        Qcow2Image::create(&path, MIB, None).unwrap();
+
+        let err = Qcow2Image::create(&path, MIB, None).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn write_read_roundtrip_persists() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        let data = pattern(200_000, 7);
+
+        {
+            let mut image = Qcow2Image::create(&path, 16 * MIB, None).unwrap();
+            // Unaligned write spanning several clusters
+            image.write_at(&data, 3 * MIB + 1234).unwrap();
+            image.flush().unwrap();
+        }
+
+        let mut image = Qcow2Image::open(&path, true).unwrap();
+        let mut buf = vec![0u8; data.len()];
+        image.read_at(&mut buf, 3 * MIB + 1234).unwrap();
+        assert_eq!(buf, data);
+
+        // Bytes around the write stay zero
+        let mut edge = [0xffu8; 1234];
+        image.read_at(&mut edge, 3 * MIB).unwrap();
+        assert!(edge.iter().all(|&b| b == 0));
+        assert_refcounts_consistent(&mut image);
+    }
+
+    #[test]
+    fn overwrite_reuses_allocated_cluster() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        let mut image = Qcow2Image::create(&path, MIB, None).unwrap();
+
+        image.write_at(&[1u8; 512], 0).unwrap();
+        let len_after_first = image.file.metadata().unwrap().len();
+        image.write_at(&[2u8; 512], 512).unwrap();
+        assert_eq!(image.file.metadata().unwrap().len(), len_after_first);
+
+        let mut buf = [0u8; 1024];
+        image.read_at(&mut buf, 0).unwrap();
+        assert!(buf[..512].iter().all(|&b| b == 1));
+        assert!(buf[512..].iter().all(|&b| b == 2));
+    }
+
+    #[test]
+    fn reads_unallocated_clusters_from_backing_file() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.path().join("base.raw");
+        let base = pattern(MIB as usize, 3);
+        std::fs::write(&base_path, &base).unwrap();
+
+        let overlay_path = dir.path().join("overlay.qcow2");
+        let mut overlay = Qcow2Image::create(
+            &overlay_path,
+            2 * MIB,
+            Some((Path::new("base.raw"), ImageFormat::Raw)),
+        )
+        .unwrap();
+
+        let mut buf = vec![0u8; 100_000];
+        overlay.read_at(&mut buf, 1000).unwrap();
+        assert_eq!(buf, &base[1000..101_000]);
+
+        // Reads past the end of a smaller backing file return zeroes
+        let mut tail = vec![0xffu8; 4096];
+        overlay.read_at(&mut tail, MIB + 10).unwrap();
+        assert!(tail.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn partial_write_copies_backing_cluster() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.path().join("base.raw");
+        let base = pattern(MIB as usize, 9);
+        std::fs::write(&base_path, &base).unwrap();
+
+        let overlay_path = dir.path().join("overlay.qcow2");
+        let mut overlay =
+            Qcow2Image::create(&overlay_path, MIB, Some((&base_path, ImageFormat::Raw))).unwrap();
+        overlay.write_at(&[0xaa; 100], 70_000).unwrap();
+
+        let mut expected = base.clone();
+        expected[70_000..70_100].fill(0xaa);
+        let mut buf = vec![0u8; MIB as usize];
+        overlay.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, expected);
+
+        // The backing file itself is untouched
+        assert_eq!(std::fs::read(&base_path).unwrap(), base);
+        assert_refcounts_consistent(&mut overlay);
+    }
+
+    #[test]
+    fn qcow2_backing_chain() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.path().join("base.qcow2");
+        {
+            let mut base = Qcow2Image::create(&base_path, MIB, None).unwrap();
+            base.write_at(&[1u8; 4096], 0).unwrap();
+            base.write_at(&[1u8; 4096], 65536).unwrap();
+        }
+
+        let mid_path = dir.path().join("mid.qcow2");
+        {
+            let mut mid =
+                Qcow2Image::create(&mid_path, MIB, Some((&base_path, ImageFormat::Qcow2))).unwrap();
+            mid.write_at(&[2u8; 4096], 65536).unwrap();
+        }
+
+        let top_path = dir.path().join("top.qcow2");
+        let mut top =
+            Qcow2Image::create(&top_path, MIB, Some((&mid_path, ImageFormat::Qcow2))).unwrap();
+
+        let mut buf = [0u8; 4096];
+        top.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, [1u8; 4096]);
+        top.read_at(&mut buf, 65536).unwrap();
+        assert_eq!(buf, [2u8; 4096]);
+    }
+
+    #[test]
+    fn raw_backing_file_is_never_probed() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.path().join("base.raw");
+        // A guest can write anything to a raw disk, including a qcow2 header
+        // pointing at host files
+        let mut base = vec![0u8; MIB as usize];
+        base[..4].copy_from_slice(&QCOW2_MAGIC.to_be_bytes());
+        std::fs::write(&base_path, &base).unwrap();
+
+        let overlay_path = dir.path().join("overlay.qcow2");
+        Qcow2Image::create(&overlay_path, MIB, Some((&base_path, ImageFormat::Raw))).unwrap();
+
+        let mut overlay = Qcow2Image::open(&overlay_path, true).unwrap();
+        let mut buf = [0u8; 8];
+        overlay.read_at(&mut buf, 0).unwrap();
+        assert_eq!(buf, base[..8]);
+    }
+
+    #[test]
+    fn zero_cluster_reads_as_zero_over_backing() {
+        let dir = TempDir::new().unwrap();
+        let base_path = dir.path().join("base.raw");
+        std::fs::write(&base_path, vec![0x55u8; MIB as usize]).unwrap();
+
+        let overlay_path = dir.path().join("overlay.qcow2");
+        let mut overlay =
+            Qcow2Image::create(&overlay_path, MIB, Some((&base_path, ImageFormat::Raw))).unwrap();
+        overlay.set_l2_entry(65536, QCOW_OFLAG_ZERO).unwrap();
+
+        let mut buf = [0xffu8; 4096];
+        overlay.read_at(&mut buf, 65536).unwrap();
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // Partially writing a zero cluster must not pull in backing data
+        overlay.write_at(&[7u8; 16], 65536 + 100).unwrap();
+        let mut cluster = vec![0xffu8; 65536];
+        overlay.read_at(&mut cluster, 65536).unwrap();
+        assert!(cluster[..100].iter().all(|&b| b == 0));
+        assert!(cluster[100..116].iter().all(|&b| b == 7));
+        assert!(cluster[116..].iter().all(|&b| b == 0));
+
+        // Neighbouring clusters still come from the backing file
+        overlay.read_at(&mut buf, 0).unwrap();
+        assert!(buf.iter().all(|&b| b == 0x55));
+    }
+
+    #[test]
+    fn refcount_table_grows() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        // 512-byte clusters: one refcount table cluster covers 64 blocks of
+        // 256 clusters each, i.e. 16384 clusters
+        let mut image =
+            Qcow2Image::create_with_cluster_bits(&path, 64 * MIB, None, MIN_CLUSTER_BITS).unwrap();
+        let initial_table_offset = image.refcount_table_offset;
+
+        let data = pattern(512, 1);
+        for i in 0..17_000u64 {
+            image.write_at(&data, i * 2048).unwrap();
+        }
+        assert_ne!(image.refcount_table_offset, initial_table_offset);
+        assert_refcounts_consistent(&mut image);
+        drop(image);
+
+        let mut image = Qcow2Image::open(&path, true).unwrap();
+        let mut buf = vec![0u8; 512];
+        image.read_at(&mut buf, 16_999 * 2048).unwrap();
+        assert_eq!(buf, data);
+        assert_refcounts_consistent(&mut image);
+    }
+
+    #[test]
+    fn read_only_rejects_writes() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        Qcow2Image::create(&path, MIB, None).unwrap();
+
+        let mut image = Qcow2Image::open(&path, true).unwrap();
+        let err = image.write_at(&[1u8; 16], 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn rejects_access_beyond_end() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        let mut image = Qcow2Image::create(&path, MIB, None).unwrap();
+
+        let mut buf = [0u8; 16];
+        assert!(image.read_at(&mut buf, MIB - 8).is_err());
+        assert!(image.write_at(&buf, MIB).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        std::fs::write(&path, vec![0u8; 4096]).unwrap();
+
+        let err = Qcow2Image::open(&path, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn rejects_unknown_incompatible_features() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        Qcow2Image::create(&path, MIB, None).unwrap();
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        // Extended L2 entries
+        file.write_all_at(&(1u64 << 4).to_be_bytes(), 72).unwrap();
+
+        let err = Qcow2Image::open(&path, true).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn dirty_image_opens_read_only_only() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        Qcow2Image::create(&path, MIB, None).unwrap();
+
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(&INCOMPAT_DIRTY.to_be_bytes(), 72)
+            .unwrap();
+
+        assert!(Qcow2Image::open(&path, true).is_ok());
+        let err = Qcow2Image::open(&path, false).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn clears_autoclear_features_when_writable() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("disk.qcow2");
+        Qcow2Image::create(&path, MIB, None).unwrap();
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        // Persistent bitmaps
+        file.write_all_at(&1u64.to_be_bytes(), HEADER_AUTOCLEAR_FEATURES)
+            .unwrap();
+        let autoclear = || {
+            let mut buf = [0u8; 8];
+            file.read_exact_at(&mut buf, HEADER_AUTOCLEAR_FEATURES)
+                .unwrap();
+            u64::from_be_bytes(buf)
+        };
+
+        Qcow2Image::open(&path, true).unwrap();
+        assert_eq!(autoclear(), 1);
+        Qcow2Image::open(&path, false).unwrap();
+        assert_eq!(autoclear(), 0);
+    }
+
+    #[test]
+    fn resolves_relative_backing_path() {
+        assert_eq!(
+            resolve_backing_path(Path::new("/images/overlay.qcow2"), "base.raw"),
+            PathBuf::from("/images/base.raw")
+        );
+        assert_eq!(
+            resolve_backing_path(Path::new("/images/overlay.qcow2"), "/other/base.raw"),
+            PathBuf::from("/other/base.raw")
+        );
+    }
+}
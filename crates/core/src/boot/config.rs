@@ -1,5 +1,5 @@
 use crate::boot::KernelCmdline;
-use crate::types::DiskImage;
+use crate::types::{DiskImage, GdbListener};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -15,6 +15,9 @@ pub struct LinuxDirectBootConfig {
     pub root_disk: Option<DiskImage>,
     #[serde(skip)]
     pub cmdline: KernelCmdline,
+    /// Socket of a GDB stub for debugging the kernel.
+    #[serde(default)]
+    pub gdb: Option<GdbListener>,
 }
 
 impl LinuxDirectBootConfig {
@@ -24,6 +27,7 @@ impl LinuxDirectBootConfig {
             initrd: initrd.into(),
             root_disk: None,
             cmdline: KernelCmdline::new(),
+            gdb: None,
         }
     }
 
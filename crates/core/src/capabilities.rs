@@ -33,16 +33,44 @@ pub struct ShareMechanismSupport {
     pub virtio_9p: bool,
 }
 
+#[derive(Debug, Clone, Default)]
+pub struct MemoryBackingSupport {
+    /// Guest RAM backed by a shareable memfd.
+    pub memfd: bool,
+    /// Transparent and hugetlbfs huge pages.
+    pub hugepages: bool,
+    /// Faulting in guest RAM before boot.
+    pub prefault: bool,
+    /// Merging identical pages across VMs.
+    pub mergeable: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct CpuSupport {
+    /// Socket, core and thread layouts other than one core per vCPU.
+    pub topology: bool,
+    /// Pinning vCPU threads to host CPUs.
+    pub pinning: bool,
+    /// CPU models and feature lists other than the host CPU.
+    pub models: bool,
+}
+
 #[derive(Debug, Clone, Default)]
 pub struct DeviceSupport {
     /// Virtio-vsock for host-guest socket communication.
     pub vsock: bool,
+    /// Virtio-balloon for returning guest memory to the host.
+    pub balloon: bool,
+    /// Virtio-rng for feeding the guest entropy from the host.
+    pub rng: bool,
+    /// Hardware watchdog that acts when the guest stops pinging it.
+    pub watchdog: bool,
+    /// Virtio devices on a PCI bus instead of memory-mapped.
+    pub virtio_pci: bool,
 }
 
 /// Capabilities advertised by a hypervisor backend.
 //
-// TODO: virtio-rng - entropy source for guest randomness
-// TODO: virtio-balloon - dynamic memory adjustment
 // TODO: rosetta - run x86_64 binaries in ARM Linux VMs (Apple-only)
 // TODO: virtio-gpu - graphics output for GUI VMs
 // TODO: virtio-input - keyboard/mouse for GUI VMs
@@ -58,6 +86,12 @@ pub struct BackendCapabilities {
     pub network_modes: NetworkModeSupport,
     pub share_mechanisms: ShareMechanismSupport,
     pub devices: DeviceSupport,
+    pub memory_backing: MemoryBackingSupport,
+    pub cpu: CpuSupport,
+    /// Rebooting the guest in place instead of stopping the VM.
+    pub reboot_in_place: bool,
+    /// Debugging the guest kernel through a GDB stub.
+    pub gdb_stub: bool,
     /// Maximum vCPUs the backend supports. None means no known limit.
     pub max_cpus: Option<u32>,
     /// Maximum guest memory in MB. None means no known limit.
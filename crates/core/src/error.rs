@@ -30,6 +30,9 @@ pub enum Error {
     #[error("VM is already running")]
     AlreadyRunning,
 
+    #[error("VM is not paused")]
+    NotPaused,
+
     #[error("console not enabled for this VM")]
     ConsoleNotEnabled,
 
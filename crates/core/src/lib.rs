@@ -1,5 +1,6 @@
 pub mod async_fd;
 pub mod backend;
+pub mod block;
 pub mod boot;
 pub mod capabilities;
 pub mod error;
@@ -9,21 +10,24 @@ pub mod vsock;
 
 pub use async_fd::{AsyncOwnedFd, AsyncPipe};
 pub use backend::{
-    BackendVmHandle, BootMethod, ConsoleIo, ConsoleStream, HypervisorBackend, VmConfig,
+    BackendVmHandle, BootMethod, ConsoleIo, ConsoleStream, ExitReason, HypervisorBackend, VmConfig,
 };
+pub use block::{BlockBackend, Qcow2Image, RawImage, open_disk};
 pub use boot::{
     CmdlineArg, EfiVariableStore, KernelCmdline, LinuxDirectBootConfig, UefiBootConfig,
 };
 pub use capabilities::{
-    BackendCapabilities, BootMethodSupport, DeviceSupport, GuestOsSupport, ImageFormatSupport,
-    NetworkModeSupport, ShareMechanismSupport,
+    BackendCapabilities, BootMethodSupport, CpuSupport, DeviceSupport, GuestOsSupport,
+    ImageFormatSupport, MemoryBackingSupport, NetworkModeSupport, ShareMechanismSupport,
 };
 pub use error::{Error, Result};
 pub use macos::{DEFAULT_ROOT_DEVICE, macos_cmdline_defaults, macos_virtualization_capabilities};
 pub use types::{
-    ClusterPortConfig, DiskImage, DomainPattern, GuestOs, HostPlatform, ImageFormat, MountMode,
-    NetworkClusterBuilder, NetworkClusterConfig, NetworkMode, NetworkPolicy, PolicyAction,
-    PolicyRule, PortForward, Protocol, ResourceConfig, RuleMatcher, ShareMechanism, SharedDir,
-    UserNatConfig, UserNatConfigBuilder, Virtio9pConfig, VirtioFsConfig,
+    CacheMode, ClusterPortConfig, CpuConfig, CpuModel, CpuTopology, DiskImage, DomainPattern,
+    GdbListener, GuestOs, HostPlatform, HugePages, IdMap, IdMapping, ImageFormat, MemoryBacking,
+    MountMode, NetworkClusterBuilder, NetworkClusterConfig, NetworkMode, NetworkPolicy,
+    PolicyAction, PolicyRule, PortForward, Protocol, ResourceConfig, RngConfig, RngRateLimit,
+    RuleMatcher, ShareMechanism, SharedDir, UserNatConfig, UserNatConfigBuilder, Virtio9pConfig,
+    VirtioFsConfig, VirtioTransport, WatchdogAction, WatchdogConfig,
 };
 pub use vsock::{VsockConfig, VsockPortConfig};
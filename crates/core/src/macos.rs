@@ -1,6 +1,7 @@
 use crate::{
-    BackendCapabilities, BootMethodSupport, DeviceSupport, GuestOsSupport, ImageFormatSupport,
-    KernelCmdline, NetworkModeSupport, ShareMechanismSupport,
+    BackendCapabilities, BootMethodSupport, CpuSupport, DeviceSupport, GuestOsSupport,
+    ImageFormatSupport, KernelCmdline, MemoryBackingSupport, NetworkModeSupport,
+    ShareMechanismSupport,
 };
 
 pub fn macos_virtualization_capabilities() -> BackendCapabilities {
@@ -26,7 +27,17 @@ pub fn macos_virtualization_capabilities() -> BackendCapabilities {
             virtio_fs: false,
             virtio_9p: false,
         },
-        devices: DeviceSupport { vsock: true },
+        devices: DeviceSupport {
+            vsock: true,
+            balloon: false,
+            rng: false,
+            watchdog: false,
+            virtio_pci: false,
+        },
+        memory_backing: MemoryBackingSupport::default(),
+        cpu: CpuSupport::default(),
+        reboot_in_place: false,
+        gdb_stub: false,
         max_cpus: None,
         max_memory_mb: None,
     }
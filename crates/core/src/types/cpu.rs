@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+
+/// How vCPUs are presented to the guest and scheduled on the host.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuConfig {
+    /// Socket, core and thread layout reported to the guest. Without one,
+    /// every vCPU is a core of a single socket.
+    #[serde(default)]
+    pub topology: Option<CpuTopology>,
+    /// Host CPU each vCPU thread is pinned to, indexed by vCPU. vCPU threads
+    /// run on any host CPU when empty.
+    #[serde(default)]
+    pub pinning: Vec<usize>,
+    /// CPU features reported to the guest.
+    #[serde(default)]
+    pub model: CpuModel,
+    /// Features reported on top of `model`, by their `/proc/cpuinfo` name.
+    #[serde(default)]
+    pub add_features: Vec<String>,
+    /// Features hidden from the guest, by their `/proc/cpuinfo` name.
+    #[serde(default)]
+    pub remove_features: Vec<String>,
+}
+
+/// Set of CPU features reported to the guest.
+///
+/// The x86-64 microarchitecture levels give guests the same features on
+/// every host that supports the level, so their behavior and snapshots do
+/// not depend on the host CPU. VMs fail to start on hosts below the level.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CpuModel {
+    /// Every feature the host CPU and hypervisor support.
+    #[default]
+    #[serde(rename = "host")]
+    Host,
+    /// SSE4.2, SSSE3, POPCNT and CMPXCHG16B.
+    #[serde(rename = "x86-64-v2")]
+    X86_64V2,
+    /// x86-64-v2 plus AVX2, BMI1/2, FMA, F16C, LZCNT, MOVBE and XSAVE.
+    #[serde(rename = "x86-64-v3")]
+    X86_64V3,
+    /// x86-64-v3 plus AVX-512 F, BW, CD, DQ and VL.
+    #[serde(rename = "x86-64-v4")]
+    X86_64V4,
+}
+
+/// Sockets, cores and threads the vCPUs are grouped into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuTopology {
+    pub sockets: u32,
+    pub cores_per_socket: u32,
+    pub threads_per_core: u32,
+}
+
+impl CpuTopology {
+    pub fn new(sockets: u32, cores_per_socket: u32, threads_per_core: u32) -> Self {
+        Self {
+            sockets,
+            cores_per_socket,
+            threads_per_core,
+        }
+    }
+
+    /// Total number of vCPUs in this topology.
+    pub fn cpus(&self) -> u32 {
+        self.sockets
+            .saturating_mul(self.cores_per_socket)
+            .saturating_mul(self.threads_per_core)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topology_cpus_multiplies_levels() {
+        assert_eq!(CpuTopology::new(2, 4, 2).cpus(), 16);
+        assert_eq!(CpuTopology::new(1, 1, 1).cpus(), 1);
+    }
+
+    #[test]
+    fn topology_cpus_saturates() {
+        assert_eq!(CpuTopology::new(u32::MAX, 2, 2).cpus(), u32::MAX);
+    }
+
+    #[test]
+    fn cpu_config_defaults_when_missing() {
+        let config: CpuConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, CpuConfig::default());
+        assert_eq!(config.model, CpuModel::Host);
+    }
+
+    #[test]
+    fn cpu_model_uses_level_names() {
+        assert_eq!(
+            serde_json::to_string(&CpuModel::X86_64V3).unwrap(),
+            "\"x86-64-v3\""
+        );
+        let model: CpuModel = serde_json::from_str("\"host\"").unwrap();
+        assert_eq!(model, CpuModel::Host);
+    }
+}
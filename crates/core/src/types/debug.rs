@@ -0,0 +1,49 @@
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Socket a GDB stub listens on for the debugger.
+///
+/// Attach with `target remote <path>` for a Unix socket or
+/// `target remote <host>:<port>` for TCP.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GdbListener {
+    Unix(PathBuf),
+    Tcp(SocketAddr),
+}
+
+impl GdbListener {
+    pub fn unix(path: impl Into<PathBuf>) -> Self {
+        Self::Unix(path.into())
+    }
+
+    pub fn tcp(addr: SocketAddr) -> Self {
+        Self::Tcp(addr)
+    }
+}
+
+impl std::fmt::Display for GdbListener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Unix(path) => write!(f, "{}", path.display()),
+            Self::Tcp(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn displays_gdb_target() {
+        assert_eq!(
+            GdbListener::unix("/tmp/gdb.sock").to_string(),
+            "/tmp/gdb.sock"
+        );
+        assert_eq!(
+            GdbListener::tcp("127.0.0.1:1234".parse().unwrap()).to_string(),
+            "127.0.0.1:1234"
+        );
+    }
+}
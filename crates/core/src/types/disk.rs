@@ -32,6 +32,10 @@ pub struct DiskImage {
     pub format: ImageFormat,
     #[serde(default)]
     pub read_only: bool,
+    /// Guest writes go to a per-VM overlay that is discarded when the VM
+    /// stops, leaving the image at `path` untouched.
+    #[serde(default)]
+    pub ephemeral: bool,
 }
 
 impl<T: Into<PathBuf>> From<T> for DiskImage {
@@ -48,6 +52,7 @@ impl DiskImage {
             path,
             format,
             read_only: false,
+            ephemeral: false,
         }
     }
 
@@ -56,13 +61,31 @@ impl DiskImage {
             path: path.into(),
             format,
             read_only: false,
+            ephemeral: false,
         }
     }
 
+    /// Creates an ephemeral disk backed by `base`.
+    ///
+    /// Shorthand for `DiskImage::new(base).ephemeral()`.
+    pub fn overlay_on(base: impl Into<PathBuf>) -> Self {
+        Self::new(base).ephemeral()
+    }
+
     pub fn read_only(mut self) -> Self {
         self.read_only = true;
         self
     }
+
+    /// Makes the disk copy-on-write for the lifetime of the VM.
+    ///
+    /// The image is only ever read. Guest writes land in a sparse per-VM
+    /// overlay that is deleted when the VM stops, so every VM starts from the
+    /// same pristine contents.
+    pub fn ephemeral(mut self) -> Self {
+        self.ephemeral = true;
+        self
+    }
 }
 
 #[cfg(test)]
@@ -162,6 +185,27 @@ mod tests {
             assert!(disk.read_only);
         }
 
+        #[test]
+        fn new_is_not_ephemeral() {
+            let disk = DiskImage::new("/path/to/disk.raw");
+            assert!(!disk.ephemeral);
+        }
+
+        #[test]
+        fn ephemeral_sets_flag() {
+            let disk = DiskImage::new("/path/to/disk.raw").ephemeral();
+            assert!(disk.ephemeral);
+            assert!(!disk.read_only);
+        }
+
+        #[test]
+        fn overlay_on_infers_format_and_is_ephemeral() {
+            let disk = DiskImage::overlay_on("/path/to/golden.qcow2");
+            assert_eq!(disk.path, PathBuf::from("/path/to/golden.qcow2"));
+            assert_eq!(disk.format, ImageFormat::Qcow2);
+            assert!(disk.ephemeral);
+        }
+
         #[test]
         fn read_only_chains_with_format() {
             let disk = DiskImage::with_format("/path/to/disk.raw", ImageFormat::Qcow2).read_only();
@@ -218,6 +262,21 @@ mod tests {
             assert!(json.contains("read_only"));
         }
 
+        #[test]
+        fn disk_image_ephemeral_defaults_to_false() {
+            let json = r#"{"path":"/disk.raw","format":"raw"}"#;
+            let disk: DiskImage = serde_json::from_str(json).unwrap();
+            assert!(!disk.ephemeral);
+        }
+
+        #[test]
+        fn disk_image_ephemeral_roundtrip() {
+            let disk = DiskImage::overlay_on("/path/to/disk.raw");
+            let json = serde_json::to_string(&disk).unwrap();
+            let deserialized: DiskImage = serde_json::from_str(&json).unwrap();
+            assert!(deserialized.ephemeral);
+        }
+
         #[test]
         fn disk_image_read_only_roundtrip() {
             let disk = DiskImage::new("/path/to/disk.qcow2").read_only();
@@ -1,15 +1,28 @@
 mod cluster;
+mod cpu;
+mod debug;
 mod disk;
 mod network;
+mod rng;
 mod share;
+mod transport;
+mod watchdog;
 
 pub use cluster::{NetworkClusterBuilder, NetworkClusterConfig};
+pub use cpu::{CpuConfig, CpuModel, CpuTopology};
+pub use debug::GdbListener;
 pub use disk::{DiskImage, ImageFormat};
 pub use network::{
     ClusterPortConfig, DomainPattern, NetworkMode, NetworkPolicy, PolicyAction, PolicyRule,
     PortForward, Protocol, RuleMatcher, UserNatConfig, UserNatConfigBuilder,
 };
-pub use share::{MountMode, ShareMechanism, SharedDir, Virtio9pConfig, VirtioFsConfig};
+pub use rng::{RngConfig, RngRateLimit};
+pub use share::{
+    CacheMode, IdMap, IdMapping, MountMode, ShareMechanism, SharedDir, Virtio9pConfig,
+    VirtioFsConfig,
+};
+pub use transport::VirtioTransport;
+pub use watchdog::{WatchdogAction, WatchdogConfig};
 
 use serde::{Deserialize, Serialize};
 
@@ -31,18 +44,67 @@ pub enum HostPlatform {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ResourceConfig {
     pub cpus: u32,
+    #[serde(default)]
+    pub cpu: CpuConfig,
     pub memory_mb: u32,
+    #[serde(default)]
+    pub memory_backing: MemoryBacking,
+    /// Adds a memory balloon device, through which the guest returns memory
+    /// it does not need to the host.
+    #[serde(default)]
+    pub balloon: bool,
+    /// Adds an entropy device fed from the host's random number generator.
+    #[serde(default)]
+    pub rng: Option<RngConfig>,
 }
 
 impl Default for ResourceConfig {
     fn default() -> Self {
         Self {
             cpus: 1,
+            cpu: CpuConfig::default(),
             memory_mb: 512,
+            memory_backing: MemoryBacking::default(),
+            balloon: false,
+            rng: None,
         }
     }
 }
 
+/// How guest RAM is allocated on the host.
+///
+/// The default is private anonymous memory with regular pages, faulted in as
+/// the guest touches it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryBacking {
+    /// Backs guest RAM with a memfd, which other processes can map to share
+    /// the guest's memory.
+    pub memfd: bool,
+    pub hugepages: HugePages,
+    /// Faults in all of guest RAM before boot, trading startup time for no
+    /// page faults while the guest runs.
+    pub prefault: bool,
+    /// Lets the host kernel merge identical pages across VMs (KSM), so VMs
+    /// booted from the same image share most of their memory. Only applies to
+    /// private memory, and needs KSM enabled on the host.
+    #[serde(default)]
+    pub mergeable: bool,
+}
+
+/// Huge page usage for guest RAM.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HugePages {
+    /// Regular pages only.
+    #[default]
+    None,
+    /// Transparent huge pages, used where the host kernel can provide them.
+    Transparent,
+    /// Pages from the host's hugetlbfs pool. Implies a memfd, and the VM fails
+    /// to start if the pool cannot hold all of guest RAM.
+    Hugetlb,
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -61,12 +123,41 @@ mod tests {
         fn serialization_roundtrip() {
             let config = ResourceConfig {
                 cpus: 4,
+                cpu: CpuConfig {
+                    topology: Some(CpuTopology::new(1, 2, 2)),
+                    pinning: vec![0, 1, 2, 3],
+                    model: CpuModel::X86_64V3,
+                    add_features: vec!["aes".into()],
+                    remove_features: vec!["avx".into()],
+                },
                 memory_mb: 2048,
+                memory_backing: MemoryBacking {
+                    memfd: true,
+                    hugepages: HugePages::Transparent,
+                    prefault: true,
+                    mergeable: false,
+                },
+                balloon: true,
+                rng: Some(RngConfig::rate_limited(
+                    64,
+                    std::time::Duration::from_secs(1),
+                )),
             };
             let json = serde_json::to_string(&config).unwrap();
             let deserialized: ResourceConfig = serde_json::from_str(&json).unwrap();
             assert_eq!(deserialized.cpus, config.cpus);
+            assert_eq!(deserialized.cpu, config.cpu);
             assert_eq!(deserialized.memory_mb, config.memory_mb);
+            assert_eq!(deserialized.memory_backing, config.memory_backing);
+            assert_eq!(deserialized.balloon, config.balloon);
+            assert_eq!(deserialized.rng, config.rng);
+        }
+
+        #[test]
+        fn memory_backing_defaults_when_missing() {
+            let config: ResourceConfig =
+                serde_json::from_str(r#"{"cpus": 2, "memory_mb": 1024}"#).unwrap();
+            assert_eq!(config.memory_backing, MemoryBacking::default());
         }
     }
 
@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// A virtio-rng device, through which the guest draws entropy from the host.
+///
+/// Without one, minimal guests can block in `getrandom()` during boot until
+/// their kernel has gathered enough entropy on its own.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RngConfig {
+    /// Caps how fast the guest can draw entropy. No limit when unset.
+    #[serde(default)]
+    pub rate_limit: Option<RngRateLimit>,
+}
+
+impl RngConfig {
+    /// A device the guest can draw at most `bytes` of entropy from in any
+    /// `period`.
+    pub fn rate_limited(bytes: u64, period: Duration) -> Self {
+        Self {
+            rate_limit: Some(RngRateLimit { bytes, period }),
+        }
+    }
+}
+
+/// At most `bytes` of entropy in each `period`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RngRateLimit {
+    pub bytes: u64,
+    pub period: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unlimited_by_default() {
+        assert_eq!(RngConfig::default().rate_limit, None);
+    }
+
+    #[test]
+    fn serialization_roundtrip() {
+        let config = RngConfig::rate_limited(1024, Duration::from_millis(500));
+        let json = serde_json::to_string(&config).unwrap();
+        assert_eq!(serde_json::from_str::<RngConfig>(&json).unwrap(), config);
+        assert_eq!(
+            serde_json::from_str::<RngConfig>("{}").unwrap(),
+            RngConfig::default()
+        );
+    }
+}
@@ -1,5 +1,6 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::path::PathBuf;
+use std::time::Duration;
 
 /// Access mode for shared directories.
 #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
@@ -12,10 +13,104 @@ pub enum MountMode {
     ReadWrite,
 }
 
+/// How long the guest may cache file data, attributes and directory entries
+/// of a virtio-fs share before checking the host again.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CacheMode {
+    /// Nothing is cached, so changes on the host show up immediately.
+    None,
+    /// Metadata is cached for a second, and file data until the guest sees
+    /// the host file's size or modification time change. Suits sources
+    /// edited on the host while the guest runs.
+    #[default]
+    Auto,
+    /// Everything is cached for a day. Only suits shares that do not change
+    /// on the host, such as read-only toolchains.
+    Always,
+}
+
+/// Treats an explicit `null` cache mode like a missing one.
+fn cache_mode_or_default<'de, D>(deserializer: D) -> Result<CacheMode, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<CacheMode>::deserialize(deserializer)?.unwrap_or_default())
+}
+
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct VirtioFsConfig {
     pub tag: Option<String>,
-    pub cache: Option<String>,
+    #[serde(default, deserialize_with = "cache_mode_or_default")]
+    pub cache: CacheMode,
+    /// How long the guest may cache directory entries, overriding the
+    /// timeout of the cache mode.
+    #[serde(default)]
+    pub entry_timeout: Option<Duration>,
+    /// How long the guest may cache file attributes, overriding the timeout
+    /// of the cache mode.
+    #[serde(default)]
+    pub attr_timeout: Option<Duration>,
+    /// Extended attribute namespaces the guest may use, such as `"user"`.
+    /// `None` allows every namespace, and an empty list disables extended
+    /// attributes. Whatever the filter, the guest's `security.*` attributes
+    /// are stored under `user.virtiofs.security.*` on the host.
+    #[serde(default)]
+    pub xattr_namespaces: Option<Vec<String>>,
+}
+
+/// How one kind of ID, user or group, maps between host and guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum IdMap {
+    /// The guest sees host IDs unchanged.
+    #[default]
+    Passthrough,
+    /// Every file appears owned by the `guest` ID, and files stay owned by
+    /// the host user running the VM.
+    Squash { guest: u32 },
+    /// Maps `count` IDs starting at `guest` to those starting at `host`, like
+    /// virtiofsd's `--translate-uid map:...`. Host IDs outside the range
+    /// appear as the overflow ID 65534.
+    Range { guest: u32, host: u32, count: u32 },
+}
+
+/// How file owners map between host and guest on a virtio-fs share.
+///
+/// Owners the host cannot represent, because IDs are squashed or the VM
+/// process lacks `CAP_CHOWN`, are kept in an extended attribute of the file
+/// and shown to the guest instead of the host owner. Symlinks cannot hold
+/// that attribute, so changing their owner that way fails with `EPERM`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IdMapping {
+    pub uid: IdMap,
+    pub gid: IdMap,
+}
+
+impl IdMapping {
+    /// The guest sees host users and groups unchanged.
+    pub fn passthrough() -> Self {
+        Self::default()
+    }
+
+    /// Every file appears owned by `uid` and `gid`.
+    pub fn squash(uid: u32, gid: u32) -> Self {
+        Self {
+            uid: IdMap::Squash { guest: uid },
+            gid: IdMap::Squash { guest: gid },
+        }
+    }
+
+    /// Maps `count` user and group IDs starting at `guest` to those
+    /// starting at `host`.
+    pub fn range(guest: u32, host: u32, count: u32) -> Self {
+        let map = IdMap::Range { guest, host, count };
+        Self { uid: map, gid: map }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.uid == IdMap::Passthrough && self.gid == IdMap::Passthrough
+    }
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -57,6 +152,10 @@ pub struct SharedDir {
     pub mode: MountMode,
     #[serde(default)]
     pub mechanism: ShareMechanism,
+    /// How file owners map between host and guest. Only virtio-fs on the
+    /// KVM backend maps owners.
+    #[serde(default)]
+    pub id_mapping: IdMapping,
 }
 
 impl SharedDir {
@@ -70,6 +169,7 @@ impl SharedDir {
             guest_path: guest_path.into(),
             mode,
             mechanism: ShareMechanism::default(),
+            id_mapping: IdMapping::default(),
         }
     }
 
@@ -84,8 +184,15 @@ impl SharedDir {
             guest_path: guest_path.into(),
             mode,
             mechanism,
+            id_mapping: IdMapping::default(),
         }
     }
+
+    /// Maps file owners between host and guest with `mapping`.
+    pub fn with_id_mapping(mut self, mapping: IdMapping) -> Self {
+        self.id_mapping = mapping;
+        self
+    }
 }
 
 #[cfg(test)]
@@ -125,11 +232,51 @@ mod tests {
         fn virtio_fs_serializes_with_tag() {
             let mechanism = ShareMechanism::VirtioFs(VirtioFsConfig {
                 tag: Some("share0".to_string()),
-                cache: Some("auto".to_string()),
+                cache: CacheMode::Auto,
+                ..Default::default()
             });
             let json = serde_json::to_string(&mechanism).unwrap();
             assert!(json.contains("\"type\":\"virtiofs\""));
             assert!(json.contains("\"tag\":\"share0\""));
+            assert!(json.contains("\"cache\":\"auto\""));
+        }
+
+        #[test]
+        fn virtio_fs_cache_defaults_to_auto() {
+            for json in [
+                r#"{"type":"virtiofs"}"#,
+                r#"{"type":"virtiofs","cache":null}"#,
+            ] {
+                let mechanism: ShareMechanism = serde_json::from_str(json).unwrap();
+                let ShareMechanism::VirtioFs(config) = mechanism else {
+                    panic!("expected virtio-fs");
+                };
+                assert_eq!(config.cache, CacheMode::Auto, "{json}");
+            }
+            assert_eq!(VirtioFsConfig::default().cache, CacheMode::Auto);
+        }
+
+        #[test]
+        fn virtio_fs_parses_cache_settings() {
+            let json =
+                r#"{"type":"virtiofs","cache":"always","attr_timeout":{"secs":5,"nanos":0}}"#;
+            let mechanism: ShareMechanism = serde_json::from_str(json).unwrap();
+            let ShareMechanism::VirtioFs(config) = mechanism else {
+                panic!("expected virtio-fs");
+            };
+            assert_eq!(config.cache, CacheMode::Always);
+            assert_eq!(config.entry_timeout, None);
+            assert_eq!(config.attr_timeout, Some(Duration::from_secs(5)));
+        }
+
+        #[test]
+        fn virtio_fs_xattr_namespaces_default_to_none() {
+            let mechanism: ShareMechanism =
+                serde_json::from_str(r#"{"type":"virtiofs","tag":"share0","cache":null}"#).unwrap();
+            let ShareMechanism::VirtioFs(config) = mechanism else {
+                panic!("expected virtio-fs");
+            };
+            assert_eq!(config.xattr_namespaces, None);
         }
 
         #[test]
@@ -154,6 +301,7 @@ mod tests {
             assert_eq!(share.guest_path, "/guest/path");
             assert_eq!(share.mode, MountMode::ReadWrite);
             assert!(matches!(share.mechanism, ShareMechanism::Auto));
+            assert!(share.id_mapping.is_passthrough());
         }
 
         #[test]
@@ -176,5 +324,34 @@ mod tests {
             assert_eq!(deserialized.guest_path, share.guest_path);
             assert_eq!(deserialized.mode, share.mode);
         }
+
+        #[test]
+        fn id_mapping_serializes_by_mode() {
+            let share = SharedDir::new("/host/path", "/guest/path", MountMode::ReadWrite)
+                .with_id_mapping(IdMapping {
+                    uid: IdMap::Squash { guest: 0 },
+                    gid: IdMap::Range {
+                        guest: 0,
+                        host: 100000,
+                        count: 65536,
+                    },
+                });
+            let json = serde_json::to_value(&share).unwrap();
+            assert_eq!(
+                json["id_mapping"]["uid"],
+                serde_json::json!({"mode": "squash", "guest": 0})
+            );
+            assert_eq!(json["id_mapping"]["gid"]["mode"], "range");
+
+            let parsed: SharedDir = serde_json::from_value(json).unwrap();
+            assert_eq!(parsed.id_mapping, share.id_mapping);
+
+            // Shares saved before the mapping existed keep passthrough
+            let parsed: SharedDir = serde_json::from_str(
+                r#"{"host_path":"/host","guest_path":"/guest","mode":"readonly"}"#,
+            )
+            .unwrap();
+            assert!(parsed.id_mapping.is_passthrough());
+        }
     }
 }
@@ -0,0 +1,36 @@
+use serde::{Deserialize, Serialize};
+
+/// How virtio devices are attached to the guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VirtioTransport {
+    /// Memory-mapped devices, declared to the guest on the kernel command
+    /// line.
+    #[default]
+    Mmio,
+    /// Devices on a PCI bus with MSI-X interrupts, which guests find without
+    /// any kernel command line parameters.
+    Pci,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults_to_mmio() {
+        assert_eq!(VirtioTransport::default(), VirtioTransport::Mmio);
+    }
+
+    #[test]
+    fn serializes_lowercase() {
+        assert_eq!(
+            serde_json::to_string(&VirtioTransport::Pci).unwrap(),
+            "\"pci\""
+        );
+        assert_eq!(
+            serde_json::from_str::<VirtioTransport>("\"mmio\"").unwrap(),
+            VirtioTransport::Mmio
+        );
+    }
+}
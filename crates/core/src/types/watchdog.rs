@@ -0,0 +1,50 @@
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// A hardware watchdog, which acts when the guest stops pinging it.
+///
+/// The watchdog stays disarmed until the guest opens `/dev/watchdog`, so it
+/// catches guests whose watchdog daemon or agent hangs, even while the
+/// kernel itself keeps running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WatchdogConfig {
+    /// How long the guest may go without pinging the watchdog.
+    pub timeout: Duration,
+    pub action: WatchdogAction,
+}
+
+impl WatchdogConfig {
+    pub fn new(timeout: Duration, action: WatchdogAction) -> Self {
+        Self { timeout, action }
+    }
+}
+
+/// What happens when the watchdog expires.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchdogAction {
+    /// Resets the guest like a reboot: the VM stops, or restarts the guest
+    /// when it reboots in place.
+    Reset,
+    /// Stops the VM as if the guest powered off.
+    Poweroff,
+    /// Stops the VM and reports it as failed, with the guest's last console
+    /// output.
+    Fail,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serialization_roundtrip() {
+        let config = WatchdogConfig::new(Duration::from_secs(30), WatchdogAction::Fail);
+        let json = serde_json::to_string(&config).unwrap();
+        assert!(json.contains("\"fail\""));
+        assert_eq!(
+            serde_json::from_str::<WatchdogConfig>(&json).unwrap(),
+            config
+        );
+    }
+}
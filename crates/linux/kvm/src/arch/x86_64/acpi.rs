@@ -0,0 +1,623 @@
+//! Minimal ACPI support for x86_64 guests.
+//!
+//! The tables only describe what the guest cannot discover otherwise: the
+//! CPUs (MADT) and the fixed-hardware power management registers (FADT), so
+//! the guest can be shut down gracefully with the power button and can power
+//! itself off through the S5 sleep state (DSDT). The DSDT also declares the
+//! pvpanic device, which the guest only finds through ACPI.
+//!
+//! Interrupts are still routed through the MP table (see `mptable`). The
+//! virtio-mmio devices are declared on the kernel command line and use IOAPIC
+//! pins that ACPI would only map if they were described in the DSDT, so
+//! guests boot with `acpi=noirq` and the MADT carries no IOAPIC entry.
+
+use capsa_core::ExitReason;
+use kvm_ioctls::VmFd;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc;
+use vm_device::MutDevicePio;
+use vm_device::bus::{PioAddress, PioAddressOffset};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::pvpanic::PVPANIC_PORT;
+use super::serial::ACPI_SCI_IRQ;
+use super::topology::MAX_XAPIC_ID;
+
+/// Start of the ACPI tables, inside the BIOS area the guest scans for the RSDP.
+pub const ACPI_TABLES_START: u64 = 0xe_0000;
+
+/// Base of the PM1a event block (status and enable registers).
+pub const ACPI_PM_PORT_BASE: u16 = 0x600;
+
+/// Size of the PM1a event block followed by the PM1a control block.
+pub const ACPI_PM_PORT_SIZE: u16 = PM1_EVT_LEN as u16 + PM1_CNT_LEN as u16;
+
+const PM1_EVT_LEN: u8 = 4;
+const PM1_CNT_LEN: u8 = 2;
+
+/// LAPIC default physical base address.
+const APIC_DEFAULT_PHYS_BASE: u32 = 0xfee0_0000;
+
+/// RTC register holding the century, reported in the FADT.
+const RTC_CENTURY_REGISTER: u8 = 0x32;
+
+const OEM_ID: &[u8; 6] = b"CAPSA ";
+const OEM_TABLE_ID: &[u8; 8] = b"CAPSAKVM";
+const CREATOR_ID: &[u8; 4] = b"CPSA";
+
+const SDT_HEADER_SIZE: usize = 36;
+const RSDP_SIZE: usize = 36;
+const FADT_SIZE: usize = 276;
+const FACS_SIZE: usize = 64;
+
+/// FADT revision 6 (ACPI 6.x).
+const FADT_REVISION: u8 = 6;
+
+/// FADT field offsets.
+const FADT_FIRMWARE_CTRL: usize = 36;
+const FADT_DSDT: usize = 40;
+const FADT_SCI_INT: usize = 46;
+const FADT_PM1A_EVT_BLK: usize = 56;
+const FADT_PM1A_CNT_BLK: usize = 64;
+const FADT_PM1_EVT_LEN: usize = 88;
+const FADT_PM1_CNT_LEN: usize = 89;
+const FADT_CENTURY: usize = 108;
+const FADT_FLAGS: usize = 112;
+const FADT_X_FIRMWARE_CTRL: usize = 132;
+const FADT_X_DSDT: usize = 140;
+
+/// FADT flag: the sleep button, if any, is a control method device. Leaving
+/// the power button flag clear declares a fixed-hardware power button.
+const FADT_SLP_BUTTON: u32 = 1 << 5;
+
+/// MADT flag: the system also has dual 8259 PICs.
+const MADT_PCAT_COMPAT: u32 = 1;
+
+/// MADT entry types.
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_NMI: u8 = 4;
+const MADT_LOCAL_X2APIC: u8 = 9;
+const MADT_LOCAL_X2APIC_NMI: u8 = 0xa;
+
+/// MADT local APIC flag: the processor is usable.
+const MADT_LAPIC_ENABLED: u32 = 1;
+
+/// PM1 status/enable bit for the power button.
+const PM1_PWRBTN: u16 = 1 << 8;
+
+/// PM1 control bits.
+const PM1_CNT_SCI_EN: u16 = 1;
+const PM1_CNT_SLP_TYP_SHIFT: u16 = 10;
+const PM1_CNT_SLP_TYP_MASK: u16 = 0x7;
+const PM1_CNT_SLP_EN: u16 = 1 << 13;
+
+/// Value the DSDT's `\_S5` object assigns to SLP_TYP for soft-off.
+const S5_SLP_TYP: u8 = 5;
+
+/// Writes the RSDP, XSDT, FADT, FACS, MADT and DSDT to guest memory.
+pub fn setup_acpi_tables(
+    mem: &GuestMemoryMmap,
+    apic_ids: &[u32],
+) -> Result<(), Box<dyn std::error::Error>> {
+    let rsdp_addr = ACPI_TABLES_START;
+    // The FACS has to be 64-byte aligned
+    let facs_addr = (rsdp_addr + RSDP_SIZE as u64).next_multiple_of(64);
+    let dsdt_addr = align_table(facs_addr + FACS_SIZE as u64);
+    let dsdt = build_dsdt();
+    let fadt_addr = align_table(dsdt_addr + dsdt.len() as u64);
+    let fadt = build_fadt(facs_addr, dsdt_addr);
+    let madt_addr = align_table(fadt_addr + fadt.len() as u64);
+    let madt = build_madt(apic_ids);
+    let xsdt_addr = align_table(madt_addr + madt.len() as u64);
+    let xsdt = build_xsdt(&[fadt_addr, madt_addr]);
+
+    mem.write_slice(&build_rsdp(xsdt_addr), GuestAddress(rsdp_addr))?;
+    mem.write_slice(&build_facs(), GuestAddress(facs_addr))?;
+    mem.write_slice(&dsdt, GuestAddress(dsdt_addr))?;
+    mem.write_slice(&fadt, GuestAddress(fadt_addr))?;
+    mem.write_slice(&madt, GuestAddress(madt_addr))?;
+    mem.write_slice(&xsdt, GuestAddress(xsdt_addr))?;
+
+    Ok(())
+}
+
+/// Tables are 16-byte aligned; the RSDP must be.
+fn align_table(addr: u64) -> u64 {
+    addr.next_multiple_of(16)
+}
+
+fn checksum(bytes: &[u8]) -> u8 {
+    let sum = bytes.iter().fold(0u8, |acc, &b| acc.wrapping_add(b));
+    (!sum).wrapping_add(1)
+}
+
+fn put_u16(buf: &mut [u8], offset: usize, value: u16) {
+    buf[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u32(buf: &mut [u8], offset: usize, value: u32) {
+    buf[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn put_u64(buf: &mut [u8], offset: usize, value: u64) {
+    buf[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+fn build_rsdp(xsdt_addr: u64) -> Vec<u8> {
+    let mut rsdp = vec![0u8; RSDP_SIZE];
+    rsdp[0..8].copy_from_slice(b"RSD PTR ");
+    rsdp[9..15].copy_from_slice(OEM_ID);
+    rsdp[15] = 2; // ACPI 2.0+, the XSDT is used instead of the RSDT
+    put_u32(&mut rsdp, 20, RSDP_SIZE as u32);
+    put_u64(&mut rsdp, 24, xsdt_addr);
+    // The first checksum covers the ACPI 1.0 part, the second the whole table
+    rsdp[8] = checksum(&rsdp[..20]);
+    rsdp[32] = checksum(&rsdp);
+    rsdp
+}
+
+/// Builds a system description table from its signature and contents.
+fn build_sdt(signature: &[u8; 4], revision: u8, body: &[u8]) -> Vec<u8> {
+    let mut table = vec![0u8; SDT_HEADER_SIZE];
+    table[0..4].copy_from_slice(signature);
+    put_u32(&mut table, 4, (SDT_HEADER_SIZE + body.len()) as u32);
+    table[8] = revision;
+    table[10..16].copy_from_slice(OEM_ID);
+    table[16..24].copy_from_slice(OEM_TABLE_ID);
+    put_u32(&mut table, 24, 1); // OEM revision
+    table[28..32].copy_from_slice(CREATOR_ID);
+    put_u32(&mut table, 32, 1); // Creator revision
+    table.extend_from_slice(body);
+    table[9] = checksum(&table);
+    table
+}
+
+fn build_xsdt(tables: &[u64]) -> Vec<u8> {
+    let body: Vec<u8> = tables.iter().flat_map(|addr| addr.to_le_bytes()).collect();
+    build_sdt(b"XSDT", 1, &body)
+}
+
+fn build_fadt(facs_addr: u64, dsdt_addr: u64) -> Vec<u8> {
+    let mut fadt = vec![0u8; FADT_SIZE];
+    put_u32(&mut fadt, FADT_FIRMWARE_CTRL, facs_addr as u32);
+    put_u32(&mut fadt, FADT_DSDT, dsdt_addr as u32);
+    put_u16(&mut fadt, FADT_SCI_INT, ACPI_SCI_IRQ as u16);
+    // SMI_CMD stays zero: the guest finds the hardware already in ACPI mode
+    put_u32(&mut fadt, FADT_PM1A_EVT_BLK, ACPI_PM_PORT_BASE as u32);
+    put_u32(
+        &mut fadt,
+        FADT_PM1A_CNT_BLK,
+        ACPI_PM_PORT_BASE as u32 + PM1_EVT_LEN as u32,
+    );
+    fadt[FADT_PM1_EVT_LEN] = PM1_EVT_LEN;
+    fadt[FADT_PM1_CNT_LEN] = PM1_CNT_LEN;
+    fadt[FADT_CENTURY] = RTC_CENTURY_REGISTER;
+    put_u32(&mut fadt, FADT_FLAGS, FADT_SLP_BUTTON);
+    put_u64(&mut fadt, FADT_X_FIRMWARE_CTRL, facs_addr);
+    put_u64(&mut fadt, FADT_X_DSDT, dsdt_addr);
+
+    build_sdt(b"FACP", FADT_REVISION, &fadt[SDT_HEADER_SIZE..])
+}
+
+/// Builds the firmware ACPI control structure, which has no checksum and is
+/// only needed because guests expect one outside hardware-reduced mode.
+fn build_facs() -> Vec<u8> {
+    let mut facs = vec![0u8; FACS_SIZE];
+    facs[0..4].copy_from_slice(b"FACS");
+    put_u32(&mut facs, 4, FACS_SIZE as u32);
+    facs[32] = 2; // Version
+    facs
+}
+
+/// Lists each vCPU under its APIC ID, using x2APIC entries for IDs that do
+/// not fit the 8-bit local APIC entry.
+fn build_madt(apic_ids: &[u32]) -> Vec<u8> {
+    let mut body = Vec::new();
+    body.extend_from_slice(&APIC_DEFAULT_PHYS_BASE.to_le_bytes());
+    body.extend_from_slice(&MADT_PCAT_COMPAT.to_le_bytes());
+
+    for (uid, &apic_id) in apic_ids.iter().enumerate() {
+        if apic_id <= MAX_XAPIC_ID {
+            body.extend_from_slice(&[MADT_LOCAL_APIC, 8, uid as u8, apic_id as u8]);
+            body.extend_from_slice(&MADT_LAPIC_ENABLED.to_le_bytes());
+        } else {
+            body.extend_from_slice(&[MADT_LOCAL_X2APIC, 16, 0, 0]);
+            body.extend_from_slice(&apic_id.to_le_bytes());
+            body.extend_from_slice(&MADT_LAPIC_ENABLED.to_le_bytes());
+            body.extend_from_slice(&(uid as u32).to_le_bytes());
+        }
+    }
+
+    // NMI on LINT1 of every processor, as in the MP table
+    body.extend_from_slice(&[MADT_LOCAL_APIC_NMI, 6, 0xff, 0, 0, 1]);
+    if apic_ids.iter().any(|&id| id > MAX_XAPIC_ID) {
+        body.extend_from_slice(&[MADT_LOCAL_X2APIC_NMI, 12, 0, 0]);
+        body.extend_from_slice(&u32::MAX.to_le_bytes());
+        body.extend_from_slice(&[1, 0, 0, 0]);
+    }
+
+    build_sdt(b"APIC", 4, &body)
+}
+
+/// Builds a DSDT holding the `\_S5` sleep state package and the pvpanic
+/// device.
+fn build_dsdt() -> Vec<u8> {
+    let mut aml = vec![
+        0x08, // NameOp
+        b'_', b'S', b'5', b'_', //
+        0x12, // PackageOp
+        0x08, // PkgLength
+        0x04, // NumElements
+        0x0a, S5_SLP_TYP, // SLP_TYPa
+        0x0a, S5_SLP_TYP, // SLP_TYPb
+        0x00,       // Reserved
+        0x00,       // Reserved
+    ];
+    aml.extend(build_pvpanic_device());
+    build_sdt(b"DSDT", 2, &aml)
+}
+
+/// Builds `Scope (\_SB) { Device (PEVT) { ... } }` declaring the pvpanic
+/// I/O port under the ID the guest driver binds to.
+fn build_pvpanic_device() -> Vec<u8> {
+    let [port_lo, port_hi] = PVPANIC_PORT.to_le_bytes();
+    let resources = [
+        0x47, 0x01, // I/O port descriptor, 16-bit decode
+        port_lo, port_hi, // Minimum base
+        port_lo, port_hi, // Maximum base
+        0x01,    // Alignment
+        0x01,    // Length
+        0x79, 0x00, // End tag
+    ];
+
+    let mut device = b"PEVT".to_vec();
+    device.push(0x08); // NameOp
+    device.extend(b"_HID");
+    device.push(0x0d); // StringPrefix
+    device.extend(b"QEMU0001\0");
+    device.push(0x08); // NameOp
+    device.extend(b"_CRS");
+    let mut buffer = vec![0x0a, resources.len() as u8]; // BufferSize
+    buffer.extend(resources);
+    device.extend(aml_package(&[0x11], &buffer)); // BufferOp
+
+    let mut scope = b"\\_SB_".to_vec();
+    scope.extend(aml_package(&[0x5b, 0x82], &device)); // DeviceOp
+    aml_package(&[0x10], &scope) // ScopeOp
+}
+
+/// Prefixes `body` with `op` and a one-byte PkgLength.
+fn aml_package(op: &[u8], body: &[u8]) -> Vec<u8> {
+    assert!(
+        body.len() < 0x3f,
+        "AML package too long for a one-byte length"
+    );
+    let mut package = op.to_vec();
+    package.push(body.len() as u8 + 1);
+    package.extend(body);
+    package
+}
+
+/// Saved state of the [`AcpiPmDevice`] registers.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AcpiPmState {
+    pub pm1_status: u16,
+    pub pm1_enable: u16,
+    pub pm1_control: u16,
+}
+
+/// ACPI PM1 event and control registers.
+///
+/// Raises the SCI when the power button is pressed and stops the VM when the
+/// guest enters the S5 (soft-off) sleep state.
+pub struct AcpiPmDevice {
+    pm1_status: u16,
+    pm1_enable: u16,
+    pm1_control: u16,
+    sci_asserted: bool,
+    vm_fd: Arc<VmFd>,
+    running: Arc<AtomicBool>,
+    exit_tx: mpsc::Sender<ExitReason>,
+}
+
+impl AcpiPmDevice {
+    pub fn new(
+        vm_fd: Arc<VmFd>,
+        running: Arc<AtomicBool>,
+        exit_tx: mpsc::Sender<ExitReason>,
+    ) -> Self {
+        Self {
+            pm1_status: 0,
+            pm1_enable: 0,
+            pm1_control: PM1_CNT_SCI_EN,
+            sci_asserted: false,
+            vm_fd,
+            running,
+            exit_tx,
+        }
+    }
+
+    /// Signals a power button press to the guest.
+    ///
+    /// Guests without ACPI support, or without anything listening for power
+    /// button events, ignore it.
+    pub fn press_power_button(&mut self) {
+        self.pm1_status |= PM1_PWRBTN;
+        self.update_sci();
+    }
+
+    pub fn save_state(&self) -> AcpiPmState {
+        AcpiPmState {
+            pm1_status: self.pm1_status,
+            pm1_enable: self.pm1_enable,
+            pm1_control: self.pm1_control,
+        }
+    }
+
+    pub fn restore_state(&mut self, state: &AcpiPmState) {
+        self.pm1_status = state.pm1_status;
+        self.pm1_enable = state.pm1_enable;
+        self.pm1_control = state.pm1_control | PM1_CNT_SCI_EN;
+        self.update_sci();
+    }
+
+    /// Puts the registers back into their power-on state.
+    pub fn reset(&mut self) {
+        self.pm1_status = 0;
+        self.pm1_enable = 0;
+        self.pm1_control = PM1_CNT_SCI_EN;
+        self.update_sci();
+    }
+
+    fn handle_pio_read(&self, offset: u64, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset + i as u64;
+            let register = match offset / 2 {
+                0 => self.pm1_status,
+                1 => self.pm1_enable,
+                2 => self.pm1_control,
+                _ => {
+                    *byte = 0xff;
+                    continue;
+                }
+            };
+            *byte = (register >> ((offset % 2) * 8)) as u8;
+        }
+    }
+
+    fn handle_pio_write(&mut self, offset: u64, data: &[u8]) {
+        for (i, &byte) in data.iter().enumerate() {
+            let offset = offset + i as u64;
+            let shift = (offset % 2) * 8;
+            let bits = (byte as u16) << shift;
+            let mask = 0xffu16 << shift;
+            match offset / 2 {
+                // Status bits are cleared by writing 1
+                0 => self.pm1_status &= !bits,
+                1 => self.pm1_enable = (self.pm1_enable & !mask) | bits,
+                2 => self.pm1_control = (self.pm1_control & !mask) | bits,
+                _ => {}
+            }
+        }
+
+        if self.pm1_control & PM1_CNT_SLP_EN != 0 {
+            // SLP_EN is write-only and always reads as zero
+            self.pm1_control &= !PM1_CNT_SLP_EN;
+            let slp_typ = (self.pm1_control >> PM1_CNT_SLP_TYP_SHIFT) & PM1_CNT_SLP_TYP_MASK;
+            if slp_typ == S5_SLP_TYP as u16 {
+                self.power_off();
+            } else {
+                tracing::debug!("ignoring unsupported ACPI sleep type {}", slp_typ);
+            }
+        }
+
+        self.update_sci();
+    }
+
+    fn power_off(&self) {
+        tracing::debug!("guest entered ACPI S5, powering off");
+        let _ = self.exit_tx.try_send(ExitReason::Poweroff);
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    /// Keeps the SCI line asserted while an enabled event is pending.
+    fn update_sci(&mut self) {
+        let asserted = self.pm1_status & self.pm1_enable & PM1_PWRBTN != 0;
+        if asserted != self.sci_asserted {
+            let _ = self.vm_fd.set_irq_line(ACPI_SCI_IRQ, asserted);
+            self.sci_asserted = asserted;
+        }
+    }
+}
+
+impl MutDevicePio for AcpiPmDevice {
+    fn pio_read(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        self.handle_pio_read(offset as u64, data);
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        self.handle_pio_write(offset as u64, data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_ioctls::Kvm;
+
+    fn create_test_device() -> (AcpiPmDevice, Arc<AtomicBool>, mpsc::Receiver<ExitReason>) {
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = kvm.create_vm().expect("Failed to create VM");
+        vm.create_irq_chip().expect("Failed to create IRQ chip");
+        let running = Arc::new(AtomicBool::new(true));
+        let (exit_tx, exit_rx) = mpsc::channel(1);
+        let device = AcpiPmDevice::new(Arc::new(vm), running.clone(), exit_tx);
+        (device, running, exit_rx)
+    }
+
+    fn read_u16(device: &AcpiPmDevice, offset: u64) -> u16 {
+        let mut data = [0u8; 2];
+        device.handle_pio_read(offset, &mut data);
+        u16::from_le_bytes(data)
+    }
+
+    fn write_u16(device: &mut AcpiPmDevice, offset: u64, val: u16) {
+        device.handle_pio_write(offset, &val.to_le_bytes());
+    }
+
+    fn table_checksum_is_valid(table: &[u8]) -> bool {
+        table.iter().fold(0u8, |acc, &b| acc.wrapping_add(b)) == 0
+    }
+
+    #[test]
+    fn tables_have_valid_checksums() {
+        let rsdp = build_rsdp(0x1000);
+        assert!(table_checksum_is_valid(&rsdp[..20]));
+        assert!(table_checksum_is_valid(&rsdp));
+
+        for table in [
+            build_dsdt(),
+            build_fadt(0x2000, 0x1000),
+            build_madt(&[0, 1, 2, 3]),
+            build_xsdt(&[0x1000, 0x2000]),
+        ] {
+            assert!(table_checksum_is_valid(&table));
+            let length = u32::from_le_bytes(table[4..8].try_into().unwrap());
+            assert_eq!(length as usize, table.len());
+        }
+    }
+
+    #[test]
+    fn dsdt_declares_pvpanic_device() {
+        let dsdt = build_dsdt();
+        let device = build_pvpanic_device();
+        assert!(dsdt.ends_with(&device));
+        assert_eq!(device[1] as usize, device.len() - 1);
+        assert!(device.windows(8).any(|w| w == b"QEMU0001"));
+        let port = PVPANIC_PORT.to_le_bytes();
+        assert!(
+            device
+                .windows(4)
+                .any(|w| w == [0x47, 0x01, port[0], port[1]])
+        );
+    }
+
+    #[test]
+    fn fadt_points_at_pm_registers() {
+        let fadt = build_fadt(0x2000, 0x1000);
+        assert_eq!(&fadt[0..4], b"FACP");
+        assert_eq!(fadt.len(), FADT_SIZE);
+        assert_eq!(
+            u32::from_le_bytes(
+                fadt[FADT_PM1A_EVT_BLK..FADT_PM1A_EVT_BLK + 4]
+                    .try_into()
+                    .unwrap()
+            ),
+            ACPI_PM_PORT_BASE as u32
+        );
+        assert_eq!(
+            u64::from_le_bytes(fadt[FADT_X_DSDT..FADT_X_DSDT + 8].try_into().unwrap()),
+            0x1000
+        );
+    }
+
+    #[test]
+    fn madt_lists_every_cpu() {
+        let madt = build_madt(&[0, 1, 2]);
+        let lapic_entries = madt[SDT_HEADER_SIZE + 8..]
+            .chunks(8)
+            .take_while(|entry| entry[0] == MADT_LOCAL_APIC)
+            .count();
+        assert_eq!(lapic_entries, 3);
+    }
+
+    #[test]
+    fn madt_uses_x2apic_entries_for_large_ids() {
+        let madt = build_madt(&[0, 254, 255]);
+        let x2apic = &madt[SDT_HEADER_SIZE + 8 + 2 * 8..];
+        assert_eq!(x2apic[0], MADT_LOCAL_X2APIC);
+        assert_eq!(u32::from_le_bytes(x2apic[4..8].try_into().unwrap()), 255);
+        assert_eq!(u32::from_le_bytes(x2apic[12..16].try_into().unwrap()), 2);
+        assert_eq!(x2apic[16], MADT_LOCAL_APIC_NMI);
+        assert_eq!(x2apic[22], MADT_LOCAL_X2APIC_NMI);
+        assert!(table_checksum_is_valid(&madt));
+    }
+
+    #[test]
+    fn power_button_sets_status_until_cleared() {
+        let (mut device, _running, _exit_rx) = create_test_device();
+        write_u16(&mut device, 2, PM1_PWRBTN);
+
+        device.press_power_button();
+        assert_eq!(read_u16(&device, 0) & PM1_PWRBTN, PM1_PWRBTN);
+        assert!(device.sci_asserted);
+
+        write_u16(&mut device, 0, PM1_PWRBTN);
+        assert_eq!(read_u16(&device, 0) & PM1_PWRBTN, 0);
+        assert!(!device.sci_asserted);
+    }
+
+    #[test]
+    fn sci_waits_for_enable() {
+        let (mut device, _running, _exit_rx) = create_test_device();
+
+        device.press_power_button();
+        assert!(!device.sci_asserted);
+
+        write_u16(&mut device, 2, PM1_PWRBTN);
+        assert!(device.sci_asserted);
+    }
+
+    #[test]
+    fn entering_s5_stops_the_vm() {
+        let (mut device, running, mut exit_rx) = create_test_device();
+        let control = read_u16(&device, 4);
+        assert_eq!(control & PM1_CNT_SCI_EN, PM1_CNT_SCI_EN);
+
+        let slp_typ = (S5_SLP_TYP as u16) << PM1_CNT_SLP_TYP_SHIFT;
+        write_u16(&mut device, 4, control | slp_typ);
+        assert!(running.load(Ordering::Relaxed));
+
+        write_u16(&mut device, 4, control | slp_typ | PM1_CNT_SLP_EN);
+        assert!(!running.load(Ordering::Relaxed));
+        assert_eq!(exit_rx.try_recv().unwrap(), ExitReason::Poweroff);
+        assert_eq!(read_u16(&device, 4) & PM1_CNT_SLP_EN, 0);
+    }
+
+    #[test]
+    fn other_sleep_states_are_ignored() {
+        let (mut device, running, _exit_rx) = create_test_device();
+        write_u16(
+            &mut device,
+            4,
+            PM1_CNT_SCI_EN | (1 << PM1_CNT_SLP_TYP_SHIFT) | PM1_CNT_SLP_EN,
+        );
+        assert!(running.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn state_roundtrip() {
+        let (mut device, _running, _exit_rx) = create_test_device();
+        write_u16(&mut device, 2, PM1_PWRBTN);
+        let state = device.save_state();
+
+        let (mut restored, _running, _exit_rx) = create_test_device();
+        restored.restore_state(&state);
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn reset_clears_pending_events() {
+        let (mut device, _running, _exit_rx) = create_test_device();
+        write_u16(&mut device, 2, PM1_PWRBTN);
+        device.press_power_button();
+        assert!(device.sci_asserted);
+
+        device.reset();
+        assert!(!device.sci_asserted);
+        assert_eq!(read_u16(&device, 0), 0);
+        assert_eq!(read_u16(&device, 4), PM1_CNT_SCI_EN);
+    }
+}
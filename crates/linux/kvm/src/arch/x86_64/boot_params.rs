@@ -1,7 +1,9 @@
+use super::acpi::ACPI_TABLES_START;
 use super::memory::{
-    BOOT_GDT_OFFSET, BOOT_PARAMS_ADDR, CMDLINE_OFFSET, PDE_START, PDPTE_START, PML4_START,
+    BOOT_GDT_OFFSET, BOOT_PARAMS_ADDR, CMDLINE_OFFSET, MEM_START, PDE_START, PDPTE_START,
+    PML4_START, guest_memory_ranges,
 };
-use linux_loader::bootparam::{boot_params, setup_header};
+use linux_loader::bootparam::{boot_e820_entry, boot_params, setup_header};
 use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
 
 pub fn setup_boot_params(
@@ -34,29 +36,40 @@ pub fn setup_boot_params(
     }
 
     let high_mem_start: u64 = 0x100000;
-    let mem_below_4g = memory_size.min(0xc0000000);
 
-    params.e820_entries = 4;
-    params.e820_table[0] = linux_loader::bootparam::boot_e820_entry {
-        addr: 0,
-        size: 0x9fc00,
-        type_: 1, // RAM
-    };
-    params.e820_table[1] = linux_loader::bootparam::boot_e820_entry {
-        addr: 0x9fc00,
-        size: 0x400,
-        type_: 2, // Reserved
-    };
-    params.e820_table[2] = linux_loader::bootparam::boot_e820_entry {
-        addr: 0xe8000,
-        size: 0x18000,
-        type_: 2, // Reserved (BIOS)
-    };
-    params.e820_table[3] = linux_loader::bootparam::boot_e820_entry {
-        addr: high_mem_start,
-        size: mem_below_4g - high_mem_start,
-        type_: 1, // RAM
-    };
+    let mut e820 = vec![
+        boot_e820_entry {
+            addr: 0,
+            size: 0x9fc00,
+            type_: 1, // RAM
+        },
+        boot_e820_entry {
+            addr: 0x9fc00,
+            size: 0x400,
+            type_: 2, // Reserved
+        },
+        boot_e820_entry {
+            addr: ACPI_TABLES_START,
+            size: 0x100000 - ACPI_TABLES_START,
+            type_: 2, // Reserved (BIOS, ACPI tables)
+        },
+    ];
+    // RAM from 1 MB up to the MMIO hole, then above 4 GB for larger guests
+    for (addr, size) in guest_memory_ranges(memory_size) {
+        let (addr, size) = if addr == MEM_START {
+            (high_mem_start, size - high_mem_start)
+        } else {
+            (addr, size)
+        };
+        e820.push(boot_e820_entry {
+            addr,
+            size,
+            type_: 1, // RAM
+        });
+    }
+
+    params.e820_entries = e820.len() as u8;
+    params.e820_table[..e820.len()].copy_from_slice(&e820);
 
     let params_bytes = unsafe {
         std::slice::from_raw_parts(
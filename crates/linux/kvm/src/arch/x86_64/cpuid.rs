@@ -0,0 +1,535 @@
+//! CPU models reported to the guest through CPUID.
+//!
+//! A model fixes the feature bits of the CPUID leaves that describe the
+//! instruction set: every bit the model does not list is cleared, so features
+//! of newer host CPUs never reach the guest. Bits that describe the host
+//! rather than the instruction set, such as speculation mitigations, are
+//! passed through unchanged.
+
+use capsa_core::{CpuConfig, CpuModel, Error, Result};
+use kvm_bindings::{CpuId, kvm_cpuid_entry2};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// Location of a feature bit in the CPUID leaves.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Feature {
+    function: u32,
+    index: u32,
+    reg: Reg,
+    bit: u32,
+}
+
+const fn feature(function: u32, index: u32, reg: Reg, bit: u32) -> Feature {
+    Feature {
+        function,
+        index,
+        reg,
+        bit,
+    }
+}
+
+/// Registers whose feature bits a model decides, with the bits passed
+/// through regardless of the model.
+const MASKED_REGISTERS: &[(u32, u32, Reg, u32)] = &[
+    // OSXSAVE and HTT reflect guest CR4 and the vCPU topology
+    (0x1, 0, Reg::Ecx, 1 << 27),
+    (0x1, 0, Reg::Edx, 1 << 28),
+    (0x7, 0, Reg::Ebx, 0),
+    // OSPKE reflects guest CR4
+    (0x7, 0, Reg::Ecx, 1 << 4),
+    // md_clear, spec_ctrl, stibp, flush_l1d, arch_capabilities, core
+    // capabilities and ssbd
+    (
+        0x7,
+        0,
+        Reg::Edx,
+        (1 << 10) | (1 << 26) | (1 << 27) | (1 << 28) | (1 << 29) | (1 << 30) | (1 << 31),
+    ),
+    (0x7, 1, Reg::Eax, 0),
+    (0xd, 1, Reg::Eax, 0),
+    (0x8000_0001, 0, Reg::Ecx, 0),
+    (0x8000_0001, 0, Reg::Edx, 0),
+];
+
+/// Features by their `/proc/cpuinfo` name.
+const FEATURES: &[(&str, Feature)] = &[
+    ("fpu", feature(0x1, 0, Reg::Edx, 0)),
+    ("vme", feature(0x1, 0, Reg::Edx, 1)),
+    ("de", feature(0x1, 0, Reg::Edx, 2)),
+    ("pse", feature(0x1, 0, Reg::Edx, 3)),
+    ("tsc", feature(0x1, 0, Reg::Edx, 4)),
+    ("msr", feature(0x1, 0, Reg::Edx, 5)),
+    ("pae", feature(0x1, 0, Reg::Edx, 6)),
+    ("mce", feature(0x1, 0, Reg::Edx, 7)),
+    ("cx8", feature(0x1, 0, Reg::Edx, 8)),
+    ("apic", feature(0x1, 0, Reg::Edx, 9)),
+    ("sep", feature(0x1, 0, Reg::Edx, 11)),
+    ("mtrr", feature(0x1, 0, Reg::Edx, 12)),
+    ("pge", feature(0x1, 0, Reg::Edx, 13)),
+    ("mca", feature(0x1, 0, Reg::Edx, 14)),
+    ("cmov", feature(0x1, 0, Reg::Edx, 15)),
+    ("pat", feature(0x1, 0, Reg::Edx, 16)),
+    ("pse36", feature(0x1, 0, Reg::Edx, 17)),
+    ("clflush", feature(0x1, 0, Reg::Edx, 19)),
+    ("mmx", feature(0x1, 0, Reg::Edx, 23)),
+    ("fxsr", feature(0x1, 0, Reg::Edx, 24)),
+    ("sse", feature(0x1, 0, Reg::Edx, 25)),
+    ("sse2", feature(0x1, 0, Reg::Edx, 26)),
+    ("ss", feature(0x1, 0, Reg::Edx, 27)),
+    ("pni", feature(0x1, 0, Reg::Ecx, 0)),
+    ("pclmulqdq", feature(0x1, 0, Reg::Ecx, 1)),
+    ("monitor", feature(0x1, 0, Reg::Ecx, 3)),
+    ("vmx", feature(0x1, 0, Reg::Ecx, 5)),
+    ("ssse3", feature(0x1, 0, Reg::Ecx, 9)),
+    ("fma", feature(0x1, 0, Reg::Ecx, 12)),
+    ("cx16", feature(0x1, 0, Reg::Ecx, 13)),
+    ("pdcm", feature(0x1, 0, Reg::Ecx, 15)),
+    ("pcid", feature(0x1, 0, Reg::Ecx, 17)),
+    ("sse4_1", feature(0x1, 0, Reg::Ecx, 19)),
+    ("sse4_2", feature(0x1, 0, Reg::Ecx, 20)),
+    ("x2apic", feature(0x1, 0, Reg::Ecx, 21)),
+    ("movbe", feature(0x1, 0, Reg::Ecx, 22)),
+    ("popcnt", feature(0x1, 0, Reg::Ecx, 23)),
+    ("tsc_deadline_timer", feature(0x1, 0, Reg::Ecx, 24)),
+    ("aes", feature(0x1, 0, Reg::Ecx, 25)),
+    ("xsave", feature(0x1, 0, Reg::Ecx, 26)),
+    ("avx", feature(0x1, 0, Reg::Ecx, 28)),
+    ("f16c", feature(0x1, 0, Reg::Ecx, 29)),
+    ("rdrand", feature(0x1, 0, Reg::Ecx, 30)),
+    ("hypervisor", feature(0x1, 0, Reg::Ecx, 31)),
+    ("fsgsbase", feature(0x7, 0, Reg::Ebx, 0)),
+    ("bmi1", feature(0x7, 0, Reg::Ebx, 3)),
+    ("hle", feature(0x7, 0, Reg::Ebx, 4)),
+    ("avx2", feature(0x7, 0, Reg::Ebx, 5)),
+    ("smep", feature(0x7, 0, Reg::Ebx, 7)),
+    ("bmi2", feature(0x7, 0, Reg::Ebx, 8)),
+    ("erms", feature(0x7, 0, Reg::Ebx, 9)),
+    ("invpcid", feature(0x7, 0, Reg::Ebx, 10)),
+    ("rtm", feature(0x7, 0, Reg::Ebx, 11)),
+    ("avx512f", feature(0x7, 0, Reg::Ebx, 16)),
+    ("avx512dq", feature(0x7, 0, Reg::Ebx, 17)),
+    ("rdseed", feature(0x7, 0, Reg::Ebx, 18)),
+    ("adx", feature(0x7, 0, Reg::Ebx, 19)),
+    ("smap", feature(0x7, 0, Reg::Ebx, 20)),
+    ("avx512ifma", feature(0x7, 0, Reg::Ebx, 21)),
+    ("clflushopt", feature(0x7, 0, Reg::Ebx, 23)),
+    ("clwb", feature(0x7, 0, Reg::Ebx, 24)),
+    ("avx512cd", feature(0x7, 0, Reg::Ebx, 28)),
+    ("sha_ni", feature(0x7, 0, Reg::Ebx, 29)),
+    ("avx512bw", feature(0x7, 0, Reg::Ebx, 30)),
+    ("avx512vl", feature(0x7, 0, Reg::Ebx, 31)),
+    ("avx512vbmi", feature(0x7, 0, Reg::Ecx, 1)),
+    ("umip", feature(0x7, 0, Reg::Ecx, 2)),
+    ("pku", feature(0x7, 0, Reg::Ecx, 3)),
+    ("waitpkg", feature(0x7, 0, Reg::Ecx, 5)),
+    ("avx512_vbmi2", feature(0x7, 0, Reg::Ecx, 6)),
+    ("gfni", feature(0x7, 0, Reg::Ecx, 8)),
+    ("vaes", feature(0x7, 0, Reg::Ecx, 9)),
+    ("vpclmulqdq", feature(0x7, 0, Reg::Ecx, 10)),
+    ("avx512_vnni", feature(0x7, 0, Reg::Ecx, 11)),
+    ("avx512_bitalg", feature(0x7, 0, Reg::Ecx, 12)),
+    ("avx512_vpopcntdq", feature(0x7, 0, Reg::Ecx, 14)),
+    ("la57", feature(0x7, 0, Reg::Ecx, 16)),
+    ("rdpid", feature(0x7, 0, Reg::Ecx, 22)),
+    ("cldemote", feature(0x7, 0, Reg::Ecx, 25)),
+    ("movdiri", feature(0x7, 0, Reg::Ecx, 27)),
+    ("movdir64b", feature(0x7, 0, Reg::Ecx, 28)),
+    ("fsrm", feature(0x7, 0, Reg::Edx, 4)),
+    ("avx512_vp2intersect", feature(0x7, 0, Reg::Edx, 8)),
+    ("serialize", feature(0x7, 0, Reg::Edx, 14)),
+    ("tsxldtrk", feature(0x7, 0, Reg::Edx, 16)),
+    ("amx_bf16", feature(0x7, 0, Reg::Edx, 22)),
+    ("avx512_fp16", feature(0x7, 0, Reg::Edx, 23)),
+    ("amx_tile", feature(0x7, 0, Reg::Edx, 24)),
+    ("amx_int8", feature(0x7, 0, Reg::Edx, 25)),
+    ("avx_vnni", feature(0x7, 1, Reg::Eax, 4)),
+    ("avx512_bf16", feature(0x7, 1, Reg::Eax, 5)),
+    ("xsaveopt", feature(0xd, 1, Reg::Eax, 0)),
+    ("xsavec", feature(0xd, 1, Reg::Eax, 1)),
+    ("xgetbv1", feature(0xd, 1, Reg::Eax, 2)),
+    ("xsaves", feature(0xd, 1, Reg::Eax, 3)),
+    ("lahf_lm", feature(0x8000_0001, 0, Reg::Ecx, 0)),
+    ("cmp_legacy", feature(0x8000_0001, 0, Reg::Ecx, 1)),
+    ("svm", feature(0x8000_0001, 0, Reg::Ecx, 2)),
+    ("cr8_legacy", feature(0x8000_0001, 0, Reg::Ecx, 4)),
+    ("abm", feature(0x8000_0001, 0, Reg::Ecx, 5)),
+    ("sse4a", feature(0x8000_0001, 0, Reg::Ecx, 6)),
+    ("misalignsse", feature(0x8000_0001, 0, Reg::Ecx, 7)),
+    ("3dnowprefetch", feature(0x8000_0001, 0, Reg::Ecx, 8)),
+    ("xop", feature(0x8000_0001, 0, Reg::Ecx, 11)),
+    ("fma4", feature(0x8000_0001, 0, Reg::Ecx, 16)),
+    ("tbm", feature(0x8000_0001, 0, Reg::Ecx, 21)),
+    ("topoext", feature(0x8000_0001, 0, Reg::Ecx, 22)),
+    ("syscall", feature(0x8000_0001, 0, Reg::Edx, 11)),
+    ("nx", feature(0x8000_0001, 0, Reg::Edx, 20)),
+    ("mmxext", feature(0x8000_0001, 0, Reg::Edx, 22)),
+    ("fxsr_opt", feature(0x8000_0001, 0, Reg::Edx, 25)),
+    ("pdpe1gb", feature(0x8000_0001, 0, Reg::Edx, 26)),
+    ("rdtscp", feature(0x8000_0001, 0, Reg::Edx, 27)),
+    ("lm", feature(0x8000_0001, 0, Reg::Edx, 29)),
+];
+
+/// Features every model has: the x86-64 baseline and the platform features
+/// a Linux guest expects, most of which KVM emulates on any host.
+const BASELINE: &[&str] = &[
+    "fpu",
+    "vme",
+    "de",
+    "pse",
+    "tsc",
+    "msr",
+    "pae",
+    "mce",
+    "cx8",
+    "apic",
+    "sep",
+    "mtrr",
+    "pge",
+    "mca",
+    "cmov",
+    "pat",
+    "pse36",
+    "clflush",
+    "mmx",
+    "fxsr",
+    "sse",
+    "sse2",
+    "syscall",
+    "nx",
+    "lm",
+    "x2apic",
+    "tsc_deadline_timer",
+    "hypervisor",
+];
+
+const X86_64_V2: &[&str] = &[
+    "cx16", "lahf_lm", "popcnt", "pni", "sse4_1", "sse4_2", "ssse3",
+];
+
+const X86_64_V3: &[&str] = &[
+    "avx", "avx2", "bmi1", "bmi2", "f16c", "fma", "abm", "movbe", "xsave",
+];
+
+const X86_64_V4: &[&str] = &["avx512f", "avx512bw", "avx512cd", "avx512dq", "avx512vl"];
+
+/// XSAVE state components, by the feature that enables them.
+const XSAVE_COMPONENTS: &[(&str, &[u32])] = &[
+    ("avx", &[2]),
+    ("avx512f", &[5, 6, 7]),
+    ("pku", &[9]),
+    ("amx_tile", &[17, 18]),
+];
+
+/// Size of the legacy XSAVE area and header, which hold x87 and SSE state.
+const XSAVE_LEGACY_SIZE: u32 = 576;
+
+fn lookup(name: &str) -> Result<Feature> {
+    FEATURES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, f)| *f)
+        .ok_or_else(|| Error::InvalidConfig(format!("unknown CPU feature: {}", name)))
+}
+
+fn model_features(model: CpuModel) -> Vec<&'static str> {
+    let levels: &[&[&str]] = match model {
+        CpuModel::Host => &[],
+        CpuModel::X86_64V2 => &[BASELINE, X86_64_V2],
+        CpuModel::X86_64V3 => &[BASELINE, X86_64_V2, X86_64_V3],
+        CpuModel::X86_64V4 => &[BASELINE, X86_64_V2, X86_64_V3, X86_64_V4],
+    };
+    levels.concat()
+}
+
+fn find(entries: &[kvm_cpuid_entry2], function: u32, index: u32) -> Option<&kvm_cpuid_entry2> {
+    entries
+        .iter()
+        .find(|e| e.function == function && e.index == index)
+}
+
+fn find_mut(
+    entries: &mut [kvm_cpuid_entry2],
+    function: u32,
+    index: u32,
+) -> Option<&mut kvm_cpuid_entry2> {
+    entries
+        .iter_mut()
+        .find(|e| e.function == function && e.index == index)
+}
+
+fn reg_mut(entry: &mut kvm_cpuid_entry2, reg: Reg) -> &mut u32 {
+    match reg {
+        Reg::Eax => &mut entry.eax,
+        Reg::Ebx => &mut entry.ebx,
+        Reg::Ecx => &mut entry.ecx,
+        Reg::Edx => &mut entry.edx,
+    }
+}
+
+fn is_set(entries: &[kvm_cpuid_entry2], feature: Feature) -> bool {
+    find(entries, feature.function, feature.index).is_some_and(|e| {
+        let value = match feature.reg {
+            Reg::Eax => e.eax,
+            Reg::Ebx => e.ebx,
+            Reg::Ecx => e.ecx,
+            Reg::Edx => e.edx,
+        };
+        value & (1 << feature.bit) != 0
+    })
+}
+
+/// Restricts the CPUID KVM supports to the features `config` asks for.
+///
+/// Fails if the host lacks a feature of the model or one of the added
+/// features, since the guest would then differ from other hosts.
+pub fn apply_cpu_model(supported: &CpuId, config: &CpuConfig) -> Result<CpuId> {
+    let mut entries = supported.as_slice().to_vec();
+
+    let mut wanted: Vec<Feature> = Vec::new();
+    if config.model != CpuModel::Host {
+        for name in model_features(config.model) {
+            wanted.push(lookup(name)?);
+        }
+    }
+    for name in &config.add_features {
+        wanted.push(lookup(name)?);
+    }
+    let removed = config
+        .remove_features
+        .iter()
+        .map(|name| lookup(name))
+        .collect::<Result<Vec<_>>>()?;
+
+    for (name, feature) in FEATURES {
+        if wanted.contains(feature) && !removed.contains(feature) && !is_set(&entries, *feature) {
+            return Err(Error::UnsupportedFeature(format!(
+                "CPU feature {} on this host",
+                name
+            )));
+        }
+    }
+
+    if config.model != CpuModel::Host {
+        for &(function, index, reg, passthrough) in MASKED_REGISTERS {
+            let model_bits = wanted
+                .iter()
+                .filter(|f| f.function == function && f.index == index && f.reg == reg)
+                .fold(0u32, |bits, f| bits | (1 << f.bit));
+            if let Some(entry) = find_mut(&mut entries, function, index) {
+                *reg_mut(entry, reg) &= model_bits | passthrough;
+            }
+        }
+    }
+    for feature in &removed {
+        if let Some(entry) = find_mut(&mut entries, feature.function, feature.index) {
+            *reg_mut(entry, feature.reg) &= !(1 << feature.bit);
+        }
+    }
+
+    if config.model != CpuModel::Host || !removed.is_empty() {
+        mask_xsave_components(&mut entries);
+    }
+
+    CpuId::from_entries(&entries)
+        .map_err(|e| Error::StartFailed(format!("failed to build CPUID: {:?}", e)))
+}
+
+/// Hides the XSAVE state of disabled features, so the guest neither enables
+/// it in XCR0 nor reserves room for it.
+fn mask_xsave_components(entries: &mut [kvm_cpuid_entry2]) {
+    let Some(leaf) = find(entries, 0xd, 0) else {
+        return;
+    };
+    let xcr0 = ((leaf.edx as u64) << 32) | leaf.eax as u64;
+
+    let mut disabled = 0u64;
+    for (name, components) in XSAVE_COMPONENTS {
+        if !is_set(entries, lookup(name).expect("XSAVE features are known")) {
+            disabled |= components.iter().fold(0u64, |bits, c| bits | (1 << c));
+        }
+    }
+    disabled &= xcr0;
+    if disabled == 0 {
+        return;
+    }
+    let xcr0 = xcr0 & !disabled;
+
+    for entry in entries.iter_mut() {
+        if entry.function == 0xd && entry.index >= 2 && disabled & (1 << entry.index) != 0 {
+            *entry = kvm_cpuid_entry2 {
+                function: entry.function,
+                index: entry.index,
+                flags: entry.flags,
+                ..Default::default()
+            };
+        }
+    }
+
+    let max_size = entries
+        .iter()
+        .filter(|e| e.function == 0xd && (2..64).contains(&e.index))
+        .filter(|e| xcr0 & (1 << e.index) != 0)
+        .map(|e| e.ebx + e.eax)
+        .fold(XSAVE_LEGACY_SIZE, u32::max);
+    if let Some(leaf) = find_mut(entries, 0xd, 0) {
+        leaf.eax = xcr0 as u32;
+        leaf.edx = (xcr0 >> 32) as u32;
+        leaf.ecx = max_size;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CPUID of a host with every known feature and the x87, SSE, AVX,
+    /// AVX-512 and PKRU XSAVE components.
+    fn full_cpuid() -> CpuId {
+        let mut entries: Vec<kvm_cpuid_entry2> = Vec::new();
+        for (_, feature) in FEATURES {
+            let entry = match find_mut(&mut entries, feature.function, feature.index) {
+                Some(entry) => entry,
+                None => {
+                    entries.push(kvm_cpuid_entry2 {
+                        function: feature.function,
+                        index: feature.index,
+                        ..Default::default()
+                    });
+                    entries.last_mut().unwrap()
+                }
+            };
+            *reg_mut(entry, feature.reg) |= 1 << feature.bit;
+        }
+        entries.push(kvm_cpuid_entry2 {
+            function: 0xd,
+            eax: 0x2e7,
+            ebx: 2696,
+            ecx: 2696,
+            ..Default::default()
+        });
+        for (index, size, offset) in [
+            (2, 256, 576),
+            (5, 64, 1088),
+            (6, 512, 1152),
+            (7, 1024, 1664),
+            (9, 8, 2688),
+        ] {
+            entries.push(kvm_cpuid_entry2 {
+                function: 0xd,
+                index,
+                eax: size,
+                ebx: offset,
+                ..Default::default()
+            });
+        }
+        CpuId::from_entries(&entries).unwrap()
+    }
+
+    fn has(cpuid: &CpuId, name: &str) -> bool {
+        is_set(cpuid.as_slice(), lookup(name).unwrap())
+    }
+
+    fn config(model: CpuModel) -> CpuConfig {
+        CpuConfig {
+            model,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn host_model_passes_cpuid_through() {
+        let supported = full_cpuid();
+        let cpuid = apply_cpu_model(&supported, &CpuConfig::default()).unwrap();
+        assert_eq!(cpuid.as_slice(), supported.as_slice());
+    }
+
+    #[test]
+    fn v2_hides_newer_features() {
+        let cpuid = apply_cpu_model(&full_cpuid(), &config(CpuModel::X86_64V2)).unwrap();
+        assert!(has(&cpuid, "sse4_2"));
+        assert!(has(&cpuid, "lm"));
+        assert!(!has(&cpuid, "avx"));
+        assert!(!has(&cpuid, "avx2"));
+        assert!(!has(&cpuid, "aes"));
+
+        // Without AVX the guest cannot enable YMM state
+        let xsave = find(cpuid.as_slice(), 0xd, 0).unwrap();
+        assert_eq!(xsave.eax, 0x3);
+        assert_eq!(xsave.ecx, XSAVE_LEGACY_SIZE);
+    }
+
+    #[test]
+    fn v3_keeps_avx_state() {
+        let cpuid = apply_cpu_model(&full_cpuid(), &config(CpuModel::X86_64V3)).unwrap();
+        assert!(has(&cpuid, "avx2"));
+        assert!(!has(&cpuid, "avx512f"));
+
+        let xsave = find(cpuid.as_slice(), 0xd, 0).unwrap();
+        assert_eq!(xsave.eax, 0x7);
+        assert_eq!(xsave.ecx, 832);
+        assert_eq!(find(cpuid.as_slice(), 0xd, 5).unwrap().eax, 0);
+    }
+
+    #[test]
+    fn feature_lists_adjust_the_model() {
+        let cpuid = apply_cpu_model(
+            &full_cpuid(),
+            &CpuConfig {
+                model: CpuModel::X86_64V2,
+                add_features: vec!["aes".into()],
+                remove_features: vec!["popcnt".into()],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert!(has(&cpuid, "aes"));
+        assert!(!has(&cpuid, "popcnt"));
+    }
+
+    #[test]
+    fn unknown_features_are_rejected() {
+        let err = apply_cpu_model(
+            &full_cpuid(),
+            &CpuConfig {
+                remove_features: vec!["warp_drive".into()],
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(err, Error::InvalidConfig(msg) if msg.contains("warp_drive")));
+    }
+
+    #[test]
+    fn missing_host_features_are_rejected() {
+        let mut supported = full_cpuid();
+        let leaf = find_mut(supported.as_mut_slice(), 0x1, 0).unwrap();
+        leaf.ecx &= !(1 << 23); // popcnt
+
+        let err = apply_cpu_model(&supported, &config(CpuModel::X86_64V2)).unwrap_err();
+        assert!(matches!(err, Error::UnsupportedFeature(msg) if msg.contains("popcnt")));
+    }
+
+    #[test]
+    fn kvm_accepts_masked_cpuid() {
+        let kvm = kvm_ioctls::Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = kvm.create_vm().expect("Failed to create VM");
+        let vcpu = vm.create_vcpu(0).expect("Failed to create vCPU");
+        let supported = kvm
+            .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+            .unwrap();
+        let config = CpuConfig {
+            remove_features: vec!["avx".into(), "rdrand".into()],
+            ..Default::default()
+        };
+        let cpuid = apply_cpu_model(&supported, &config).unwrap();
+        vcpu.set_cpuid2(&cpuid).unwrap();
+        assert!(!has(&cpuid, "rdrand"));
+    }
+}
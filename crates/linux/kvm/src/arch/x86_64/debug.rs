@@ -0,0 +1,325 @@
+//! x86_64 support for the GDB stub: the debugger's register layout, guest
+//! debugging through `KVM_SET_GUEST_DEBUG` and guest virtual address
+//! translation.
+
+use kvm_bindings::{
+    KVM_GUESTDBG_ENABLE, KVM_GUESTDBG_SINGLESTEP, KVM_GUESTDBG_USE_HW_BP, KVM_GUESTDBG_USE_SW_BP,
+    kvm_debug_exit_arch, kvm_fpu, kvm_guest_debug, kvm_regs, kvm_sregs,
+};
+use kvm_ioctls::VcpuFd;
+
+/// Breakpoint instruction patched into guest memory for software breakpoints.
+pub const SW_BREAKPOINT_INSN: u8 = 0xcc;
+
+/// Number of debug address registers, and so of hardware breakpoints.
+pub const MAX_HW_BREAKPOINTS: usize = 4;
+
+/// Size of the general purpose registers, `rip`, `eflags` and the segment
+/// selectors at the start of GDB's register layout.
+pub const GDB_CORE_REGISTERS_SIZE: usize = 16 * 8 + 8 + 4 + 6 * 4;
+
+/// Reads the registers of a vCPU in the layout GDB expects for amd64.
+///
+/// The general purpose registers come first, followed by the x87 and SSE
+/// state.
+pub fn read_gdb_registers(vcpu: &VcpuFd) -> Result<Vec<u8>, kvm_ioctls::Error> {
+    let regs = vcpu.get_regs()?;
+    let sregs = vcpu.get_sregs()?;
+    let fpu = vcpu.get_fpu()?;
+    Ok(encode_registers(&regs, &sregs, &fpu))
+}
+
+/// Writes the general purpose registers, `rip` and `eflags` of a vCPU from
+/// GDB's register layout.
+///
+/// Segment selectors and the x87 and SSE state are left alone; changing a
+/// selector without its descriptor would corrupt the segment.
+pub fn write_gdb_registers(vcpu: &VcpuFd, data: &[u8]) -> Result<(), kvm_ioctls::Error> {
+    let mut regs = vcpu.get_regs()?;
+    decode_registers(&mut regs, data);
+    vcpu.set_regs(&regs)
+}
+
+fn general_registers(regs: &kvm_regs) -> [u64; 16] {
+    [
+        regs.rax, regs.rbx, regs.rcx, regs.rdx, regs.rsi, regs.rdi, regs.rbp, regs.rsp, regs.r8,
+        regs.r9, regs.r10, regs.r11, regs.r12, regs.r13, regs.r14, regs.r15,
+    ]
+}
+
+fn general_registers_mut(regs: &mut kvm_regs) -> [&mut u64; 16] {
+    [
+        &mut regs.rax,
+        &mut regs.rbx,
+        &mut regs.rcx,
+        &mut regs.rdx,
+        &mut regs.rsi,
+        &mut regs.rdi,
+        &mut regs.rbp,
+        &mut regs.rsp,
+        &mut regs.r8,
+        &mut regs.r9,
+        &mut regs.r10,
+        &mut regs.r11,
+        &mut regs.r12,
+        &mut regs.r13,
+        &mut regs.r14,
+        &mut regs.r15,
+    ]
+}
+
+fn encode_registers(regs: &kvm_regs, sregs: &kvm_sregs, fpu: &kvm_fpu) -> Vec<u8> {
+    let mut data = Vec::new();
+    for value in general_registers(regs) {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    data.extend_from_slice(&regs.rip.to_le_bytes());
+    data.extend_from_slice(&(regs.rflags as u32).to_le_bytes());
+    for segment in [
+        &sregs.cs, &sregs.ss, &sregs.ds, &sregs.es, &sregs.fs, &sregs.gs,
+    ] {
+        data.extend_from_slice(&u32::from(segment.selector).to_le_bytes());
+    }
+
+    for st in &fpu.fpr {
+        data.extend_from_slice(&st[..10]);
+    }
+    let x87_control = [
+        u32::from(fpu.fcw),
+        u32::from(fpu.fsw),
+        u32::from(full_tag_word(fpu.ftwx)),
+        0,
+        fpu.last_ip as u32,
+        0,
+        fpu.last_dp as u32,
+        u32::from(fpu.last_opcode),
+    ];
+    for value in x87_control {
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    for xmm in &fpu.xmm {
+        data.extend_from_slice(xmm);
+    }
+    data.extend_from_slice(&fpu.mxcsr.to_le_bytes());
+    data
+}
+
+fn decode_registers(regs: &mut kvm_regs, data: &[u8]) {
+    let mut values = data
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+    for (register, value) in general_registers_mut(regs).into_iter().zip(values.by_ref()) {
+        *register = value;
+    }
+    if let Some(rip) = values.next() {
+        regs.rip = rip;
+    }
+    if let Some(eflags) = data.get(17 * 8..17 * 8 + 4) {
+        regs.rflags = u64::from(u32::from_le_bytes(eflags.try_into().unwrap()));
+    }
+}
+
+/// Expands the abridged FXSAVE tag byte into the x87 tag word, reporting
+/// every register in use as valid.
+fn full_tag_word(abridged: u8) -> u16 {
+    (0..8)
+        .filter(|i| abridged & (1 << i) == 0)
+        .fold(0, |tags, i| tags | (0b11 << (i * 2)))
+}
+
+/// Enables guest debugging on a vCPU with the given hardware breakpoints,
+/// stepping a single instruction if `single_step` is set.
+///
+/// Software breakpoints are always intercepted, so every `int3` the guest
+/// runs stops it.
+pub fn set_guest_debug(
+    vcpu: &VcpuFd,
+    hw_breakpoints: &[u64],
+    single_step: bool,
+) -> Result<(), kvm_ioctls::Error> {
+    let mut debug = kvm_guest_debug {
+        control: KVM_GUESTDBG_ENABLE | KVM_GUESTDBG_USE_SW_BP,
+        ..Default::default()
+    };
+    if single_step {
+        debug.control |= KVM_GUESTDBG_SINGLESTEP;
+    }
+    if !hw_breakpoints.is_empty() {
+        debug.control |= KVM_GUESTDBG_USE_HW_BP;
+        for (index, &addr) in hw_breakpoints.iter().enumerate() {
+            debug.arch.debugreg[index] = addr;
+        }
+        debug.arch.debugreg[7] = debug_control_register(hw_breakpoints.len());
+    }
+    vcpu.set_guest_debug(&debug)
+}
+
+/// Disables guest debugging on a vCPU.
+pub fn clear_guest_debug(vcpu: &VcpuFd) -> Result<(), kvm_ioctls::Error> {
+    vcpu.set_guest_debug(&kvm_guest_debug::default())
+}
+
+/// DR7 value enabling the first `count` debug address registers as
+/// instruction breakpoints.
+fn debug_control_register(count: usize) -> u64 {
+    // Global enable bits; the condition and length fields stay zero, which
+    // means "break on execution"
+    (0..count).fold(0, |dr7, index| dr7 | (0b10 << (index * 2)))
+}
+
+/// Exception vector of the debug exception, raised by single steps and
+/// hardware breakpoints.
+const DB_VECTOR: u32 = 1;
+
+/// Exception vector of the breakpoint exception, raised by `int3`.
+const BP_VECTOR: u32 = 3;
+
+/// Bits of DR6 telling which debug address register matched.
+const DR6_BREAKPOINT_HIT: u64 = 0xf;
+
+/// Why a vCPU stopped with `KVM_EXIT_DEBUG`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DebugStop {
+    SoftwareBreakpoint,
+    HardwareBreakpoint,
+    SingleStep,
+}
+
+impl DebugStop {
+    /// Classifies a debug exit by the exception the guest raised.
+    pub fn from_exit(exit: &kvm_debug_exit_arch) -> Self {
+        if exit.exception == BP_VECTOR {
+            Self::SoftwareBreakpoint
+        } else if exit.exception == DB_VECTOR && exit.dr6 & DR6_BREAKPOINT_HIT != 0 {
+            Self::HardwareBreakpoint
+        } else {
+            Self::SingleStep
+        }
+    }
+}
+
+/// Delivers an intercepted `int3` to the guest, for breakpoints the guest
+/// placed itself, such as the kernel's own text patching.
+///
+/// KVM reuses the length of the trapping instruction, so the guest sees the
+/// exception as if it had never been intercepted.
+pub fn reinject_breakpoint(vcpu: &VcpuFd) -> Result<(), kvm_ioctls::Error> {
+    let mut events = vcpu.get_vcpu_events()?;
+    events.exception.injected = 1;
+    events.exception.nr = BP_VECTOR as u8;
+    events.exception.has_error_code = 0;
+    events.exception.error_code = 0;
+    vcpu.set_vcpu_events(&events)
+}
+
+/// Translates a guest virtual address through the page tables the vCPU is
+/// using, returning None if it is not mapped.
+pub fn translate_gva(vcpu: &VcpuFd, gva: u64) -> Option<u64> {
+    let translation = vcpu.translate_gva(gva).ok()?;
+    (translation.valid != 0).then_some(translation.physical_address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_bindings::kvm_segment;
+
+    fn test_registers() -> (kvm_regs, kvm_sregs, kvm_fpu) {
+        let regs = kvm_regs {
+            rax: 1,
+            rbx: 2,
+            r15: 16,
+            rip: 0xffffffff81000000,
+            rflags: 0x246,
+            ..Default::default()
+        };
+        let sregs = kvm_sregs {
+            cs: kvm_segment {
+                selector: 0x10,
+                ..Default::default()
+            },
+            ss: kvm_segment {
+                selector: 0x18,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let fpu = kvm_fpu {
+            fcw: 0x37f,
+            ftwx: 0b1,
+            mxcsr: 0x1f80,
+            ..Default::default()
+        };
+        (regs, sregs, fpu)
+    }
+
+    #[test]
+    fn encodes_gdb_register_layout() {
+        let (regs, sregs, fpu) = test_registers();
+        let data = encode_registers(&regs, &sregs, &fpu);
+
+        assert_eq!(
+            data.len(),
+            GDB_CORE_REGISTERS_SIZE + 8 * 10 + 8 * 4 + 16 * 16 + 4
+        );
+        assert_eq!(data[0..8], 1u64.to_le_bytes());
+        assert_eq!(data[8..16], 2u64.to_le_bytes());
+        assert_eq!(data[120..128], 16u64.to_le_bytes());
+        assert_eq!(data[128..136], 0xffffffff81000000u64.to_le_bytes());
+        assert_eq!(data[136..140], 0x246u32.to_le_bytes());
+        assert_eq!(data[140..144], 0x10u32.to_le_bytes());
+        assert_eq!(data[144..148], 0x18u32.to_le_bytes());
+        assert_eq!(data[data.len() - 4..], 0x1f80u32.to_le_bytes());
+    }
+
+    #[test]
+    fn decodes_written_registers() {
+        let (regs, sregs, fpu) = test_registers();
+        let mut data = encode_registers(&regs, &sregs, &fpu);
+        data[0..8].copy_from_slice(&42u64.to_le_bytes());
+        data[128..136].copy_from_slice(&0x1000u64.to_le_bytes());
+        data[136..140].copy_from_slice(&0x2u32.to_le_bytes());
+
+        let mut written = regs;
+        decode_registers(&mut written, &data);
+        assert_eq!(written.rax, 42);
+        assert_eq!(written.rbx, 2);
+        assert_eq!(written.rip, 0x1000);
+        assert_eq!(written.rflags, 0x2);
+    }
+
+    #[test]
+    fn expands_tag_word() {
+        assert_eq!(full_tag_word(0xff), 0);
+        assert_eq!(full_tag_word(0), 0xffff);
+        assert_eq!(full_tag_word(0b1), 0xfffc);
+    }
+
+    #[test]
+    fn classifies_debug_exits() {
+        let exit = |exception, dr6| kvm_debug_exit_arch {
+            exception,
+            dr6,
+            ..Default::default()
+        };
+        assert_eq!(
+            DebugStop::from_exit(&exit(BP_VECTOR, 0)),
+            DebugStop::SoftwareBreakpoint
+        );
+        assert_eq!(
+            DebugStop::from_exit(&exit(DB_VECTOR, 0x4002)),
+            DebugStop::HardwareBreakpoint
+        );
+        assert_eq!(
+            DebugStop::from_exit(&exit(DB_VECTOR, 0x4000)),
+            DebugStop::SingleStep
+        );
+    }
+
+    #[test]
+    fn enables_debug_address_registers() {
+        assert_eq!(debug_control_register(0), 0);
+        assert_eq!(debug_control_register(1), 0b10);
+        assert_eq!(debug_control_register(4), 0b10101010);
+    }
+}
@@ -0,0 +1,91 @@
+//! i8042 keyboard controller, only there for guests to reboot through.
+//!
+//! With `reboot=k`, Linux reboots by pulsing the CPU reset line through the
+//! controller's command port. No keyboard is attached.
+
+use capsa_core::ExitReason;
+use std::sync::Arc;
+use vm_device::MutDevicePio;
+use vm_device::bus::{PioAddress, PioAddressOffset};
+
+use crate::reboot::RebootControl;
+
+/// Data port of the i8042 controller.
+pub const I8042_DATA_PORT: u16 = 0x60;
+/// Status and command port of the i8042 controller.
+pub const I8042_COMMAND_PORT: u16 = 0x64;
+
+/// Command that pulses the CPU reset line.
+const I8042_CMD_RESET_CPU: u8 = 0xfe;
+
+/// Resets the guest when it pulses the CPU reset line.
+pub struct I8042Device {
+    reboot: Arc<RebootControl>,
+}
+
+impl I8042Device {
+    pub fn new(reboot: Arc<RebootControl>) -> Self {
+        Self { reboot }
+    }
+}
+
+impl MutDevicePio for I8042Device {
+    fn pio_read(&mut self, _base: PioAddress, _offset: PioAddressOffset, data: &mut [u8]) {
+        // An empty output buffer and an idle input buffer, so the guest
+        // never waits for the controller
+        data.fill(0);
+    }
+
+    fn pio_write(&mut self, base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        if base.0 + offset == I8042_COMMAND_PORT && data.first() == Some(&I8042_CMD_RESET_CPU) {
+            tracing::debug!("guest pulsed the CPU reset line");
+            self.reboot.reset(ExitReason::Reboot);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pause::PauseControl;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use tokio::sync::mpsc;
+
+    fn create_test_device() -> (I8042Device, Arc<AtomicBool>, mpsc::Receiver<ExitReason>) {
+        let running = Arc::new(AtomicBool::new(true));
+        let (exit_tx, exit_rx) = mpsc::channel(1);
+        let reboot = RebootControl::new(
+            false,
+            running.clone(),
+            Arc::new(PauseControl::new()),
+            exit_tx,
+        );
+        (I8042Device::new(Arc::new(reboot)), running, exit_rx)
+    }
+
+    #[test]
+    fn controller_is_always_ready() {
+        let (mut device, _, _) = create_test_device();
+        let mut status = [0xff];
+        device.pio_read(PioAddress(I8042_COMMAND_PORT), 0, &mut status);
+        assert_eq!(status[0], 0);
+    }
+
+    #[test]
+    fn reset_command_reboots() {
+        let (mut device, running, mut exit_rx) = create_test_device();
+        device.pio_write(PioAddress(I8042_COMMAND_PORT), 0, &[I8042_CMD_RESET_CPU]);
+
+        assert!(!running.load(Ordering::Relaxed));
+        assert_eq!(exit_rx.try_recv().unwrap(), ExitReason::Reboot);
+    }
+
+    #[test]
+    fn reset_byte_on_data_port_is_ignored() {
+        let (mut device, running, mut exit_rx) = create_test_device();
+        device.pio_write(PioAddress(I8042_DATA_PORT), 0, &[I8042_CMD_RESET_CPU]);
+
+        assert!(running.load(Ordering::Relaxed));
+        assert!(exit_rx.try_recv().is_err());
+    }
+}
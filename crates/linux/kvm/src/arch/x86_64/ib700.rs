@@ -0,0 +1,83 @@
+//! IB700 watchdog timer card, which Linux drives with the `ib700wdt` driver.
+//!
+//! Writing a timeout to the start port starts the timer or restarts its
+//! countdown, and writing to the stop port stops it. The timeout the guest
+//! writes is ignored in favour of the one configured on the host.
+
+use std::sync::Arc;
+use vm_device::MutDevicePio;
+use vm_device::bus::{PioAddress, PioAddressOffset};
+
+use crate::watchdog::Watchdog;
+
+/// Port the guest writes to stop the watchdog.
+pub const IB700_STOP_PORT: u16 = 0x441;
+/// Port the guest writes to start and ping the watchdog.
+pub const IB700_START_PORT: u16 = 0x443;
+
+/// Passes the guest's pings on to the host-side watchdog.
+pub struct Ib700Device {
+    watchdog: Arc<Watchdog>,
+}
+
+impl Ib700Device {
+    pub fn new(watchdog: Arc<Watchdog>) -> Self {
+        Self { watchdog }
+    }
+}
+
+impl MutDevicePio for Ib700Device {
+    fn pio_read(&mut self, _base: PioAddress, _offset: PioAddressOffset, data: &mut [u8]) {
+        data.fill(0);
+    }
+
+    fn pio_write(&mut self, base: PioAddress, offset: PioAddressOffset, _data: &[u8]) {
+        match base.0 + offset {
+            IB700_START_PORT => self.watchdog.ping(),
+            IB700_STOP_PORT => self.watchdog.stop(),
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crash::GuestCrash;
+    use crate::pause::PauseControl;
+    use crate::reboot::RebootControl;
+    use capsa_core::{WatchdogAction, WatchdogConfig};
+    use std::sync::atomic::AtomicBool;
+    use std::time::Duration;
+    use tokio::sync::mpsc;
+
+    fn create_test_device() -> Ib700Device {
+        let running = Arc::new(AtomicBool::new(true));
+        let (exit_tx, _) = mpsc::channel(1);
+        let reboot = RebootControl::new(
+            false,
+            running.clone(),
+            Arc::new(PauseControl::new()),
+            exit_tx.clone(),
+        );
+        Ib700Device::new(Arc::new(Watchdog::new(
+            WatchdogConfig::new(Duration::from_secs(30), WatchdogAction::Reset),
+            Arc::new(GuestCrash::default()),
+            Arc::new(reboot),
+            running,
+            exit_tx,
+        )))
+    }
+
+    #[test]
+    fn start_and_stop_ports_control_the_watchdog() {
+        let mut device = create_test_device();
+
+        // The driver writes the index of its timeout, 30 s here
+        device.pio_write(PioAddress(IB700_START_PORT), 0, &[0]);
+        assert!(device.watchdog.deadline().is_some());
+
+        device.pio_write(PioAddress(IB700_STOP_PORT), 0, &[0]);
+        assert!(device.watchdog.deadline().is_none());
+    }
+}
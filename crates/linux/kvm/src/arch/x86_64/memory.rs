@@ -13,12 +13,23 @@
 //! 0x0000_A000   0x1000      PDPTE page table
 //! 0x0000_B000   0x1000      PDE page table
 //! 0x0002_0000   0x10000     Kernel command line
+//! 0x000E_0000   0x20000     ACPI tables (reserved BIOS area)
 //! 0x0100_0000   -           Kernel load address (16 MB)
 //! 0x0400_0000   -           Initrd load address (64 MB)
+//! 0xC000_0000   0x4000_0000 32-bit MMIO hole (devices, IOAPIC, LAPIC)
+//! 0xD000_0000   0x1000_0000 virtio-mmio device registers
+//! 0xE000_0000   0x1000_0000 PCI window for virtio-pci BARs
+//! 0x1_0000_0000 -           Guest memory above 3 GB
 //! ```
+//!
+//! Guest memory fills the space below the MMIO hole first and continues at
+//! 4 GB, so device addresses never overlap RAM regardless of guest size.
 
+use capsa_core::{HugePages, MemoryBacking};
 use nix::libc;
-use vm_memory::{GuestAddress, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
+use nix::sys::memfd::{MemFdCreateFlag, memfd_create};
+use std::fs::File;
+use vm_memory::{FileOffset, GuestAddress, GuestMemoryMmap, GuestRegionMmap, MmapRegion};
 
 /// GDT location in low memory (follows real-mode convention).
 pub const BOOT_GDT_OFFSET: u64 = 0x500;
@@ -56,20 +67,173 @@ pub const BOOT_PARAMS_ADDR: u64 = 0x7000;
 
 pub const MEM_START: u64 = 0;
 
-pub fn create_guest_memory(memory_mb: u64) -> Result<GuestMemoryMmap, Box<dyn std::error::Error>> {
+/// Start of the 32-bit MMIO hole reserved for device registers (3 GB).
+pub const MMIO_HOLE_START: u64 = 0xc000_0000;
+
+/// End of the 32-bit MMIO hole, where memory above 3 GB is placed (4 GB).
+pub const MMIO_HOLE_END: u64 = 0x1_0000_0000;
+
+/// Start of the window in the MMIO hole that PCI BARs are placed in.
+pub const PCI_MMIO_START: u64 = 0xe000_0000;
+
+/// Size of the PCI window (256 MB), ending well below the IOAPIC and LAPIC.
+pub const PCI_MMIO_SIZE: u64 = 0x1000_0000;
+
+/// Largest guest memory size in MB.
+///
+/// Keeps the top of guest memory below 512 GB, the smallest guest physical
+/// address space (39 bits) found on x86_64 hosts.
+pub const MAX_MEMORY_MB: u32 = (((1 << 39) - (MMIO_HOLE_END - MMIO_HOLE_START)) >> 20) as u32;
+
+/// Returns the `(guest address, size)` ranges that hold `mem_size` bytes of
+/// guest memory around the MMIO hole.
+pub fn guest_memory_ranges(mem_size: u64) -> Vec<(u64, u64)> {
+    let low_size = mem_size.min(MMIO_HOLE_START - MEM_START);
+    let mut ranges = vec![(MEM_START, low_size)];
+    if mem_size > low_size {
+        ranges.push((MMIO_HOLE_END, mem_size - low_size));
+    }
+    ranges
+}
+
+/// Allocates guest memory as described by `backing`.
+///
+/// Memfd-backed regions map the same file, which stays reachable through
+/// each region's [`FileOffset`] so other processes can map guest memory.
+pub fn create_guest_memory(
+    memory_mb: u64,
+    backing: &MemoryBacking,
+) -> Result<GuestMemoryMmap, Box<dyn std::error::Error>> {
     let mem_size = memory_mb * 1024 * 1024;
-    let mmap_region = MmapRegion::build(
-        None,
-        mem_size as usize,
-        libc::PROT_READ | libc::PROT_WRITE,
-        libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
-    )?;
-    let mem_region = GuestRegionMmap::new(mmap_region, GuestAddress(MEM_START))
-        .ok_or("failed to create guest region")?;
-    GuestMemoryMmap::from_regions(vec![mem_region])
+    let hugetlb = backing.hugepages == HugePages::Hugetlb;
+
+    // KSM only scans private anonymous mappings
+    if backing.mergeable && (backing.memfd || hugetlb) {
+        return Err("kernel same-page merging requires private anonymous memory".into());
+    }
+
+    let memfd = if backing.memfd || hugetlb {
+        let mut flags = MemFdCreateFlag::MFD_CLOEXEC;
+        if hugetlb {
+            flags |= MemFdCreateFlag::MFD_HUGETLB;
+        }
+        let fd = memfd_create(c"capsa-guest-memory", flags)
+            .map_err(|e| format!("failed to create memfd: {}", e))?;
+        let file = File::from(fd);
+        file.set_len(mem_size)
+            .map_err(|e| format!("failed to size memfd to {} MB: {}", memory_mb, e))?;
+        Some(file)
+    } else {
+        None
+    };
+
+    let mut mem_regions = Vec::new();
+    let mut file_offset = 0;
+
+    for (guest_addr, size) in guest_memory_ranges(mem_size) {
+        let (file, flags) = match &memfd {
+            Some(file) => (
+                Some(FileOffset::new(file.try_clone()?, file_offset)),
+                libc::MAP_SHARED,
+            ),
+            None => (None, libc::MAP_ANONYMOUS | libc::MAP_PRIVATE),
+        };
+        let mmap_region = MmapRegion::build(
+            file,
+            size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            flags,
+        )?;
+
+        if backing.hugepages == HugePages::Transparent {
+            advise(&mmap_region, libc::MADV_HUGEPAGE)
+                .map_err(|e| format!("failed to enable transparent huge pages: {}", e))?;
+        }
+        if backing.mergeable {
+            advise(&mmap_region, libc::MADV_MERGEABLE)
+                .map_err(|e| format!("failed to enable same-page merging: {}", e))?;
+        }
+        // Populating after the advice lets the kernel use huge pages
+        if backing.prefault {
+            advise(&mmap_region, libc::MADV_POPULATE_WRITE)
+                .map_err(|e| format!("failed to prefault guest memory: {}", e))?;
+        }
+
+        let mem_region = GuestRegionMmap::new(mmap_region, GuestAddress(guest_addr))
+            .ok_or("failed to create guest region")?;
+        mem_regions.push(mem_region);
+        file_offset += size;
+    }
+
+    GuestMemoryMmap::from_regions(mem_regions)
+        .map_err(|e| format!("failed to create guest memory: {}", e).into())
+}
+
+fn advise(region: &MmapRegion, advice: libc::c_int) -> std::io::Result<()> {
+    let ret = unsafe { libc::madvise(region.as_ptr().cast(), region.size(), advice) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Maps guest memory saved in a snapshot file.
+///
+/// `regions` lists `(guest address, size)` pairs stored back to back from
+/// `offset`. The mapping is private, so pages are read lazily on first access
+/// and guest writes never reach the file.
+pub fn create_guest_memory_from_file(
+    file: &File,
+    offset: u64,
+    regions: &[(u64, u64)],
+) -> Result<GuestMemoryMmap, Box<dyn std::error::Error>> {
+    let mut mem_regions = Vec::with_capacity(regions.len());
+    let mut file_offset = offset;
+
+    for &(guest_addr, size) in regions {
+        let mmap_region = MmapRegion::build(
+            Some(FileOffset::new(file.try_clone()?, file_offset)),
+            size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_NORESERVE | libc::MAP_PRIVATE,
+        )?;
+        let mem_region = GuestRegionMmap::new(mmap_region, GuestAddress(guest_addr))
+            .ok_or("failed to create guest region")?;
+        mem_regions.push(mem_region);
+        file_offset += size;
+    }
+
+    GuestMemoryMmap::from_regions(mem_regions)
         .map_err(|e| format!("failed to create guest memory: {}", e).into())
 }
 
 pub fn initrd_load_addr(_kernel_end: u64) -> u64 {
     INITRD_LOAD_ADDR
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: u64 = 1 << 30;
+
+    #[test]
+    fn small_guest_uses_one_range() {
+        assert_eq!(guest_memory_ranges(2 * GB), vec![(0, 2 * GB)]);
+    }
+
+    #[test]
+    fn large_guest_continues_above_mmio_hole() {
+        assert_eq!(
+            guest_memory_ranges(8 * GB),
+            vec![(0, 3 * GB), (MMIO_HOLE_END, 5 * GB)]
+        );
+    }
+
+    #[test]
+    fn max_memory_fits_39_bit_address_space() {
+        let ranges = guest_memory_ranges(MAX_MEMORY_MB as u64 * 1024 * 1024);
+        let (addr, size) = ranges.last().unwrap();
+        assert_eq!(addr + size, 1 << 39);
+    }
+}
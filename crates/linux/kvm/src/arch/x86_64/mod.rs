@@ -1,11 +1,29 @@
+mod acpi;
 mod boot_params;
+mod cpuid;
+mod debug;
+mod i8042;
+mod ib700;
 mod memory;
 mod mptable;
+mod pvpanic;
 mod serial;
+mod snapshot;
+mod topology;
 mod vcpu;
+mod vmcore;
 
+pub use acpi::*;
 pub use boot_params::*;
+pub use cpuid::*;
+pub use debug::*;
+pub use i8042::*;
+pub use ib700::*;
 pub use memory::*;
 pub use mptable::*;
+pub use pvpanic::*;
 pub use serial::*;
+pub use snapshot::*;
+pub use topology::*;
 pub use vcpu::*;
+pub use vmcore::*;
@@ -10,13 +10,12 @@
 
 use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
 
+use super::topology::MAX_XAPIC_ID;
+
 /// MP Floating Pointer structure location in guest memory.
 /// Placed in the EBDA area, near the end of conventional memory.
 pub const MPTABLE_START: u64 = 0x9_fc00;
 
-/// Maximum number of legacy IRQs to configure in the MP table.
-const MAX_IRQ: u8 = 24;
-
 /// IOAPIC default physical base address.
 const IO_APIC_DEFAULT_PHYS_BASE: u32 = 0xfec0_0000;
 
@@ -161,19 +160,36 @@ fn compute_checksum<T>(data: &T) -> u8 {
 ///
 /// This is required for Linux to properly configure IOAPIC interrupt routing,
 /// which is needed for virtio-mmio devices to receive interrupts.
+///
+/// Processor entries only hold 8-bit APIC IDs, so vCPUs beyond the xAPIC
+/// range are left to the MADT.
+///
+/// Only the IOAPIC pins in `irqs` get an interrupt source entry, so the
+/// guest never sees pins that no device is wired to.
 pub fn setup_mptable(
     mem: &GuestMemoryMmap,
-    num_cpus: u8,
+    apic_ids: &[u32],
+    irqs: &[u32],
 ) -> Result<(), Box<dyn std::error::Error>> {
     let mut base = GuestAddress(MPTABLE_START);
-    let ioapic_id = num_cpus + 1;
+    let cpu_ids: Vec<u8> = apic_ids
+        .iter()
+        .filter(|&&id| id <= MAX_XAPIC_ID)
+        .map(|&id| id as u8)
+        .collect();
+    let num_cpus = cpu_ids.len();
+    let ioapic_id = cpu_ids
+        .iter()
+        .max()
+        .map_or(0, |&id| id.saturating_add(1))
+        .min(MAX_XAPIC_ID as u8);
 
     // Calculate total table size for checksum
     let config_table_size = std::mem::size_of::<MpcTable>()
-        + std::mem::size_of::<MpcCpu>() * num_cpus as usize
+        + std::mem::size_of::<MpcCpu>() * num_cpus
         + std::mem::size_of::<MpcBus>()
         + std::mem::size_of::<MpcIoapic>()
-        + std::mem::size_of::<MpcIntsrc>() * MAX_IRQ as usize
+        + std::mem::size_of::<MpcIntsrc>() * irqs.len()
         + std::mem::size_of::<MpcLintsrc>() * 2;
 
     // Write MP Floating Pointer structure
@@ -212,7 +228,7 @@ pub fn setup_mptable(
         oemcount: (num_cpus as u16)
             + 1  // bus
             + 1  // ioapic
-            + (MAX_IRQ as u16)  // interrupt sources
+            + (irqs.len() as u16)  // interrupt sources
             + 2, // local interrupt sources
         lapic: APIC_DEFAULT_PHYS_BASE,
         reserved: 0,
@@ -221,12 +237,12 @@ pub fn setup_mptable(
     base = base.unchecked_add(std::mem::size_of::<MpcTable>() as u64);
 
     // Write CPU entries
-    for cpu_id in 0..num_cpus {
+    for (i, &cpu_id) in cpu_ids.iter().enumerate() {
         let cpu = MpcCpu {
             type_: MP_PROCESSOR,
             apicid: cpu_id,
             apicver: APIC_VERSION,
-            cpuflag: CPU_ENABLED | if cpu_id == 0 { CPU_BOOTPROCESSOR } else { 0 },
+            cpuflag: CPU_ENABLED | if i == 0 { CPU_BOOTPROCESSOR } else { 0 },
             cpufeature: 0x600,  // CPU stepping
             featureflag: 0x201, // APIC + FPU
             reserved: [0; 2],
@@ -274,7 +290,8 @@ pub fn setup_mptable(
     base = base.unchecked_add(std::mem::size_of::<MpcIoapic>() as u64);
 
     // Write interrupt source entries for each IRQ
-    for irq in 0..MAX_IRQ {
+    for &irq in irqs {
+        let irq = irq as u8;
         let intsrc = MpcIntsrc {
             type_: MP_INTSRC,
             irqtype: MP_INT,
@@ -0,0 +1,114 @@
+//! pvpanic device, through which the guest kernel reports panics.
+//!
+//! The guest finds the device through the `QEMU0001` entry in the DSDT and
+//! writes the event to its single I/O port from a panic notifier, before
+//! `panic=-1` resets the machine.
+
+use capsa_core::ExitReason;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::mpsc;
+use vm_device::MutDevicePio;
+use vm_device::bus::{PioAddress, PioAddressOffset};
+
+use crate::crash::GuestCrash;
+
+/// I/O port of the pvpanic device, as on QEMU.
+pub const PVPANIC_PORT: u16 = 0x505;
+
+/// The guest kernel panicked.
+const PVPANIC_PANICKED: u8 = 1 << 0;
+/// The guest kernel panicked and is handing over to a kdump crash kernel.
+const PVPANIC_CRASH_LOADED: u8 = 1 << 1;
+
+/// Stops the VM with a crash report when the guest kernel panics.
+///
+/// Panics handled by a crash kernel are only logged, since the guest keeps
+/// running to write its crash dump.
+pub struct PvPanicDevice {
+    crash: Arc<GuestCrash>,
+    running: Arc<AtomicBool>,
+    exit_tx: mpsc::Sender<ExitReason>,
+}
+
+impl PvPanicDevice {
+    pub fn new(
+        crash: Arc<GuestCrash>,
+        running: Arc<AtomicBool>,
+        exit_tx: mpsc::Sender<ExitReason>,
+    ) -> Self {
+        Self {
+            crash,
+            running,
+            exit_tx,
+        }
+    }
+
+    fn handle_event(&self, event: u8) {
+        if event & PVPANIC_PANICKED != 0 {
+            tracing::warn!("guest kernel panicked");
+            self.crash.set_panicked();
+            let _ = self.exit_tx.try_send(ExitReason::Panic);
+            self.running.store(false, Ordering::Relaxed);
+        } else if event & PVPANIC_CRASH_LOADED != 0 {
+            tracing::warn!("guest kernel panicked, running its crash kernel");
+        }
+    }
+}
+
+impl MutDevicePio for PvPanicDevice {
+    fn pio_read(&mut self, _base: PioAddress, _offset: PioAddressOffset, data: &mut [u8]) {
+        // Reading the port tells the guest which events the device handles
+        data.fill(0);
+        if let Some(byte) = data.first_mut() {
+            *byte = PVPANIC_PANICKED | PVPANIC_CRASH_LOADED;
+        }
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, _offset: PioAddressOffset, data: &[u8]) {
+        if let Some(&event) = data.first() {
+            self.handle_event(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_test_device() -> (PvPanicDevice, Arc<GuestCrash>, mpsc::Receiver<ExitReason>) {
+        let crash = Arc::new(GuestCrash::default());
+        let running = Arc::new(AtomicBool::new(true));
+        let (exit_tx, exit_rx) = mpsc::channel(1);
+        let device = PvPanicDevice::new(crash.clone(), running, exit_tx);
+        (device, crash, exit_rx)
+    }
+
+    #[test]
+    fn advertises_supported_events() {
+        let (mut device, _, _) = create_test_device();
+        let mut data = [0xff];
+        device.pio_read(PioAddress(PVPANIC_PORT), 0, &mut data);
+        assert_eq!(data[0], PVPANIC_PANICKED | PVPANIC_CRASH_LOADED);
+    }
+
+    #[test]
+    fn panic_stops_the_vm() {
+        let (mut device, crash, mut exit_rx) = create_test_device();
+        device.pio_write(PioAddress(PVPANIC_PORT), 0, &[PVPANIC_PANICKED]);
+
+        assert!(!device.running.load(Ordering::Relaxed));
+        assert_eq!(exit_rx.try_recv().unwrap(), ExitReason::Panic);
+        assert!(crash.report().is_some());
+    }
+
+    #[test]
+    fn crash_kernel_keeps_running() {
+        let (mut device, crash, mut exit_rx) = create_test_device();
+        device.pio_write(PioAddress(PVPANIC_PORT), 0, &[PVPANIC_CRASH_LOADED]);
+
+        assert!(device.running.load(Ordering::Relaxed));
+        assert!(exit_rx.try_recv().is_err());
+        assert!(crash.report().is_none());
+    }
+}
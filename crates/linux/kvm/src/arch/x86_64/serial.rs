@@ -2,15 +2,11 @@ pub const SERIAL_PORT_BASE: u16 = 0x3f8;
 pub const SERIAL_PORT_END: u16 = 0x3ff;
 pub const SERIAL_IRQ: u32 = 4;
 
+/// Virtio-mmio devices get consecutive `VIRTIO_MMIO_SIZE` slots from here
+/// up to the PCI window, and their IRQs from the free IOAPIC pins.
 pub const VIRTIO_MMIO_BASE: u64 = 0xd000_0000;
 pub const VIRTIO_MMIO_SIZE: u64 = 0x200;
-pub const VIRTIO_CONSOLE_IRQ: u32 = 5;
 
-pub const VIRTIO_NET_MMIO_BASE: u64 = 0xd000_0200;
-pub const VIRTIO_NET_IRQ: u32 = 6;
-
-pub const VIRTIO_VSOCK_MMIO_BASE: u64 = 0xd000_0400;
-pub const VIRTIO_VSOCK_IRQ: u32 = 7;
-
-pub const VIRTIO_FS_MMIO_BASE: u64 = 0xd000_0600;
-pub const VIRTIO_FS_IRQ: u32 = 8;
+/// ACPI system control interrupt, raised for power button presses. Uses the
+/// last IOAPIC pin so it stays clear of the virtio-mmio device IRQs.
+pub const ACPI_SCI_IRQ: u32 = 23;
@@ -0,0 +1,245 @@
+//! Save and restore of x86_64 vCPU and in-kernel device state.
+//!
+//! The state structs wrap the raw KVM structures, which are serialized
+//! byte-for-byte. Snapshots are therefore only portable between hosts with
+//! compatible CPUs.
+
+use kvm_bindings::{
+    KVM_IRQCHIP_IOAPIC, KVM_IRQCHIP_PIC_MASTER, KVM_IRQCHIP_PIC_SLAVE, KVM_MAX_MSR_ENTRIES, Msrs,
+    kvm_clock_data, kvm_debugregs, kvm_irqchip, kvm_lapic_state, kvm_mp_state, kvm_msr_entry,
+    kvm_pit_state2, kvm_regs, kvm_sregs, kvm_vcpu_events, kvm_xcrs, kvm_xsave,
+};
+use kvm_ioctls::{Kvm, VcpuFd, VmFd};
+use nix::libc;
+use serde::{Deserialize, Serialize};
+
+/// Architectural state of a single vCPU.
+#[derive(Serialize, Deserialize)]
+pub struct VcpuState {
+    tsc_khz: Option<u32>,
+    mp_state: kvm_mp_state,
+    regs: kvm_regs,
+    sregs: kvm_sregs,
+    xsave: kvm_xsave,
+    xcrs: kvm_xcrs,
+    debug_regs: kvm_debugregs,
+    lapic: kvm_lapic_state,
+    msrs: Vec<kvm_msr_entry>,
+    events: kvm_vcpu_events,
+}
+
+/// State of the in-kernel PIC, IOAPIC, PIT and KVM clock.
+#[derive(Serialize, Deserialize)]
+pub struct VmArchState {
+    pic_master: kvm_irqchip,
+    pic_slave: kvm_irqchip,
+    ioapic: kvm_irqchip,
+    pit: kvm_pit_state2,
+    clock: kvm_clock_data,
+}
+
+/// Returns the MSRs that KVM can save and restore on this host.
+pub fn snapshot_msr_indices(kvm: &Kvm) -> Result<Vec<u32>, kvm_ioctls::Error> {
+    Ok(kvm.get_msr_index_list()?.as_slice().to_vec())
+}
+
+/// Captures the state of a vCPU that is not currently running.
+///
+/// Pending I/O must have been completed (see `KVM_RUN` with `immediate_exit`)
+/// before calling this, otherwise the result of the last exit is lost.
+pub fn save_vcpu_state(vcpu: &VcpuFd, msr_indices: &[u32]) -> Result<VcpuState, kvm_ioctls::Error> {
+    Ok(VcpuState {
+        tsc_khz: vcpu.get_tsc_khz().ok(),
+        mp_state: vcpu.get_mp_state()?,
+        regs: vcpu.get_regs()?,
+        sregs: vcpu.get_sregs()?,
+        xsave: vcpu.get_xsave()?,
+        xcrs: vcpu.get_xcrs()?,
+        debug_regs: vcpu.get_debug_regs()?,
+        lapic: vcpu.get_lapic()?,
+        msrs: save_msrs(vcpu, msr_indices)?,
+        events: vcpu.get_vcpu_events()?,
+    })
+}
+
+/// Restores vCPU state captured by [`save_vcpu_state`].
+///
+/// The vCPU must already have its CPUID configured via `init_vcpu`.
+pub fn restore_vcpu_state(vcpu: &VcpuFd, state: &VcpuState) -> Result<(), kvm_ioctls::Error> {
+    if let Some(khz) = state.tsc_khz
+        && let Err(e) = vcpu.set_tsc_khz(khz)
+    {
+        // Without TSC scaling the guest sees the host frequency, which the
+        // kvmclock compensates for
+        tracing::warn!("failed to restore TSC frequency of {} kHz: {}", khz, e);
+    }
+
+    vcpu.set_mp_state(state.mp_state)?;
+    vcpu.set_regs(&state.regs)?;
+    vcpu.set_sregs(&state.sregs)?;
+    vcpu.set_xsave(&state.xsave)?;
+    vcpu.set_xcrs(&state.xcrs)?;
+    vcpu.set_debug_regs(&state.debug_regs)?;
+    // The LAPIC must follow sregs, which carry the APIC base
+    vcpu.set_lapic(&state.lapic)?;
+
+    for chunk in state.msrs.chunks(KVM_MAX_MSR_ENTRIES) {
+        let msrs = Msrs::from_entries(chunk).map_err(|_| kvm_ioctls::Error::new(libc::EINVAL))?;
+        let written = vcpu.set_msrs(&msrs)?;
+        if written != chunk.len() {
+            tracing::error!("failed to restore MSR 0x{:x}", chunk[written].index);
+            return Err(kvm_ioctls::Error::new(libc::EINVAL));
+        }
+    }
+
+    // Events go last so pending exceptions and interrupts are not clobbered
+    vcpu.set_vcpu_events(&state.events)?;
+    Ok(())
+}
+
+fn save_msrs(vcpu: &VcpuFd, indices: &[u32]) -> Result<Vec<kvm_msr_entry>, kvm_ioctls::Error> {
+    let mut saved = Vec::with_capacity(indices.len());
+    let mut remaining = indices;
+
+    while !remaining.is_empty() {
+        let chunk = &remaining[..remaining.len().min(KVM_MAX_MSR_ENTRIES)];
+        let entries: Vec<kvm_msr_entry> = chunk
+            .iter()
+            .map(|&index| kvm_msr_entry {
+                index,
+                ..Default::default()
+            })
+            .collect();
+        let mut msrs =
+            Msrs::from_entries(&entries).map_err(|_| kvm_ioctls::Error::new(libc::EINVAL))?;
+
+        let read = vcpu.get_msrs(&mut msrs)?;
+        saved.extend_from_slice(&msrs.as_slice()[..read]);
+
+        // KVM stops at the first MSR it cannot read, so skip it and go on
+        remaining = &remaining[(read + 1).min(chunk.len())..];
+    }
+
+    Ok(saved)
+}
+
+/// Captures the in-kernel interrupt controller, PIT and clock state.
+pub fn save_vm_state(vm_fd: &VmFd) -> Result<VmArchState, kvm_ioctls::Error> {
+    Ok(VmArchState {
+        pic_master: get_irqchip(vm_fd, KVM_IRQCHIP_PIC_MASTER)?,
+        pic_slave: get_irqchip(vm_fd, KVM_IRQCHIP_PIC_SLAVE)?,
+        ioapic: get_irqchip(vm_fd, KVM_IRQCHIP_IOAPIC)?,
+        pit: vm_fd.get_pit2()?,
+        clock: vm_fd.get_clock()?,
+    })
+}
+
+/// Restores state captured by [`save_vm_state`].
+///
+/// The IRQ chip and PIT must already have been created.
+pub fn restore_vm_state(vm_fd: &VmFd, state: &VmArchState) -> Result<(), kvm_ioctls::Error> {
+    restore_vm_devices(vm_fd, state)?;
+
+    // KVM_SET_CLOCK rejects the informational flags reported by KVM_GET_CLOCK
+    let clock = kvm_clock_data {
+        clock: state.clock.clock,
+        ..Default::default()
+    };
+    vm_fd.set_clock(&clock)?;
+    Ok(())
+}
+
+/// Restores the interrupt controllers and PIT from `state`, leaving the
+/// clock running.
+pub fn restore_vm_devices(vm_fd: &VmFd, state: &VmArchState) -> Result<(), kvm_ioctls::Error> {
+    vm_fd.set_irqchip(&state.pic_master)?;
+    vm_fd.set_irqchip(&state.pic_slave)?;
+    vm_fd.set_irqchip(&state.ioapic)?;
+    vm_fd.set_pit2(&state.pit)?;
+    Ok(())
+}
+
+fn get_irqchip(vm_fd: &VmFd, chip_id: u32) -> Result<kvm_irqchip, kvm_ioctls::Error> {
+    let mut irqchip = kvm_irqchip {
+        chip_id,
+        ..Default::default()
+    };
+    vm_fd.get_irqchip(&mut irqchip)?;
+    Ok(irqchip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::{Topology, init_vcpu};
+    use kvm_bindings::{KVM_MAX_CPUID_ENTRIES, kvm_pit_config};
+
+    fn create_vm(kvm: &Kvm) -> VmFd {
+        let vm_fd = kvm.create_vm().unwrap();
+        vm_fd.create_irq_chip().unwrap();
+        vm_fd.create_pit2(kvm_pit_config::default()).unwrap();
+        vm_fd
+    }
+
+    fn init_test_vcpu(kvm: &Kvm, vcpu: &VcpuFd) {
+        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        init_vcpu(vcpu, &cpuid, &Topology::new(1, None), 0).unwrap();
+    }
+
+    #[test]
+    fn vcpu_state_roundtrip() {
+        let kvm = Kvm::new().unwrap();
+        let msr_indices = snapshot_msr_indices(&kvm).unwrap();
+
+        let source_vm = create_vm(&kvm);
+        let source = source_vm.create_vcpu(0).unwrap();
+        init_test_vcpu(&kvm, &source);
+        let mut regs = source.get_regs().unwrap();
+        regs.rax = 0x1234_5678;
+        regs.rip = 0x10_0000;
+        source.set_regs(&regs).unwrap();
+
+        let state = save_vcpu_state(&source, &msr_indices).unwrap();
+        assert!(!state.msrs.is_empty());
+
+        let target_vm = create_vm(&kvm);
+        let target = target_vm.create_vcpu(0).unwrap();
+        init_test_vcpu(&kvm, &target);
+        restore_vcpu_state(&target, &state).unwrap();
+
+        let restored = target.get_regs().unwrap();
+        assert_eq!(restored.rax, 0x1234_5678);
+        assert_eq!(restored.rip, 0x10_0000);
+        assert_eq!(target.get_sregs().unwrap().cr0, state.sregs.cr0);
+    }
+
+    #[test]
+    fn vm_state_roundtrip() {
+        let kvm = Kvm::new().unwrap();
+
+        let source = create_vm(&kvm);
+        let state = save_vm_state(&source).unwrap();
+        assert_eq!(state.ioapic.chip_id, KVM_IRQCHIP_IOAPIC);
+
+        let target = create_vm(&kvm);
+        restore_vm_state(&target, &state).unwrap();
+
+        let restored_clock = target.get_clock().unwrap();
+        assert!(restored_clock.clock >= state.clock.clock);
+    }
+
+    #[test]
+    fn vcpu_state_serializes() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = create_vm(&kvm);
+        let vcpu = vm_fd.create_vcpu(0).unwrap();
+        init_test_vcpu(&kvm, &vcpu);
+
+        let state = save_vcpu_state(&vcpu, &snapshot_msr_indices(&kvm).unwrap()).unwrap();
+        let bytes = bincode::serialize(&state).unwrap();
+        let decoded: VcpuState = bincode::deserialize(&bytes).unwrap();
+
+        assert_eq!(decoded.regs.rip, state.regs.rip);
+        assert_eq!(decoded.msrs.len(), state.msrs.len());
+    }
+}
@@ -0,0 +1,242 @@
+//! vCPU topology reported to the guest.
+//!
+//! Each vCPU's APIC ID packs its socket, core and thread numbers into bit
+//! fields just wide enough for the topology, as on physical x86 systems. The
+//! guest recovers the layout from the field widths in CPUID leaves 0xB and
+//! 0x1F, so the same IDs have to be used for the vCPU IDs, the MP table and
+//! the MADT.
+
+use capsa_core::CpuTopology;
+use kvm_bindings::{CpuId, KVM_CPUID_FLAG_SIGNIFCANT_INDEX, kvm_cpuid_entry2};
+
+/// Highest APIC ID that fits the 8-bit xAPIC ID field; 0xff is the broadcast
+/// address. vCPUs above it can only be reached in x2APIC mode.
+pub const MAX_XAPIC_ID: u32 = 0xfe;
+
+const CPUID_FEATURES: u32 = 0x1;
+const CPUID_CACHE_PARAMS: u32 = 0x4;
+const CPUID_EXT_TOPOLOGY: u32 = 0xb;
+const CPUID_V2_EXT_TOPOLOGY: u32 = 0x1f;
+
+/// CPUID.1:EDX bit advertising more than one logical processor per package.
+const CPUID_1_EDX_HTT: u32 = 1 << 28;
+
+/// Level types in CPUID leaves 0xB and 0x1F.
+const LEVEL_TYPE_SMT: u32 = 1;
+const LEVEL_TYPE_CORE: u32 = 2;
+
+/// Cache level field of CPUID leaf 0x4.
+const CACHE_LEVEL_SHIFT: u32 = 5;
+const CACHE_LEVEL_MASK: u32 = 0x7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Topology {
+    sockets: u32,
+    cores_per_socket: u32,
+    threads_per_core: u32,
+}
+
+impl Topology {
+    /// Returns the layout of `cpus` vCPUs, one core per vCPU in a single
+    /// socket unless `topology` says otherwise.
+    pub fn new(cpus: u32, topology: Option<&CpuTopology>) -> Self {
+        match topology {
+            Some(t) => Self {
+                sockets: t.sockets,
+                cores_per_socket: t.cores_per_socket,
+                threads_per_core: t.threads_per_core,
+            },
+            None => Self {
+                sockets: 1,
+                cores_per_socket: cpus,
+                threads_per_core: 1,
+            },
+        }
+    }
+
+    pub fn cpus(&self) -> u32 {
+        self.sockets * self.cores_per_socket * self.threads_per_core
+    }
+
+    fn thread_bits(&self) -> u32 {
+        bits_for(self.threads_per_core)
+    }
+
+    fn core_bits(&self) -> u32 {
+        bits_for(self.cores_per_socket)
+    }
+
+    /// Width of the APIC ID field below the socket number.
+    fn package_bits(&self) -> u32 {
+        self.thread_bits() + self.core_bits()
+    }
+
+    /// Returns the APIC ID of the vCPU at `index`, counting threads first.
+    pub fn apic_id(&self, index: u32) -> u32 {
+        let thread = index % self.threads_per_core;
+        let core = (index / self.threads_per_core) % self.cores_per_socket;
+        let socket = index / (self.threads_per_core * self.cores_per_socket);
+        (socket << self.package_bits()) | (core << self.thread_bits()) | thread
+    }
+
+    /// Returns the APIC IDs of all vCPUs, boot processor first.
+    pub fn apic_ids(&self) -> Vec<u32> {
+        (0..self.cpus()).map(|i| self.apic_id(i)).collect()
+    }
+
+    /// Whether some vCPU has an APIC ID only x2APIC can address.
+    pub fn needs_x2apic(&self) -> bool {
+        self.apic_id(self.cpus() - 1) > MAX_XAPIC_ID
+    }
+
+    /// Rewrites the topology leaves of `cpuid` for the vCPU with `apic_id`.
+    ///
+    /// Leaves KVM does not report are left out, so the guest does not see
+    /// topology leaves the host CPU lacks.
+    pub fn apply_to_cpuid(&self, cpuid: &CpuId, apic_id: u32) -> CpuId {
+        let max_leaf = cpuid
+            .as_slice()
+            .iter()
+            .find(|e| e.function == 0)
+            .map_or(0, |e| e.eax);
+        let logical_per_package = 1u32 << self.package_bits();
+
+        let mut entries: Vec<kvm_cpuid_entry2> = cpuid
+            .as_slice()
+            .iter()
+            .filter(|e| e.function != CPUID_EXT_TOPOLOGY && e.function != CPUID_V2_EXT_TOPOLOGY)
+            .copied()
+            .collect();
+
+        for entry in &mut entries {
+            match entry.function {
+                CPUID_FEATURES => {
+                    entry.ebx = (entry.ebx & 0xffff)
+                        | (logical_per_package.min(0xff) << 16)
+                        | ((apic_id & 0xff) << 24);
+                    if logical_per_package > 1 {
+                        entry.edx |= CPUID_1_EDX_HTT;
+                    } else {
+                        entry.edx &= !CPUID_1_EDX_HTT;
+                    }
+                }
+                CPUID_CACHE_PARAMS => {
+                    let level = (entry.eax >> CACHE_LEVEL_SHIFT) & CACHE_LEVEL_MASK;
+                    if level == 0 {
+                        continue;
+                    }
+                    // L1 and L2 are per core, the last level is per socket
+                    let sharing_bits = if level >= 3 {
+                        self.package_bits()
+                    } else {
+                        self.thread_bits()
+                    };
+                    entry.eax = (entry.eax & 0x3fff)
+                        | ((((1 << sharing_bits) - 1) & 0xfff) << 14)
+                        | ((((1 << self.core_bits()) - 1) & 0x3f) << 26);
+                }
+                _ => {}
+            }
+        }
+
+        for leaf in [CPUID_EXT_TOPOLOGY, CPUID_V2_EXT_TOPOLOGY] {
+            if max_leaf >= leaf {
+                entries.extend(self.topology_leaf(leaf, apic_id));
+            }
+        }
+
+        CpuId::from_entries(&entries).expect("topology leaves fit in the CPUID buffer")
+    }
+
+    /// Builds the SMT, core and terminating subleaves of leaf 0xB or 0x1F.
+    fn topology_leaf(&self, function: u32, apic_id: u32) -> [kvm_cpuid_entry2; 3] {
+        let subleaf = |index: u32, shift: u32, count: u32, level_type: u32| kvm_cpuid_entry2 {
+            function,
+            index,
+            flags: KVM_CPUID_FLAG_SIGNIFCANT_INDEX,
+            eax: shift,
+            ebx: count & 0xffff,
+            ecx: (level_type << 8) | index,
+            edx: apic_id,
+            ..Default::default()
+        };
+        [
+            subleaf(0, self.thread_bits(), self.threads_per_core, LEVEL_TYPE_SMT),
+            subleaf(
+                1,
+                self.package_bits(),
+                self.threads_per_core * self.cores_per_socket,
+                LEVEL_TYPE_CORE,
+            ),
+            subleaf(2, 0, 0, 0),
+        ]
+    }
+}
+
+/// Number of bits needed to number `count` items.
+fn bits_for(count: u32) -> u32 {
+    count.next_power_of_two().trailing_zeros()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_ioctls::Kvm;
+
+    fn leaf(cpuid: &CpuId, function: u32, index: u32) -> kvm_cpuid_entry2 {
+        *cpuid
+            .as_slice()
+            .iter()
+            .find(|e| e.function == function && e.index == index)
+            .expect("leaf not found")
+    }
+
+    #[test]
+    fn default_topology_numbers_cpus_contiguously() {
+        let topology = Topology::new(4, None);
+        assert_eq!(topology.apic_ids(), vec![0, 1, 2, 3]);
+        assert!(!topology.needs_x2apic());
+    }
+
+    #[test]
+    fn apic_ids_leave_gaps_for_odd_levels() {
+        // Three cores take two bits, so the second socket starts at 8
+        let topology = Topology::new(12, Some(&CpuTopology::new(2, 3, 2)));
+        assert_eq!(
+            topology.apic_ids(),
+            vec![0, 1, 2, 3, 4, 5, 8, 9, 10, 11, 12, 13]
+        );
+    }
+
+    #[test]
+    fn large_guests_need_x2apic() {
+        assert!(!Topology::new(255, None).needs_x2apic());
+        assert!(Topology::new(256, None).needs_x2apic());
+        assert!(Topology::new(8, Some(&CpuTopology::new(2, 129, 1))).needs_x2apic());
+    }
+
+    #[test]
+    fn cpuid_reports_topology() {
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let supported = kvm
+            .get_supported_cpuid(kvm_bindings::KVM_MAX_CPUID_ENTRIES)
+            .unwrap();
+        let topology = Topology::new(8, Some(&CpuTopology::new(2, 2, 2)));
+        let cpuid = topology.apply_to_cpuid(&supported, 6);
+
+        let features = leaf(&cpuid, CPUID_FEATURES, 0);
+        assert_eq!(features.ebx >> 24, 6);
+        assert_eq!((features.ebx >> 16) & 0xff, 4);
+        assert_ne!(features.edx & CPUID_1_EDX_HTT, 0);
+
+        let smt = leaf(&cpuid, CPUID_EXT_TOPOLOGY, 0);
+        assert_eq!(smt.eax, 1);
+        assert_eq!(smt.ebx, 2);
+        assert_eq!(smt.edx, 6);
+        let core = leaf(&cpuid, CPUID_EXT_TOPOLOGY, 1);
+        assert_eq!(core.eax, 2);
+        assert_eq!(core.ebx, 4);
+        assert_eq!(core.ecx >> 8, LEVEL_TYPE_CORE);
+        assert_eq!(leaf(&cpuid, CPUID_EXT_TOPOLOGY, 2).ecx >> 8, 0);
+    }
+}
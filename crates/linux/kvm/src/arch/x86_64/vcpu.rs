@@ -1,15 +1,23 @@
-use kvm_bindings::{KVM_MAX_CPUID_ENTRIES, kvm_regs, kvm_segment};
-use kvm_ioctls::{Kvm, VcpuExit, VcpuFd};
+use capsa_core::ExitReason;
+use kvm_bindings::{
+    CpuId, KVM_SYSTEM_EVENT_CRASH, KVM_SYSTEM_EVENT_RESET, KVM_SYSTEM_EVENT_SHUTDOWN, kvm_regs,
+    kvm_segment,
+};
+use kvm_ioctls::{VcpuExit, VcpuFd};
 use nix::libc;
 use nix::sys::signal::{SigSet, SigmaskHow, Signal, pthread_sigmask};
-use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
 use tokio::sync::mpsc;
 use vm_device::MutDevicePio;
 use vm_device::bus::{MmioAddress, PioAddress, PioAddressOffset};
 use vm_device::device_manager::{IoManager, MmioManager, PioManager};
 
 use super::memory::{BOOT_GDT_OFFSET, BOOT_IDT_OFFSET, BOOT_STACK_POINTER, PML4_START};
+use super::topology::Topology;
+use crate::gdb::VcpuDebug;
+use crate::pause::PauseControl;
+use crate::reboot::RebootControl;
 
 pub const RTC_INDEX_PORT: u16 = 0x70;
 
@@ -180,9 +188,15 @@ impl MutDevicePio for RtcDevice {
     }
 }
 
-pub fn init_vcpu(vcpu: &VcpuFd, kvm: &Kvm) -> Result<(), kvm_ioctls::Error> {
-    let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)?;
-    vcpu.set_cpuid2(&cpuid)?;
+/// Sets the CPUID of the vCPU with `apic_id` to `cpuid`, with the topology
+/// leaves describing its place in `topology`.
+pub fn init_vcpu(
+    vcpu: &VcpuFd,
+    cpuid: &CpuId,
+    topology: &Topology,
+    apic_id: u32,
+) -> Result<(), kvm_ioctls::Error> {
+    vcpu.set_cpuid2(&topology.apply_to_cpuid(cpuid, apic_id))?;
     Ok(())
 }
 
@@ -260,23 +274,40 @@ pub fn setup_sregs(vcpu: &VcpuFd, _memory_size: u64) -> Result<(), kvm_ioctls::E
 /// This function handles the main vCPU execution loop, processing I/O exits
 /// and signaling termination via the `exit_tx` channel.
 ///
-/// # Exit Codes
+/// # Exit Reasons
 ///
-/// - `0`: Normal exit (HLT or Shutdown)
-/// - `1`: Error during vCPU execution
-/// - `-1`: Killed by external signal (running flag set to false)
+/// - [`ExitReason::Poweroff`]: HLT without an in-kernel LAPIC, or a KVM
+///   shutdown event
+/// - [`ExitReason::TripleFault`]: the vCPU shut down, unless the VM reboots
+///   in place (see `reboot`)
+/// - [`ExitReason::InternalError`]: KVM could not run the vCPU
+/// - [`ExitReason::Killed`]: the running flag was cleared by the host
 ///
 /// # Multi-CPU Behavior
 ///
 /// When running multiple vCPUs, each runs this loop independently. The exit
-/// channel has capacity 1, so only the first vCPU to exit will have its code
-/// recorded. Other vCPUs will detect the `running` flag is false and exit
-/// with code `-1`.
+/// channel has capacity 1, so only the first vCPU to exit will have its
+/// reason recorded. Other vCPUs will detect the `running` flag is false and
+/// exit with [`ExitReason::Killed`].
+///
+/// # Pausing
+///
+/// The vCPU fd is only locked while the vCPU runs. When `pause` is requested,
+/// the thread completes any in-flight I/O and parks without holding the lock,
+/// so its state can be read or written from another thread.
+///
+/// # Debugging
+///
+/// With a GDB stub attached, breakpoints and single steps exit to `debug`,
+/// which stops the guest by pausing it.
 pub fn run_vcpu(
-    mut vcpu: VcpuFd,
+    vcpu: Arc<Mutex<VcpuFd>>,
     io_manager: Arc<IoManager>,
     running: Arc<AtomicBool>,
-    exit_tx: mpsc::Sender<i32>,
+    pause: Arc<PauseControl>,
+    reboot: Arc<RebootControl>,
+    debug: Option<VcpuDebug>,
+    exit_tx: mpsc::Sender<ExitReason>,
 ) {
     // Unblock SIGUSR1 to allow graceful vCPU shutdown.
     // SIGUSR1 is sent by KvmVmHandle::kill() to interrupt blocking KVM_RUN calls.
@@ -293,13 +324,51 @@ pub fn run_vcpu(
 
     loop {
         if !running.load(Ordering::Relaxed) {
-            let _ = exit_tx.try_send(-1);
+            let _ = exit_tx.try_send(ExitReason::Killed);
             break;
         }
 
+        if pause.is_requested() {
+            complete_pending_io(&mut vcpu.lock().unwrap());
+            pause.park();
+            continue;
+        }
+
+        let mut vcpu = vcpu.lock().unwrap();
         match vcpu.run() {
-            Ok(VcpuExit::Hlt) | Ok(VcpuExit::Shutdown) => {
-                let _ = exit_tx.try_send(0);
+            Ok(VcpuExit::Hlt) => {
+                let _ = exit_tx.try_send(ExitReason::Poweroff);
+                break;
+            }
+            Ok(VcpuExit::Shutdown) => {
+                tracing::debug!("vCPU triple faulted");
+                reboot.reset(ExitReason::TripleFault);
+            }
+            Ok(VcpuExit::SystemEvent(event, _)) => match event {
+                KVM_SYSTEM_EVENT_SHUTDOWN => {
+                    let _ = exit_tx.try_send(ExitReason::Poweroff);
+                    break;
+                }
+                KVM_SYSTEM_EVENT_RESET => reboot.reset(ExitReason::Reboot),
+                KVM_SYSTEM_EVENT_CRASH => {
+                    let _ = exit_tx.try_send(ExitReason::Panic);
+                    break;
+                }
+                _ => tracing::debug!("ignoring KVM system event {}", event),
+            },
+            Ok(VcpuExit::FailEntry(reason, cpu)) => {
+                let message = format!(
+                    "vCPU entry failed on host CPU {} with hardware reason 0x{:x}",
+                    cpu, reason
+                );
+                tracing::error!("{}", message);
+                let _ = exit_tx.try_send(ExitReason::InternalError { message });
+                break;
+            }
+            Ok(VcpuExit::InternalError) => {
+                let message = internal_error_message(&mut vcpu);
+                tracing::error!("{}", message);
+                let _ = exit_tx.try_send(ExitReason::InternalError { message });
                 break;
             }
             Ok(VcpuExit::IoIn(port, data)) => {
@@ -326,13 +395,20 @@ pub fn run_vcpu(
                     tracing::trace!("unhandled MMIO write to 0x{:08x}: {:?}", addr, e);
                 }
             }
+            Ok(VcpuExit::Debug(exit)) => {
+                if let Some(debug) = &debug {
+                    debug.stopped(&vcpu, &exit);
+                }
+            }
             Ok(_) => {}
             Err(e) => {
                 if e.errno() == libc::EAGAIN || e.errno() == libc::EINTR {
                     continue;
                 }
                 tracing::error!("vcpu run error: {}", e);
-                let _ = exit_tx.try_send(1);
+                let _ = exit_tx.try_send(ExitReason::InternalError {
+                    message: format!("failed to run vCPU: {}", e),
+                });
                 break;
             }
         }
@@ -340,6 +416,29 @@ pub fn run_vcpu(
     running.store(false, Ordering::Relaxed);
 }
 
+/// Describes a `KVM_EXIT_INTERNAL_ERROR`, whose details KVM only reports in
+/// the shared `kvm_run` area.
+fn internal_error_message(vcpu: &mut VcpuFd) -> String {
+    let internal = unsafe { vcpu.get_kvm_run().__bindgen_anon_1.internal };
+    let ndata = (internal.ndata as usize).min(internal.data.len());
+    format!(
+        "KVM internal error, suberror {}, data {:x?}",
+        internal.suberror,
+        &internal.data[..ndata]
+    )
+}
+
+/// Lets KVM finish the instruction behind the last PIO/MMIO exit.
+///
+/// Until `KVM_RUN` is re-entered, the result of an I/O read is not yet in the
+/// guest registers, so saving the vCPU state before that would lose it.
+fn complete_pending_io(vcpu: &mut VcpuFd) {
+    vcpu.set_kvm_immediate_exit(1);
+    // Returns EINTR as soon as pending operations have completed
+    let _ = vcpu.run();
+    vcpu.set_kvm_immediate_exit(0);
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
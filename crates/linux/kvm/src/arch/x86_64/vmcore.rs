@@ -0,0 +1,118 @@
+//! x86_64 parts of guest memory dumps: the ELF machine type and the vCPU
+//! registers in `NT_PRSTATUS` notes.
+
+use kvm_bindings::{kvm_regs, kvm_sregs};
+use kvm_ioctls::VcpuFd;
+
+/// `EM_X86_64`
+pub const ELF_MACHINE: u16 = 62;
+
+/// Size of the kernel's `struct elf_prstatus` on x86_64.
+const PRSTATUS_SIZE: usize = 336;
+
+/// Offsets of `pr_pid` and `pr_reg` in `struct elf_prstatus`.
+const PRSTATUS_PID_OFFSET: usize = 32;
+const PRSTATUS_REGS_OFFSET: usize = 112;
+
+/// Reads the registers of a vCPU as the descriptor of an `NT_PRSTATUS` note.
+///
+/// vCPUs appear as processes numbered from 1, the way QEMU dumps them.
+pub fn read_prstatus(vcpu: &VcpuFd, index: usize) -> Result<Vec<u8>, kvm_ioctls::Error> {
+    let regs = vcpu.get_regs()?;
+    let sregs = vcpu.get_sregs()?;
+    Ok(encode_prstatus(&regs, &sregs, index as u32 + 1))
+}
+
+fn encode_prstatus(regs: &kvm_regs, sregs: &kvm_sregs, pid: u32) -> Vec<u8> {
+    let mut prstatus = vec![0u8; PRSTATUS_SIZE];
+    prstatus[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4].copy_from_slice(&pid.to_le_bytes());
+
+    // Layout of `struct user_regs_struct`; there is no interrupted system
+    // call, so `orig_rax` stays zero
+    let user_regs = [
+        regs.r15,
+        regs.r14,
+        regs.r13,
+        regs.r12,
+        regs.rbp,
+        regs.rbx,
+        regs.r11,
+        regs.r10,
+        regs.r9,
+        regs.r8,
+        regs.rax,
+        regs.rcx,
+        regs.rdx,
+        regs.rsi,
+        regs.rdi,
+        0,
+        regs.rip,
+        u64::from(sregs.cs.selector),
+        regs.rflags,
+        regs.rsp,
+        u64::from(sregs.ss.selector),
+        sregs.fs.base,
+        sregs.gs.base,
+        u64::from(sregs.ds.selector),
+        u64::from(sregs.es.selector),
+        u64::from(sregs.fs.selector),
+        u64::from(sregs.gs.selector),
+    ];
+    for (index, value) in user_regs.iter().enumerate() {
+        let offset = PRSTATUS_REGS_OFFSET + index * 8;
+        prstatus[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    prstatus
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_bindings::kvm_segment;
+
+    #[test]
+    fn encodes_prstatus_layout() {
+        let regs = kvm_regs {
+            r15: 15,
+            rax: 0xa,
+            rip: 0xffffffff81000000,
+            rflags: 0x246,
+            rsp: 0xffffc90000003f00,
+            ..Default::default()
+        };
+        let sregs = kvm_sregs {
+            cs: kvm_segment {
+                selector: 0x10,
+                ..Default::default()
+            },
+            gs: kvm_segment {
+                selector: 0x18,
+                base: 0xffff888007a00000,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let prstatus = encode_prstatus(&regs, &sregs, 2);
+        let reg = |index: usize| {
+            let offset = PRSTATUS_REGS_OFFSET + index * 8;
+            u64::from_le_bytes(prstatus[offset..offset + 8].try_into().unwrap())
+        };
+
+        assert_eq!(prstatus.len(), PRSTATUS_SIZE);
+        assert_eq!(prstatus[32..36], 2u32.to_le_bytes());
+        assert_eq!(reg(0), 15);
+        assert_eq!(reg(10), 0xa);
+        assert_eq!(reg(16), 0xffffffff81000000);
+        assert_eq!(reg(17), 0x10);
+        assert_eq!(reg(18), 0x246);
+        assert_eq!(reg(19), 0xffffc90000003f00);
+        assert_eq!(reg(22), 0xffff888007a00000);
+        assert_eq!(reg(26), 0x18);
+        // pr_fpvalid and padding
+        assert!(
+            prstatus[PRSTATUS_REGS_OFFSET + 27 * 8..]
+                .iter()
+                .all(|&b| b == 0)
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Detection of guest kernel crashes.
+//!
+//! The guest reports panics through the pvpanic device, and a watchdog set
+//! to fail the VM reports guests that stopped pinging it. Console output is
+//! tapped on its way to the host so the crash report can show what the guest
+//! printed last, usually the panic message and backtrace.
+
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Console output kept for crash reports.
+const CONSOLE_TAIL_SIZE: usize = 16 * 1024;
+
+/// Number of console lines included in a crash report.
+const CRASH_REPORT_LINES: usize = 50;
+
+/// Crash state shared by the pvpanic device, the console devices and the VM
+/// handle.
+#[derive(Default)]
+pub struct GuestCrash {
+    panicked: AtomicBool,
+    watchdog_expired: AtomicBool,
+    console_tail: Mutex<VecDeque<u8>>,
+}
+
+impl GuestCrash {
+    /// Records that the guest kernel panicked.
+    pub fn set_panicked(&self) {
+        self.panicked.store(true, Ordering::Relaxed);
+    }
+
+    /// Records that the guest stopped pinging a watchdog set to fail the VM.
+    pub fn set_watchdog_expired(&self) {
+        self.watchdog_expired.store(true, Ordering::Relaxed);
+    }
+
+    /// Describes the crash and the console output leading up to it, if the
+    /// guest crashed.
+    pub fn report(&self) -> Option<String> {
+        let what = if self.panicked.load(Ordering::Relaxed) {
+            "guest kernel panicked"
+        } else if self.watchdog_expired.load(Ordering::Relaxed) {
+            "guest watchdog expired"
+        } else {
+            return None;
+        };
+
+        let mut tail = self.console_tail.lock().unwrap();
+        let output = String::from_utf8_lossy(tail.make_contiguous()).into_owned();
+        let lines: Vec<&str> = output
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.is_empty())
+            .collect();
+        let last = &lines[lines.len().saturating_sub(CRASH_REPORT_LINES)..];
+
+        if last.is_empty() {
+            Some(what.into())
+        } else {
+            Some(format!(
+                "{}; last console output:\n{}",
+                what,
+                last.join("\n")
+            ))
+        }
+    }
+
+    /// Wraps a console writer so its output is kept for crash reports.
+    pub fn tap<W: Write>(self: &Arc<Self>, inner: W) -> ConsoleTap<W> {
+        ConsoleTap {
+            inner,
+            crash: self.clone(),
+        }
+    }
+
+    fn record_console(&self, data: &[u8]) {
+        let mut tail = self.console_tail.lock().unwrap();
+        tail.extend(data);
+        let excess = tail.len().saturating_sub(CONSOLE_TAIL_SIZE);
+        tail.drain(..excess);
+    }
+}
+
+/// Console writer that also records the output in [`GuestCrash`].
+pub struct ConsoleTap<W> {
+    inner: W,
+    crash: Arc<GuestCrash>,
+}
+
+impl<W: Write> Write for ConsoleTap<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.inner.write(buf) {
+            Ok(written) => {
+                self.crash.record_console(&buf[..written]);
+                Ok(written)
+            }
+            Err(e) => {
+                // Output nobody reads is still worth reporting
+                self.crash.record_console(buf);
+                Err(e)
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn no_report_without_panic() {
+        let crash = Arc::new(GuestCrash::default());
+        crash.tap(io::sink()).write_all(b"booting\n").unwrap();
+        assert!(crash.report().is_none());
+    }
+
+    #[test]
+    fn report_ends_with_console_output() {
+        let crash = Arc::new(GuestCrash::default());
+        let mut console = crash.tap(Vec::new());
+        for i in 0..100 {
+            write!(console, "line {}\r\n", i).unwrap();
+        }
+        crash.set_panicked();
+
+        let report = crash.report().unwrap();
+        assert!(report.starts_with("guest kernel panicked"));
+        assert!(report.ends_with("line 98\nline 99"));
+        assert!(!report.contains("line 49\n"));
+        assert!(console.inner.ends_with(b"line 99\r\n"));
+    }
+
+    #[test]
+    fn watchdog_expiry_is_reported() {
+        let crash = Arc::new(GuestCrash::default());
+        crash.tap(io::sink()).write_all(b"agent stuck\n").unwrap();
+        crash.set_watchdog_expired();
+
+        assert_eq!(
+            crash.report().unwrap(),
+            "guest watchdog expired; last console output:\nagent stuck"
+        );
+    }
+
+    #[test]
+    fn console_tail_is_bounded() {
+        let crash = Arc::new(GuestCrash::default());
+        let mut console = crash.tap(io::sink());
+        console.write_all(&[b'x'; CONSOLE_TAIL_SIZE + 100]).unwrap();
+        assert_eq!(crash.console_tail.lock().unwrap().len(), CONSOLE_TAIL_SIZE);
+    }
+}
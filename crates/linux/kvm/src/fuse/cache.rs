@@ -0,0 +1,116 @@
+//! Guest caching of virtio-fs shares.
+//!
+//! The guest kernel keeps directory entries and attributes for as long as
+//! each reply allows, and file data according to the open flags and the
+//! FUSE_INIT capabilities it is given.
+
+use std::time::Duration;
+
+use capsa_core::CacheMode;
+
+use super::protocol::{FOPEN_CACHE_DIR, FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE, FUSE_AUTO_INVAL_DATA};
+
+/// How long the guest may cache a share's metadata, and how it caches file
+/// data.
+#[derive(Debug, Clone, Copy)]
+pub struct CachePolicy {
+    mode: CacheMode,
+    entry_timeout: Duration,
+    attr_timeout: Duration,
+}
+
+impl CachePolicy {
+    /// Caches like `mode`, with the given timeouts replacing its own.
+    pub fn new(
+        mode: CacheMode,
+        entry_timeout: Option<Duration>,
+        attr_timeout: Option<Duration>,
+    ) -> Self {
+        let timeout = match mode {
+            CacheMode::None => Duration::ZERO,
+            CacheMode::Auto => Duration::from_secs(1),
+            CacheMode::Always => Duration::from_secs(24 * 60 * 60),
+        };
+        Self {
+            mode,
+            entry_timeout: entry_timeout.unwrap_or(timeout),
+            attr_timeout: attr_timeout.unwrap_or(timeout),
+        }
+    }
+
+    /// Seconds and nanoseconds the guest may keep a directory entry.
+    pub fn entry_valid(&self) -> (u64, u32) {
+        (
+            self.entry_timeout.as_secs(),
+            self.entry_timeout.subsec_nanos(),
+        )
+    }
+
+    /// Seconds and nanoseconds the guest may keep file attributes.
+    pub fn attr_valid(&self) -> (u64, u32) {
+        (
+            self.attr_timeout.as_secs(),
+            self.attr_timeout.subsec_nanos(),
+        )
+    }
+
+    /// FUSE_INIT capabilities the policy needs. With `FUSE_AUTO_INVAL_DATA`
+    /// the guest drops cached data once it sees a file's size or
+    /// modification time change.
+    pub fn init_flags(&self) -> u32 {
+        match self.mode {
+            CacheMode::Auto => FUSE_AUTO_INVAL_DATA,
+            CacheMode::None | CacheMode::Always => 0,
+        }
+    }
+
+    /// Open flags for files. Without `FOPEN_KEEP_CACHE` the guest also drops
+    /// cached data when a file is opened.
+    pub fn file_open_flags(&self) -> u32 {
+        match self.mode {
+            CacheMode::None => FOPEN_DIRECT_IO,
+            CacheMode::Auto => 0,
+            CacheMode::Always => FOPEN_KEEP_CACHE,
+        }
+    }
+
+    /// Open flags for directories.
+    pub fn dir_open_flags(&self) -> u32 {
+        match self.mode {
+            CacheMode::Always => FOPEN_KEEP_CACHE | FOPEN_CACHE_DIR,
+            CacheMode::None | CacheMode::Auto => 0,
+        }
+    }
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self::new(CacheMode::default(), None, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_override_the_mode() {
+        let policy = CachePolicy::new(CacheMode::Always, Some(Duration::from_millis(1500)), None);
+        assert_eq!(policy.entry_valid(), (1, 500_000_000));
+        assert_eq!(policy.attr_valid(), (86400, 0));
+        assert_eq!(policy.file_open_flags(), FOPEN_KEEP_CACHE);
+    }
+
+    #[test]
+    fn only_auto_invalidates_data_on_change() {
+        assert_eq!(CachePolicy::default().init_flags(), FUSE_AUTO_INVAL_DATA);
+        assert_eq!(
+            CachePolicy::new(CacheMode::None, None, None).init_flags(),
+            0
+        );
+        assert_eq!(
+            CachePolicy::new(CacheMode::Always, None, None).init_flags(),
+            0
+        );
+    }
+}
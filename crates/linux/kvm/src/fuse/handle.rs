@@ -7,12 +7,14 @@
 use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
 use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
 use std::os::unix::fs::OpenOptionsExt;
 use std::path::Path;
 
 use nix::libc;
+use serde::{Deserialize, Serialize};
 
-use super::inode::errno_from_io;
+use super::inode::{InodeTable, errno_from_io};
 
 pub const MAX_HANDLES: usize = 4096;
 
@@ -33,6 +35,21 @@ pub struct Handle {
     pub flags: u32,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+struct SavedHandle {
+    fh: u64,
+    ino: u64,
+    flags: u32,
+    is_dir: bool,
+}
+
+/// Open handles saved in a VM snapshot.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HandleTableState {
+    handles: Vec<SavedHandle>,
+    next_fh: u64,
+}
+
 pub struct HandleTable {
     handles: HashMap<u64, Handle>,
     next_fh: u64,
@@ -57,25 +74,7 @@ impl HandleTable {
             return Err(libc::EMFILE);
         }
 
-        let linux_flags = flags as i32;
-
-        let read = (linux_flags & libc::O_ACCMODE) == libc::O_RDONLY
-            || (linux_flags & libc::O_ACCMODE) == libc::O_RDWR;
-        let write = (linux_flags & libc::O_ACCMODE) == libc::O_WRONLY
-            || (linux_flags & libc::O_ACCMODE) == libc::O_RDWR;
-
-        if write && read_only {
-            return Err(libc::EROFS);
-        }
-
-        let file = OpenOptions::new()
-            .read(read)
-            .write(write)
-            .append((linux_flags & libc::O_APPEND) != 0)
-            .truncate((linux_flags & libc::O_TRUNC) != 0)
-            .custom_flags(linux_flags & !(libc::O_ACCMODE | libc::O_CREAT | libc::O_EXCL))
-            .open(path)
-            .map_err(|e| errno_from_io(&e))?;
+        let file = open_host_file(path, flags, read_only)?;
 
         let fh = self.next_fh;
         self.next_fh += 1;
@@ -140,40 +139,7 @@ impl HandleTable {
             return Err(libc::EMFILE);
         }
 
-        let read_dir = std::fs::read_dir(path).map_err(|e| errno_from_io(&e))?;
-
-        let mut entries = Vec::new();
-
-        entries.push(DirEntry {
-            ino,
-            name: ".".to_string(),
-            typ: libc::DT_DIR as u32,
-        });
-
-        entries.push(DirEntry {
-            ino: 0,
-            name: "..".to_string(),
-            typ: libc::DT_DIR as u32,
-        });
-
-        for entry in read_dir {
-            let entry = entry.map_err(|e| errno_from_io(&e))?;
-            let file_type = entry.file_type().map_err(|e| errno_from_io(&e))?;
-
-            let typ = if file_type.is_dir() {
-                libc::DT_DIR
-            } else if file_type.is_symlink() {
-                libc::DT_LNK
-            } else if file_type.is_file() {
-                libc::DT_REG
-            } else {
-                libc::DT_UNKNOWN
-            } as u32;
-
-            let name = entry.file_name().to_string_lossy().to_string();
-
-            entries.push(DirEntry { ino: 0, name, typ });
-        }
+        let entries = read_dir_entries(path, ino)?;
 
         let fh = self.next_fh;
         self.next_fh += 1;
@@ -260,6 +226,67 @@ impl HandleTable {
         Ok(())
     }
 
+    /// Seeks like `lseek`, including to the next data or hole with
+    /// `SEEK_DATA` and `SEEK_HOLE`, and returns the new offset.
+    pub fn seek_file(&self, fh: u64, offset: u64, whence: u32) -> Result<u64, i32> {
+        let file = self.file(fh)?;
+
+        let ret = unsafe { libc::lseek(file.as_raw_fd(), offset as i64, whence as i32) };
+        if ret < 0 {
+            return Err(errno_from_io(&std::io::Error::last_os_error()));
+        }
+
+        Ok(ret as u64)
+    }
+
+    /// Allocates, punches or zeroes a byte range like `fallocate`, passing
+    /// the guest's mode flags through.
+    pub fn fallocate_file(&self, fh: u64, offset: u64, length: u64, mode: u32) -> Result<(), i32> {
+        let file = self.file(fh)?;
+
+        let ret =
+            unsafe { libc::fallocate(file.as_raw_fd(), mode as i32, offset as i64, length as i64) };
+        if ret != 0 {
+            return Err(errno_from_io(&std::io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Copies between two open files on the host, which can share extents
+    /// instead of copying data when the filesystem supports it.
+    pub fn copy_file_range(
+        &self,
+        fh_in: u64,
+        offset_in: u64,
+        fh_out: u64,
+        offset_out: u64,
+        len: u64,
+    ) -> Result<u64, i32> {
+        let fd_in = self.file(fh_in)?.as_raw_fd();
+        let fd_out = self.file(fh_out)?.as_raw_fd();
+
+        let mut off_in = offset_in as i64;
+        let mut off_out = offset_out as i64;
+        let ret = unsafe {
+            libc::copy_file_range(fd_in, &mut off_in, fd_out, &mut off_out, len as usize, 0)
+        };
+        if ret < 0 {
+            return Err(errno_from_io(&std::io::Error::last_os_error()));
+        }
+
+        Ok(ret as u64)
+    }
+
+    fn file(&self, fh: u64) -> Result<&File, i32> {
+        let handle = self.handles.get(&fh).ok_or(libc::EBADF)?;
+
+        match &handle.kind {
+            HandleKind::File(f) => Ok(f),
+            HandleKind::Dir(_) => Err(libc::EISDIR),
+        }
+    }
+
     pub fn read_dir(&self, fh: u64, offset: u64) -> Result<&[DirEntry], i32> {
         let handle = self.handles.get(&fh).ok_or(libc::EBADF)?;
 
@@ -275,6 +302,73 @@ impl HandleTable {
 
         Ok(&entries[offset..])
     }
+
+    pub fn save_state(&self) -> HandleTableState {
+        let handles = self
+            .handles
+            .iter()
+            .map(|(&fh, handle)| SavedHandle {
+                fh,
+                ino: handle.ino,
+                flags: handle.flags,
+                is_dir: matches!(handle.kind, HandleKind::Dir(_)),
+            })
+            .collect();
+
+        HandleTableState {
+            handles,
+            next_fh: self.next_fh,
+        }
+    }
+
+    /// Reopens the handles in `state` using the paths in `inodes`.
+    ///
+    /// Files are reopened without `O_TRUNC`, `O_CREAT` or `O_EXCL` so their
+    /// contents are left alone. Handles that can no longer be opened are
+    /// dropped and the guest gets `EBADF` when it next uses them.
+    pub fn restore_state(
+        &mut self,
+        state: &HandleTableState,
+        inodes: &InodeTable,
+        read_only: bool,
+    ) {
+        self.handles.clear();
+        self.next_fh = state.next_fh;
+
+        for saved in state.handles.iter().take(MAX_HANDLES) {
+            let Some(path) = inodes.get_path(saved.ino) else {
+                continue;
+            };
+
+            let kind = if saved.is_dir {
+                read_dir_entries(path, saved.ino).map(HandleKind::Dir)
+            } else {
+                let reopen_flags =
+                    saved.flags & !((libc::O_TRUNC | libc::O_CREAT | libc::O_EXCL) as u32);
+                open_host_file(path, reopen_flags, read_only).map(HandleKind::File)
+            };
+
+            match kind {
+                Ok(kind) => {
+                    self.handles.insert(
+                        saved.fh,
+                        Handle {
+                            kind,
+                            ino: saved.ino,
+                            flags: saved.flags,
+                        },
+                    );
+                }
+                Err(errno) => {
+                    tracing::warn!(
+                        "virtio-fs: failed to reopen {:?} after restore: errno {}",
+                        path,
+                        errno
+                    );
+                }
+            }
+        }
+    }
 }
 
 impl Default for HandleTable {
@@ -283,6 +377,67 @@ impl Default for HandleTable {
     }
 }
 
+fn open_host_file(path: &Path, flags: u32, read_only: bool) -> Result<File, i32> {
+    let linux_flags = flags as i32;
+
+    let read = (linux_flags & libc::O_ACCMODE) == libc::O_RDONLY
+        || (linux_flags & libc::O_ACCMODE) == libc::O_RDWR;
+    let write = (linux_flags & libc::O_ACCMODE) == libc::O_WRONLY
+        || (linux_flags & libc::O_ACCMODE) == libc::O_RDWR;
+
+    if write && read_only {
+        return Err(libc::EROFS);
+    }
+
+    OpenOptions::new()
+        .read(read)
+        .write(write)
+        .append((linux_flags & libc::O_APPEND) != 0)
+        .truncate((linux_flags & libc::O_TRUNC) != 0)
+        .custom_flags(linux_flags & !(libc::O_ACCMODE | libc::O_CREAT | libc::O_EXCL))
+        .open(path)
+        .map_err(|e| errno_from_io(&e))
+}
+
+fn read_dir_entries(path: &Path, ino: u64) -> Result<Vec<DirEntry>, i32> {
+    let read_dir = std::fs::read_dir(path).map_err(|e| errno_from_io(&e))?;
+
+    let mut entries = Vec::new();
+
+    entries.push(DirEntry {
+        ino,
+        name: ".".to_string(),
+        typ: libc::DT_DIR as u32,
+    });
+
+    entries.push(DirEntry {
+        ino: 0,
+        name: "..".to_string(),
+        typ: libc::DT_DIR as u32,
+    });
+
+    for entry in read_dir {
+        let entry = entry.map_err(|e| errno_from_io(&e))?;
+        let file_type = entry.file_type().map_err(|e| errno_from_io(&e))?;
+
+        let typ = if file_type.is_dir() {
+            libc::DT_DIR
+        } else if file_type.is_symlink() {
+            libc::DT_LNK
+        } else if file_type.is_file() {
+            libc::DT_REG
+        } else {
+            libc::DT_UNKNOWN
+        } as u32;
+
+        let name = entry.file_name().to_string_lossy().to_string();
+
+        entries.push(DirEntry { ino: 0, name, typ });
+    }
+
+    Ok(entries)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -356,6 +511,46 @@ mod tests {
         assert!(entries.iter().any(|e| e.name == "b.txt"));
     }
 
+    #[test]
+    fn seek_finds_data_and_holes() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("sparse.bin");
+        fs::write(&path, "data").unwrap();
+
+        let mut table = HandleTable::new();
+        let fh = table
+            .open_file(&path, libc::O_RDWR as u32, 2, false)
+            .unwrap();
+        table.fallocate_file(fh, 0, 1024 * 1024, 0).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().len(), 1024 * 1024);
+
+        assert_eq!(table.seek_file(fh, 0, libc::SEEK_DATA as u32), Ok(0));
+        let hole = table.seek_file(fh, 0, libc::SEEK_HOLE as u32).unwrap();
+        assert!((4..=1024 * 1024).contains(&hole));
+        assert_eq!(
+            table.seek_file(fh, 2 * 1024 * 1024, libc::SEEK_DATA as u32),
+            Err(libc::ENXIO)
+        );
+    }
+
+    #[test]
+    fn copy_file_range_between_handles() {
+        let tmp = TempDir::new().unwrap();
+        fs::write(tmp.path().join("src.txt"), "hello world").unwrap();
+        fs::write(tmp.path().join("dst.txt"), "").unwrap();
+
+        let mut table = HandleTable::new();
+        let src = table
+            .open_file(&tmp.path().join("src.txt"), libc::O_RDONLY as u32, 2, false)
+            .unwrap();
+        let dst = table
+            .open_file(&tmp.path().join("dst.txt"), libc::O_RDWR as u32, 3, false)
+            .unwrap();
+
+        assert_eq!(table.copy_file_range(src, 6, dst, 0, 5), Ok(5));
+        assert_eq!(table.read_file(dst, 0, 100).unwrap(), b"world");
+    }
+
     #[test]
     fn handle_limit_enforced() {
         let tmp = TempDir::new().unwrap();
@@ -387,4 +582,41 @@ mod tests {
         let result = table.open_file(&path, libc::O_RDONLY as u32, 99999, false);
         assert_eq!(result, Err(libc::EMFILE));
     }
+
+    #[test]
+    fn restore_reopens_handles_without_truncating() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        fs::write(root.join("test.txt"), "hello").unwrap();
+        fs::create_dir(root.join("dir")).unwrap();
+
+        let mut inodes = InodeTable::new(root.clone());
+        let file_ino = inodes.lookup(1, "test.txt").unwrap();
+        let dir_ino = inodes.lookup(1, "dir").unwrap();
+
+        let mut table = HandleTable::new();
+        let file_fh = table
+            .open_file(
+                &root.join("test.txt"),
+                (libc::O_RDWR | libc::O_TRUNC) as u32,
+                file_ino,
+                false,
+            )
+            .unwrap();
+        table.write_file(file_fh, 0, b"restored").unwrap();
+        let dir_fh = table.open_dir(&root.join("dir"), dir_ino).unwrap();
+        let state = table.save_state();
+
+        let mut restored = HandleTable::new();
+        restored.restore_state(&state, &inodes, false);
+
+        assert_eq!(restored.read_file(file_fh, 0, 100).unwrap(), b"restored");
+        assert_eq!(restored.read_dir(dir_fh, 0).unwrap()[0].name, ".");
+        assert!(
+            restored
+                .open_file(&root.join("test.txt"), 0, file_ino, false)
+                .unwrap()
+                > dir_fh
+        );
+    }
 }
@@ -0,0 +1,301 @@
+//! User and group ID mapping for virtio-fs.
+//!
+//! Host owners are translated to guest IDs in the attributes the guest sees,
+//! and guest IDs back to host IDs for chown. An owner the host cannot hold,
+//! because the mapping squashes IDs or the VM process lacks `CAP_CHOWN`, is
+//! kept in the `user.virtiofs.owner` attribute as `uid:gid` guest IDs and
+//! shown in place of the host owner. The guest cannot reach that attribute
+//! through the xattr requests. Symlinks cannot have `user.*` attributes, so
+//! such a chown of a symlink fails with `EPERM`.
+//!
+//! Reading the attribute costs a host call for every lookup, so with the
+//! passthrough mapping it is only read once the share has kept an owner in
+//! it. Until then, owners kept there by an earlier VM are not shown.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use capsa_core::{IdMap, IdMapping};
+use nix::libc;
+
+use super::inode::errno_from_io;
+use super::protocol::FuseAttr;
+use super::xattr;
+
+const OWNER_XATTR: &str = "user.virtiofs.owner";
+
+/// ID shown for host owners outside a range mapping.
+const OVERFLOW_ID: u32 = 65534;
+
+/// Translates file owners between host and guest.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IdMapper {
+    mapping: IdMapping,
+    /// Whether an owner the host refused was kept in the owner attribute
+    owners_kept: bool,
+}
+
+impl IdMapper {
+    pub fn new(mapping: IdMapping) -> Self {
+        Self {
+            mapping,
+            owners_kept: false,
+        }
+    }
+
+    /// Whether the share has kept an owner in the owner attribute, for a
+    /// snapshot to carry over.
+    pub fn owners_kept(&self) -> bool {
+        self.owners_kept
+    }
+
+    /// Restores whether the share has kept an owner, after a snapshot.
+    pub fn set_owners_kept(&mut self, kept: bool) {
+        self.owners_kept = kept;
+    }
+
+    /// Replaces the host owner in `attr`, read from `path`, with the owner
+    /// the guest sees.
+    pub fn map_attr(&self, path: &Path, attr: &mut FuseAttr) {
+        (attr.uid, attr.gid) = self.kept_owner(path).unwrap_or((
+            to_guest(&self.mapping.uid, attr.uid),
+            to_guest(&self.mapping.gid, attr.gid),
+        ));
+    }
+
+    /// Changes the owner of `path` to the given guest IDs, leaving out those
+    /// that are `None`. Does not follow a final symlink.
+    pub fn chown(&mut self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<(), i32> {
+        let host_uid = uid.map(|id| to_host(&self.mapping.uid, id)).transpose()?;
+        let host_gid = gid.map(|id| to_host(&self.mapping.gid, id)).transpose()?;
+        let saved = self.kept_owner(path);
+
+        // The host keeps the owner where the mapping gives it host IDs
+        if !matches!(host_uid, Some(None)) && !matches!(host_gid, Some(None)) {
+            match lchown(path, host_uid.flatten(), host_gid.flatten()) {
+                Ok(()) => {
+                    let Some((saved_uid, saved_gid)) = saved else {
+                        return Ok(());
+                    };
+                    // A saved owner still overrides the host's
+                    let owner = (uid.unwrap_or(saved_uid), gid.unwrap_or(saved_gid));
+                    return write_owner(path, owner);
+                }
+                Err(libc::EPERM) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        let metadata = std::fs::symlink_metadata(path).map_err(|e| errno_from_io(&e))?;
+        if metadata.file_type().is_symlink() {
+            // There is nowhere to keep the owner
+            return Err(libc::EPERM);
+        }
+        let (current_uid, current_gid) = saved.unwrap_or((
+            to_guest(&self.mapping.uid, metadata.uid()),
+            to_guest(&self.mapping.gid, metadata.gid()),
+        ));
+        write_owner(
+            path,
+            (uid.unwrap_or(current_uid), gid.unwrap_or(current_gid)),
+        )?;
+        self.owners_kept = true;
+        Ok(())
+    }
+
+    /// Gives a file the guest just created to the guest user and group that
+    /// created it, where they are range mapped. Otherwise the file keeps the
+    /// host user running the VM as its owner.
+    pub fn set_creator(&mut self, path: &Path, uid: u32, gid: u32) {
+        let uid = matches!(self.mapping.uid, IdMap::Range { .. }).then_some(uid);
+        let gid = matches!(self.mapping.gid, IdMap::Range { .. }).then_some(gid);
+        if uid.is_none() && gid.is_none() {
+            return;
+        }
+
+        if let Err(e) = self.chown(path, uid, gid) {
+            tracing::debug!(
+                "virtio-fs: cannot give {} to its creator: errno {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    /// Returns the owner kept in the owner attribute of `path`, where the
+    /// share may have one.
+    fn kept_owner(&self, path: &Path) -> Option<(u32, u32)> {
+        if self.mapping.is_passthrough() && !self.owners_kept {
+            return None;
+        }
+        read_owner(path)
+    }
+}
+
+fn to_guest(map: &IdMap, id: u32) -> u32 {
+    match *map {
+        IdMap::Passthrough => id,
+        IdMap::Squash { guest } => guest,
+        IdMap::Range { guest, host, count } => id
+            .checked_sub(host)
+            .filter(|&offset| offset < count)
+            .and_then(|offset| guest.checked_add(offset))
+            .unwrap_or(OVERFLOW_ID),
+    }
+}
+
+/// Returns the host ID for a guest ID, or `None` where the mapping has no
+/// host ID for it to take.
+fn to_host(map: &IdMap, id: u32) -> Result<Option<u32>, i32> {
+    match *map {
+        IdMap::Passthrough => Ok(Some(id)),
+        IdMap::Squash { .. } => Ok(None),
+        IdMap::Range { guest, host, count } => id
+            .checked_sub(guest)
+            .filter(|&offset| offset < count)
+            .and_then(|offset| host.checked_add(offset))
+            .map(Some)
+            // Like an ID outside the user namespace of a chown caller
+            .ok_or(libc::EINVAL),
+    }
+}
+
+fn read_owner(path: &Path) -> Option<(u32, u32)> {
+    let value = xattr::get(path, OWNER_XATTR, 32).ok()?;
+    let (uid, gid) = std::str::from_utf8(&value).ok()?.split_once(':')?;
+    Some((uid.parse().ok()?, gid.parse().ok()?))
+}
+
+fn write_owner(path: &Path, (uid, gid): (u32, u32)) -> Result<(), i32> {
+    xattr::set(path, OWNER_XATTR, format!("{uid}:{gid}").as_bytes(), 0)
+}
+
+fn lchown(path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<(), i32> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|_| libc::EINVAL)?;
+    let ret = unsafe {
+        libc::lchown(
+            path.as_ptr(),
+            uid.unwrap_or(u32::MAX),
+            gid.unwrap_or(u32::MAX),
+        )
+    };
+    if ret != 0 {
+        return Err(errno_from_io(&std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn range_maps_both_ways() {
+        let map = IdMap::Range {
+            guest: 0,
+            host: 100000,
+            count: 65536,
+        };
+        assert_eq!(to_guest(&map, 100000), 0);
+        assert_eq!(to_guest(&map, 101000), 1000);
+        assert_eq!(to_guest(&map, 1000), OVERFLOW_ID);
+        assert_eq!(to_guest(&map, 165536), OVERFLOW_ID);
+
+        assert_eq!(to_host(&map, 1000), Ok(Some(101000)));
+        assert_eq!(to_host(&map, 65536), Err(libc::EINVAL));
+    }
+
+    #[test]
+    fn squashed_owners_are_kept_in_an_xattr() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("file.txt");
+        std::fs::write(&path, "").unwrap();
+        let host_uid = std::fs::metadata(&path).unwrap().uid();
+
+        let mut ids = IdMapper::new(IdMapping::squash(0, 0));
+        let mut attr = FuseAttr::default();
+        ids.map_attr(&path, &mut attr);
+        assert_eq!((attr.uid, attr.gid), (0, 0));
+
+        ids.chown(&path, Some(1000), None).unwrap();
+        ids.map_attr(&path, &mut attr);
+        assert_eq!((attr.uid, attr.gid), (1000, 0));
+
+        // The host owner is left alone
+        assert_eq!(std::fs::metadata(&path).unwrap().uid(), host_uid);
+    }
+
+    #[test]
+    fn passthrough_reports_host_owners() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("file.txt");
+        std::fs::write(&path, "").unwrap();
+
+        let mut ids = IdMapper::default();
+        let mut attr = FuseAttr {
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+        ids.map_attr(&path, &mut attr);
+        assert_eq!((attr.uid, attr.gid), (1000, 1000));
+
+        // Changing nothing is always allowed
+        ids.chown(&path, None, None).unwrap();
+    }
+
+    #[test]
+    fn passthrough_keeps_owners_the_host_refuses() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("file.txt");
+        std::fs::write(&path, "").unwrap();
+
+        // Only root can give the file away on the host, everyone else gets
+        // the owner kept in the xattr
+        let mut ids = IdMapper::default();
+        ids.chown(&path, Some(4242), Some(4242)).unwrap();
+
+        let metadata = std::fs::metadata(&path).unwrap();
+        let mut attr = FuseAttr {
+            uid: metadata.uid(),
+            gid: metadata.gid(),
+            ..Default::default()
+        };
+        ids.map_attr(&path, &mut attr);
+        assert_eq!((attr.uid, attr.gid), (4242, 4242));
+    }
+
+    #[test]
+    fn symlinks_cannot_keep_an_owner() {
+        let tmp = TempDir::new().unwrap();
+        let link = tmp.path().join("link");
+        std::os::unix::fs::symlink("target", &link).unwrap();
+
+        let mut ids = IdMapper::new(IdMapping::squash(0, 0));
+        assert_eq!(ids.chown(&link, Some(1000), None), Err(libc::EPERM));
+    }
+
+    #[test]
+    fn passthrough_reads_kept_owners_only_once_it_keeps_one() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("file.txt");
+        std::fs::write(&path, "").unwrap();
+        write_owner(&path, (4242, 4242)).unwrap();
+
+        let mut ids = IdMapper::default();
+        let mut attr = FuseAttr {
+            uid: 1000,
+            gid: 1000,
+            ..Default::default()
+        };
+        ids.map_attr(&path, &mut attr);
+        assert_eq!((attr.uid, attr.gid), (1000, 1000));
+
+        ids.set_owners_kept(true);
+        ids.map_attr(&path, &mut attr);
+        assert_eq!((attr.uid, attr.gid), (4242, 4242));
+    }
+}
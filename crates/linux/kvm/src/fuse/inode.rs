@@ -11,6 +11,7 @@ use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 use nix::libc;
+use serde::{Deserialize, Serialize};
 
 use super::protocol::FuseAttr;
 
@@ -23,6 +24,20 @@ pub struct InodeData {
     pub nlookup: u64,
 }
 
+#[derive(Clone, Serialize, Deserialize)]
+struct SavedInode {
+    ino: u64,
+    path: PathBuf,
+    nlookup: u64,
+}
+
+/// Inodes known to the guest, saved in a VM snapshot.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct InodeTableState {
+    inodes: Vec<SavedInode>,
+    next_ino: u64,
+}
+
 pub struct InodeTable {
     host_root: PathBuf,
     by_guest_ino: HashMap<u64, InodeData>,
@@ -176,6 +191,64 @@ impl InodeTable {
             }
         }
     }
+
+    pub fn save_state(&self) -> InodeTableState {
+        let inodes = self
+            .by_guest_ino
+            .iter()
+            .filter(|&(&ino, _)| ino != ROOT_INODE)
+            .map(|(&ino, data)| SavedInode {
+                ino,
+                path: data.path.clone(),
+                nlookup: data.nlookup,
+            })
+            .collect();
+
+        InodeTableState {
+            inodes,
+            next_ino: self.next_ino,
+        }
+    }
+
+    /// Replaces the table with the inodes in `state`.
+    ///
+    /// Host inode numbers are looked up again rather than trusted from the
+    /// snapshot. Paths that no longer exist or that resolve outside the shared
+    /// directory are dropped, so the guest gets `ENOENT` for them.
+    pub fn restore_state(&mut self, state: &InodeTableState) {
+        let root = self.by_guest_ino.remove(&ROOT_INODE);
+        self.by_guest_ino.clear();
+        self.by_host_key.clear();
+        if let Some(root) = root {
+            self.by_guest_ino.insert(ROOT_INODE, root);
+        }
+        self.next_ino = state.next_ino.max(ROOT_INODE + 1);
+
+        for saved in state.inodes.iter().take(MAX_INODES) {
+            if saved.ino == ROOT_INODE || saved.ino >= self.next_ino {
+                continue;
+            }
+            let Ok(canonical) = self.validate_path(&saved.path) else {
+                continue;
+            };
+            let Ok(metadata) = std::fs::metadata(&canonical) else {
+                continue;
+            };
+
+            let host_key = (metadata.dev(), metadata.ino());
+            if self.by_host_key.contains_key(&host_key) {
+                continue;
+            }
+            self.by_host_key.insert(host_key, saved.ino);
+            self.by_guest_ino.insert(
+                saved.ino,
+                InodeData {
+                    path: canonical,
+                    nlookup: saved.nlookup,
+                },
+            );
+        }
+    }
 }
 
 pub fn metadata_to_attr(ino: u64, metadata: &Metadata) -> FuseAttr {
@@ -402,4 +475,33 @@ mod tests {
 
         assert!(table.by_guest_ino.len() <= MAX_INODES);
     }
+
+    #[test]
+    fn restore_keeps_guest_inode_numbers() {
+        let tmp = TempDir::new().unwrap();
+        let root = tmp.path().canonicalize().unwrap();
+        fs::write(root.join("kept.txt"), "content").unwrap();
+        fs::write(root.join("deleted.txt"), "content").unwrap();
+
+        let mut table = InodeTable::new(root.clone());
+        let kept = table.lookup(ROOT_INODE, "kept.txt").unwrap();
+        let deleted = table.lookup(ROOT_INODE, "deleted.txt").unwrap();
+        table.incref(kept);
+        let state = table.save_state();
+
+        fs::remove_file(root.join("deleted.txt")).unwrap();
+        let mut restored = InodeTable::new(root.clone());
+        restored.restore_state(&state);
+
+        assert_eq!(
+            restored.get_path(kept),
+            Some(root.join("kept.txt").as_path())
+        );
+        assert_eq!(restored.get(kept).unwrap().nlookup, 2);
+        assert!(restored.get(deleted).is_none());
+        assert_eq!(restored.lookup(ROOT_INODE, "kept.txt").unwrap(), kept);
+
+        fs::write(root.join("new.txt"), "content").unwrap();
+        assert!(restored.lookup(ROOT_INODE, "new.txt").unwrap() > deleted);
+    }
 }
@@ -0,0 +1,303 @@
+//! File locks for virtio-fs.
+//!
+//! Guest locks are held on the host as open file description locks: OFD
+//! locks for byte ranges, and flock locks for whole files. Either kind
+//! belongs to an open file description rather than a process, so every guest
+//! lock owner gets a description of its own for each inode it locks, and its
+//! locks conflict with other owners' on the host like they do in the guest.
+//! Closing the description drops all of the owner's locks on the file.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+use nix::libc;
+
+use super::inode::errno_from_io;
+use super::protocol::FuseFileLock;
+
+/// Most inode and lock owner pairs that can hold locks at once.
+pub const MAX_LOCK_FILES: usize = 4096;
+
+/// End of a lock that extends to the end of the file, however it grows.
+pub const LOCK_END_OF_FILE: u64 = i64::MAX as u64;
+
+/// Host file descriptions holding the guest's locks, by inode and lock owner.
+pub struct LockTable {
+    files: HashMap<(u64, u64), File>,
+}
+
+impl LockTable {
+    pub fn new() -> Self {
+        Self {
+            files: HashMap::new(),
+        }
+    }
+
+    /// Returns a lock of another owner that conflicts with `lock`, or `lock`
+    /// with its type set to `F_UNLCK` if there is none.
+    pub fn test(
+        &self,
+        path: &Path,
+        ino: u64,
+        owner: u64,
+        lock: &FuseFileLock,
+        read_only: bool,
+    ) -> Result<FuseFileLock, i32> {
+        // The owner's own locks never conflict, so any other description
+        // will do for an owner that holds none
+        let opened;
+        let file = match self.files.get(&(ino, owner)) {
+            Some(f) => f,
+            None => {
+                opened = open_lock_file(path, read_only)?;
+                &opened
+            }
+        };
+
+        let mut flock = to_flock(lock)?;
+        fcntl_lock(file, libc::F_OFD_GETLK, &mut flock)?;
+
+        if flock.l_type == libc::F_UNLCK as i16 {
+            return Ok(FuseFileLock {
+                typ: libc::F_UNLCK as u32,
+                ..*lock
+            });
+        }
+
+        let start = flock.l_start as u64;
+        Ok(FuseFileLock {
+            start,
+            end: if flock.l_len == 0 {
+                LOCK_END_OF_FILE
+            } else {
+                start + flock.l_len as u64 - 1
+            },
+            typ: flock.l_type as u32,
+            // The holder is a host process, or another guest lock owner
+            pid: 0,
+        })
+    }
+
+    /// Sets or clears a byte-range lock without waiting. Fails with `EAGAIN`
+    /// while another owner holds a conflicting lock.
+    pub fn set_posix(
+        &mut self,
+        path: &Path,
+        ino: u64,
+        owner: u64,
+        lock: &FuseFileLock,
+        read_only: bool,
+    ) -> Result<(), i32> {
+        let Some(file) = self.owner_file(path, ino, owner, lock.typ, read_only)? else {
+            return Ok(());
+        };
+
+        let mut flock = to_flock(lock)?;
+        fcntl_lock(file, libc::F_OFD_SETLK, &mut flock)
+    }
+
+    /// Sets or clears a whole-file lock without waiting. Fails with `EAGAIN`
+    /// while another owner holds a conflicting lock.
+    pub fn set_flock(
+        &mut self,
+        path: &Path,
+        ino: u64,
+        owner: u64,
+        typ: u32,
+        read_only: bool,
+    ) -> Result<(), i32> {
+        let Some(file) = self.owner_file(path, ino, owner, typ, read_only)? else {
+            return Ok(());
+        };
+
+        let operation = match typ as i32 {
+            libc::F_RDLCK => libc::LOCK_SH,
+            libc::F_WRLCK => libc::LOCK_EX,
+            libc::F_UNLCK => libc::LOCK_UN,
+            _ => return Err(libc::EINVAL),
+        };
+
+        let ret = unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) };
+        if ret != 0 {
+            return Err(errno_from_io(&std::io::Error::last_os_error()));
+        }
+
+        Ok(())
+    }
+
+    /// Drops every lock `owner` holds on `ino`, returning whether it had a
+    /// description that could hold any.
+    pub fn release(&mut self, ino: u64, owner: u64) -> bool {
+        self.files.remove(&(ino, owner)).is_some()
+    }
+
+    /// Drops every lock, for a guest that reset the device.
+    pub fn clear(&mut self) {
+        self.files.clear();
+    }
+
+    /// Returns the owner's description of `ino`, opening one to take a lock.
+    /// An owner without one holds no locks, so there is nothing to unlock.
+    fn owner_file(
+        &mut self,
+        path: &Path,
+        ino: u64,
+        owner: u64,
+        typ: u32,
+        read_only: bool,
+    ) -> Result<Option<&File>, i32> {
+        let len = self.files.len();
+        match self.files.entry((ino, owner)) {
+            Entry::Occupied(entry) => Ok(Some(entry.into_mut())),
+            Entry::Vacant(_) if typ == libc::F_UNLCK as u32 => Ok(None),
+            Entry::Vacant(_) if len >= MAX_LOCK_FILES => Err(libc::ENOLCK),
+            Entry::Vacant(entry) => Ok(Some(entry.insert(open_lock_file(path, read_only)?))),
+        }
+    }
+}
+
+impl Default for LockTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Opens `path` for writing where possible, since write locks need a
+/// description open for writing. Directories and read-only shares only get
+/// read locks.
+fn open_lock_file(path: &Path, read_only: bool) -> Result<File, i32> {
+    if !read_only && let Ok(file) = OpenOptions::new().read(true).write(true).open(path) {
+        return Ok(file);
+    }
+
+    File::open(path).map_err(|e| errno_from_io(&e))
+}
+
+/// Converts a guest lock range, failing with `EINVAL` for one that ends
+/// before it starts or starts past the largest file offset.
+fn to_flock(lock: &FuseFileLock) -> Result<libc::flock, i32> {
+    if lock.end < lock.start || lock.start > LOCK_END_OF_FILE {
+        return Err(libc::EINVAL);
+    }
+
+    let mut flock: libc::flock = unsafe { std::mem::zeroed() };
+    flock.l_type = lock.typ as i16;
+    flock.l_whence = libc::SEEK_SET as i16;
+    flock.l_start = lock.start as i64;
+    flock.l_len = if lock.end >= LOCK_END_OF_FILE {
+        0
+    } else {
+        (lock.end - lock.start + 1) as i64
+    };
+    Ok(flock)
+}
+
+fn fcntl_lock(file: &File, cmd: i32, flock: &mut libc::flock) -> Result<(), i32> {
+    let ret = unsafe { libc::fcntl(file.as_raw_fd(), cmd, flock as *mut libc::flock) };
+    if ret != 0 {
+        return Err(errno_from_io(&std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn range(typ: i32, start: u64, end: u64) -> FuseFileLock {
+        FuseFileLock {
+            start,
+            end,
+            typ: typ as u32,
+            pid: 0,
+        }
+    }
+
+    #[test]
+    fn byte_range_locks_conflict_between_owners() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("db.sqlite");
+        fs::write(&path, "").unwrap();
+
+        let mut locks = LockTable::new();
+        let write = range(libc::F_WRLCK, 0, 99);
+        locks.set_posix(&path, 2, 1, &write, false).unwrap();
+
+        // The owner's own lock never conflicts, another owner's does
+        assert_eq!(
+            locks.test(&path, 2, 1, &write, false).unwrap().typ,
+            libc::F_UNLCK as u32
+        );
+        let conflict = locks.test(&path, 2, 2, &write, false).unwrap();
+        assert_eq!(conflict, range(libc::F_WRLCK, 0, 99));
+        assert_eq!(
+            locks.set_posix(&path, 2, 2, &range(libc::F_RDLCK, 50, 50), false),
+            Err(libc::EAGAIN)
+        );
+        let tail = range(libc::F_WRLCK, 100, LOCK_END_OF_FILE);
+        locks.set_posix(&path, 2, 2, &tail, false).unwrap();
+
+        locks.release(2, 1);
+        locks.set_posix(&path, 2, 2, &write, false).unwrap();
+    }
+
+    #[test]
+    fn flock_locks_whole_files() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("Cargo.lock");
+        fs::write(&path, "").unwrap();
+
+        let mut locks = LockTable::new();
+        let (shared, exclusive) = (libc::F_RDLCK as u32, libc::F_WRLCK as u32);
+        locks.set_flock(&path, 2, 1, shared, false).unwrap();
+        locks.set_flock(&path, 2, 2, shared, false).unwrap();
+        assert_eq!(
+            locks.set_flock(&path, 2, 3, exclusive, false),
+            Err(libc::EAGAIN)
+        );
+
+        let unlock = libc::F_UNLCK as u32;
+        locks.set_flock(&path, 2, 1, unlock, false).unwrap();
+        locks.release(2, 2);
+        locks.set_flock(&path, 2, 3, exclusive, false).unwrap();
+
+        // Directories can be locked too
+        locks.set_flock(tmp.path(), 1, 1, exclusive, false).unwrap();
+    }
+
+    #[test]
+    fn rejects_reversed_ranges() {
+        let tmp = TempDir::new().unwrap();
+        let path = tmp.path().join("file.txt");
+        fs::write(&path, "").unwrap();
+
+        let mut locks = LockTable::new();
+        let reversed = range(libc::F_WRLCK, 100, 99);
+        assert_eq!(
+            locks.set_posix(&path, 2, 1, &reversed, false),
+            Err(libc::EINVAL)
+        );
+        assert_eq!(locks.test(&path, 2, 1, &reversed, false), Err(libc::EINVAL));
+
+        // An end of u64::MAX still means the end of the file
+        let tail = range(libc::F_WRLCK, 100, u64::MAX);
+        locks.set_posix(&path, 2, 1, &tail, false).unwrap();
+    }
+
+    #[test]
+    fn unlock_without_locks_opens_nothing() {
+        let tmp = TempDir::new().unwrap();
+        let mut locks = LockTable::new();
+
+        let unlock = range(libc::F_UNLCK, 0, LOCK_END_OF_FILE);
+        locks
+            .set_posix(&tmp.path().join("missing"), 2, 1, &unlock, false)
+            .unwrap();
+        assert!(locks.files.is_empty());
+    }
+}
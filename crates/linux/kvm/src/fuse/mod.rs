@@ -1,12 +1,21 @@
 //! FUSE protocol implementation for virtio-fs.
 //!
 //! This module provides the FUSE protocol handling for the virtio-fs device.
-//! It includes protocol types, inode management, and file handle tracking.
+//! It includes protocol types, inode management, file handle tracking,
+//! file locks and the guest's cache policy.
 
+mod cache;
 mod handle;
+mod idmap;
 mod inode;
+mod lock;
 mod protocol;
+pub mod xattr;
 
-pub use handle::HandleTable;
-pub use inode::{InodeTable, errno_from_io, metadata_to_attr};
+pub use cache::CachePolicy;
+pub use handle::{HandleTable, HandleTableState};
+pub use idmap::IdMapper;
+pub use inode::{InodeTable, InodeTableState, errno_from_io, metadata_to_attr};
+pub use lock::LockTable;
 pub use protocol::*;
+pub use xattr::XattrPolicy;
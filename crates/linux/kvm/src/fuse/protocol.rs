@@ -656,6 +656,10 @@ impl FuseFlushIn {
     }
 }
 
+// Release flags
+pub const FUSE_RELEASE_FLUSH: u32 = 1 << 0;
+pub const FUSE_RELEASE_FLOCK_UNLOCK: u32 = 1 << 1;
+
 /// Fsync input.
 #[repr(C)]
 #[derive(Clone, Copy, Default, Debug)]
@@ -816,6 +820,151 @@ impl FuseAccessIn {
     }
 }
 
+/// A file lock, as the guest kernel describes it.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub struct FuseFileLock {
+    pub start: u64,
+    /// Last byte of the lock, inclusive
+    pub end: u64,
+    pub typ: u32,
+    pub pid: u32,
+}
+
+pub const FUSE_FILE_LOCK_SIZE: usize = std::mem::size_of::<FuseFileLock>();
+
+impl FuseFileLock {
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < FUSE_FILE_LOCK_SIZE {
+            return None;
+        }
+        Some(Self {
+            start: u64::from_le_bytes(data[0..8].try_into().ok()?),
+            end: u64::from_le_bytes(data[8..16].try_into().ok()?),
+            typ: u32::from_le_bytes(data[16..20].try_into().ok()?),
+            pid: u32::from_le_bytes(data[20..24].try_into().ok()?),
+        })
+    }
+
+    pub fn to_bytes(self) -> [u8; FUSE_FILE_LOCK_SIZE] {
+        let mut buf = [0u8; FUSE_FILE_LOCK_SIZE];
+        buf[0..8].copy_from_slice(&self.start.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.end.to_le_bytes());
+        buf[16..20].copy_from_slice(&self.typ.to_le_bytes());
+        buf[20..24].copy_from_slice(&self.pid.to_le_bytes());
+        buf
+    }
+}
+
+/// Getlk, setlk and setlkw input.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuseLkIn {
+    pub fh: u64,
+    pub owner: u64,
+    pub lk: FuseFileLock,
+    pub lk_flags: u32,
+    pub padding: u32,
+}
+
+pub const FUSE_LK_IN_SIZE: usize = std::mem::size_of::<FuseLkIn>();
+
+impl FuseLkIn {
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < FUSE_LK_IN_SIZE {
+            return None;
+        }
+        Some(Self {
+            fh: u64::from_le_bytes(data[0..8].try_into().ok()?),
+            owner: u64::from_le_bytes(data[8..16].try_into().ok()?),
+            lk: FuseFileLock::from_bytes(&data[16..40])?,
+            lk_flags: u32::from_le_bytes(data[40..44].try_into().ok()?),
+            padding: u32::from_le_bytes(data[44..48].try_into().ok()?),
+        })
+    }
+}
+
+// Lock flags
+pub const FUSE_LK_FLOCK: u32 = 1 << 0;
+
+/// Getlk output.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuseLkOut {
+    pub lk: FuseFileLock,
+}
+
+pub const FUSE_LK_OUT_SIZE: usize = std::mem::size_of::<FuseLkOut>();
+
+impl FuseLkOut {
+    pub fn to_bytes(self) -> [u8; FUSE_LK_OUT_SIZE] {
+        self.lk.to_bytes()
+    }
+}
+
+/// Getxattr and listxattr input.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuseGetxattrIn {
+    pub size: u32,
+    pub padding: u32,
+}
+
+pub const FUSE_GETXATTR_IN_SIZE: usize = std::mem::size_of::<FuseGetxattrIn>();
+
+impl FuseGetxattrIn {
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < FUSE_GETXATTR_IN_SIZE {
+            return None;
+        }
+        Some(Self {
+            size: u32::from_le_bytes(data[0..4].try_into().ok()?),
+            padding: u32::from_le_bytes(data[4..8].try_into().ok()?),
+        })
+    }
+}
+
+/// Getxattr and listxattr output, when the guest asks for the size only.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuseGetxattrOut {
+    pub size: u32,
+    pub padding: u32,
+}
+
+pub const FUSE_GETXATTR_OUT_SIZE: usize = std::mem::size_of::<FuseGetxattrOut>();
+
+impl FuseGetxattrOut {
+    pub fn to_bytes(self) -> [u8; FUSE_GETXATTR_OUT_SIZE] {
+        let mut buf = [0u8; FUSE_GETXATTR_OUT_SIZE];
+        buf[0..4].copy_from_slice(&self.size.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.padding.to_le_bytes());
+        buf
+    }
+}
+
+/// Setxattr input.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuseSetxattrIn {
+    pub size: u32,
+    pub flags: u32,
+}
+
+pub const FUSE_SETXATTR_IN_SIZE: usize = std::mem::size_of::<FuseSetxattrIn>();
+
+impl FuseSetxattrIn {
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < FUSE_SETXATTR_IN_SIZE {
+            return None;
+        }
+        Some(Self {
+            size: u32::from_le_bytes(data[0..4].try_into().ok()?),
+            flags: u32::from_le_bytes(data[4..8].try_into().ok()?),
+        })
+    }
+}
+
 /// Statfs output.
 #[repr(C)]
 #[derive(Clone, Copy, Default, Debug)]
@@ -879,6 +1028,130 @@ impl FuseDirent {
     }
 }
 
+/// Directory entry with its lookup result (for readdirplus).
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuseDirentplus {
+    pub entry_out: FuseEntryOut,
+    pub dirent: FuseDirent,
+    // name follows, padded to 8-byte boundary
+}
+
+pub const FUSE_DIRENTPLUS_SIZE: usize = std::mem::size_of::<FuseDirentplus>();
+
+impl FuseDirentplus {
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut buf = self.entry_out.to_bytes();
+        buf.extend_from_slice(&self.dirent.to_bytes());
+        buf
+    }
+
+    pub fn entry_size(name_len: usize) -> usize {
+        (FUSE_DIRENTPLUS_SIZE + name_len + 7) & !7
+    }
+}
+
+/// Lseek input.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuseLseekIn {
+    pub fh: u64,
+    pub offset: u64,
+    pub whence: u32,
+    pub padding: u32,
+}
+
+pub const FUSE_LSEEK_IN_SIZE: usize = std::mem::size_of::<FuseLseekIn>();
+
+impl FuseLseekIn {
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < FUSE_LSEEK_IN_SIZE {
+            return None;
+        }
+        Some(Self {
+            fh: u64::from_le_bytes(data[0..8].try_into().ok()?),
+            offset: u64::from_le_bytes(data[8..16].try_into().ok()?),
+            whence: u32::from_le_bytes(data[16..20].try_into().ok()?),
+            padding: u32::from_le_bytes(data[20..24].try_into().ok()?),
+        })
+    }
+}
+
+/// Lseek output.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuseLseekOut {
+    pub offset: u64,
+}
+
+pub const FUSE_LSEEK_OUT_SIZE: usize = std::mem::size_of::<FuseLseekOut>();
+
+impl FuseLseekOut {
+    pub fn to_bytes(self) -> [u8; FUSE_LSEEK_OUT_SIZE] {
+        self.offset.to_le_bytes()
+    }
+}
+
+/// Fallocate input.
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuseFallocateIn {
+    pub fh: u64,
+    pub offset: u64,
+    pub length: u64,
+    pub mode: u32,
+    pub padding: u32,
+}
+
+pub const FUSE_FALLOCATE_IN_SIZE: usize = std::mem::size_of::<FuseFallocateIn>();
+
+impl FuseFallocateIn {
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < FUSE_FALLOCATE_IN_SIZE {
+            return None;
+        }
+        Some(Self {
+            fh: u64::from_le_bytes(data[0..8].try_into().ok()?),
+            offset: u64::from_le_bytes(data[8..16].try_into().ok()?),
+            length: u64::from_le_bytes(data[16..24].try_into().ok()?),
+            mode: u32::from_le_bytes(data[24..28].try_into().ok()?),
+            padding: u32::from_le_bytes(data[28..32].try_into().ok()?),
+        })
+    }
+}
+
+/// Copy_file_range input. The reply is a [`FuseWriteOut`].
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FuseCopyFileRangeIn {
+    pub fh_in: u64,
+    pub off_in: u64,
+    pub nodeid_out: u64,
+    pub fh_out: u64,
+    pub off_out: u64,
+    pub len: u64,
+    pub flags: u64,
+}
+
+pub const FUSE_COPY_FILE_RANGE_IN_SIZE: usize = std::mem::size_of::<FuseCopyFileRangeIn>();
+
+impl FuseCopyFileRangeIn {
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < FUSE_COPY_FILE_RANGE_IN_SIZE {
+            return None;
+        }
+        Some(Self {
+            fh_in: u64::from_le_bytes(data[0..8].try_into().ok()?),
+            off_in: u64::from_le_bytes(data[8..16].try_into().ok()?),
+            nodeid_out: u64::from_le_bytes(data[16..24].try_into().ok()?),
+            fh_out: u64::from_le_bytes(data[24..32].try_into().ok()?),
+            off_out: u64::from_le_bytes(data[32..40].try_into().ok()?),
+            len: u64::from_le_bytes(data[40..48].try_into().ok()?),
+            flags: u64::from_le_bytes(data[48..56].try_into().ok()?),
+        })
+    }
+}
+
 /// Extract a null-terminated string from a byte slice.
 pub fn extract_name(data: &[u8]) -> Option<&str> {
     CStr::from_bytes_until_nul(data)
@@ -980,10 +1253,52 @@ mod tests {
         assert_eq!(header.unique, 999);
     }
 
+    #[test]
+    fn parse_fuse_lk_in() {
+        let mut data = [0u8; 48];
+        data[8..16].copy_from_slice(&7u64.to_le_bytes()); // owner
+        data[16..24].copy_from_slice(&100u64.to_le_bytes()); // lk.start
+        data[24..32].copy_from_slice(&199u64.to_le_bytes()); // lk.end
+        data[32..36].copy_from_slice(&(libc::F_WRLCK as u32).to_le_bytes()); // lk.typ
+        data[40..44].copy_from_slice(&FUSE_LK_FLOCK.to_le_bytes()); // lk_flags
+
+        let lk_in = FuseLkIn::from_bytes(&data).unwrap();
+        assert_eq!(lk_in.owner, 7);
+        assert_eq!(lk_in.lk.start, 100);
+        assert_eq!(lk_in.lk.end, 199);
+        assert_eq!(lk_in.lk.typ, libc::F_WRLCK as u32);
+        assert_eq!(lk_in.lk_flags, FUSE_LK_FLOCK);
+    }
+
+    #[test]
+    fn parse_fuse_setxattr_in() {
+        let mut data = [0u8; 8];
+        data[0..4].copy_from_slice(&5u32.to_le_bytes()); // size
+        data[4..8].copy_from_slice(&1u32.to_le_bytes()); // flags (XATTR_CREATE)
+
+        let setxattr = FuseSetxattrIn::from_bytes(&data).unwrap();
+        assert_eq!(setxattr.size, 5);
+        assert_eq!(setxattr.flags, 1);
+        assert!(FuseSetxattrIn::from_bytes(&data[..4]).is_none());
+    }
+
     #[test]
     fn dirent_entry_size_alignment() {
         assert_eq!(FuseDirent::entry_size(1), 32); // 24 + 1 -> 32
         assert_eq!(FuseDirent::entry_size(8), 32); // 24 + 8 -> 32
         assert_eq!(FuseDirent::entry_size(9), 40); // 24 + 9 -> 40
     }
+
+    #[test]
+    fn direntplus_layout() {
+        assert_eq!(FUSE_ENTRY_OUT_SIZE, 128);
+        assert_eq!(FUSE_DIRENTPLUS_SIZE, 152);
+        assert_eq!(FuseDirentplus::default().to_bytes().len(), 152);
+        assert_eq!(FuseDirentplus::entry_size(1), 160); // 152 + 1 -> 160
+    }
+
+    #[test]
+    fn copy_file_range_in_size() {
+        assert_eq!(FUSE_COPY_FILE_RANGE_IN_SIZE, 56);
+    }
 }
@@ -0,0 +1,209 @@
+//! Extended attributes for virtio-fs.
+//!
+//! Guest attribute names are filtered by namespace before they reach the host.
+//! `security.*` attributes hold LSM labels and file capabilities that the host
+//! acts on, so the guest's are stored under `user.virtiofs.security.*`
+//! instead, and the host's own are hidden from it.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use nix::libc;
+
+const SECURITY_PREFIX: &str = "security.";
+const REMAP_PREFIX: &str = "user.virtiofs.";
+
+/// Largest attribute value or name list Linux allows.
+pub const XATTR_SIZE_MAX: usize = 64 * 1024;
+
+/// Which attributes the guest may use, and where they live on the host.
+#[derive(Debug, Clone, Default)]
+pub struct XattrPolicy {
+    /// Namespaces the guest may use, or `None` for all of them
+    namespaces: Option<Vec<String>>,
+}
+
+impl XattrPolicy {
+    pub fn new(namespaces: Option<Vec<String>>) -> Self {
+        Self { namespaces }
+    }
+
+    /// Maps a guest attribute name to the name it is stored under on the
+    /// host, or returns the errno for names the guest may not use.
+    pub fn to_host(&self, name: &str) -> Result<String, i32> {
+        if !self.allows(name) {
+            return Err(libc::EOPNOTSUPP);
+        }
+        // Otherwise the guest could forge its own security attributes
+        if name.starts_with(REMAP_PREFIX) {
+            return Err(libc::EPERM);
+        }
+        if name.starts_with(SECURITY_PREFIX) {
+            Ok(format!("{REMAP_PREFIX}{name}"))
+        } else {
+            Ok(name.to_string())
+        }
+    }
+
+    /// Maps a host attribute name to the name the guest sees, or returns
+    /// `None` for attributes hidden from the guest.
+    pub fn to_guest<'a>(&self, name: &'a str) -> Option<&'a str> {
+        let guest = match name.strip_prefix(REMAP_PREFIX) {
+            Some(remapped) if remapped.starts_with(SECURITY_PREFIX) => remapped,
+            Some(_) => return None,
+            None if name.starts_with(SECURITY_PREFIX) => return None,
+            None => name,
+        };
+        self.allows(guest).then_some(guest)
+    }
+
+    /// Maps a NUL-separated list of host attribute names to the list the
+    /// guest sees.
+    pub fn guest_list(&self, host_names: &[u8]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for name in host_names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+            let Ok(name) = std::str::from_utf8(name) else {
+                continue;
+            };
+            if let Some(guest) = self.to_guest(name) {
+                list.extend_from_slice(guest.as_bytes());
+                list.push(0);
+            }
+        }
+        list
+    }
+
+    fn allows(&self, name: &str) -> bool {
+        let Some(namespaces) = &self.namespaces else {
+            return true;
+        };
+        let namespace = name.split_once('.').map_or("", |(ns, _)| ns);
+        namespaces.iter().any(|ns| ns == namespace)
+    }
+}
+
+/// Reads an attribute of `path`, without following a final symlink. A
+/// `size` of zero returns a buffer as long as the value without reading it.
+pub fn get(path: &Path, name: &str, size: usize) -> Result<Vec<u8>, i32> {
+    let path = c_path(path)?;
+    let name = CString::new(name).map_err(|_| libc::EINVAL)?;
+    let mut value = vec![0u8; size.min(XATTR_SIZE_MAX)];
+    let ret = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_mut_ptr().cast(),
+            value.len(),
+        )
+    };
+    let len = check(ret)?;
+    value.resize(len, 0);
+    Ok(value)
+}
+
+/// Lists the attribute names of `path` as NUL-separated names, without
+/// following a final symlink.
+pub fn list(path: &Path) -> Result<Vec<u8>, i32> {
+    let path = c_path(path)?;
+    loop {
+        let size = check(unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) })?;
+        let mut names = vec![0u8; size];
+        let ret = unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr().cast(), size) };
+        match check(ret) {
+            Ok(len) => {
+                names.truncate(len);
+                return Ok(names);
+            }
+            // An attribute was added since the size was read
+            Err(libc::ERANGE) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Sets an attribute of `path`, without following a final symlink.
+pub fn set(path: &Path, name: &str, value: &[u8], flags: i32) -> Result<(), i32> {
+    let path = c_path(path)?;
+    let name = CString::new(name).map_err(|_| libc::EINVAL)?;
+    let ret = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr().cast(),
+            value.len(),
+            flags,
+        )
+    };
+    check(ret as isize).map(|_| ())
+}
+
+/// Removes an attribute of `path`, without following a final symlink.
+pub fn remove(path: &Path, name: &str) -> Result<(), i32> {
+    let path = c_path(path)?;
+    let name = CString::new(name).map_err(|_| libc::EINVAL)?;
+    let ret = unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) };
+    check(ret as isize).map(|_| ())
+}
+
+fn c_path(path: &Path) -> Result<CString, i32> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|_| libc::EINVAL)
+}
+
+fn check(ret: isize) -> Result<usize, i32> {
+    if ret < 0 {
+        Err(std::io::Error::last_os_error()
+            .raw_os_error()
+            .unwrap_or(libc::EIO))
+    } else {
+        Ok(ret as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn security_attributes_are_remapped() {
+        let policy = XattrPolicy::default();
+        assert_eq!(
+            policy.to_host("security.selinux").unwrap(),
+            "user.virtiofs.security.selinux"
+        );
+        assert_eq!(
+            policy.to_guest("user.virtiofs.security.selinux"),
+            Some("security.selinux")
+        );
+        assert_eq!(policy.to_host("user.mime_type").unwrap(), "user.mime_type");
+
+        // The host's own security attributes stay hidden, and the guest
+        // cannot reach the remapped ones directly
+        assert_eq!(policy.to_guest("security.selinux"), None);
+        assert_eq!(policy.to_guest("user.virtiofs.other"), None);
+        assert_eq!(
+            policy.to_host("user.virtiofs.security.capability"),
+            Err(libc::EPERM)
+        );
+    }
+
+    #[test]
+    fn namespace_filter_applies_to_guest_names() {
+        let policy = XattrPolicy::new(Some(vec!["user".to_string()]));
+        assert!(policy.to_host("user.comment").is_ok());
+        assert_eq!(policy.to_host("trusted.overlay"), Err(libc::EOPNOTSUPP));
+        assert_eq!(policy.to_host("security.selinux"), Err(libc::EOPNOTSUPP));
+
+        let listed = policy.guest_list(
+            b"user.comment\0trusted.overlay\0user.virtiofs.security.selinux\0system.posix_acl_access\0",
+        );
+        assert_eq!(listed, b"user.comment\0");
+    }
+
+    #[test]
+    fn empty_filter_disables_xattrs() {
+        let policy = XattrPolicy::new(Some(Vec::new()));
+        assert_eq!(policy.to_host("user.comment"), Err(libc::EOPNOTSUPP));
+        assert!(policy.guest_list(b"user.comment\0").is_empty());
+    }
+}
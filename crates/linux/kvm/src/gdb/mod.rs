@@ -0,0 +1,777 @@
+//! GDB remote stub for debugging guest kernels.
+//!
+//! The stub speaks the GDB Remote Serial Protocol on a Unix or TCP socket,
+//! one debugger at a time. The guest is stopped whenever the debugger looks
+//! at it: stopping parks every vCPU through [`PauseControl`], so registers
+//! can be read from the unlocked vCPU fds. Continuing enables guest debugging
+//! on each vCPU with `KVM_SET_GUEST_DEBUG` and releases them again.
+//!
+//! Each vCPU is a thread to the debugger, numbered from 1. Software
+//! breakpoints patch an `int3` into guest memory; hardware breakpoints use
+//! the debug address registers, which also work before the kernel has
+//! mapped its text.
+
+mod packet;
+
+use crate::arch::{
+    DebugStop, MAX_HW_BREAKPOINTS, SW_BREAKPOINT_INSN, clear_guest_debug, read_gdb_registers,
+    reinject_breakpoint, set_guest_debug, translate_gva, write_gdb_registers,
+};
+use crate::pause::PauseControl;
+use capsa_core::{Error, GdbListener, Result};
+use kvm_bindings::kvm_debug_exit_arch;
+use kvm_ioctls::VcpuFd;
+use nix::sys::pthread::Pthread;
+use packet::{
+    INTERRUPT, Incoming, MAX_PACKET_SIZE, decode_hex, encode_hex, parse_hex, read_byte,
+    read_packet, write_packet,
+};
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+/// How often the stub checks for a stopped guest while it runs, and for a
+/// stopped VM while waiting for a debugger.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Guest pages are translated one at a time.
+const PAGE_SIZE: u64 = 4096;
+
+// Error replies carry an errno value
+const EFAULT: &[u8] = b"E0e";
+const EINVAL: &[u8] = b"E16";
+const ENOSPC: &[u8] = b"E1c";
+
+/// Guest memory patched with a software breakpoint.
+struct SoftwareBreakpoint {
+    gpa: u64,
+    original: u8,
+}
+
+/// Debug state shared by the stub and the vCPU threads.
+pub struct GdbControl {
+    pause: Arc<PauseControl>,
+    /// First vCPU to hit a breakpoint or finish a step since the guest was
+    /// last continued
+    stop: Mutex<Option<(usize, DebugStop)>>,
+    /// Software breakpoints by guest virtual address
+    breakpoints: Mutex<HashMap<u64, SoftwareBreakpoint>>,
+}
+
+impl GdbControl {
+    pub fn new(pause: Arc<PauseControl>) -> Self {
+        Self {
+            pause,
+            stop: Mutex::new(None),
+            breakpoints: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn take_stop(&self) -> Option<(usize, DebugStop)> {
+        self.stop.lock().unwrap().take()
+    }
+}
+
+/// Reports the debug exits of one vCPU to the stub.
+pub struct VcpuDebug {
+    pub index: usize,
+    pub control: Arc<GdbControl>,
+}
+
+impl VcpuDebug {
+    /// Handles a `KVM_EXIT_DEBUG` on the vCPU thread.
+    ///
+    /// Stops the guest for the debugger, unless the guest hit an `int3` of
+    /// its own, which goes back to the guest.
+    pub fn stopped(&self, vcpu: &VcpuFd, exit: &kvm_debug_exit_arch) {
+        let reason = DebugStop::from_exit(exit);
+        if reason == DebugStop::SoftwareBreakpoint
+            && !self
+                .control
+                .breakpoints
+                .lock()
+                .unwrap()
+                .contains_key(&exit.pc)
+        {
+            if let Err(e) = reinject_breakpoint(vcpu) {
+                tracing::warn!("failed to reinject guest breakpoint: {}", e);
+            }
+            return;
+        }
+
+        self.control
+            .stop
+            .lock()
+            .unwrap()
+            .get_or_insert((self.index, reason));
+        self.control.pause.request();
+    }
+}
+
+/// Socket the stub waits for a debugger on.
+pub enum GdbSocket {
+    Unix(UnixListener, PathBuf),
+    Tcp(TcpListener),
+}
+
+impl GdbSocket {
+    /// Binds the socket, replacing a stale Unix socket file.
+    pub fn bind(listener: &GdbListener) -> Result<Self> {
+        let bind_error = |e: io::Error| {
+            Error::StartFailed(format!("failed to bind GDB stub to {}: {}", listener, e))
+        };
+        let socket = match listener {
+            GdbListener::Unix(path) => {
+                let _ = std::fs::remove_file(path);
+                Self::Unix(UnixListener::bind(path).map_err(bind_error)?, path.clone())
+            }
+            GdbListener::Tcp(addr) => Self::Tcp(TcpListener::bind(addr).map_err(bind_error)?),
+        };
+        // Accepting polls so the stub notices when the VM stops
+        match &socket {
+            Self::Unix(listener, _) => listener.set_nonblocking(true),
+            Self::Tcp(listener) => listener.set_nonblocking(true),
+        }
+        .map_err(bind_error)?;
+        Ok(socket)
+    }
+
+    fn accept(&self) -> io::Result<Connection> {
+        let connection = match self {
+            Self::Unix(listener, _) => Connection::Unix(listener.accept()?.0),
+            Self::Tcp(listener) => {
+                let stream = listener.accept()?.0;
+                // Replies are small and the debugger waits for each one
+                stream.set_nodelay(true)?;
+                Connection::Tcp(stream)
+            }
+        };
+        connection.set_nonblocking(false)?;
+        Ok(connection)
+    }
+}
+
+impl Drop for GdbSocket {
+    fn drop(&mut self) {
+        if let Self::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Connection to a debugger.
+enum Connection {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+impl Connection {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.set_nonblocking(nonblocking),
+            Self::Tcp(stream) => stream.set_nonblocking(nonblocking),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.set_read_timeout(timeout),
+            Self::Tcp(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.read(buf),
+            Self::Tcp(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Unix(stream) => stream.write(buf),
+            Self::Tcp(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Unix(stream) => stream.flush(),
+            Self::Tcp(stream) => stream.flush(),
+        }
+    }
+}
+
+/// The parts of a VM the debugger works on.
+pub struct GdbStub {
+    pub control: Arc<GdbControl>,
+    pub vcpus: Vec<Arc<Mutex<VcpuFd>>>,
+    pub vcpu_threads: Vec<Pthread>,
+    pub memory: Arc<GuestMemoryMmap>,
+    pub running: Arc<AtomicBool>,
+}
+
+impl GdbStub {
+    /// Serves debuggers on `socket` until the VM stops.
+    ///
+    /// The vCPUs must already be asked to park, so the guest waits for the
+    /// first debugger.
+    pub fn spawn(self, socket: GdbSocket) -> std::thread::JoinHandle<()> {
+        std::thread::spawn(move || {
+            while self.running.load(Ordering::Relaxed) {
+                let connection = match socket.accept() {
+                    Ok(connection) => connection,
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                        std::thread::sleep(POLL_INTERVAL);
+                        continue;
+                    }
+                    Err(e) => {
+                        tracing::warn!("gdb: failed to accept debugger: {}", e);
+                        std::thread::sleep(POLL_INTERVAL);
+                        continue;
+                    }
+                };
+
+                tracing::info!("gdb: debugger attached");
+                let mut session = Session::new(&self, connection);
+                if let Err(e) = session.run() {
+                    tracing::warn!("gdb: debugger connection failed: {}", e);
+                }
+                session.detach();
+                tracing::info!("gdb: debugger detached");
+            }
+        })
+    }
+}
+
+/// What the guest did after it was continued.
+enum StopEvent {
+    Stopped(StopReply),
+    Exited,
+    Disconnected,
+}
+
+/// Stop reply the debugger is sent.
+#[derive(Clone, Copy)]
+struct StopReply {
+    vcpu: usize,
+    signal: u8,
+    reason: Option<DebugStop>,
+}
+
+impl StopReply {
+    const SIGINT: u8 = 2;
+    const SIGTRAP: u8 = 5;
+
+    fn encode(&self) -> Vec<u8> {
+        let mut reply = format!("T{:02x}thread:{:x};", self.signal, self.vcpu + 1);
+        match self.reason {
+            Some(DebugStop::SoftwareBreakpoint) => reply.push_str("swbreak:;"),
+            Some(DebugStop::HardwareBreakpoint) => reply.push_str("hwbreak:;"),
+            _ => {}
+        }
+        reply.into_bytes()
+    }
+}
+
+/// One attached debugger.
+struct Session<'a> {
+    stub: &'a GdbStub,
+    connection: Connection,
+    /// vCPU whose registers and page tables the debugger works with
+    current: usize,
+    last_stop: StopReply,
+    hw_breakpoints: Vec<u64>,
+}
+
+impl<'a> Session<'a> {
+    fn new(stub: &'a GdbStub, connection: Connection) -> Self {
+        Self {
+            stub,
+            connection,
+            current: 0,
+            last_stop: StopReply {
+                vcpu: 0,
+                signal: StopReply::SIGTRAP,
+                reason: None,
+            },
+            hw_breakpoints: Vec::new(),
+        }
+    }
+
+    fn run(&mut self) -> io::Result<()> {
+        // The debugger expects the guest to be stopped when it attaches
+        if !self.stop_guest() {
+            return Ok(());
+        }
+
+        while let Some(incoming) = read_packet(&mut self.connection)? {
+            // Interrupts only matter while the guest runs
+            let Incoming::Packet(packet) = incoming else {
+                continue;
+            };
+            tracing::trace!("gdb: <- {}", String::from_utf8_lossy(&packet));
+
+            match packet.first() {
+                Some(b'c') => match self.resume(false)? {
+                    StopEvent::Stopped(stop) => self.reply(&stop.encode())?,
+                    StopEvent::Exited => return self.reply(b"W00"),
+                    StopEvent::Disconnected => return Ok(()),
+                },
+                Some(b's') => match self.resume(true)? {
+                    StopEvent::Stopped(stop) => self.reply(&stop.encode())?,
+                    StopEvent::Exited => return self.reply(b"W00"),
+                    StopEvent::Disconnected => return Ok(()),
+                },
+                Some(b'D') => return self.reply(b"OK"),
+                // Killing would leave the VM handle with a dead guest, so the
+                // debugger only lets go of it
+                Some(b'k') => return Ok(()),
+                _ => {
+                    let reply = self.handle(&packet);
+                    self.reply(&reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn reply(&mut self, data: &[u8]) -> io::Result<()> {
+        tracing::trace!("gdb: -> {}", String::from_utf8_lossy(data));
+        write_packet(&mut self.connection, data)
+    }
+
+    /// Handles a packet while the guest is stopped.
+    ///
+    /// Unsupported packets get an empty reply, which makes the debugger fall
+    /// back to simpler ones.
+    fn handle(&mut self, packet: &[u8]) -> Vec<u8> {
+        let Some((&command, args)) = packet.split_first() else {
+            return Vec::new();
+        };
+        match command {
+            b'?' => self.last_stop.encode(),
+            b'g' => self.read_registers(),
+            b'G' => self.write_registers(args),
+            b'm' => self.read_memory(args),
+            b'M' => self.write_memory(args),
+            b'H' => self.select_thread(args),
+            b'T' => match parse_thread(args) {
+                Some(vcpu) if vcpu < self.stub.vcpus.len() => b"OK".to_vec(),
+                _ => EINVAL.to_vec(),
+            },
+            b'Z' => self.insert_breakpoint(args),
+            b'z' => self.remove_breakpoint(args),
+            b'q' => self.query(args),
+            _ => Vec::new(),
+        }
+    }
+
+    fn query(&self, query: &[u8]) -> Vec<u8> {
+        if query.starts_with(b"Supported") {
+            format!("PacketSize={:x};swbreak+;hwbreak+", MAX_PACKET_SIZE).into_bytes()
+        } else if query == b"Attached" {
+            b"1".to_vec()
+        } else if query == b"C" {
+            format!("QC{:x}", self.current + 1).into_bytes()
+        } else if query == b"fThreadInfo" {
+            let threads: Vec<String> = (1..=self.stub.vcpus.len())
+                .map(|thread| format!("{:x}", thread))
+                .collect();
+            format!("m{}", threads.join(",")).into_bytes()
+        } else if query == b"sThreadInfo" {
+            b"l".to_vec()
+        } else {
+            Vec::new()
+        }
+    }
+
+    /// Selects the vCPU later commands work on. Step and continue use the
+    /// same vCPU, as the other vCPUs keep running either way.
+    fn select_thread(&mut self, args: &[u8]) -> Vec<u8> {
+        let Some(thread) = args.get(1..) else {
+            return EINVAL.to_vec();
+        };
+        // 0 and -1 mean any and all threads
+        if thread == b"0" || thread == b"-1" {
+            return b"OK".to_vec();
+        }
+        match parse_thread(thread) {
+            Some(vcpu) if vcpu < self.stub.vcpus.len() => {
+                self.current = vcpu;
+                b"OK".to_vec()
+            }
+            _ => EINVAL.to_vec(),
+        }
+    }
+
+    fn read_registers(&self) -> Vec<u8> {
+        let vcpu = self.stub.vcpus[self.current].lock().unwrap();
+        match read_gdb_registers(&vcpu) {
+            Ok(registers) => encode_hex(&registers).into_bytes(),
+            Err(e) => {
+                tracing::warn!("gdb: failed to read vCPU registers: {}", e);
+                EFAULT.to_vec()
+            }
+        }
+    }
+
+    fn write_registers(&self, args: &[u8]) -> Vec<u8> {
+        let Some(data) = decode_hex(args) else {
+            return EINVAL.to_vec();
+        };
+        let vcpu = self.stub.vcpus[self.current].lock().unwrap();
+        match write_gdb_registers(&vcpu, &data) {
+            Ok(()) => b"OK".to_vec(),
+            Err(e) => {
+                tracing::warn!("gdb: failed to write vCPU registers: {}", e);
+                EFAULT.to_vec()
+            }
+        }
+    }
+
+    /// Reads guest virtual memory, stopping early at an unmapped page.
+    fn read_memory(&self, args: &[u8]) -> Vec<u8> {
+        let Some((addr, len)) = parse_range(args) else {
+            return EINVAL.to_vec();
+        };
+        let len = len.min(MAX_PACKET_SIZE as u64 / 2);
+
+        let vcpu = self.stub.vcpus[self.current].lock().unwrap();
+        let mut data = Vec::new();
+        while (data.len() as u64) < len {
+            let gva = addr.wrapping_add(data.len() as u64);
+            let Some(gpa) = translate_gva(&vcpu, gva) else {
+                break;
+            };
+            let mut chunk = vec![0u8; page_chunk(gva, len - data.len() as u64)];
+            if self
+                .stub
+                .memory
+                .read_slice(&mut chunk, GuestAddress(gpa))
+                .is_err()
+            {
+                break;
+            }
+            data.extend_from_slice(&chunk);
+        }
+
+        if data.is_empty() && len > 0 {
+            EFAULT.to_vec()
+        } else {
+            encode_hex(&data).into_bytes()
+        }
+    }
+
+    fn write_memory(&self, args: &[u8]) -> Vec<u8> {
+        let Some(colon) = args.iter().position(|&b| b == b':') else {
+            return EINVAL.to_vec();
+        };
+        let (Some((addr, len)), Some(data)) =
+            (parse_range(&args[..colon]), decode_hex(&args[colon + 1..]))
+        else {
+            return EINVAL.to_vec();
+        };
+        if data.len() as u64 != len {
+            return EINVAL.to_vec();
+        }
+
+        let vcpu = self.stub.vcpus[self.current].lock().unwrap();
+        let mut written = 0;
+        while written < data.len() {
+            let gva = addr.wrapping_add(written as u64);
+            let Some(gpa) = translate_gva(&vcpu, gva) else {
+                return EFAULT.to_vec();
+            };
+            let end = written + page_chunk(gva, (data.len() - written) as u64);
+            if self
+                .stub
+                .memory
+                .write_slice(&data[written..end], GuestAddress(gpa))
+                .is_err()
+            {
+                return EFAULT.to_vec();
+            }
+            written = end;
+        }
+        b"OK".to_vec()
+    }
+
+    fn insert_breakpoint(&mut self, args: &[u8]) -> Vec<u8> {
+        let Some((kind, addr)) = parse_breakpoint(args) else {
+            return EINVAL.to_vec();
+        };
+        match kind {
+            b'0' => {
+                let mut breakpoints = self.stub.control.breakpoints.lock().unwrap();
+                if breakpoints.contains_key(&addr) {
+                    return b"OK".to_vec();
+                }
+                let vcpu = self.stub.vcpus[self.current].lock().unwrap();
+                let Some(gpa) = translate_gva(&vcpu, addr) else {
+                    return EFAULT.to_vec();
+                };
+                let memory = &self.stub.memory;
+                let Ok(original) = memory.read_obj::<u8>(GuestAddress(gpa)) else {
+                    return EFAULT.to_vec();
+                };
+                if memory
+                    .write_obj(SW_BREAKPOINT_INSN, GuestAddress(gpa))
+                    .is_err()
+                {
+                    return EFAULT.to_vec();
+                }
+                breakpoints.insert(addr, SoftwareBreakpoint { gpa, original });
+                b"OK".to_vec()
+            }
+            b'1' => {
+                if self.hw_breakpoints.contains(&addr) {
+                    return b"OK".to_vec();
+                }
+                if self.hw_breakpoints.len() == MAX_HW_BREAKPOINTS {
+                    return ENOSPC.to_vec();
+                }
+                self.hw_breakpoints.push(addr);
+                b"OK".to_vec()
+            }
+            // Watchpoints are not supported
+            _ => Vec::new(),
+        }
+    }
+
+    fn remove_breakpoint(&mut self, args: &[u8]) -> Vec<u8> {
+        let Some((kind, addr)) = parse_breakpoint(args) else {
+            return EINVAL.to_vec();
+        };
+        match kind {
+            b'0' => {
+                let breakpoint = self.stub.control.breakpoints.lock().unwrap().remove(&addr);
+                match breakpoint {
+                    Some(breakpoint) if self.restore(&breakpoint).is_err() => EFAULT.to_vec(),
+                    _ => b"OK".to_vec(),
+                }
+            }
+            b'1' => {
+                self.hw_breakpoints.retain(|&hw| hw != addr);
+                b"OK".to_vec()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn restore(
+        &self,
+        breakpoint: &SoftwareBreakpoint,
+    ) -> std::result::Result<(), vm_memory::GuestMemoryError> {
+        self.stub
+            .memory
+            .write_obj(breakpoint.original, GuestAddress(breakpoint.gpa))
+    }
+
+    /// Parks every vCPU. Returns false if the VM stopped running instead.
+    fn stop_guest(&self) -> bool {
+        self.stub
+            .control
+            .pause
+            .pause(&self.stub.vcpu_threads, &self.stub.running)
+    }
+
+    /// Continues the guest, stepping the current vCPU by one instruction if
+    /// `step` is set, and waits until it stops again.
+    fn resume(&mut self, step: bool) -> io::Result<StopEvent> {
+        for (index, vcpu) in self.stub.vcpus.iter().enumerate() {
+            let vcpu = vcpu.lock().unwrap();
+            if let Err(e) =
+                set_guest_debug(&vcpu, &self.hw_breakpoints, step && index == self.current)
+            {
+                tracing::warn!(
+                    "gdb: failed to set guest debugging on vCPU {}: {}",
+                    index,
+                    e
+                );
+            }
+        }
+        self.stub.control.take_stop();
+        self.stub.control.pause.resume();
+
+        let interrupted = match self.wait_for_stop() {
+            Ok(Some(interrupted)) => interrupted,
+            result => {
+                // Breakpoints can only be cleaned up in a stopped guest
+                self.stop_guest();
+                return result.map(|_| StopEvent::Disconnected);
+            }
+        };
+        if !self.stop_guest() {
+            return Ok(StopEvent::Exited);
+        }
+
+        // A vCPU may have stopped on its own while the guest was interrupted
+        self.last_stop = match self.stub.control.take_stop() {
+            Some((vcpu, reason)) => StopReply {
+                vcpu,
+                signal: StopReply::SIGTRAP,
+                reason: Some(reason),
+            },
+            None => StopReply {
+                vcpu: self.current,
+                signal: if interrupted {
+                    StopReply::SIGINT
+                } else {
+                    StopReply::SIGTRAP
+                },
+                reason: None,
+            },
+        };
+        self.current = self.last_stop.vcpu;
+        Ok(StopEvent::Stopped(self.last_stop))
+    }
+
+    /// Waits until a vCPU stops, the debugger interrupts the guest or the VM
+    /// stops running.
+    ///
+    /// Returns whether the debugger interrupted the guest, or None if it
+    /// disconnected.
+    fn wait_for_stop(&mut self) -> io::Result<Option<bool>> {
+        self.connection.set_read_timeout(Some(POLL_INTERVAL))?;
+        let result = loop {
+            if self.stub.control.pause.is_requested() || !self.stub.running.load(Ordering::Relaxed)
+            {
+                break Ok(Some(false));
+            }
+            match read_byte(&mut self.connection) {
+                Ok(Some(INTERRUPT)) => break Ok(Some(true)),
+                Ok(Some(_)) => continue,
+                Ok(None) => break Ok(None),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    continue;
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        self.connection.set_read_timeout(None)?;
+        result
+    }
+
+    /// Removes all breakpoints and lets the guest run on its own.
+    ///
+    /// The guest is expected to be stopped, unless the VM stopped running.
+    fn detach(&mut self) {
+        if !self.stub.running.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let breakpoints: Vec<SoftwareBreakpoint> = self
+            .stub
+            .control
+            .breakpoints
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, breakpoint)| breakpoint)
+            .collect();
+        for breakpoint in &breakpoints {
+            if let Err(e) = self.restore(breakpoint) {
+                tracing::warn!("gdb: failed to remove breakpoint: {}", e);
+            }
+        }
+        self.hw_breakpoints.clear();
+        for vcpu in &self.stub.vcpus {
+            if let Err(e) = clear_guest_debug(&vcpu.lock().unwrap()) {
+                tracing::warn!("gdb: failed to disable guest debugging: {}", e);
+            }
+        }
+
+        self.stub.control.take_stop();
+        self.stub.control.pause.resume();
+    }
+}
+
+/// Number of bytes from `gva` to access before crossing into the next page.
+fn page_chunk(gva: u64, remaining: u64) -> usize {
+    (PAGE_SIZE - gva % PAGE_SIZE).min(remaining) as usize
+}
+
+/// Parses a thread ID into a vCPU index.
+fn parse_thread(thread: &[u8]) -> Option<usize> {
+    let thread = parse_hex(thread)?;
+    (thread as usize).checked_sub(1)
+}
+
+/// Parses `addr,length`.
+fn parse_range(args: &[u8]) -> Option<(u64, u64)> {
+    let comma = args.iter().position(|&b| b == b',')?;
+    Some((parse_hex(&args[..comma])?, parse_hex(&args[comma + 1..])?))
+}
+
+/// Parses `type,addr,kind` of a breakpoint packet into its type and address.
+fn parse_breakpoint(args: &[u8]) -> Option<(u8, u64)> {
+    let mut fields = args.split(|&b| b == b',');
+    let kind = match fields.next()? {
+        [kind] => *kind,
+        _ => return None,
+    };
+    let addr = parse_hex(fields.next()?)?;
+    fields.next()?;
+    Some((kind, addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_packet_arguments() {
+        assert_eq!(
+            parse_range(b"ffffffff81000000,40"),
+            Some((0xffffffff81000000, 0x40))
+        );
+        assert_eq!(parse_range(b"1000"), None);
+        assert_eq!(
+            parse_breakpoint(b"0,ffffffff81000000,1"),
+            Some((b'0', 0xffffffff81000000))
+        );
+        assert_eq!(parse_breakpoint(b"1,1000"), None);
+        assert_eq!(parse_thread(b"1"), Some(0));
+        assert_eq!(parse_thread(b"0"), None);
+    }
+
+    #[test]
+    fn splits_accesses_at_page_boundaries() {
+        assert_eq!(page_chunk(0x1000, 0x2000), 0x1000);
+        assert_eq!(page_chunk(0x1ff0, 0x100), 0x10);
+        assert_eq!(page_chunk(0x1000, 0x10), 0x10);
+    }
+
+    #[test]
+    fn encodes_stop_replies() {
+        let stop = StopReply {
+            vcpu: 1,
+            signal: StopReply::SIGTRAP,
+            reason: Some(DebugStop::SoftwareBreakpoint),
+        };
+        assert_eq!(stop.encode(), b"T05thread:2;swbreak:;");
+
+        let stop = StopReply {
+            vcpu: 0,
+            signal: StopReply::SIGINT,
+            reason: None,
+        };
+        assert_eq!(stop.encode(), b"T02thread:1;");
+    }
+}
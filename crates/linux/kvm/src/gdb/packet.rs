@@ -0,0 +1,210 @@
+//! Framing of GDB Remote Serial Protocol packets.
+//!
+//! Packets travel as `$<data>#<checksum>`, where the checksum is the sum of
+//! the data bytes modulo 256 in two hex digits. The receiver acknowledges
+//! each packet with `+`, or asks for it again with `-`. A lone 0x03 byte
+//! interrupts a running target.
+
+use std::io::{self, Read, Write};
+
+/// Byte the debugger sends to interrupt a running target (Ctrl-C).
+pub const INTERRUPT: u8 = 0x03;
+
+/// Largest packet the debugger may send, advertised in `qSupported`.
+pub const MAX_PACKET_SIZE: usize = 0x4000;
+
+/// What the debugger sent.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Incoming {
+    Packet(Vec<u8>),
+    Interrupt,
+}
+
+/// Reads the next packet or interrupt from the debugger, acknowledging
+/// packets as they arrive.
+///
+/// Returns None once the debugger disconnects. A packet larger than
+/// [`MAX_PACKET_SIZE`] fails with `InvalidData`.
+pub fn read_packet<S: Read + Write>(stream: &mut S) -> io::Result<Option<Incoming>> {
+    loop {
+        // Acknowledgements of our own replies are not checked
+        match read_byte(stream)? {
+            None => return Ok(None),
+            Some(INTERRUPT) => return Ok(Some(Incoming::Interrupt)),
+            Some(b'$') => {}
+            Some(_) => continue,
+        }
+
+        let mut data = Vec::new();
+        loop {
+            match read_byte(stream)? {
+                None => return Ok(None),
+                Some(b'#') => break,
+                Some(_) if data.len() >= MAX_PACKET_SIZE => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("packet longer than {} bytes", MAX_PACKET_SIZE),
+                    ));
+                }
+                Some(byte) => data.push(byte),
+            }
+        }
+        let mut checksum = [0u8; 2];
+        for digit in &mut checksum {
+            match read_byte(stream)? {
+                None => return Ok(None),
+                Some(byte) => *digit = byte,
+            }
+        }
+
+        if decode_hex(&checksum).as_deref() == Some(&[checksum_of(&data)]) {
+            stream.write_all(b"+")?;
+            return Ok(Some(Incoming::Packet(data)));
+        }
+        tracing::debug!("gdb: bad packet checksum, asking for retransmission");
+        stream.write_all(b"-")?;
+    }
+}
+
+/// Sends a packet to the debugger.
+pub fn write_packet<W: Write>(stream: &mut W, data: &[u8]) -> io::Result<()> {
+    let mut packet = Vec::with_capacity(data.len() + 4);
+    packet.push(b'$');
+    packet.extend_from_slice(data);
+    packet.push(b'#');
+    packet.extend_from_slice(encode_hex(&[checksum_of(data)]).as_bytes());
+    stream.write_all(&packet)?;
+    stream.flush()
+}
+
+/// Reads one byte, returning None at end of stream.
+pub fn read_byte<R: Read>(stream: &mut R) -> io::Result<Option<u8>> {
+    let mut byte = [0u8; 1];
+    loop {
+        match stream.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Encodes bytes as lowercase hex digits.
+pub fn encode_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes hex digits into bytes, returning None if they are malformed.
+pub fn decode_hex(hex: &[u8]) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    hex.chunks_exact(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// Parses a hex number such as an address or length.
+pub fn parse_hex(hex: &[u8]) -> Option<u64> {
+    u64::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// Input read from a buffer, with everything written kept for checking.
+    struct Loopback {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Loopback {
+        fn new(input: &[u8]) -> Self {
+            Self {
+                input: Cursor::new(input.to_vec()),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for Loopback {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Loopback {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn reads_and_acknowledges_packets() {
+        let mut stream = Loopback::new(b"+$g#67$m10,4#2e");
+        assert_eq!(
+            read_packet(&mut stream).unwrap(),
+            Some(Incoming::Packet(b"g".to_vec()))
+        );
+        assert_eq!(
+            read_packet(&mut stream).unwrap(),
+            Some(Incoming::Packet(b"m10,4".to_vec()))
+        );
+        assert_eq!(read_packet(&mut stream).unwrap(), None);
+        assert_eq!(stream.output, b"++");
+    }
+
+    #[test]
+    fn rejects_bad_checksums() {
+        let mut stream = Loopback::new(b"$g#00$g#67");
+        assert_eq!(
+            read_packet(&mut stream).unwrap(),
+            Some(Incoming::Packet(b"g".to_vec()))
+        );
+        assert_eq!(stream.output, b"-+");
+    }
+
+    #[test]
+    fn rejects_oversized_packets() {
+        let mut input = vec![b'$'];
+        input.resize(MAX_PACKET_SIZE + 2, b'0');
+        let mut stream = Loopback::new(&input);
+        let err = read_packet(&mut stream).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(stream.output.is_empty());
+    }
+
+    #[test]
+    fn reads_interrupts() {
+        let mut stream = Loopback::new(&[INTERRUPT]);
+        assert_eq!(read_packet(&mut stream).unwrap(), Some(Incoming::Interrupt));
+    }
+
+    #[test]
+    fn writes_packets() {
+        let mut output = Vec::new();
+        write_packet(&mut output, b"OK").unwrap();
+        assert_eq!(output, b"$OK#9a");
+    }
+
+    #[test]
+    fn converts_hex() {
+        assert_eq!(encode_hex(&[0x00, 0xab, 0x10]), "00ab10");
+        assert_eq!(decode_hex(b"00ab10"), Some(vec![0x00, 0xab, 0x10]));
+        assert_eq!(decode_hex(b"0"), None);
+        assert_eq!(decode_hex(b"zz"), None);
+        assert_eq!(parse_hex(b"ffffffff81000000"), Some(0xffffffff81000000));
+        assert_eq!(parse_hex(b""), None);
+    }
+}
@@ -0,0 +1,200 @@
+//! Interrupt routing for the in-kernel IRQ chip.
+//!
+//! GSIs below [`NUM_IOAPIC_PINS`] are wired to the IOAPIC pin of the same
+//! number, matching the MP table. GSIs above that are handed out to
+//! virtio-pci devices by the [`ResourceAllocator`] and routed to whichever
+//! MSI-X messages the guest programs, so raising one of them with
+//! `KVM_IRQ_LINE` sends those messages.
+//!
+//! [`ResourceAllocator`]: crate::resources::ResourceAllocator
+
+use capsa_core::{Error, Result};
+use kvm_bindings::{
+    KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQ_ROUTING_MSI, KVM_IRQCHIP_IOAPIC, kvm_irq_routing_entry,
+};
+use kvm_ioctls::VmFd;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+pub const NUM_IOAPIC_PINS: usize = 24;
+
+/// GSIs available for MSI routes, two for each PCI device slot.
+pub const MAX_MSI_GSIS: usize = 64;
+
+/// MSI messages a single GSI may be routed to.
+pub const MAX_MESSAGES_PER_GSI: usize = 2;
+
+const MAX_ROUTES: usize = NUM_IOAPIC_PINS + MAX_MSI_GSIS * MAX_MESSAGES_PER_GSI;
+
+#[repr(C)]
+struct KvmIrqRouting {
+    nr: u32,
+    flags: u32,
+    entries: [kvm_irq_routing_entry; MAX_ROUTES],
+}
+
+/// An MSI message as programmed into an MSI-X table entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MsiMessage {
+    pub address: u64,
+    pub data: u32,
+}
+
+/// The VM's GSI routing table.
+pub struct GsiRouting {
+    vm_fd: Arc<VmFd>,
+    state: Mutex<RoutingState>,
+}
+
+#[derive(Default)]
+struct RoutingState {
+    msi: BTreeMap<u32, Vec<MsiMessage>>,
+}
+
+impl GsiRouting {
+    /// Routes every GSI through the IOAPIC, which is required for irqfd to
+    /// work correctly.
+    pub fn new(vm_fd: Arc<VmFd>) -> Result<Self> {
+        let routing = Self {
+            vm_fd,
+            state: Mutex::new(RoutingState::default()),
+        };
+        routing
+            .commit(&routing.state.lock().unwrap())
+            .map_err(|e| Error::StartFailed(format!("failed to set GSI routing: {}", e)))?;
+        tracing::debug!("GSI routing configured for {} IOAPIC pins", NUM_IOAPIC_PINS);
+        Ok(routing)
+    }
+
+    /// Routes `gsi` to `messages`, replacing its previous routes.
+    ///
+    /// An empty list leaves the GSI unrouted.
+    pub fn set_msi_routes(&self, gsi: u32, messages: &[MsiMessage]) -> Result<()> {
+        if messages.len() > MAX_MESSAGES_PER_GSI {
+            return Err(Error::InvalidConfig(format!(
+                "GSI {} routed to {} MSI messages",
+                gsi,
+                messages.len()
+            )));
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.msi.get(&gsi).map_or(&[][..], Vec::as_slice) == messages {
+            return Ok(());
+        }
+        if messages.is_empty() {
+            state.msi.remove(&gsi);
+        } else {
+            state.msi.insert(gsi, messages.to_vec());
+        }
+        self.commit(&state).map_err(|e| {
+            Error::Io(std::io::Error::other(format!(
+                "failed to set GSI routing: {}",
+                e
+            )))
+        })
+    }
+
+    fn commit(&self, state: &RoutingState) -> std::result::Result<(), kvm_ioctls::Error> {
+        let routing = routing_table(&state.msi);
+        let routing_ptr = &*routing as *const KvmIrqRouting as *const kvm_bindings::kvm_irq_routing;
+        unsafe { self.vm_fd.set_gsi_routing(&*routing_ptr) }
+    }
+}
+
+fn routing_table(msi: &BTreeMap<u32, Vec<MsiMessage>>) -> Box<KvmIrqRouting> {
+    let mut routing = Box::new(KvmIrqRouting {
+        nr: 0,
+        flags: 0,
+        entries: [kvm_irq_routing_entry::default(); MAX_ROUTES],
+    });
+
+    // Route the first GSIs through IOAPIC to match MP table configuration
+    for gsi in 0..NUM_IOAPIC_PINS {
+        let entry = &mut routing.entries[gsi];
+        entry.gsi = gsi as u32;
+        entry.type_ = KVM_IRQ_ROUTING_IRQCHIP;
+        entry.u.irqchip.irqchip = KVM_IRQCHIP_IOAPIC;
+        entry.u.irqchip.pin = gsi as u32;
+    }
+
+    // Allocation and set_msi_routes bound the number of entries
+    let messages = msi
+        .iter()
+        .flat_map(|(&gsi, messages)| messages.iter().map(move |message| (gsi, message)));
+    for (entry, (gsi, message)) in routing.entries[NUM_IOAPIC_PINS..].iter_mut().zip(messages) {
+        entry.gsi = gsi;
+        entry.type_ = KVM_IRQ_ROUTING_MSI;
+        entry.u.msi.address_lo = message.address as u32;
+        entry.u.msi.address_hi = (message.address >> 32) as u32;
+        entry.u.msi.data = message.data;
+    }
+
+    routing.nr = (NUM_IOAPIC_PINS + msi.values().map(Vec::len).sum::<usize>()) as u32;
+    routing
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_ioctls::Kvm;
+
+    #[test]
+    fn routes_ioapic_pins_and_msi_messages() {
+        let mut msi = BTreeMap::new();
+        msi.insert(
+            25,
+            vec![MsiMessage {
+                address: 0x1_fee0_1000,
+                data: 0x41,
+            }],
+        );
+        msi.insert(
+            24,
+            vec![
+                MsiMessage {
+                    address: 0xfee0_0000,
+                    data: 0x30,
+                },
+                MsiMessage {
+                    address: 0xfee0_0000,
+                    data: 0x31,
+                },
+            ],
+        );
+        let routing = routing_table(&msi);
+
+        assert_eq!(routing.nr as usize, NUM_IOAPIC_PINS + 3);
+        let entry = &routing.entries[5];
+        assert_eq!((entry.gsi, entry.type_), (5, KVM_IRQ_ROUTING_IRQCHIP));
+        assert_eq!(unsafe { entry.u.irqchip.pin }, 5);
+
+        let entries = &routing.entries[NUM_IOAPIC_PINS..NUM_IOAPIC_PINS + 3];
+        assert!(entries.iter().all(|e| e.type_ == KVM_IRQ_ROUTING_MSI));
+        assert_eq!(
+            entries.iter().map(|e| e.gsi).collect::<Vec<_>>(),
+            [24, 24, 25]
+        );
+        let msi = unsafe { entries[2].u.msi };
+        assert_eq!((msi.address_lo, msi.address_hi), (0xfee0_1000, 1));
+        assert_eq!(msi.data, 0x41);
+    }
+
+    #[test]
+    fn sets_and_clears_msi_routes() {
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = kvm.create_vm().expect("Failed to create VM");
+        vm.create_irq_chip().expect("Failed to create IRQ chip");
+        let routing = GsiRouting::new(Arc::new(vm)).unwrap();
+
+        let message = MsiMessage {
+            address: 0xfee0_0000,
+            data: 0x30,
+        };
+        routing.set_msi_routes(24, &[message]).unwrap();
+        routing.set_msi_routes(24, &[]).unwrap();
+        assert!(matches!(
+            routing.set_msi_routes(24, &[message; 3]),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+}
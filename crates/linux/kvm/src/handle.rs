@@ -1,39 +1,75 @@
+use crate::arch::{read_prstatus, save_vcpu_state, save_vm_state, snapshot_msr_indices};
+use crate::crash::GuestCrash;
+use crate::pause::PauseControl;
+use crate::reboot::{BootState, RebootControl, reboot_guest};
+use crate::snapshot::{SnapshotState, VmDevices, memory_regions, write_snapshot};
+use crate::virtio::{BALLOON_PAGE_SIZE, VirtioBalloon};
+use crate::vmcore::write_vmcore;
+use crate::watchdog::Watchdog;
 use async_trait::async_trait;
-use capsa_core::{AsyncPipe, BackendVmHandle, ConsoleStream, Error, Result};
+use capsa_core::{AsyncPipe, BackendVmHandle, ConsoleStream, Error, ExitReason, Result, VmConfig};
+use kvm_ioctls::{Kvm, VcpuFd, VmFd};
 use nix::fcntl::{FcntlArg, OFlag, fcntl};
 use nix::sys::pthread::{Pthread, pthread_kill};
 use nix::sys::signal::Signal;
 use std::os::fd::{AsRawFd, OwnedFd};
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
 use tokio::sync::{Mutex, mpsc};
 use tokio::task::JoinHandle as TokioJoinHandle;
 use vm_memory::GuestMemoryMmap;
 
+/// Parts of a running VM that are saved in a snapshot.
+pub struct VmComponents {
+    pub vm_fd: Arc<VmFd>,
+    pub config: VmConfig,
+    pub vcpus: Vec<Arc<std::sync::Mutex<VcpuFd>>>,
+    pub devices: VmDevices,
+}
+
 pub struct KvmVmHandle {
     running: Arc<AtomicBool>,
-    exit_rx: Mutex<Option<mpsc::Receiver<i32>>>,
+    pause: Arc<PauseControl>,
+    /// Set while paused by `pause`, as opposed to briefly for a snapshot
+    paused: Arc<AtomicBool>,
+    exit_rx: Mutex<Option<mpsc::Receiver<ExitReason>>>,
+    /// Why the VM stopped, once `wait` has seen it
+    exit_reason: Mutex<Option<ExitReason>>,
     vcpu_handles: Mutex<Vec<std::thread::JoinHandle<()>>>,
-    vcpu_thread_ids: Mutex<Vec<Pthread>>,
+    vcpu_thread_ids: Arc<Mutex<Vec<Pthread>>>,
     console_input_task: Mutex<Option<TokioJoinHandle<()>>>,
     console_read_fd: Mutex<Option<OwnedFd>>,
     console_write_fd: Mutex<Option<OwnedFd>>,
     console_enabled: bool,
-    #[allow(dead_code)]
-    memory: Arc<GuestMemoryMmap>, // Keep memory alive for the VM's lifetime
-    #[allow(dead_code)]
-    network_task: Option<TokioJoinHandle<()>>, // Keep network polling task alive
+    memory: Arc<GuestMemoryMmap>,
+    components: Arc<VmComponents>,
+    crash: Arc<GuestCrash>,
+    /// Virtio-net worker, stopped on kill
+    network_task: Option<TokioJoinHandle<()>>,
+    /// User NAT stack behind virtio-net, stopped on kill
+    network_stack_task: Option<TokioJoinHandle<()>>,
     #[allow(dead_code)]
     serial_irq_task: Option<TokioJoinHandle<()>>, // Keep serial IRQ injection task alive
-    #[allow(dead_code)]
-    vsock_task: Option<TokioJoinHandle<()>>, // Keep vsock polling task alive
+    /// Virtio-vsock worker, stopped on kill
+    vsock_task: Option<TokioJoinHandle<()>>,
+    /// Virtio-rng worker, stopped on kill
+    rng_task: Option<TokioJoinHandle<()>>,
+    /// Virtio-fs lock workers, stopped on kill
+    fs_tasks: Vec<TokioJoinHandle<()>>,
+    /// Reboots the guest in place when it resets, stopped on kill
+    reboot_task: Option<TokioJoinHandle<()>>,
+    /// Acts on the watchdog when the guest stops pinging it, stopped on kill
+    watchdog_task: Option<TokioJoinHandle<()>>,
 }
 
 impl KvmVmHandle {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         running: Arc<AtomicBool>,
-        exit_rx: mpsc::Receiver<i32>,
+        pause: Arc<PauseControl>,
+        exit_rx: mpsc::Receiver<ExitReason>,
         vcpu_handles: Vec<std::thread::JoinHandle<()>>,
         vcpu_thread_ids: Vec<Pthread>,
         console_input_task: Option<TokioJoinHandle<()>>,
@@ -41,24 +77,113 @@ impl KvmVmHandle {
         console_write_fd: Option<OwnedFd>,
         console_enabled: bool,
         memory: Arc<GuestMemoryMmap>,
+        components: VmComponents,
+        crash: Arc<GuestCrash>,
+        reboot: Arc<RebootControl>,
+        boot: Option<BootState>,
         network_task: Option<TokioJoinHandle<()>>,
+        network_stack_task: Option<TokioJoinHandle<()>>,
         serial_irq_task: Option<TokioJoinHandle<()>>,
         vsock_task: Option<TokioJoinHandle<()>>,
+        rng_task: Option<TokioJoinHandle<()>>,
+        fs_tasks: Vec<TokioJoinHandle<()>>,
     ) -> Self {
+        let paused = Arc::new(AtomicBool::new(false));
+        let vcpu_thread_ids = Arc::new(Mutex::new(vcpu_thread_ids));
+        let components = Arc::new(components);
+
+        let reboot_task = boot.map(|boot| {
+            tokio::spawn(reboot_worker(
+                reboot,
+                Arc::new(boot),
+                running.clone(),
+                pause.clone(),
+                paused.clone(),
+                vcpu_thread_ids.clone(),
+                memory.clone(),
+                components.clone(),
+            ))
+        });
+        let watchdog_task = components.devices.watchdog.clone().map(|watchdog| {
+            tokio::spawn(watchdog_worker(
+                watchdog,
+                running.clone(),
+                pause.clone(),
+                vcpu_thread_ids.clone(),
+            ))
+        });
+
         Self {
             running,
+            pause,
+            paused,
             exit_rx: Mutex::new(Some(exit_rx)),
+            exit_reason: Mutex::new(None),
             vcpu_handles: Mutex::new(vcpu_handles),
-            vcpu_thread_ids: Mutex::new(vcpu_thread_ids),
+            vcpu_thread_ids,
             console_input_task: Mutex::new(console_input_task),
             console_read_fd: Mutex::new(console_read_fd),
             console_write_fd: Mutex::new(console_write_fd),
             console_enabled,
             memory,
+            components,
+            crash,
             network_task,
+            network_stack_task,
             serial_irq_task,
             vsock_task,
+            rng_task,
+            fs_tasks,
+            reboot_task,
+            watchdog_task,
+        }
+    }
+
+    /// Runs `f` with all vCPUs parked and device activity stopped.
+    ///
+    /// The VM resumes afterwards, unless it was already paused.
+    async fn run_paused<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&VmComponents, &GuestMemoryMmap) -> Result<()> + Send + 'static,
+    {
+        // Holding the thread IDs keeps `kill` from racing with `f`
+        let thread_ids = self.vcpu_thread_ids.lock().await;
+        if !self.running.load(Ordering::Relaxed) {
+            return Err(Error::NotRunning);
         }
+
+        let threads = thread_ids.clone();
+        let running = self.running.clone();
+        let pause = self.pause.clone();
+        let already_paused = self.paused.load(Ordering::Relaxed);
+        let memory = self.memory.clone();
+        let components = self.components.clone();
+
+        let result = tokio::task::spawn_blocking(move || {
+            if already_paused {
+                return f(&components, &memory);
+            }
+            let result = if pause_vm(&pause, &threads, &running, &components) {
+                f(&components, &memory)
+            } else {
+                Err(Error::NotRunning)
+            };
+            pause.resume();
+            result
+        })
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+
+        drop(thread_ids);
+        result
+    }
+
+    fn balloon(&self) -> Result<&std::sync::Mutex<VirtioBalloon>> {
+        self.components
+            .devices
+            .balloon
+            .as_deref()
+            .ok_or_else(|| Error::UnsupportedFeature("memory balloon".into()))
     }
 }
 
@@ -68,26 +193,40 @@ impl BackendVmHandle for KvmVmHandle {
         self.running.load(Ordering::Relaxed)
     }
 
-    async fn wait(&self) -> Result<i32> {
+    async fn wait(&self) -> Result<ExitReason> {
         let mut rx_guard = self.exit_rx.lock().await;
         if let Some(mut rx) = rx_guard.take() {
             drop(rx_guard);
-            match rx.recv().await {
-                Some(code) => Ok(code),
-                None => Ok(0),
-            }
+            let reason = rx.recv().await.unwrap_or(ExitReason::Killed);
+            *self.exit_reason.lock().await = Some(reason.clone());
+            Ok(reason)
         } else {
-            Ok(0)
+            drop(rx_guard);
+            Ok(self
+                .exit_reason
+                .lock()
+                .await
+                .clone()
+                .unwrap_or(ExitReason::Poweroff))
         }
     }
 
+    /// Presses the ACPI power button so the guest can power itself off.
+    ///
+    /// Returns right away; the caller waits for the guest and kills it if it
+    /// does not react. VMs without the ACPI device are killed instead.
     async fn shutdown(&self) -> Result<()> {
-        // TODO: Implement ACPI shutdown
-        self.kill().await
+        let Some(pm) = &self.components.devices.pm else {
+            return self.kill().await;
+        };
+        pm.lock().unwrap().press_power_button();
+        Ok(())
     }
 
     async fn kill(&self) -> Result<()> {
         self.running.store(false, Ordering::Relaxed);
+        // Parked vCPU threads only notice `running` once released
+        self.pause.resume();
 
         // Send SIGUSR1 to each vCPU thread to interrupt vcpu.run()
         let thread_ids = self.vcpu_thread_ids.lock().await;
@@ -103,6 +242,22 @@ impl BackendVmHandle for KvmVmHandle {
             let _ = handle.join();
         }
 
+        // Device workers would otherwise wait for notifications forever
+        for task in [
+            &self.network_task,
+            &self.network_stack_task,
+            &self.vsock_task,
+            &self.rng_task,
+            &self.reboot_task,
+            &self.watchdog_task,
+        ]
+        .into_iter()
+        .flatten()
+        .chain(&self.fs_tasks)
+        {
+            task.abort();
+        }
+
         // Close the console write pipe to signal EOF to the console input task
         tracing::debug!("kill: closing console write pipe");
         drop(self.console_write_fd.lock().await.take());
@@ -150,6 +305,246 @@ impl BackendVmHandle for KvmVmHandle {
             _ => Err(Error::ConsoleNotEnabled),
         }
     }
+
+    async fn crash_report(&self) -> Option<String> {
+        self.crash.report()
+    }
+
+    async fn pause(&self) -> Result<()> {
+        // Holding the thread IDs keeps `kill` from racing with the pause
+        let thread_ids = self.vcpu_thread_ids.lock().await;
+        if !self.running.load(Ordering::Relaxed) {
+            return Err(Error::NotRunning);
+        }
+        if self.paused.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+
+        let threads = thread_ids.clone();
+        let running = self.running.clone();
+        let pause = self.pause.clone();
+        let components = self.components.clone();
+
+        let paused = tokio::task::spawn_blocking(move || {
+            let paused = pause_vm(&pause, &threads, &running, &components);
+            if !paused {
+                pause.resume();
+            }
+            paused
+        })
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(e)))?;
+
+        if !paused {
+            return Err(Error::NotRunning);
+        }
+        self.paused.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    async fn resume(&self) -> Result<()> {
+        let _thread_ids = self.vcpu_thread_ids.lock().await;
+        if !self.running.load(Ordering::Relaxed) {
+            return Err(Error::NotRunning);
+        }
+        if !self.paused.swap(false, Ordering::Relaxed) {
+            return Err(Error::NotPaused);
+        }
+        self.pause.resume();
+        Ok(())
+    }
+
+    async fn snapshot(&self, path: &Path) -> Result<()> {
+        let path = path.to_path_buf();
+        self.run_paused(move |components, memory| save_snapshot(&path, components, memory))
+            .await
+    }
+
+    /// Writes guest memory and vCPU registers to `path` as an ELF core.
+    ///
+    /// The VM is paused while the dump is written, so its memory and
+    /// registers are consistent.
+    async fn dump_memory(&self, path: &Path) -> Result<()> {
+        let path = path.to_path_buf();
+        self.run_paused(move |components, memory| dump_guest_memory(&path, components, memory))
+            .await
+    }
+
+    async fn set_balloon_size(&self, size_mb: u32) -> Result<()> {
+        let balloon = self.balloon()?;
+        let pages = u64::from(size_mb) * 1024 * 1024 / BALLOON_PAGE_SIZE;
+        let pages = u32::try_from(pages).map_err(|_| {
+            Error::InvalidConfig(format!("balloon size {} MB is too large", size_mb))
+        })?;
+        balloon.lock().unwrap().set_target_pages(pages);
+        Ok(())
+    }
+
+    async fn balloon_size(&self) -> Result<u32> {
+        let pages = self.balloon()?.lock().unwrap().actual_pages();
+        Ok((u64::from(pages) * BALLOON_PAGE_SIZE / (1024 * 1024)) as u32)
+    }
+}
+
+/// Reboots the guest in place each time it resets, until a reboot fails.
+#[allow(clippy::too_many_arguments)]
+async fn reboot_worker(
+    reboot: Arc<RebootControl>,
+    boot: Arc<BootState>,
+    running: Arc<AtomicBool>,
+    pause: Arc<PauseControl>,
+    paused: Arc<AtomicBool>,
+    vcpu_thread_ids: Arc<Mutex<Vec<Pthread>>>,
+    memory: Arc<GuestMemoryMmap>,
+    components: Arc<VmComponents>,
+) {
+    loop {
+        reboot.wait_requested().await;
+
+        // Holding the thread IDs keeps `kill`, `pause` and `snapshot` out
+        let thread_ids = vcpu_thread_ids.lock().await;
+        if !running.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let threads = thread_ids.clone();
+        let running = running.clone();
+        let pause = pause.clone();
+        let already_paused = paused.load(Ordering::Relaxed);
+        let boot = boot.clone();
+        let memory = memory.clone();
+        let components = components.clone();
+
+        let result = tokio::task::spawn_blocking(move || {
+            if !already_paused && !pause_vm(&pause, &threads, &running, &components) {
+                pause.resume();
+                return Ok(());
+            }
+            let result = reboot_guest(&boot, &components, &memory);
+            if !already_paused || result.is_err() {
+                pause.resume();
+            }
+            result
+        })
+        .await
+        .map_err(|e| Error::Io(std::io::Error::other(e)))
+        .and_then(|result| result);
+
+        drop(thread_ids);
+        if let Err(e) = result {
+            tracing::error!("failed to reboot guest: {}", e);
+            reboot.fail(format!("failed to reboot guest: {}", e));
+            return;
+        }
+    }
+}
+
+/// Takes the watchdog's action once the guest goes too long without pinging
+/// it.
+async fn watchdog_worker(
+    watchdog: Arc<Watchdog>,
+    running: Arc<AtomicBool>,
+    pause: Arc<PauseControl>,
+    vcpu_thread_ids: Arc<Mutex<Vec<Pthread>>>,
+) {
+    loop {
+        let deadline = watchdog.deadline();
+        tokio::select! {
+            _ = watchdog.changed() => {}
+            _ = pause.wait_requested() => {
+                // The guest cannot ping a paused VM, so the countdown starts
+                // over once it runs again
+                pause.wait_resumed().await;
+                watchdog.restart();
+            }
+            _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now).into()),
+                if deadline.is_some() =>
+            {
+                if pause.is_requested() || watchdog.deadline() != deadline {
+                    continue;
+                }
+                tracing::warn!("guest watchdog expired");
+                watchdog.expire();
+
+                if !running.load(Ordering::Relaxed) {
+                    // vCPUs in KVM_RUN only notice the VM stopped once
+                    // interrupted
+                    for &tid in vcpu_thread_ids.lock().await.iter() {
+                        let _ = pthread_kill(tid, Signal::SIGUSR1);
+                    }
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Parks the vCPU threads and waits for in-flight device polls to finish.
+///
+/// Returns false if the VM stopped first. The pause request stays in place
+/// either way.
+fn pause_vm(
+    pause: &PauseControl,
+    threads: &[Pthread],
+    running: &AtomicBool,
+    components: &VmComponents,
+) -> bool {
+    if !pause.pause(threads, running) {
+        return false;
+    }
+    components.devices.wait_idle();
+    true
+}
+
+/// Writes the state of a VM whose vCPUs are all parked.
+fn save_snapshot(path: &Path, components: &VmComponents, memory: &GuestMemoryMmap) -> Result<()> {
+    let snapshot_error = |e: kvm_ioctls::Error| {
+        Error::Io(std::io::Error::other(format!(
+            "failed to snapshot VM: {}",
+            e
+        )))
+    };
+
+    let kvm = Kvm::new().map_err(snapshot_error)?;
+    let msr_indices = snapshot_msr_indices(&kvm).map_err(snapshot_error)?;
+
+    let vcpus = components
+        .vcpus
+        .iter()
+        .map(|vcpu| save_vcpu_state(&vcpu.lock().unwrap(), &msr_indices))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(snapshot_error)?;
+
+    let state = SnapshotState {
+        memory_regions: memory_regions(memory),
+        vm: save_vm_state(&components.vm_fd).map_err(snapshot_error)?,
+        vcpus,
+        devices: components.devices.save_state()?,
+    };
+
+    write_snapshot(path, &components.config, &state, memory)
+}
+
+/// Writes a memory dump of a VM whose vCPUs are all parked.
+fn dump_guest_memory(
+    path: &Path,
+    components: &VmComponents,
+    memory: &GuestMemoryMmap,
+) -> Result<()> {
+    let prstatus = components
+        .vcpus
+        .iter()
+        .enumerate()
+        .map(|(index, vcpu)| read_prstatus(&vcpu.lock().unwrap(), index))
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| {
+            Error::Io(std::io::Error::other(format!(
+                "failed to read vCPU registers: {}",
+                e
+            )))
+        })?;
+
+    write_vmcore(path, memory, &prstatus)
 }
 
 fn set_nonblocking(fd: &OwnedFd) -> Result<()> {
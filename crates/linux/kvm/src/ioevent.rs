@@ -0,0 +1,182 @@
+//! Virtio queue notifications delivered through KVM ioeventfds.
+//!
+//! A guest write to a device's queue notification register normally exits to
+//! the vCPU thread, which processes the queue before resuming the guest. With
+//! an ioeventfd registered for the register, KVM completes the write in the
+//! kernel and signals an eventfd, and the device's worker task processes the
+//! queue instead.
+//!
+//! The notify region of a virtio-pci device lives in its BAR, which the guest
+//! may move. Its ioeventfd is registered through a [`NotifierRegistration`]
+//! that the transport moves along with the BAR.
+
+use std::sync::{Arc, Mutex};
+
+use capsa_core::{Error, Result};
+use kvm_ioctls::{IoEventAddress, NoDatamatch, VmFd};
+use tokio::io::unix::AsyncFd;
+use vmm_sys_util::eventfd::{EFD_NONBLOCK, EventFd};
+
+/// Wakes a device worker when the guest notifies any of its queues.
+pub struct QueueNotifier {
+    evt: AsyncFd<EventFd>,
+}
+
+impl QueueNotifier {
+    /// Registers an ioeventfd for the queue notification register at
+    /// `notify_addr`: QUEUE_NOTIFY of a virtio-mmio device, or the notify
+    /// region of a virtio-pci device's BAR.
+    pub fn new(vm_fd: &VmFd, notify_addr: u64) -> Result<Self> {
+        let evt = EventFd::new(EFD_NONBLOCK).map_err(Error::Io)?;
+        vm_fd
+            .register_ioevent(&evt, &IoEventAddress::Mmio(notify_addr), NoDatamatch)
+            .map_err(|e| Error::StartFailed(format!("failed to register ioeventfd: {}", e)))?;
+        let evt = AsyncFd::new(evt).map_err(Error::Io)?;
+        Ok(Self { evt })
+    }
+
+    /// Registers an ioeventfd through `registration`, wherever the guest has
+    /// placed the notification register.
+    pub fn with_registration(registration: &NotifierRegistration) -> Result<Self> {
+        let evt = EventFd::new(EFD_NONBLOCK).map_err(Error::Io)?;
+        registration.register(evt.try_clone().map_err(Error::Io)?)?;
+        let evt = AsyncFd::new(evt).map_err(Error::Io)?;
+        Ok(Self { evt })
+    }
+
+    /// Waits until the guest notifies a queue.
+    ///
+    /// Notifications that arrive while the worker is busy are coalesced into
+    /// a single wakeup.
+    pub async fn notified(&self) -> std::io::Result<()> {
+        let mut guard = self.evt.readable().await?;
+        guard.clear_ready();
+        // Resets the counter; fails with EAGAIN if it was already reset
+        let _ = guard.get_inner().read();
+        Ok(())
+    }
+}
+
+/// The ioeventfd of a queue notification register the guest can move.
+///
+/// The transport calls [`move_to`](Self::move_to) whenever the register's
+/// address changes, and the ioeventfd, once there is one, is registered
+/// there instead. Writes to the old address then reach whatever the guest
+/// put there, rather than the device's notifier.
+#[derive(Clone)]
+pub struct NotifierRegistration {
+    vm_fd: Arc<VmFd>,
+    state: Arc<Mutex<RegistrationState>>,
+}
+
+#[derive(Default)]
+struct RegistrationState {
+    /// Address of the register while the guest can reach it
+    addr: Option<u64>,
+    evt: Option<EventFd>,
+}
+
+impl NotifierRegistration {
+    /// Starts out with the register unreachable, as a BAR is until the guest
+    /// enables memory decoding.
+    pub fn new(vm_fd: Arc<VmFd>) -> Self {
+        Self {
+            vm_fd,
+            state: Default::default(),
+        }
+    }
+
+    /// Moves the ioeventfd to `addr`, or unregisters it while the register
+    /// cannot be reached.
+    pub fn move_to(&self, addr: Option<u64>) {
+        let mut state = self.state.lock().unwrap();
+        if state.addr == addr {
+            return;
+        }
+        if let (Some(evt), Some(old)) = (&state.evt, state.addr)
+            && let Err(e) =
+                self.vm_fd
+                    .unregister_ioevent(evt, &IoEventAddress::Mmio(old), NoDatamatch)
+        {
+            tracing::warn!("failed to unregister ioeventfd at {:#x}: {}", old, e);
+        }
+        // Without an ioeventfd, notifications still reach the device as MMIO
+        // writes
+        if let (Some(evt), Some(new)) = (&state.evt, addr)
+            && let Err(e) =
+                self.vm_fd
+                    .register_ioevent(evt, &IoEventAddress::Mmio(new), NoDatamatch)
+        {
+            tracing::warn!("failed to register ioeventfd at {:#x}: {}", new, e);
+        }
+        state.addr = addr;
+    }
+
+    /// Address the ioeventfd follows, while the guest can reach it.
+    #[cfg(test)]
+    pub fn addr(&self) -> Option<u64> {
+        self.state.lock().unwrap().addr
+    }
+
+    fn register(&self, evt: EventFd) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.evt.is_some() {
+            return Err(Error::StartFailed(
+                "queue notifier registered twice".to_string(),
+            ));
+        }
+        if let Some(addr) = state.addr {
+            self.vm_fd
+                .register_ioevent(&evt, &IoEventAddress::Mmio(addr), NoDatamatch)
+                .map_err(|e| Error::StartFailed(format!("failed to register ioeventfd: {}", e)))?;
+        }
+        state.evt = Some(evt);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_ioctls::Kvm;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn notified_wakes_on_eventfd_signal() {
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = kvm.create_vm().expect("Failed to create VM");
+        let notifier = QueueNotifier::new(&vm, 0xd000_0050).expect("Failed to register");
+
+        // KVM signals the same eventfd when the guest writes the register
+        notifier.evt.get_ref().write(1).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), notifier.notified())
+            .await
+            .expect("notification was not delivered")
+            .unwrap();
+
+        // The counter was reset, so there is nothing left to deliver
+        let pending = tokio::time::timeout(Duration::from_millis(20), notifier.notified()).await;
+        assert!(pending.is_err());
+    }
+
+    #[tokio::test]
+    async fn registration_follows_the_register() {
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = Arc::new(kvm.create_vm().expect("Failed to create VM"));
+        let registration = NotifierRegistration::new(vm.clone());
+        let _notifier = QueueNotifier::with_registration(&registration).unwrap();
+
+        registration.move_to(Some(0xe000_3000));
+        registration.move_to(Some(0xe100_3000));
+
+        // The old address is free again, and the new one is taken
+        let evt = EventFd::new(EFD_NONBLOCK).unwrap();
+        let old = IoEventAddress::Mmio(0xe000_3000);
+        let new = IoEventAddress::Mmio(0xe100_3000);
+        vm.register_ioevent(&evt, &old, NoDatamatch).unwrap();
+        assert!(vm.register_ioevent(&evt, &new, NoDatamatch).is_err());
+
+        registration.move_to(None);
+        vm.register_ioevent(&evt, &new, NoDatamatch).unwrap();
+    }
+}
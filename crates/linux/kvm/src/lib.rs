@@ -7,7 +7,19 @@
 //!
 //! - **Linux Direct Boot**: Boots Linux kernels directly using bzImage format
 //! - **Serial Console**: Provides bidirectional console access via emulated 8250 UART
-//! - **Multi-CPU Support**: Configurable vCPU count
+//! - **Multi-CPU Support**: Configurable vCPU count, topology and host CPU pinning
+//! - **CPU Models**: Masks guest CPUID to an x86-64 level or explicit feature lists
+//! - **Disk Images**: Attaches raw and qcow2 disk images as virtio-blk devices
+//! - **Snapshots**: Saves a running VM to a file and resumes it later
+//! - **Graceful Shutdown**: Signals the guest through an ACPI power button
+//! - **Crash Detection**: Reports guest kernel panics through a pvpanic device
+//! - **Reboot Handling**: Reports guest reboots, or restarts the guest in place
+//! - **Watchdog**: Resets, stops or fails guests that stop pinging an IB700 watchdog
+//! - **Memory Reclaim**: Returns unused guest memory through a virtio-balloon device
+//! - **Entropy**: Feeds the guest randomness from the host through a virtio-rng device
+//! - **Kernel Debugging**: Serves a GDB stub with breakpoints and single-stepping
+//! - **Memory Dumps**: Writes guest memory and registers as an ELF vmcore
+//! - **Virtio PCI**: Optionally attaches devices through virtio-pci with MSI-X instead of virtio-mmio
 //!
 //! # Requirements
 //!
@@ -27,18 +39,29 @@
 //! ```
 
 mod arch;
+mod crash;
 mod fuse;
+mod gdb;
+mod gsi;
 mod handle;
+mod ioevent;
+mod pause;
+mod pci;
+mod reboot;
+mod resources;
 mod serial;
+mod snapshot;
 mod virtio;
 mod vm;
+mod vmcore;
 mod vsock_bridge;
+mod watchdog;
 
 use async_trait::async_trait;
 use capsa_core::{
-    BackendCapabilities, BackendVmHandle, BootMethodSupport, DeviceSupport, GuestOsSupport,
-    HostPlatform, HypervisorBackend, ImageFormatSupport, KernelCmdline, NetworkModeSupport, Result,
-    ShareMechanismSupport, VmConfig,
+    BackendCapabilities, BackendVmHandle, BootMethodSupport, CpuSupport, DeviceSupport,
+    GuestOsSupport, HostPlatform, HypervisorBackend, ImageFormatSupport, KernelCmdline,
+    MemoryBackingSupport, NetworkModeSupport, Result, ShareMechanismSupport, VmConfig,
 };
 use std::path::Path;
 
@@ -61,7 +84,7 @@ impl KvmBackend {
                 },
                 image_formats: ImageFormatSupport {
                     raw: true,
-                    qcow2: false,
+                    qcow2: true,
                 },
                 network_modes: NetworkModeSupport {
                     none: true,
@@ -73,9 +96,28 @@ impl KvmBackend {
                     virtio_fs: true,
                     virtio_9p: false,
                 },
-                devices: DeviceSupport { vsock: true },
+                devices: DeviceSupport {
+                    vsock: true,
+                    balloon: true,
+                    rng: true,
+                    watchdog: true,
+                    virtio_pci: true,
+                },
+                memory_backing: MemoryBackingSupport {
+                    memfd: true,
+                    hugepages: true,
+                    prefault: true,
+                    mergeable: true,
+                },
+                cpu: CpuSupport {
+                    topology: true,
+                    pinning: true,
+                    models: true,
+                },
+                reboot_in_place: true,
+                gdb_stub: true,
                 max_cpus: None,
-                max_memory_mb: None,
+                max_memory_mb: Some(arch::MAX_MEMORY_MB),
             },
         }
     }
@@ -117,11 +159,15 @@ impl HypervisorBackend for KvmBackend {
         let mut cmdline = KernelCmdline::new();
         // Use virtio-console (hvc0) for console I/O
         cmdline.console("hvc0");
-        cmdline.arg("reboot", "t");
+        // Reboot through the keyboard controller, so reboots can be told
+        // apart from triple faults
+        cmdline.arg("reboot", "k");
         cmdline.arg("panic", "-1");
         cmdline.flag("threadirqs"); // Use threaded interrupt handlers
-        // Force use of MP tables for interrupt routing (no ACPI in this minimal VMM)
-        cmdline.arg("acpi", "off");
+        // ACPI only provides the CPUs and power button; interrupts are routed
+        // through the MP table so the virtio-mmio IRQs on the cmdline work,
+        // and the PCI bus is found by probing configuration space
+        cmdline.arg("acpi", "noirq");
         // Note: virtio-net is added dynamically in vm.rs when UserNat is enabled
         cmdline
     }
@@ -129,4 +175,12 @@ impl HypervisorBackend for KvmBackend {
     fn default_root_device(&self) -> &str {
         "/dev/vda"
     }
+
+    fn snapshot_config(&self, path: &Path) -> Result<VmConfig> {
+        snapshot::read_snapshot_config(path)
+    }
+
+    async fn restore(&self, path: &Path, config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
+        vm::restore_vm(path, config).await
+    }
 }
@@ -0,0 +1,245 @@
+//! Parking of vCPU threads and device polling tasks.
+//!
+//! Pausing and snapshots need every vCPU stopped at an instruction boundary
+//! with no I/O in flight. vCPU threads check [`PauseControl::is_requested`] on
+//! every exit and park themselves until the VM is resumed. Device worker
+//! tasks wait in [`PauseControl::wait_resumed`] instead.
+
+use nix::sys::pthread::{Pthread, pthread_kill};
+use nix::sys::signal::Signal;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// How often parked-thread waits are retried while pausing.
+///
+/// A SIGUSR1 that arrives just before a thread enters `KVM_RUN` is lost, so
+/// the signal is repeated until every thread has parked.
+const PAUSE_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+#[derive(Default)]
+struct PauseState {
+    requested: bool,
+    parked: usize,
+}
+
+/// Coordinates pausing and resuming the vCPU threads of a VM.
+#[derive(Default)]
+pub struct PauseControl {
+    state: Mutex<PauseState>,
+    changed: Condvar,
+    /// Mirrors `requested` for async tasks
+    paused: watch::Sender<bool>,
+}
+
+impl PauseControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns true if vCPU threads should park.
+    pub fn is_requested(&self) -> bool {
+        self.state.lock().unwrap().requested
+    }
+
+    /// Parks the calling vCPU thread until the VM is resumed.
+    pub fn park(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.parked += 1;
+        self.changed.notify_all();
+        while state.requested {
+            state = self.changed.wait(state).unwrap();
+        }
+        state.parked -= 1;
+    }
+
+    /// Waits until no pause is requested.
+    ///
+    /// A pause can begin as soon as this returns, so callers that touch guest
+    /// memory must check [`is_requested`](Self::is_requested) again while
+    /// holding the device lock.
+    pub async fn wait_resumed(&self) {
+        let mut paused = self.paused.subscribe();
+        // The sender lives as long as `self`, so this cannot fail
+        let _ = paused.wait_for(|paused| !paused).await;
+    }
+
+    /// Waits until a pause is requested.
+    pub async fn wait_requested(&self) {
+        let mut paused = self.paused.subscribe();
+        // The sender lives as long as `self`, so this cannot fail
+        let _ = paused.wait_for(|paused| *paused).await;
+    }
+
+    /// Runs `f` on a device once no pause is requested.
+    ///
+    /// The request is checked again while holding the device lock, so `f`
+    /// never runs while the VM is paused.
+    pub async fn run_resumed<D, R>(&self, device: &Mutex<D>, f: impl FnOnce(&mut D) -> R) -> R {
+        loop {
+            self.wait_resumed().await;
+            let mut device = device.lock().unwrap();
+            if !self.is_requested() {
+                return f(&mut device);
+            }
+        }
+    }
+
+    /// Asks the vCPU threads to park at their next exit, without waiting.
+    ///
+    /// Lets a vCPU thread stop the VM from a device handler, where waiting
+    /// for itself to park would never return. [`pause`](Self::pause) still
+    /// has to be called before touching vCPU state.
+    pub fn request(&self) {
+        self.state.lock().unwrap().requested = true;
+        self.paused.send_replace(true);
+    }
+
+    /// Stops all vCPU threads and waits until each one has parked.
+    ///
+    /// Returns false if the VM stopped running before all threads parked. The
+    /// pause request stays in place either way; call [`resume`](Self::resume)
+    /// to release the threads.
+    pub fn pause(&self, threads: &[Pthread], running: &AtomicBool) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.requested = true;
+        self.paused.send_replace(true);
+
+        while state.parked < threads.len() {
+            if !running.load(Ordering::Relaxed) {
+                return false;
+            }
+            for &tid in threads {
+                if let Err(e) = pthread_kill(tid, Signal::SIGUSR1) {
+                    tracing::warn!("failed to send SIGUSR1 to vCPU thread: {}", e);
+                }
+            }
+            state = self
+                .changed
+                .wait_timeout(state, PAUSE_RETRY_INTERVAL)
+                .unwrap()
+                .0;
+        }
+        true
+    }
+
+    /// Releases all parked vCPU threads.
+    pub fn resume(&self) {
+        self.state.lock().unwrap().requested = false;
+        self.paused.send_replace(false);
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn pause_waits_for_threads_to_park() {
+        let control = Arc::new(PauseControl::new());
+        let running = Arc::new(AtomicBool::new(true));
+        let iterations = Arc::new(std::sync::atomic::AtomicU64::new(0));
+
+        let (tid_tx, tid_rx) = std::sync::mpsc::channel();
+        let thread = {
+            let control = control.clone();
+            let running = running.clone();
+            let iterations = iterations.clone();
+            std::thread::spawn(move || {
+                let _ = tid_tx.send(nix::sys::pthread::pthread_self());
+                while running.load(Ordering::Relaxed) {
+                    if control.is_requested() {
+                        control.park();
+                        continue;
+                    }
+                    iterations.fetch_add(1, Ordering::Relaxed);
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            })
+        };
+        let tid = tid_rx.recv().unwrap();
+
+        // SIGUSR1 must not kill the test process
+        unsafe {
+            extern "C" fn noop(_: nix::libc::c_int) {}
+            let action = nix::sys::signal::SigAction::new(
+                nix::sys::signal::SigHandler::Handler(noop),
+                nix::sys::signal::SaFlags::empty(),
+                nix::sys::signal::SigSet::empty(),
+            );
+            nix::sys::signal::sigaction(Signal::SIGUSR1, &action).unwrap();
+        }
+
+        assert!(control.pause(&[tid], &running));
+        let paused_at = iterations.load(Ordering::Relaxed);
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(iterations.load(Ordering::Relaxed), paused_at);
+
+        control.resume();
+        running.store(false, Ordering::Relaxed);
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn pause_fails_when_vm_is_not_running() {
+        let control = PauseControl::new();
+        let running = AtomicBool::new(false);
+        let tid = nix::sys::pthread::pthread_self();
+
+        assert!(!control.pause(&[tid], &running));
+        assert!(control.is_requested());
+
+        control.resume();
+        assert!(!control.is_requested());
+    }
+
+    #[tokio::test]
+    async fn wait_resumed_blocks_while_paused() {
+        let control = Arc::new(PauseControl::new());
+        control.wait_resumed().await;
+
+        let running = AtomicBool::new(false);
+        control.pause(&[nix::sys::pthread::pthread_self()], &running);
+
+        let waiter = {
+            let control = control.clone();
+            tokio::spawn(async move { control.wait_resumed().await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        control.resume();
+        tokio::time::timeout(Duration::from_secs(5), waiter)
+            .await
+            .expect("waiter was not released")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn run_resumed_waits_for_resume() {
+        let control = Arc::new(PauseControl::new());
+        let device = Arc::new(Mutex::new(0u32));
+        control.run_resumed(&device, |count| *count += 1).await;
+
+        let running = AtomicBool::new(false);
+        control.pause(&[nix::sys::pthread::pthread_self()], &running);
+
+        let worker = {
+            let control = control.clone();
+            let device = device.clone();
+            tokio::spawn(async move { control.run_resumed(&device, |count| *count += 1).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert_eq!(*device.lock().unwrap(), 1);
+
+        control.resume();
+        tokio::time::timeout(Duration::from_secs(5), worker)
+            .await
+            .expect("worker was not released")
+            .unwrap();
+        assert_eq!(*device.lock().unwrap(), 2);
+    }
+}
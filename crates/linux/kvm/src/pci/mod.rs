@@ -0,0 +1,663 @@
+//! A PCI bus for virtio-pci devices.
+//!
+//! The bus has a single segment and bus number, with a host bridge in slot
+//! 0 and up to 31 single-function devices after it. The guest reaches
+//! configuration space through configuration mechanism #1 (the address and
+//! data ports at 0xCF8 and 0xCFC); there is no ECAM region and no MCFG
+//! table. Guests boot with `acpi=noirq`, so Linux scans the bus itself
+//! instead of looking for a host bridge in the DSDT.
+//!
+//! Each device has one 64-bit memory BAR, placed in the PCI window at
+//! startup. Devices only interrupt through MSI-X, so none has an interrupt
+//! pin and no routing table is needed.
+
+mod msix;
+
+pub use msix::{
+    MSIX_CONTROL_OFFSET, MSIX_ENABLE, MSIX_FUNCTION_MASK, MsixTable, PCI_CAP_ID_MSIX,
+    msix_capability,
+};
+
+use capsa_core::{Error, Result};
+use std::sync::{Arc, Mutex, RwLock};
+use vm_device::bus::{MmioAddress, MmioAddressOffset, PioAddress, PioAddressOffset};
+use vm_device::{DeviceMmio, MutDevicePio};
+
+/// Port of the configuration address register, followed by the data port.
+pub const PCI_CONFIG_IO_PORT: u16 = 0xcf8;
+pub const PCI_CONFIG_IO_SIZE: u16 = 8;
+
+pub const PCI_CONFIG_SIZE: usize = 256;
+
+const PCI_MAX_SLOTS: usize = 32;
+
+// Type 0 configuration header registers
+const PCI_VENDOR_ID: usize = 0x00;
+const PCI_COMMAND: usize = 0x04;
+const PCI_STATUS: usize = 0x06;
+const PCI_REVISION_ID: usize = 0x08;
+const PCI_CLASS_PROG: usize = 0x09;
+const PCI_BAR0: usize = 0x10;
+const PCI_SUBSYSTEM_VENDOR_ID: usize = 0x2c;
+const PCI_CAPABILITY_LIST: usize = 0x34;
+const PCI_INTERRUPT_LINE: usize = 0x3c;
+
+const PCI_COMMAND_MEMORY: u16 = 1 << 1;
+const PCI_COMMAND_MASTER: u16 = 1 << 2;
+const PCI_COMMAND_INTX_DISABLE: u16 = 1 << 10;
+const PCI_STATUS_CAP_LIST: u16 = 1 << 4;
+
+const PCI_BAR_MEM_TYPE_64: u32 = 0x4;
+
+/// First byte after the standard header, where capabilities start.
+const PCI_CAPABILITY_START: usize = 0x40;
+
+// Host bridge identity (the QEMU host bridge, which guests know)
+const HOST_BRIDGE_VENDOR_ID: u16 = 0x1b36;
+const HOST_BRIDGE_DEVICE_ID: u16 = 0x0008;
+const PCI_CLASS_BRIDGE_HOST: u32 = 0x06_00_00;
+
+/// Identity of a PCI function, as reported in its configuration header.
+pub struct PciIdentity {
+    pub vendor_id: u16,
+    pub device_id: u16,
+    /// Class, subclass and programming interface
+    pub class: u32,
+    pub revision: u8,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+}
+
+/// The 256-byte configuration space of a PCI function.
+///
+/// Each byte has a mask of the bits the guest may change; everything else
+/// is read-only.
+pub struct PciConfig {
+    regs: [u8; PCI_CONFIG_SIZE],
+    writable: [u8; PCI_CONFIG_SIZE],
+    /// Power-on contents, restored on reset
+    initial: [u8; PCI_CONFIG_SIZE],
+    bar_size: u64,
+    last_capability: Option<usize>,
+    next_capability: usize,
+}
+
+impl PciConfig {
+    pub fn new(identity: &PciIdentity) -> Self {
+        let mut config = Self {
+            regs: [0; PCI_CONFIG_SIZE],
+            writable: [0; PCI_CONFIG_SIZE],
+            initial: [0; PCI_CONFIG_SIZE],
+            bar_size: 0,
+            last_capability: None,
+            next_capability: PCI_CAPABILITY_START,
+        };
+        config.set(PCI_VENDOR_ID, &identity.vendor_id.to_le_bytes());
+        config.set(PCI_VENDOR_ID + 2, &identity.device_id.to_le_bytes());
+        config.set(PCI_REVISION_ID, &[identity.revision]);
+        config.set(PCI_CLASS_PROG, &identity.class.to_le_bytes()[..3]);
+        config.set(
+            PCI_SUBSYSTEM_VENDOR_ID,
+            &identity.subsystem_vendor_id.to_le_bytes(),
+        );
+        config.set(
+            PCI_SUBSYSTEM_VENDOR_ID + 2,
+            &identity.subsystem_id.to_le_bytes(),
+        );
+
+        let command = PCI_COMMAND_MEMORY | PCI_COMMAND_MASTER | PCI_COMMAND_INTX_DISABLE;
+        config.writable[PCI_COMMAND..PCI_COMMAND + 2].copy_from_slice(&command.to_le_bytes());
+        config.writable[PCI_INTERRUPT_LINE] = 0xff;
+        config
+    }
+
+    /// Gives the function a 64-bit memory BAR of `size` bytes at `addr`,
+    /// using BAR0 and BAR1.
+    ///
+    /// `size` must be a power of two, so the guest can size the BAR by
+    /// writing all ones and reading back the address bits that stuck.
+    pub fn set_bar(&mut self, addr: u64, size: u64) {
+        self.bar_size = size;
+        let low = (addr as u32 & !0xf) | PCI_BAR_MEM_TYPE_64;
+        self.set(PCI_BAR0, &low.to_le_bytes());
+        self.set(PCI_BAR0 + 4, &((addr >> 32) as u32).to_le_bytes());
+        let low_mask = !(size - 1) as u32 & !0xf;
+        self.writable[PCI_BAR0..PCI_BAR0 + 4].copy_from_slice(&low_mask.to_le_bytes());
+        self.writable[PCI_BAR0 + 4..PCI_BAR0 + 8].copy_from_slice(&[0xff; 4]);
+    }
+
+    /// Appends a capability to the capability list and returns its offset.
+    ///
+    /// `body` follows the capability ID and next pointer, and `writable`
+    /// masks the bits of `body` the guest may change.
+    pub fn add_capability(&mut self, id: u8, body: &[u8], writable: &[u8]) -> usize {
+        let offset = self.next_capability;
+        assert!(
+            offset + 2 + body.len() <= PCI_CONFIG_SIZE,
+            "PCI capabilities overflow configuration space"
+        );
+        match self.last_capability {
+            Some(last) => self.set(last + 1, &[offset as u8]),
+            None => {
+                self.set(PCI_CAPABILITY_LIST, &[offset as u8]);
+                let status = self.read_u16(PCI_STATUS) | PCI_STATUS_CAP_LIST;
+                self.set(PCI_STATUS, &status.to_le_bytes());
+            }
+        }
+        self.set(offset, &[id, 0]);
+        self.set(offset + 2, body);
+        self.writable[offset + 2..offset + 2 + writable.len()].copy_from_slice(writable);
+
+        self.last_capability = Some(offset);
+        self.next_capability = (offset + 2 + body.len()).next_multiple_of(4);
+        offset
+    }
+
+    pub fn read(&self, offset: usize, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.regs.get(offset + i).copied().unwrap_or(0xff);
+        }
+    }
+
+    pub fn write(&mut self, offset: usize, data: &[u8]) {
+        for (i, value) in data.iter().enumerate() {
+            let Some(mask) = self.writable.get(offset + i) else {
+                break;
+            };
+            let reg = &mut self.regs[offset + i];
+            *reg = (*reg & !mask) | (value & mask);
+        }
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_le_bytes([self.regs[offset], self.regs[offset + 1]])
+    }
+
+    pub fn read_u32(&self, offset: usize) -> u32 {
+        u32::from_le_bytes(self.regs[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Returns the guest address and size of the BAR while the guest has
+    /// memory decoding enabled.
+    pub fn bar_range(&self) -> Option<(u64, u64)> {
+        if self.bar_size == 0 || self.read_u16(PCI_COMMAND) & PCI_COMMAND_MEMORY == 0 {
+            return None;
+        }
+        let low = u64::from(self.read_u32(PCI_BAR0) & !0xf);
+        let high = u64::from(self.read_u32(PCI_BAR0 + 4));
+        Some(((high << 32) | low, self.bar_size))
+    }
+
+    /// Puts configuration space back into its power-on state.
+    pub fn reset(&mut self) {
+        self.regs = self.initial;
+    }
+
+    /// Sets power-on contents.
+    fn set(&mut self, offset: usize, bytes: &[u8]) {
+        self.regs[offset..offset + bytes.len()].copy_from_slice(bytes);
+        self.initial[offset..offset + bytes.len()].copy_from_slice(bytes);
+    }
+}
+
+/// A function on the PCI bus.
+pub trait PciDevice: Send {
+    fn config(&self) -> &PciConfig;
+
+    /// Handles a guest write to configuration space.
+    fn write_config(&mut self, offset: usize, data: &[u8]);
+
+    /// Handles a guest read from the BAR, at `offset` from its start.
+    fn read_bar(&mut self, offset: u64, data: &mut [u8]);
+
+    /// Handles a guest write to the BAR, at `offset` from its start.
+    fn write_bar(&mut self, offset: u64, data: &[u8]);
+
+    /// Puts the function back into its power-on state.
+    fn reset(&mut self);
+}
+
+struct HostBridge {
+    config: PciConfig,
+}
+
+impl PciDevice for HostBridge {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        self.config.write(offset, data);
+    }
+
+    fn read_bar(&mut self, _offset: u64, data: &mut [u8]) {
+        data.fill(0xff);
+    }
+
+    fn write_bar(&mut self, _offset: u64, _data: &[u8]) {}
+
+    fn reset(&mut self) {
+        self.config.reset();
+    }
+}
+
+/// Bus 0 and the PCI memory window its BARs live in.
+///
+/// Accesses to the window are decoded through a table of BAR ranges kept
+/// outside the device locks, which is updated whenever the guest writes
+/// configuration space.
+pub struct PciBus {
+    devices: Vec<Arc<Mutex<dyn PciDevice>>>,
+    bars: RwLock<Vec<Option<(u64, u64)>>>,
+}
+
+impl PciBus {
+    pub fn new() -> Self {
+        let host_bridge = HostBridge {
+            config: PciConfig::new(&PciIdentity {
+                vendor_id: HOST_BRIDGE_VENDOR_ID,
+                device_id: HOST_BRIDGE_DEVICE_ID,
+                class: PCI_CLASS_BRIDGE_HOST,
+                revision: 0,
+                subsystem_vendor_id: 0,
+                subsystem_id: 0,
+            }),
+        };
+        Self {
+            devices: vec![Arc::new(Mutex::new(host_bridge))],
+            bars: RwLock::new(vec![None]),
+        }
+    }
+
+    /// Plugs `device` into the next free slot and returns the slot number.
+    pub fn add_device(&mut self, device: Arc<Mutex<dyn PciDevice>>) -> Result<u8> {
+        if self.devices.len() == PCI_MAX_SLOTS {
+            return Err(Error::InvalidConfig(format!(
+                "too many PCI devices: bus 0 has {} slots",
+                PCI_MAX_SLOTS - 1
+            )));
+        }
+        let bar = device.lock().unwrap().config().bar_range();
+        self.devices.push(device);
+        self.bars.get_mut().unwrap().push(bar);
+        Ok((self.devices.len() - 1) as u8)
+    }
+
+    /// Reads configuration space of function 0 in `slot`. Absent functions
+    /// read as all ones.
+    pub fn read_config(&self, slot: u8, function: u8, offset: usize, data: &mut [u8]) {
+        match self.function(slot, function) {
+            Some(device) => device.lock().unwrap().config().read(offset, data),
+            None => data.fill(0xff),
+        }
+    }
+
+    pub fn write_config(&self, slot: u8, function: u8, offset: usize, data: &[u8]) {
+        let Some(device) = self.function(slot, function) else {
+            return;
+        };
+        let bar = {
+            let mut device = device.lock().unwrap();
+            device.write_config(offset, data);
+            device.config().bar_range()
+        };
+        self.bars.write().unwrap()[slot as usize] = bar;
+    }
+
+    /// Resets every function, for a guest that reboots in place.
+    pub fn reset(&self) {
+        for (slot, device) in self.devices.iter().enumerate() {
+            let bar = {
+                let mut device = device.lock().unwrap();
+                device.reset();
+                device.config().bar_range()
+            };
+            self.bars.write().unwrap()[slot] = bar;
+        }
+    }
+
+    fn function(&self, slot: u8, function: u8) -> Option<&Arc<Mutex<dyn PciDevice>>> {
+        if function != 0 {
+            return None;
+        }
+        self.devices.get(slot as usize)
+    }
+
+    /// Finds the device whose BAR holds `len` bytes at `addr`, with the
+    /// offset of `addr` into the BAR.
+    fn decode(&self, addr: u64, len: usize) -> Option<(&Arc<Mutex<dyn PciDevice>>, u64)> {
+        let bars = self.bars.read().unwrap();
+        bars.iter()
+            .position(|bar| {
+                bar.is_some_and(|(base, size)| addr >= base && addr + len as u64 <= base + size)
+            })
+            .map(|slot| (&self.devices[slot], addr - bars[slot].unwrap().0))
+    }
+}
+
+impl Default for PciBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DeviceMmio for PciBus {
+    fn mmio_read(&self, base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        match self.decode(base.0 + offset, data.len()) {
+            Some((device, offset)) => device.lock().unwrap().read_bar(offset, data),
+            None => data.fill(0xff),
+        }
+    }
+
+    fn mmio_write(&self, base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        if let Some((device, offset)) = self.decode(base.0 + offset, data.len()) {
+            device.lock().unwrap().write_bar(offset, data);
+        }
+    }
+}
+
+/// Configuration mechanism #1: the guest writes the bus, device, function
+/// and register it wants to 0xCF8, then accesses the register through
+/// 0xCFC-0xCFF.
+pub struct PciConfigIo {
+    bus: Arc<PciBus>,
+    address: u32,
+}
+
+const CONFIG_ADDRESS_ENABLE: u32 = 1 << 31;
+/// Bits of the address register that hold a value; the rest read as zero.
+const CONFIG_ADDRESS_MASK: u32 = 0x80ff_fffc;
+
+impl PciConfigIo {
+    pub fn new(bus: Arc<PciBus>) -> Self {
+        Self { bus, address: 0 }
+    }
+
+    /// Returns the slot, function and register offset the address register
+    /// selects, if it is enabled and selects bus 0.
+    fn target(&self, port_offset: PioAddressOffset) -> Option<(u8, u8, usize)> {
+        if self.address & CONFIG_ADDRESS_ENABLE == 0 || (self.address >> 16) & 0xff != 0 {
+            return None;
+        }
+        let slot = ((self.address >> 11) & 0x1f) as u8;
+        let function = ((self.address >> 8) & 0x7) as u8;
+        let register = (self.address & 0xfc) as usize + (port_offset as usize - 4);
+        Some((slot, function, register))
+    }
+}
+
+impl MutDevicePio for PciConfigIo {
+    fn pio_read(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &mut [u8]) {
+        match offset {
+            0 if data.len() == 4 => data.copy_from_slice(&self.address.to_le_bytes()),
+            4..=7 => match self.target(offset) {
+                Some((slot, function, register)) => {
+                    self.bus.read_config(slot, function, register, data)
+                }
+                None => data.fill(0xff),
+            },
+            _ => data.fill(0xff),
+        }
+    }
+
+    fn pio_write(&mut self, _base: PioAddress, offset: PioAddressOffset, data: &[u8]) {
+        match offset {
+            // Byte writes to 0xCF8-0xCFB belong to other chipset registers
+            // that guests probe for; they are ignored
+            0 if data.len() == 4 => {
+                self.address = u32::from_le_bytes(data.try_into().unwrap()) & CONFIG_ADDRESS_MASK;
+            }
+            4..=7 => {
+                if let Some((slot, function, register)) = self.target(offset) {
+                    self.bus.write_config(slot, function, register, data);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::arch::PCI_MMIO_START;
+
+    const BAR_SIZE: u64 = 0x8000;
+
+    struct TestDevice {
+        config: PciConfig,
+        last_write: Option<(u64, Vec<u8>)>,
+    }
+
+    impl TestDevice {
+        fn new(bar: u64) -> Self {
+            let mut config = PciConfig::new(&PciIdentity {
+                vendor_id: 0x1af4,
+                device_id: 0x1042,
+                class: 0x01_00_00,
+                revision: 1,
+                subsystem_vendor_id: 0x1af4,
+                subsystem_id: 0x1100,
+            });
+            config.set_bar(bar, BAR_SIZE);
+            Self {
+                config,
+                last_write: None,
+            }
+        }
+    }
+
+    impl PciDevice for TestDevice {
+        fn config(&self) -> &PciConfig {
+            &self.config
+        }
+
+        fn write_config(&mut self, offset: usize, data: &[u8]) {
+            self.config.write(offset, data);
+        }
+
+        fn read_bar(&mut self, offset: u64, data: &mut [u8]) {
+            data.fill(offset as u8);
+        }
+
+        fn write_bar(&mut self, offset: u64, data: &[u8]) {
+            self.last_write = Some((offset, data.to_vec()));
+        }
+
+        fn reset(&mut self) {
+            self.config.reset();
+        }
+    }
+
+    fn config_address(slot: u8, register: u8) -> [u8; 4] {
+        (CONFIG_ADDRESS_ENABLE | (u32::from(slot) << 11) | u32::from(register)).to_le_bytes()
+    }
+
+    fn read_config_u32(io: &mut PciConfigIo, slot: u8, register: u8) -> u32 {
+        io.pio_write(
+            PioAddress(PCI_CONFIG_IO_PORT),
+            0,
+            &config_address(slot, register),
+        );
+        let mut data = [0u8; 4];
+        io.pio_read(PioAddress(PCI_CONFIG_IO_PORT), 4, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_config_u32(io: &mut PciConfigIo, slot: u8, register: u8, value: u32) {
+        io.pio_write(
+            PioAddress(PCI_CONFIG_IO_PORT),
+            0,
+            &config_address(slot, register),
+        );
+        io.pio_write(PioAddress(PCI_CONFIG_IO_PORT), 4, &value.to_le_bytes());
+    }
+
+    fn bus_with_device() -> (Arc<PciBus>, Arc<Mutex<TestDevice>>, u64) {
+        let mut bus = PciBus::new();
+        let bar = PCI_MMIO_START;
+        let device = Arc::new(Mutex::new(TestDevice::new(bar)));
+        assert_eq!(bus.add_device(device.clone()).unwrap(), 1);
+        (Arc::new(bus), device, bar)
+    }
+
+    #[test]
+    fn address_register_reads_back() {
+        let (bus, _, _) = bus_with_device();
+        let mut io = PciConfigIo::new(bus);
+        let base = PioAddress(PCI_CONFIG_IO_PORT);
+
+        // Linux probes for mechanism #1 this way
+        io.pio_write(base, 3, &[0x01]);
+        io.pio_write(base, 0, &0x8000_0000u32.to_le_bytes());
+        let mut data = [0u8; 4];
+        io.pio_read(base, 0, &mut data);
+        assert_eq!(u32::from_le_bytes(data), 0x8000_0000);
+    }
+
+    #[test]
+    fn scans_bus_zero() {
+        let (bus, _, _) = bus_with_device();
+        let mut io = PciConfigIo::new(bus);
+
+        assert_eq!(read_config_u32(&mut io, 0, 0), 0x0008_1b36);
+        assert_eq!(
+            read_config_u32(&mut io, 0, 0x08) >> 8,
+            PCI_CLASS_BRIDGE_HOST
+        );
+        assert_eq!(read_config_u32(&mut io, 1, 0), 0x1042_1af4);
+        assert_eq!(read_config_u32(&mut io, 1, 0x2c), 0x1100_1af4);
+        assert_eq!(read_config_u32(&mut io, 2, 0), 0xffff_ffff);
+
+        // Other functions and buses are empty
+        io.pio_write(
+            PioAddress(PCI_CONFIG_IO_PORT),
+            0,
+            &(CONFIG_ADDRESS_ENABLE | (1 << 11) | (1 << 8)).to_le_bytes(),
+        );
+        let mut data = [0u8; 4];
+        io.pio_read(PioAddress(PCI_CONFIG_IO_PORT), 4, &mut data);
+        assert_eq!(data, [0xff; 4]);
+        io.pio_write(
+            PioAddress(PCI_CONFIG_IO_PORT),
+            0,
+            &(CONFIG_ADDRESS_ENABLE | (1 << 16)).to_le_bytes(),
+        );
+        io.pio_read(PioAddress(PCI_CONFIG_IO_PORT), 4, &mut data);
+        assert_eq!(data, [0xff; 4]);
+    }
+
+    #[test]
+    fn reads_single_bytes_of_registers() {
+        let (bus, _, _) = bus_with_device();
+        let mut io = PciConfigIo::new(bus);
+
+        io.pio_write(PioAddress(PCI_CONFIG_IO_PORT), 0, &config_address(1, 0));
+        let mut byte = [0u8];
+        io.pio_read(PioAddress(PCI_CONFIG_IO_PORT), 6, &mut byte);
+        assert_eq!(byte, [0x42]);
+    }
+
+    #[test]
+    fn bar_sizing() {
+        let (bus, _, bar) = bus_with_device();
+        let mut io = PciConfigIo::new(bus);
+
+        assert_eq!(
+            read_config_u32(&mut io, 1, 0x10),
+            bar as u32 | PCI_BAR_MEM_TYPE_64
+        );
+        write_config_u32(&mut io, 1, 0x10, 0xffff_ffff);
+        write_config_u32(&mut io, 1, 0x14, 0xffff_ffff);
+        assert_eq!(
+            read_config_u32(&mut io, 1, 0x10),
+            !(BAR_SIZE as u32 - 1) | PCI_BAR_MEM_TYPE_64
+        );
+        assert_eq!(read_config_u32(&mut io, 1, 0x14), 0xffff_ffff);
+
+        write_config_u32(&mut io, 1, 0x10, bar as u32);
+        write_config_u32(&mut io, 1, 0x14, 0);
+        assert_eq!(
+            read_config_u32(&mut io, 1, 0x10),
+            bar as u32 | PCI_BAR_MEM_TYPE_64
+        );
+    }
+
+    #[test]
+    fn decodes_bar_only_with_memory_enabled() {
+        let (bus, device, bar) = bus_with_device();
+        let mut io = PciConfigIo::new(bus.clone());
+        let window = MmioAddress(PCI_MMIO_START);
+        let offset = bar - PCI_MMIO_START + 0x10;
+
+        let mut data = [0u8; 4];
+        bus.mmio_read(window, offset, &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        write_config_u32(&mut io, 1, 0x04, u32::from(PCI_COMMAND_MEMORY));
+        bus.mmio_read(window, offset, &mut data);
+        assert_eq!(data, [0x10; 4]);
+        bus.mmio_write(window, offset, &[1, 2]);
+        assert_eq!(device.lock().unwrap().last_write, Some((0x10, vec![1, 2])));
+
+        // Accesses must not run past the end of the BAR
+        bus.mmio_read(window, offset - 0x10 + BAR_SIZE - 2, &mut data);
+        assert_eq!(data, [0xff; 4]);
+
+        bus.reset();
+        bus.mmio_read(window, offset, &mut data);
+        assert_eq!(data, [0xff; 4]);
+    }
+
+    #[test]
+    fn read_only_registers_ignore_writes() {
+        let (bus, _, _) = bus_with_device();
+        let mut io = PciConfigIo::new(bus);
+
+        write_config_u32(&mut io, 1, 0, 0);
+        assert_eq!(read_config_u32(&mut io, 1, 0), 0x1042_1af4);
+        write_config_u32(&mut io, 1, 0x3c, 0xffff_ff0b);
+        assert_eq!(read_config_u32(&mut io, 1, 0x3c), 0x0b);
+    }
+
+    #[test]
+    fn chains_capabilities() {
+        let mut config = PciConfig::new(&PciIdentity {
+            vendor_id: 0x1af4,
+            device_id: 0x1041,
+            class: 0x02_00_00,
+            revision: 1,
+            subsystem_vendor_id: 0x1af4,
+            subsystem_id: 0x1100,
+        });
+        assert_eq!(config.add_capability(0x11, &[0; 10], &[0xff; 10]), 0x40);
+        assert_eq!(config.add_capability(0x09, &[0; 14], &[]), 0x4c);
+
+        assert_ne!(config.read_u16(PCI_STATUS) & PCI_STATUS_CAP_LIST, 0);
+        assert_eq!(config.regs[PCI_CAPABILITY_LIST], 0x40);
+        assert_eq!(config.regs[0x40..0x42], [0x11, 0x4c]);
+        assert_eq!(config.regs[0x4c..0x4e], [0x09, 0]);
+
+        config.write(0x42, &[0xaa]);
+        config.write(0x4e, &[0xaa]);
+        assert_eq!((config.regs[0x42], config.regs[0x4e]), (0xaa, 0));
+        config.reset();
+        assert_eq!(config.regs[0x42], 0);
+        assert_eq!(config.regs[0x40..0x42], [0x11, 0x4c]);
+    }
+
+    #[test]
+    fn rejects_devices_past_the_last_slot() {
+        let mut bus = PciBus::new();
+        for slot in 1..PCI_MAX_SLOTS {
+            let bar = PCI_MMIO_START + (slot as u64 - 1) * BAR_SIZE;
+            let device = Arc::new(Mutex::new(TestDevice::new(bar)));
+            assert_eq!(bus.add_device(device).unwrap() as usize, slot);
+        }
+        let device = Arc::new(Mutex::new(TestDevice::new(0)));
+        assert!(matches!(
+            bus.add_device(device),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+}
@@ -0,0 +1,168 @@
+//! MSI-X capability and vector table.
+//!
+//! Each table entry holds the address and data of the message the guest
+//! wants for that vector, and a mask bit. The pending bit array is not
+//! emulated: interrupts raised while a vector is masked are resent once it
+//! is unmasked, which is indistinguishable for a guest that only clears
+//! masks.
+
+use crate::gsi::MsiMessage;
+
+/// MSI-X capability ID.
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+/// Message control: all vectors are masked.
+pub const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+/// Message control: MSI-X is enabled.
+pub const MSIX_ENABLE: u16 = 1 << 15;
+
+/// Offset of message control within the capability.
+pub const MSIX_CONTROL_OFFSET: usize = 2;
+
+/// Size of a table entry: address (8), data (4), vector control (4).
+pub const MSIX_ENTRY_SIZE: u64 = 16;
+
+const VECTOR_CONTROL_MASKED: u32 = 1;
+
+/// Returns the body of an MSI-X capability after its ID and next pointer,
+/// with the mask of bits the guest may write, for `table_size` vectors
+/// whose table and pending bits are at the given offsets of BAR `bar`.
+pub fn msix_capability(
+    table_size: u16,
+    bar: u8,
+    table_offset: u32,
+    pba_offset: u32,
+) -> (Vec<u8>, Vec<u8>) {
+    let mut body = Vec::with_capacity(10);
+    body.extend_from_slice(&(table_size - 1).to_le_bytes());
+    body.extend_from_slice(&(table_offset | u32::from(bar)).to_le_bytes());
+    body.extend_from_slice(&(pba_offset | u32::from(bar)).to_le_bytes());
+
+    // Only the enable and function mask bits are writable
+    let mut writable = vec![0; body.len()];
+    writable[..2].copy_from_slice(&(MSIX_ENABLE | MSIX_FUNCTION_MASK).to_le_bytes());
+    (body, writable)
+}
+
+/// The MSI-X vector table of a PCI function.
+pub struct MsixTable {
+    entries: Vec<[u8; MSIX_ENTRY_SIZE as usize]>,
+}
+
+impl MsixTable {
+    /// Creates a table of `size` vectors, all masked.
+    pub fn new(size: usize) -> Self {
+        let mut table = Self {
+            entries: vec![[0; MSIX_ENTRY_SIZE as usize]; size],
+        };
+        table.reset();
+        table
+    }
+
+    /// Size of the table in bytes.
+    pub fn size(&self) -> u64 {
+        self.entries.len() as u64 * MSIX_ENTRY_SIZE
+    }
+
+    pub fn read(&self, offset: u64, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = self.byte(offset + i as u64).map_or(0, |b| *b);
+        }
+    }
+
+    pub fn write(&mut self, offset: u64, data: &[u8]) {
+        for (i, value) in data.iter().enumerate() {
+            if let Some(byte) = self.byte_mut(offset + i as u64) {
+                *byte = *value;
+            }
+        }
+    }
+
+    /// Returns the message programmed for `vector`, or None if the vector
+    /// does not exist or is masked.
+    pub fn message(&self, vector: u16) -> Option<MsiMessage> {
+        let entry = self.entries.get(vector as usize)?;
+        let field =
+            |offset: usize| u32::from_le_bytes(entry[offset..offset + 4].try_into().unwrap());
+        if field(12) & VECTOR_CONTROL_MASKED != 0 {
+            return None;
+        }
+        Some(MsiMessage {
+            address: u64::from(field(0)) | (u64::from(field(4)) << 32),
+            data: field(8),
+        })
+    }
+
+    /// Clears every entry and masks it, as after a function reset.
+    pub fn reset(&mut self) {
+        for entry in &mut self.entries {
+            *entry = [0; MSIX_ENTRY_SIZE as usize];
+            entry[12..].copy_from_slice(&VECTOR_CONTROL_MASKED.to_le_bytes());
+        }
+    }
+
+    fn byte(&self, offset: u64) -> Option<&u8> {
+        let entry = self.entries.get((offset / MSIX_ENTRY_SIZE) as usize)?;
+        entry.get((offset % MSIX_ENTRY_SIZE) as usize)
+    }
+
+    fn byte_mut(&mut self, offset: u64) -> Option<&mut u8> {
+        let entry = self.entries.get_mut((offset / MSIX_ENTRY_SIZE) as usize)?;
+        entry.get_mut((offset % MSIX_ENTRY_SIZE) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_start_masked() {
+        let table = MsixTable::new(2);
+        assert_eq!(table.size(), 32);
+        assert_eq!(table.message(0), None);
+
+        let mut control = [0u8; 4];
+        table.read(12, &mut control);
+        assert_eq!(u32::from_le_bytes(control), VECTOR_CONTROL_MASKED);
+    }
+
+    #[test]
+    fn unmasked_entries_have_messages() {
+        let mut table = MsixTable::new(2);
+        table.write(16, &0xfee0_1000u32.to_le_bytes());
+        table.write(20, &0u32.to_le_bytes());
+        table.write(24, &0x4041u32.to_le_bytes());
+        assert_eq!(table.message(1), None);
+
+        table.write(28, &0u32.to_le_bytes());
+        assert_eq!(
+            table.message(1),
+            Some(MsiMessage {
+                address: 0xfee0_1000,
+                data: 0x4041,
+            })
+        );
+        assert_eq!(table.message(0), None);
+        assert_eq!(table.message(2), None);
+
+        table.reset();
+        assert_eq!(table.message(1), None);
+    }
+
+    #[test]
+    fn ignores_accesses_past_the_table() {
+        let mut table = MsixTable::new(1);
+        table.write(16, &[0xff; 4]);
+        let mut data = [0xaa; 4];
+        table.read(16, &mut data);
+        assert_eq!(data, [0; 4]);
+    }
+
+    #[test]
+    fn capability_layout() {
+        let (body, writable) = msix_capability(2, 0, 0x4000, 0x5000);
+        assert_eq!(body, [1, 0, 0, 0x40, 0, 0, 0, 0x50, 0, 0]);
+        assert_eq!(writable, [0, 0xc0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+}
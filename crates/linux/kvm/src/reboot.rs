@@ -0,0 +1,159 @@
+//! Guest reboots.
+//!
+//! Guests reboot through the i8042 keyboard controller (`reboot=k`) and
+//! triple fault when nothing else works. Either stops the VM, unless it was
+//! configured to reboot in place: then the vCPUs are parked, and the VM
+//! handle puts the vCPUs and devices back into their power-on state and loads
+//! the kernel again. Devices stay allocated, so host-side resources such as
+//! vsock sockets and port forwards survive the reboot.
+
+use crate::arch::{VcpuState, VmArchState, restore_vcpu_state, restore_vm_devices};
+use crate::handle::VmComponents;
+use crate::pause::PauseControl;
+use crate::vm::BootImage;
+use capsa_core::{Error, ExitReason, Result};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{Notify, mpsc};
+use vm_memory::GuestMemoryMmap;
+
+/// Decides what happens when the guest resets its CPUs.
+pub struct RebootControl {
+    in_place: bool,
+    running: Arc<AtomicBool>,
+    pause: Arc<PauseControl>,
+    exit_tx: mpsc::Sender<ExitReason>,
+    requested: Notify,
+}
+
+impl RebootControl {
+    pub fn new(
+        in_place: bool,
+        running: Arc<AtomicBool>,
+        pause: Arc<PauseControl>,
+        exit_tx: mpsc::Sender<ExitReason>,
+    ) -> Self {
+        Self {
+            in_place,
+            running,
+            pause,
+            exit_tx,
+            requested: Notify::new(),
+        }
+    }
+
+    /// Handles a reset of the guest's CPUs.
+    ///
+    /// Stops the VM with `reason`, unless it reboots in place. Then the vCPUs
+    /// park until the VM handle has rebooted the guest.
+    pub fn reset(&self, reason: ExitReason) {
+        if self.in_place {
+            tracing::debug!("guest reset ({:?}), rebooting in place", reason);
+            self.pause.request();
+            self.requested.notify_one();
+        } else {
+            let _ = self.exit_tx.try_send(reason);
+            self.running.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Waits until the guest resets while rebooting in place.
+    pub async fn wait_requested(&self) {
+        self.requested.notified().await;
+    }
+
+    /// Stops the VM after a reboot failed.
+    pub fn fail(&self, message: String) {
+        let _ = self
+            .exit_tx
+            .try_send(ExitReason::InternalError { message });
+        self.running.store(false, Ordering::Relaxed);
+    }
+}
+
+/// What the guest is put back to when it reboots in place.
+pub struct BootState {
+    pub image: BootImage,
+    pub vm: VmArchState,
+    /// Power-on state of each vCPU, before it was set up for the kernel.
+    pub vcpus: Vec<VcpuState>,
+}
+
+/// Reboots a guest whose vCPUs are all parked.
+pub fn reboot_guest(
+    boot: &BootState,
+    components: &VmComponents,
+    memory: &GuestMemoryMmap,
+) -> Result<()> {
+    let reboot_error = |e: kvm_ioctls::Error| {
+        Error::Io(std::io::Error::other(format!(
+            "failed to reboot VM: {}",
+            e
+        )))
+    };
+
+    components.devices.reset()?;
+    let kernel_entry = boot.image.load(memory)?;
+    restore_vm_devices(&components.vm_fd, &boot.vm).map_err(reboot_error)?;
+
+    for (index, (vcpu, state)) in components.vcpus.iter().zip(&boot.vcpus).enumerate() {
+        let vcpu = vcpu.lock().unwrap();
+        restore_vcpu_state(&vcpu, state).map_err(reboot_error)?;
+        boot.image.setup_vcpu(&vcpu, index, kernel_entry)?;
+    }
+
+    tracing::info!("guest rebooted in place");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_control(in_place: bool) -> (RebootControl, mpsc::Receiver<ExitReason>) {
+        let running = Arc::new(AtomicBool::new(true));
+        let pause = Arc::new(PauseControl::new());
+        let (exit_tx, exit_rx) = mpsc::channel(1);
+        (
+            RebootControl::new(in_place, running, pause, exit_tx),
+            exit_rx,
+        )
+    }
+
+    #[test]
+    fn reset_stops_the_vm() {
+        let (control, mut exit_rx) = create_control(false);
+        control.reset(ExitReason::TripleFault);
+
+        assert!(!control.running.load(Ordering::Relaxed));
+        assert!(!control.pause.is_requested());
+        assert_eq!(exit_rx.try_recv().unwrap(), ExitReason::TripleFault);
+    }
+
+    #[tokio::test]
+    async fn reset_in_place_parks_the_vcpus() {
+        let (control, mut exit_rx) = create_control(true);
+        control.reset(ExitReason::Reboot);
+
+        assert!(control.running.load(Ordering::Relaxed));
+        assert!(control.pause.is_requested());
+        assert!(exit_rx.try_recv().is_err());
+        tokio::time::timeout(std::time::Duration::from_secs(5), control.wait_requested())
+            .await
+            .expect("reboot was not requested");
+    }
+
+    #[test]
+    fn failed_reboot_stops_the_vm() {
+        let (control, mut exit_rx) = create_control(true);
+        control.fail("no kernel".into());
+
+        assert!(!control.running.load(Ordering::Relaxed));
+        assert_eq!(
+            exit_rx.try_recv().unwrap(),
+            ExitReason::InternalError {
+                message: "no kernel".into()
+            }
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! Guest addresses and interrupts handed out to devices.
+//!
+//! Every device that needs MMIO space or an interrupt gets it from a
+//! [`ResourceAllocator`] while the VM is built, so devices never overlap and
+//! running out is reported as a config error rather than as a guest that
+//! can't find its devices. The allocator remembers what it handed out, and
+//! the kernel cmdline and MP table are generated from that.
+//!
+//! Allocation is deterministic, so the same VM config always gets the same
+//! layout, which snapshots rely on.
+
+use crate::arch::{
+    ACPI_SCI_IRQ, PCI_MMIO_SIZE, PCI_MMIO_START, SERIAL_IRQ, VIRTIO_MMIO_BASE, VIRTIO_MMIO_SIZE,
+};
+use crate::gsi::{MAX_MSI_GSIS, NUM_IOAPIC_PINS};
+use capsa_core::{Error, Result};
+use std::collections::BTreeSet;
+
+/// IRQs of the ISA bus. The MP table always describes them, so legacy
+/// drivers find the timer, keyboard and RTC.
+const NUM_ISA_IRQS: u32 = 16;
+
+/// Pins below this are the ISA timer, keyboard, PIC cascade and serial
+/// ports, and are never handed out.
+const FIRST_DEVICE_IRQ: u32 = 5;
+
+/// Hands out MMIO ranges, IOAPIC pins and MSI GSIs.
+pub struct ResourceAllocator {
+    virtio_mmio: AddressAllocator,
+    pci: AddressAllocator,
+    /// IOAPIC pins in use
+    pins: BTreeSet<u32>,
+    allocated_msi_gsis: u32,
+    /// Base address and pin of each virtio-mmio device, in allocation order
+    virtio_mmio_devices: Vec<(u64, u32)>,
+}
+
+impl ResourceAllocator {
+    /// Creates an allocator with the fixed pins of the serial port and the
+    /// ACPI SCI already taken.
+    pub fn new() -> Self {
+        Self {
+            virtio_mmio: AddressAllocator::new("virtio-mmio", VIRTIO_MMIO_BASE, PCI_MMIO_START),
+            pci: AddressAllocator::new("PCI", PCI_MMIO_START, PCI_MMIO_START + PCI_MMIO_SIZE),
+            pins: BTreeSet::from([SERIAL_IRQ, ACPI_SCI_IRQ]),
+            allocated_msi_gsis: 0,
+            virtio_mmio_devices: Vec::new(),
+        }
+    }
+
+    /// Reserves a register slot and an IOAPIC pin for a virtio-mmio device,
+    /// and returns them.
+    ///
+    /// The device is declared on the kernel cmdline in allocation order,
+    /// which is the order the guest probes it in.
+    pub fn allocate_virtio_mmio(&mut self) -> Result<(u64, u32)> {
+        let base = self.virtio_mmio.allocate(VIRTIO_MMIO_SIZE)?;
+        let irq = self.allocate_irq()?;
+        self.virtio_mmio_devices.push((base, irq));
+        Ok((base, irq))
+    }
+
+    /// Reserves `size` bytes of the PCI window for a BAR, aligned to its
+    /// size.
+    pub fn allocate_pci_bar(&mut self, size: u64) -> Result<u64> {
+        self.pci.allocate(size)
+    }
+
+    /// Reserves a GSI above the IOAPIC pins, for MSI routes.
+    pub fn allocate_msi_gsi(&mut self) -> Result<u32> {
+        if self.allocated_msi_gsis as usize == MAX_MSI_GSIS {
+            return Err(Error::InvalidConfig(format!(
+                "too many PCI devices: all {} MSI interrupts are in use",
+                MAX_MSI_GSIS
+            )));
+        }
+        let gsi = NUM_IOAPIC_PINS as u32 + self.allocated_msi_gsis;
+        self.allocated_msi_gsis += 1;
+        Ok(gsi)
+    }
+
+    /// Returns the `virtio_mmio.device=` parameters that declare every
+    /// virtio-mmio device to the guest, each preceded by a space.
+    pub fn virtio_mmio_cmdline(&self) -> String {
+        self.virtio_mmio_devices
+            .iter()
+            .map(|(base, irq)| {
+                format!(
+                    " virtio_mmio.device=0x{:x}@0x{:x}:{}",
+                    VIRTIO_MMIO_SIZE, base, irq
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the IOAPIC pins the MP table describes: the ISA IRQs and
+    /// every pin in use above them.
+    pub fn ioapic_pins(&self) -> Vec<u32> {
+        let isa = 0..NUM_ISA_IRQS;
+        let devices = self.pins.range(NUM_ISA_IRQS..).copied();
+        isa.chain(devices).collect()
+    }
+
+    fn allocate_irq(&mut self) -> Result<u32> {
+        let irq = (FIRST_DEVICE_IRQ..NUM_IOAPIC_PINS as u32)
+            .find(|irq| !self.pins.contains(irq))
+            .ok_or_else(|| {
+                Error::InvalidConfig(format!(
+                    "too many virtio-mmio devices: all {} device IRQs are in use; \
+                     the virtio-pci transport supports more devices",
+                    NUM_IOAPIC_PINS as u32 - FIRST_DEVICE_IRQ - 1
+                ))
+            })?;
+        self.pins.insert(irq);
+        Ok(irq)
+    }
+}
+
+impl Default for ResourceAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Hands out ranges of a guest address window, lowest first.
+struct AddressAllocator {
+    name: &'static str,
+    next: u64,
+    end: u64,
+}
+
+impl AddressAllocator {
+    fn new(name: &'static str, start: u64, end: u64) -> Self {
+        Self {
+            name,
+            next: start,
+            end,
+        }
+    }
+
+    /// Allocates `size` bytes aligned to `size`, which must be a power of
+    /// two.
+    fn allocate(&mut self, size: u64) -> Result<u64> {
+        let addr = self.next.next_multiple_of(size);
+        if addr + size > self.end {
+            return Err(Error::InvalidConfig(format!(
+                "too many devices: the {} MMIO window is full",
+                self.name
+            )));
+        }
+        self.next = addr + size;
+        Ok(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn allocates_virtio_mmio_devices_in_order() {
+        let mut resources = ResourceAllocator::new();
+        assert_eq!(
+            resources.allocate_virtio_mmio().unwrap(),
+            (VIRTIO_MMIO_BASE, FIRST_DEVICE_IRQ)
+        );
+        assert_eq!(
+            resources.allocate_virtio_mmio().unwrap(),
+            (VIRTIO_MMIO_BASE + VIRTIO_MMIO_SIZE, FIRST_DEVICE_IRQ + 1)
+        );
+        assert_eq!(
+            resources.virtio_mmio_cmdline(),
+            " virtio_mmio.device=0x200@0xd0000000:5 virtio_mmio.device=0x200@0xd0000200:6"
+        );
+    }
+
+    #[test]
+    fn runs_out_of_ioapic_pins() {
+        let mut resources = ResourceAllocator::new();
+        let mut irqs = Vec::new();
+        while let Ok((_, irq)) = resources.allocate_virtio_mmio() {
+            irqs.push(irq);
+        }
+        assert_eq!(irqs, (FIRST_DEVICE_IRQ..ACPI_SCI_IRQ).collect::<Vec<_>>());
+        assert!(matches!(
+            resources.allocate_virtio_mmio(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn mp_table_describes_isa_and_used_pins() {
+        let mut resources = ResourceAllocator::new();
+        assert_eq!(
+            resources.ioapic_pins(),
+            (0..NUM_ISA_IRQS).chain([ACPI_SCI_IRQ]).collect::<Vec<_>>()
+        );
+
+        for _ in FIRST_DEVICE_IRQ..=NUM_ISA_IRQS {
+            resources.allocate_virtio_mmio().unwrap();
+        }
+        assert_eq!(
+            resources.ioapic_pins(),
+            (0..=NUM_ISA_IRQS).chain([ACPI_SCI_IRQ]).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn aligns_pci_bars_to_their_size() {
+        let mut resources = ResourceAllocator::new();
+        assert_eq!(resources.allocate_pci_bar(0x1000).unwrap(), PCI_MMIO_START);
+        assert_eq!(
+            resources.allocate_pci_bar(0x8000).unwrap(),
+            PCI_MMIO_START + 0x8000
+        );
+        assert!(matches!(
+            resources.allocate_pci_bar(PCI_MMIO_SIZE),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn allocates_msi_gsis_until_exhausted() {
+        let mut resources = ResourceAllocator::new();
+        for i in 0..MAX_MSI_GSIS {
+            assert_eq!(
+                resources.allocate_msi_gsi().unwrap(),
+                (NUM_IOAPIC_PINS + i) as u32
+            );
+        }
+        assert!(matches!(
+            resources.allocate_msi_gsi(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+}
@@ -0,0 +1,657 @@
+//! VM snapshot file format.
+//!
+//! A snapshot file is laid out as:
+//!
+//! ```text
+//! Offset        Size          Description
+//! ─────────────────────────────────────────────────────────────
+//! 0             8             Magic ("CAPSASNP")
+//! 8             4             Format version (little endian)
+//! 12            4             Reserved
+//! 16            8             Length of the VM config
+//! 24            8             Length of the device state
+//! 32            -             VM config (JSON)
+//! -             -             vCPU, in-kernel and virtio device state (bincode)
+//! page aligned  -             Guest memory, one region after another
+//! ```
+//!
+//! Guest memory is page aligned so a restored VM can map it straight from the
+//! file instead of reading it in.
+
+use crate::arch::{AcpiPmDevice, AcpiPmState, Topology, VcpuState, VmArchState};
+use crate::pci::PciBus;
+use crate::virtio::{
+    VIRTIO_MMIO_STATUS, VirtioBalloon, VirtioBalloonState, VirtioBlk, VirtioConsole, VirtioFs,
+    VirtioFsState, VirtioNet, VirtioNetState, VirtioRng, VirtioTransportState, VirtioVsock,
+};
+use crate::watchdog::Watchdog;
+use capsa_core::{Error, NetworkMode, Result, VmConfig};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use vm_device::MutDeviceMmio;
+use vm_device::bus::MmioAddress;
+use vm_memory::{Address, Bytes, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"CAPSASNP";
+/// Bumped whenever devices move, since a restored guest finds them where
+/// they were when the snapshot was taken.
+const SNAPSHOT_VERSION: u32 = 2;
+const HEADER_SIZE: u64 = 32;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Guest memory is copied through a buffer of this size.
+const MEMORY_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Upper bound on the serialized config and device state, to reject corrupt
+/// headers before allocating.
+const MAX_STATE_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Location of a guest memory region in guest physical address space.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemoryRegionState {
+    pub guest_addr: u64,
+    pub size: u64,
+}
+
+/// Saved state of the virtio devices, in the order they are created, and of
+/// the ACPI power management registers.
+///
+/// The serial port, RTC and watchdog are not saved. The guest only uses the
+/// serial port for early boot output, the RTC is read from the host clock,
+/// and the watchdog is armed again by the guest's next ping.
+#[derive(Default, Serialize, Deserialize)]
+pub struct DeviceStates {
+    pub console: Option<VirtioTransportState>,
+    pub net: Option<VirtioNetState>,
+    pub vsock: Option<VirtioTransportState>,
+    pub fs: Vec<VirtioFsState>,
+    pub blk: Vec<VirtioTransportState>,
+    pub balloon: Option<VirtioBalloonState>,
+    pub rng: Option<VirtioTransportState>,
+    pub pm: Option<AcpiPmState>,
+}
+
+/// The devices of a VM, kept so their state can be saved.
+#[derive(Clone, Default)]
+pub struct VmDevices {
+    pub console: Option<Arc<Mutex<VirtioConsole>>>,
+    pub net: Option<Arc<Mutex<VirtioNet>>>,
+    pub vsock: Option<Arc<Mutex<VirtioVsock>>>,
+    pub fs: Vec<Arc<Mutex<VirtioFs>>>,
+    pub blk: Vec<Arc<Mutex<VirtioBlk>>>,
+    pub balloon: Option<Arc<Mutex<VirtioBalloon>>>,
+    pub rng: Option<Arc<Mutex<VirtioRng>>>,
+    pub pm: Option<Arc<Mutex<AcpiPmDevice>>>,
+    pub watchdog: Option<Arc<Watchdog>>,
+    /// The PCI bus, when the virtio devices sit on it
+    pub pci: Option<Arc<PciBus>>,
+}
+
+impl VmDevices {
+    /// Waits for device polls that started before the VM was paused.
+    ///
+    /// Polling tasks check for a pause while holding the device lock, so once
+    /// each lock has been taken no device touches guest memory until resume.
+    pub fn wait_idle(&self) {
+        if let Some(device) = &self.console {
+            drop(device.lock().unwrap());
+        }
+        if let Some(device) = &self.net {
+            drop(device.lock().unwrap());
+        }
+        if let Some(device) = &self.vsock {
+            drop(device.lock().unwrap());
+        }
+        if let Some(device) = &self.rng {
+            drop(device.lock().unwrap());
+        }
+        for device in &self.fs {
+            drop(device.lock().unwrap());
+        }
+    }
+
+    /// Captures the state of every device.
+    ///
+    /// The vCPUs must be paused so no MMIO access is in progress. Disks are
+    /// flushed first since the snapshot refers to their current contents.
+    pub fn save_state(&self) -> Result<DeviceStates> {
+        // The virtio-pci transport keeps state of its own, which snapshots
+        // have no room for yet
+        if self.pci.is_some() {
+            return Err(Error::UnsupportedFeature(
+                "snapshots of VMs with virtio-pci devices".into(),
+            ));
+        }
+
+        let mut blk = Vec::with_capacity(self.blk.len());
+        for device in &self.blk {
+            let mut device = device.lock().unwrap();
+            device.flush().map_err(Error::Io)?;
+            blk.push(device.save_state());
+        }
+
+        Ok(DeviceStates {
+            console: self
+                .console
+                .as_ref()
+                .map(|d| d.lock().unwrap().save_state()),
+            net: self.net.as_ref().map(|d| d.lock().unwrap().save_state()),
+            vsock: self.vsock.as_ref().map(|d| d.lock().unwrap().save_state()),
+            fs: self
+                .fs
+                .iter()
+                .map(|d| d.lock().unwrap().save_state())
+                .collect(),
+            blk,
+            balloon: self
+                .balloon
+                .as_ref()
+                .map(|d| d.lock().unwrap().save_state()),
+            rng: self.rng.as_ref().map(|d| d.lock().unwrap().save_state()),
+            pm: self.pm.as_ref().map(|d| d.lock().unwrap().save_state()),
+        })
+    }
+
+    /// Puts every device back into its power-on state, for a guest that
+    /// reboots in place.
+    ///
+    /// The vCPUs must be paused. Virtio devices are reset the way a guest
+    /// driver resets them, by clearing their status register.
+    pub fn reset(&self) -> Result<()> {
+        if let Some(device) = &self.console {
+            reset_virtio_device(device);
+        }
+        if let Some(device) = &self.net {
+            reset_virtio_device(device);
+        }
+        if let Some(device) = &self.vsock {
+            reset_virtio_device(device);
+        }
+        for device in &self.fs {
+            reset_virtio_device(device);
+        }
+        for device in &self.blk {
+            device.lock().unwrap().flush().map_err(Error::Io)?;
+            reset_virtio_device(device);
+        }
+        if let Some(device) = &self.balloon {
+            reset_virtio_device(device);
+        }
+        if let Some(device) = &self.rng {
+            reset_virtio_device(device);
+        }
+        if let Some(device) = &self.pm {
+            device.lock().unwrap().reset();
+        }
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.stop();
+        }
+        if let Some(pci) = &self.pci {
+            pci.reset();
+        }
+        Ok(())
+    }
+
+    /// Restores state captured by [`save_state`](Self::save_state).
+    pub fn restore_state(&self, states: &DeviceStates) -> Result<()> {
+        if self.console.is_some() != states.console.is_some()
+            || self.net.is_some() != states.net.is_some()
+            || self.vsock.is_some() != states.vsock.is_some()
+            || self.fs.len() != states.fs.len()
+            || self.blk.len() != states.blk.len()
+            || self.balloon.is_some() != states.balloon.is_some()
+            || self.rng.is_some() != states.rng.is_some()
+            || self.pm.is_some() != states.pm.is_some()
+        {
+            return Err(Error::StartFailed(
+                "snapshot devices do not match the VM config".into(),
+            ));
+        }
+
+        if let (Some(device), Some(state)) = (&self.console, &states.console) {
+            device.lock().unwrap().restore_state(state)?;
+        }
+        if let (Some(device), Some(state)) = (&self.net, &states.net) {
+            device.lock().unwrap().restore_state(state)?;
+        }
+        if let (Some(device), Some(state)) = (&self.vsock, &states.vsock) {
+            device.lock().unwrap().restore_state(state)?;
+        }
+        for (device, state) in self.fs.iter().zip(&states.fs) {
+            device.lock().unwrap().restore_state(state)?;
+        }
+        for (device, state) in self.blk.iter().zip(&states.blk) {
+            device.lock().unwrap().restore_state(state)?;
+        }
+        if let (Some(device), Some(state)) = (&self.balloon, &states.balloon) {
+            device.lock().unwrap().restore_state(state)?;
+        }
+        if let (Some(device), Some(state)) = (&self.rng, &states.rng) {
+            device.lock().unwrap().restore_state(state)?;
+        }
+        if let (Some(device), Some(state)) = (&self.pm, &states.pm) {
+            device.lock().unwrap().restore_state(state);
+        }
+        Ok(())
+    }
+}
+
+fn reset_virtio_device<D: MutDeviceMmio>(device: &Mutex<D>) {
+    device
+        .lock()
+        .unwrap()
+        .mmio_write(MmioAddress(0), VIRTIO_MMIO_STATUS, &0u32.to_le_bytes());
+}
+
+/// Everything in a snapshot except the VM config and guest memory.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotState {
+    pub memory_regions: Vec<MemoryRegionState>,
+    pub vm: VmArchState,
+    pub vcpus: Vec<VcpuState>,
+    pub devices: DeviceStates,
+}
+
+/// An open snapshot file.
+pub struct Snapshot {
+    pub file: File,
+    pub config: VmConfig,
+    pub state: SnapshotState,
+    /// File offset of the first guest memory region.
+    pub memory_offset: u64,
+}
+
+impl Snapshot {
+    /// Opens a snapshot and reads everything except guest memory.
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut file = File::open(path).map_err(|e| open_error(path, e))?;
+        let (config_len, state_len) = read_header(&mut file, path)?;
+        let config = read_config(&mut file, config_len, path)?;
+
+        let mut state = vec![0u8; state_len as usize];
+        file.read_exact(&mut state)
+            .map_err(|e| invalid_snapshot(path, e))?;
+        let state: SnapshotState =
+            bincode::deserialize(&state).map_err(|e| invalid_snapshot(path, e))?;
+
+        let memory_offset = align_to_page(HEADER_SIZE + config_len + state_len);
+        let memory_size: u64 = state.memory_regions.iter().map(|r| r.size).sum();
+        let file_size = file.metadata().map_err(|e| open_error(path, e))?.len();
+        if memory_offset.saturating_add(memory_size) > file_size {
+            return Err(invalid_snapshot(path, "guest memory is truncated"));
+        }
+
+        Ok(Self {
+            file,
+            config,
+            state,
+            memory_offset,
+        })
+    }
+}
+
+/// Reads only the VM config from a snapshot.
+pub fn read_snapshot_config(path: &Path) -> Result<VmConfig> {
+    let mut file = File::open(path).map_err(|e| open_error(path, e))?;
+    let (config_len, _) = read_header(&mut file, path)?;
+    read_config(&mut file, config_len, path)
+}
+
+/// Writes a snapshot of a paused VM to `path`.
+///
+/// The file is written next to `path` and renamed into place. A VM restored
+/// from `path` maps its memory from the old file, which must not change.
+pub fn write_snapshot(
+    path: &Path,
+    config: &VmConfig,
+    state: &SnapshotState,
+    memory: &GuestMemoryMmap,
+) -> Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    let partial = Path::new(&partial);
+
+    let result = write_snapshot_file(partial, config, state, memory)
+        .and_then(|()| std::fs::rename(partial, path));
+    if result.is_err() {
+        let _ = std::fs::remove_file(partial);
+    }
+    result.map_err(|e| {
+        Error::Io(std::io::Error::new(
+            e.kind(),
+            format!("failed to write snapshot {}: {}", path.display(), e),
+        ))
+    })
+}
+
+fn write_snapshot_file(
+    path: &Path,
+    config: &VmConfig,
+    state: &SnapshotState,
+    memory: &GuestMemoryMmap,
+) -> std::io::Result<()> {
+    let config = serde_json::to_vec(config)?;
+    let state = bincode::serialize(state).map_err(std::io::Error::other)?;
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(SNAPSHOT_MAGIC)?;
+    file.write_all(&SNAPSHOT_VERSION.to_le_bytes())?;
+    file.write_all(&0u32.to_le_bytes())?;
+    file.write_all(&(config.len() as u64).to_le_bytes())?;
+    file.write_all(&(state.len() as u64).to_le_bytes())?;
+    file.write_all(&config)?;
+    file.write_all(&state)?;
+
+    let written = HEADER_SIZE + config.len() as u64 + state.len() as u64;
+    let padding = align_to_page(written) - written;
+    file.write_all(&vec![0u8; padding as usize])?;
+
+    write_memory(&mut file, memory)?;
+
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+/// Writes guest memory to `out`, one region after another.
+pub fn write_memory<W: Write>(out: &mut W, memory: &GuestMemoryMmap) -> std::io::Result<()> {
+    let mut buf = vec![0u8; MEMORY_CHUNK_SIZE];
+    for region in memory.iter() {
+        let start = region.start_addr();
+        let mut offset = 0u64;
+        while offset < region.len() {
+            let len = (region.len() - offset).min(MEMORY_CHUNK_SIZE as u64) as usize;
+            let addr = start
+                .checked_add(offset)
+                .ok_or_else(|| std::io::Error::other("guest address overflow"))?;
+            memory
+                .read_slice(&mut buf[..len], addr)
+                .map_err(std::io::Error::other)?;
+            out.write_all(&buf[..len])?;
+            offset += len as u64;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the guest memory regions to save.
+pub fn memory_regions(memory: &GuestMemoryMmap) -> Vec<MemoryRegionState> {
+    memory
+        .iter()
+        .map(|region| MemoryRegionState {
+            guest_addr: region.start_addr().raw_value(),
+            size: region.len(),
+        })
+        .collect()
+}
+
+/// Checks that `config` describes the same hardware as the snapshot.
+///
+/// Guest memory and device state only make sense on an identical VM. Host-side
+/// settings such as vsock socket paths may differ.
+pub fn check_compatible(snapshot: &VmConfig, config: &VmConfig) -> Result<()> {
+    let mismatch = |what: &str| {
+        Err(Error::InvalidConfig(format!(
+            "{} differs from the snapshot",
+            what
+        )))
+    };
+
+    if snapshot.resources.cpus != config.resources.cpus {
+        return mismatch("CPU count");
+    }
+    let topology =
+        |c: &VmConfig| Topology::new(c.resources.cpus, c.resources.cpu.topology.as_ref());
+    if topology(snapshot) != topology(config) {
+        return mismatch("CPU topology");
+    }
+    let (old, new) = (&snapshot.resources.cpu, &config.resources.cpu);
+    if old.model != new.model
+        || old.add_features != new.add_features
+        || old.remove_features != new.remove_features
+    {
+        return mismatch("CPU model");
+    }
+    if snapshot.resources.memory_mb != config.resources.memory_mb {
+        return mismatch("memory size");
+    }
+    if snapshot.console_enabled != config.console_enabled {
+        return mismatch("console");
+    }
+    if network_device(&snapshot.network) != network_device(&config.network) {
+        return mismatch("network device");
+    }
+    if snapshot.vsock.is_enabled() != config.vsock.is_enabled() {
+        return mismatch("vsock device");
+    }
+    if snapshot.shares.len() != config.shares.len() {
+        return mismatch("number of shared directories");
+    }
+    let disk_count = |c: &VmConfig| c.root_disk.iter().count() + c.disks.len();
+    if disk_count(snapshot) != disk_count(config) {
+        return mismatch("number of disks");
+    }
+    if snapshot.resources.balloon != config.resources.balloon {
+        return mismatch("balloon device");
+    }
+    if snapshot.resources.rng.is_some() != config.resources.rng.is_some() {
+        return mismatch("entropy device");
+    }
+    if snapshot.virtio_transport != config.virtio_transport {
+        return mismatch("virtio transport");
+    }
+    Ok(())
+}
+
+fn network_device(network: &NetworkMode) -> bool {
+    matches!(network, NetworkMode::UserNat(_) | NetworkMode::Cluster(_))
+}
+
+fn read_header(file: &mut File, path: &Path) -> Result<(u64, u64)> {
+    let mut header = [0u8; HEADER_SIZE as usize];
+    file.read_exact(&mut header)
+        .map_err(|e| invalid_snapshot(path, e))?;
+
+    if &header[0..8] != SNAPSHOT_MAGIC {
+        return Err(invalid_snapshot(path, "not a capsa snapshot"));
+    }
+    let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if version != SNAPSHOT_VERSION {
+        return Err(invalid_snapshot(
+            path,
+            format!("unsupported snapshot version {}", version),
+        ));
+    }
+
+    let config_len = u64::from_le_bytes(header[16..24].try_into().unwrap());
+    let state_len = u64::from_le_bytes(header[24..32].try_into().unwrap());
+    if config_len > MAX_STATE_SIZE || state_len > MAX_STATE_SIZE {
+        return Err(invalid_snapshot(path, "state is too large"));
+    }
+    Ok((config_len, state_len))
+}
+
+fn read_config(file: &mut File, config_len: u64, path: &Path) -> Result<VmConfig> {
+    file.seek(SeekFrom::Start(HEADER_SIZE))
+        .map_err(|e| invalid_snapshot(path, e))?;
+    let mut config = vec![0u8; config_len as usize];
+    file.read_exact(&mut config)
+        .map_err(|e| invalid_snapshot(path, e))?;
+    serde_json::from_slice(&config).map_err(|e| invalid_snapshot(path, e))
+}
+
+fn align_to_page(offset: u64) -> u64 {
+    offset.div_ceil(PAGE_SIZE) * PAGE_SIZE
+}
+
+fn open_error(path: &Path, e: std::io::Error) -> Error {
+    Error::StartFailed(format!("failed to open snapshot {}: {}", path.display(), e))
+}
+
+fn invalid_snapshot(path: &Path, reason: impl std::fmt::Display) -> Error {
+    Error::StartFailed(format!("invalid snapshot {}: {}", path.display(), reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsa_core::{
+        BootMethod, CpuModel, CpuTopology, DiskImage, ResourceConfig, RngConfig, UserNatConfig,
+        VirtioTransport, VsockConfig, VsockPortConfig,
+    };
+    use std::path::PathBuf;
+    use tempfile::TempDir;
+
+    fn test_config() -> VmConfig {
+        VmConfig {
+            boot: BootMethod::LinuxDirect {
+                kernel: PathBuf::from("/kernel"),
+                initrd: PathBuf::from("/initrd"),
+                cmdline: "console=hvc0".to_string(),
+            },
+            root_disk: Some(DiskImage::new("/rootfs.raw")),
+            disks: Vec::new(),
+            resources: ResourceConfig::default(),
+            shares: Vec::new(),
+            network: NetworkMode::None,
+            console_enabled: true,
+            vsock: VsockConfig::default(),
+            reboot_in_place: false,
+            gdb: None,
+            virtio_transport: VirtioTransport::default(),
+            watchdog: None,
+            cluster_network_fd: None,
+        }
+    }
+
+    fn write_header(path: &Path, magic: &[u8; 8], version: u32) {
+        let mut header = Vec::new();
+        header.extend_from_slice(magic);
+        header.extend_from_slice(&version.to_le_bytes());
+        header.extend_from_slice(&[0u8; 20]);
+        std::fs::write(path, header).unwrap();
+    }
+
+    #[test]
+    fn identical_config_is_compatible() {
+        assert!(check_compatible(&test_config(), &test_config()).is_ok());
+    }
+
+    #[test]
+    fn vsock_paths_may_differ() {
+        let mut snapshot = test_config();
+        snapshot
+            .vsock
+            .add_port(VsockPortConfig::listen(1024, "/tmp/a.sock"));
+        let mut config = test_config();
+        config
+            .vsock
+            .add_port(VsockPortConfig::listen(1024, "/tmp/b.sock"));
+
+        assert!(check_compatible(&snapshot, &config).is_ok());
+    }
+
+    #[test]
+    fn hardware_changes_are_incompatible() {
+        let snapshot = test_config();
+
+        let mut config = test_config();
+        config.resources.cpus += 1;
+        assert!(check_compatible(&snapshot, &config).is_err());
+
+        let mut config = test_config();
+        config.resources.memory_mb *= 2;
+        assert!(check_compatible(&snapshot, &config).is_err());
+
+        let mut config = test_config();
+        config.network = NetworkMode::UserNat(UserNatConfig::default());
+        assert!(check_compatible(&snapshot, &config).is_err());
+
+        let mut config = test_config();
+        config.disks.push(DiskImage::new("/data.raw"));
+        assert!(check_compatible(&snapshot, &config).is_err());
+
+        let mut config = test_config();
+        config.resources.balloon = true;
+        assert!(check_compatible(&snapshot, &config).is_err());
+
+        let mut config = test_config();
+        config.resources.rng = Some(RngConfig::default());
+        assert!(check_compatible(&snapshot, &config).is_err());
+
+        let mut config = test_config();
+        config.virtio_transport = VirtioTransport::Pci;
+        assert!(check_compatible(&snapshot, &config).is_err());
+
+        let mut config = test_config();
+        config.resources.cpu.topology = Some(CpuTopology::new(1, 1, 1));
+        config.resources.cpu.pinning = vec![0];
+        assert!(check_compatible(&snapshot, &config).is_ok());
+        config.resources.cpus = 2;
+        config.resources.cpu.topology = Some(CpuTopology::new(1, 1, 2));
+        let mut snapshot = test_config();
+        snapshot.resources.cpus = 2;
+        assert!(check_compatible(&snapshot, &config).is_err());
+
+        let snapshot = test_config();
+        let mut config = test_config();
+        config.resources.cpu.model = CpuModel::X86_64V2;
+        assert!(check_compatible(&snapshot, &config).is_err());
+        let mut config = test_config();
+        config.resources.cpu.remove_features = vec!["avx".into()];
+        assert!(check_compatible(&snapshot, &config).is_err());
+    }
+
+    #[test]
+    fn rejects_foreign_files() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("snapshot");
+        write_header(&path, b"NOTASNAP", SNAPSHOT_VERSION);
+
+        let err = read_snapshot_config(&path).unwrap_err();
+        assert!(err.to_string().contains("not a capsa snapshot"));
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("snapshot");
+        write_header(&path, SNAPSHOT_MAGIC, SNAPSHOT_VERSION + 1);
+
+        let err = read_snapshot_config(&path).unwrap_err();
+        assert!(err.to_string().contains("unsupported snapshot version"));
+    }
+
+    #[test]
+    fn config_roundtrip() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("snapshot");
+        let config = serde_json::to_vec(&test_config()).unwrap();
+
+        let mut file = Vec::new();
+        file.extend_from_slice(SNAPSHOT_MAGIC);
+        file.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        file.extend_from_slice(&0u32.to_le_bytes());
+        file.extend_from_slice(&(config.len() as u64).to_le_bytes());
+        file.extend_from_slice(&0u64.to_le_bytes());
+        file.extend_from_slice(&config);
+        std::fs::write(&path, file).unwrap();
+
+        let restored = read_snapshot_config(&path).unwrap();
+        assert!(restored.console_enabled);
+        assert_eq!(
+            restored.root_disk.unwrap().path,
+            PathBuf::from("/rootfs.raw")
+        );
+    }
+
+    #[test]
+    fn memory_offset_is_page_aligned() {
+        assert_eq!(align_to_page(0), 0);
+        assert_eq!(align_to_page(1), PAGE_SIZE);
+        assert_eq!(align_to_page(PAGE_SIZE), PAGE_SIZE);
+        assert_eq!(align_to_page(PAGE_SIZE + 1), 2 * PAGE_SIZE);
+    }
+}
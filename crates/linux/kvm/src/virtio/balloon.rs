@@ -0,0 +1,613 @@
+//! Virtio memory balloon device implementation.
+//!
+//! Lets the host take memory back from a running guest. The host sets a
+//! target balloon size, and the guest driver inflates the balloon by handing
+//! over pages, which are then released on the host. With free page reporting
+//! the guest also hands over pages it has freed on its own, so idle guests
+//! return memory without the host asking.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use capsa_core::Result;
+use nix::libc;
+use serde::{Deserialize, Serialize};
+use virtio_queue::desc::split::Descriptor;
+use virtio_queue::{Queue, QueueT};
+use vm_device::MutDeviceMmio;
+use vm_device::bus::{MmioAddress, MmioAddressOffset};
+use vm_memory::{
+    Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, GuestMemoryRegion,
+    MemoryRegionAddress,
+};
+
+use super::common::{
+    VIRTIO_MMIO_CONFIG, VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL,
+    VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_DRIVER_FEATURES_SEL,
+    VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_MAGIC,
+    VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_AVAIL_HIGH, VIRTIO_MMIO_QUEUE_AVAIL_LOW,
+    VIRTIO_MMIO_QUEUE_DESC_HIGH, VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_NOTIFY,
+    VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY,
+    VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW,
+    VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION, VirtioQueueState,
+    VirtioTransportState,
+};
+use super::{MAX_DESCRIPTOR_LEN, VirtioDevice, VirtioInterrupt, validate_queue_addresses};
+
+const VIRTIO_ID_BALLOON: u32 = 5;
+
+const INFLATE_QUEUE_INDEX: usize = 0;
+const DEFLATE_QUEUE_INDEX: usize = 1;
+/// The stats and free page hint queues are not offered, so the reporting
+/// queue directly follows the deflate queue.
+const REPORTING_QUEUE_INDEX: usize = 2;
+const NUM_QUEUES: usize = 3;
+
+const QUEUE_SIZE: u16 = 256;
+
+const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+
+const VIRTIO_INT_USED_RING: u32 = 1;
+const VIRTIO_INT_CONFIG: u32 = 2;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u64 = 1 << 2;
+const VIRTIO_BALLOON_F_REPORTING: u64 = 1 << 5;
+
+/// Page frame numbers on the inflate and deflate queues are in 4 KiB units,
+/// whatever the guest page size.
+pub const BALLOON_PAGE_SIZE: u64 = 4096;
+
+// Config space layout: num_pages(4) + actual(4)
+const CONFIG_ACTUAL_OFFSET: u64 = 4;
+const BALLOON_CONFIG_SIZE: u64 = 8;
+
+/// Balloon state saved in VM snapshots.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VirtioBalloonState {
+    transport: VirtioTransportState,
+    num_pages: u32,
+    actual: u32,
+}
+
+/// Virtio balloon device using MMIO transport
+pub struct VirtioBalloon {
+    device_features: u64,
+    driver_features: u64,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    device_status: u32,
+
+    queue_sel: u32,
+    queues: [VirtioQueueState; NUM_QUEUES],
+
+    interrupt_status: AtomicU32,
+    interrupt: VirtioInterrupt,
+
+    memory: Option<Arc<GuestMemoryMmap>>,
+
+    /// Balloon size requested by the host, in balloon pages
+    num_pages: u32,
+    /// Balloon size reported by the guest, in balloon pages
+    actual: u32,
+}
+
+impl VirtioBalloon {
+    /// Create a new virtio-balloon device with an empty balloon.
+    ///
+    /// The guest may deflate the balloon on its own when it runs out of
+    /// memory, so a large target never makes the guest OOM.
+    pub fn new(interrupt: VirtioInterrupt) -> Self {
+        Self {
+            device_features: VIRTIO_F_VERSION_1
+                | VIRTIO_BALLOON_F_DEFLATE_ON_OOM
+                | VIRTIO_BALLOON_F_REPORTING,
+            driver_features: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            device_status: 0,
+            queue_sel: 0,
+            queues: Default::default(),
+            interrupt_status: AtomicU32::new(0),
+            interrupt,
+            memory: None,
+            num_pages: 0,
+            actual: 0,
+        }
+    }
+
+    pub fn set_memory(&mut self, memory: Arc<GuestMemoryMmap>) {
+        self.memory = Some(memory);
+    }
+
+    /// Captures the device state for a VM snapshot.
+    pub fn save_state(&self) -> VirtioBalloonState {
+        VirtioBalloonState {
+            transport: VirtioTransportState {
+                driver_features: self.driver_features,
+                device_features_sel: self.device_features_sel,
+                driver_features_sel: self.driver_features_sel,
+                device_status: self.device_status,
+                queue_sel: self.queue_sel,
+                interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+                queues: self.queues.to_vec(),
+            },
+            num_pages: self.num_pages,
+            actual: self.actual,
+        }
+    }
+
+    /// Restores state captured by [`save_state`](Self::save_state).
+    pub fn restore_state(&mut self, state: &VirtioBalloonState) -> Result<()> {
+        let transport = &state.transport;
+        self.queues = transport.queues("virtio-balloon")?;
+        self.driver_features = transport.driver_features;
+        self.device_features_sel = transport.device_features_sel;
+        self.driver_features_sel = transport.driver_features_sel;
+        self.device_status = transport.device_status;
+        self.queue_sel = transport.queue_sel;
+        self.interrupt_status
+            .store(transport.interrupt_status, Ordering::SeqCst);
+        self.num_pages = state.num_pages;
+        self.actual = state.actual;
+        Ok(())
+    }
+
+    /// Asks the guest to grow or shrink the balloon to `pages` balloon pages.
+    ///
+    /// The guest adjusts the balloon in the background; see
+    /// [`actual_pages`](Self::actual_pages) for its progress.
+    pub fn set_target_pages(&mut self, pages: u32) {
+        if pages == self.num_pages {
+            return;
+        }
+        self.num_pages = pages;
+        // The driver reads the target when it starts, so only a running
+        // driver needs to be told
+        if self.is_activated() {
+            self.interrupt_status
+                .fetch_or(VIRTIO_INT_CONFIG, Ordering::SeqCst);
+            self.interrupt.signal_config();
+        }
+    }
+
+    /// Returns the balloon size the guest last reported, in balloon pages.
+    pub fn actual_pages(&self) -> u32 {
+        self.actual
+    }
+
+    fn signal_used_queue(&self) {
+        self.interrupt_status
+            .fetch_or(VIRTIO_INT_USED_RING, Ordering::SeqCst);
+        self.interrupt.signal_used_ring();
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_status & VIRTIO_STATUS_DRIVER_OK != 0
+    }
+
+    fn current_queue(&self) -> &VirtioQueueState {
+        &self.queues[self.queue_sel as usize]
+    }
+
+    fn current_queue_mut(&mut self) -> &mut VirtioQueueState {
+        &mut self.queues[self.queue_sel as usize]
+    }
+
+    /// Process all buffers on one of the queues.
+    ///
+    /// Pages on the inflate and reporting queues are released on the host.
+    /// Deflated pages need no work since released pages are mapped again when
+    /// the guest touches them.
+    fn process_queue(&mut self, index: usize) {
+        let memory = match &self.memory {
+            Some(m) => m.clone(),
+            None => return,
+        };
+        let memory = memory.as_ref();
+
+        let queue_state = &self.queues[index];
+        if !queue_state.ready {
+            return;
+        }
+
+        let mut queue = Queue::new(queue_state.size).unwrap();
+        let _ = queue.try_set_desc_table_address(GuestAddress(queue_state.desc_table));
+        let _ = queue.try_set_avail_ring_address(GuestAddress(queue_state.avail_ring));
+        let _ = queue.try_set_used_ring_address(GuestAddress(queue_state.used_ring));
+        queue.set_next_avail(queue_state.next_avail);
+        queue.set_next_used(queue_state.next_used);
+        queue.set_ready(true);
+
+        let mut used_any = false;
+
+        while let Some(mut desc_chain) = queue.pop_descriptor_chain(memory) {
+            for desc in desc_chain.by_ref() {
+                let desc: Descriptor = desc;
+                match index {
+                    INFLATE_QUEUE_INDEX if !desc.is_write_only() => {
+                        release_pfns(memory, &desc);
+                    }
+                    REPORTING_QUEUE_INDEX if desc.is_write_only() => {
+                        release_range(memory, desc.addr(), desc.len() as u64);
+                    }
+                    _ => {}
+                }
+            }
+
+            if queue.add_used(memory, desc_chain.head_index(), 0).is_ok() {
+                used_any = true;
+            }
+        }
+
+        self.queues[index].next_avail = queue.next_avail();
+        self.queues[index].next_used = queue.next_used();
+
+        if used_any {
+            self.signal_used_queue();
+        }
+    }
+
+    fn handle_mmio_read(&self, offset: u64, data: &mut [u8]) {
+        // Config space may be read with different sizes (1, 2, 4 bytes)
+        if (VIRTIO_MMIO_CONFIG..VIRTIO_MMIO_CONFIG + BALLOON_CONFIG_SIZE).contains(&offset) {
+            let mut config = [0u8; BALLOON_CONFIG_SIZE as usize];
+            config[..4].copy_from_slice(&self.num_pages.to_le_bytes());
+            config[4..].copy_from_slice(&self.actual.to_le_bytes());
+            let config_offset = (offset - VIRTIO_MMIO_CONFIG) as usize;
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = config.get(config_offset + i).copied().unwrap_or(0);
+            }
+            return;
+        }
+
+        let val: u32 = match offset {
+            VIRTIO_MMIO_MAGIC => VIRTIO_MMIO_MAGIC_VALUE,
+            VIRTIO_MMIO_VERSION => 2, // virtio 1.0+
+            VIRTIO_MMIO_DEVICE_ID => VIRTIO_ID_BALLOON,
+            VIRTIO_MMIO_VENDOR_ID => 0x554d4551, // "QEMU" for compatibility
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                if self.device_features_sel == 0 {
+                    self.device_features as u32
+                } else {
+                    (self.device_features >> 32) as u32
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => QUEUE_SIZE as u32,
+            VIRTIO_MMIO_QUEUE_READY => {
+                if self.current_queue().ready {
+                    1
+                } else {
+                    0
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_STATUS => self.device_status,
+            _ => 0,
+        };
+
+        if data.len() >= 4 {
+            data[..4].copy_from_slice(&val.to_le_bytes());
+        }
+    }
+
+    fn handle_mmio_write(&mut self, offset: u64, data: &[u8]) {
+        if data.len() < 4 {
+            return;
+        }
+        let val = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                if self.driver_features_sel == 0 {
+                    self.driver_features = (self.driver_features & 0xffffffff00000000) | val as u64;
+                } else {
+                    self.driver_features =
+                        (self.driver_features & 0x00000000ffffffff) | ((val as u64) << 32);
+                }
+            }
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            VIRTIO_MMIO_QUEUE_SEL => {
+                if val < NUM_QUEUES as u32 {
+                    self.queue_sel = val;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM => {
+                self.current_queue_mut().size = val as u16;
+            }
+            VIRTIO_MMIO_QUEUE_READY => {
+                if val == 1 {
+                    let q = self.current_queue();
+                    if let Some(ref memory) = self.memory
+                        && !validate_queue_addresses(
+                            memory,
+                            q.desc_table,
+                            q.avail_ring,
+                            q.used_ring,
+                            q.size,
+                        )
+                    {
+                        return;
+                    }
+                }
+                self.current_queue_mut().ready = val == 1;
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                if self.is_activated() && (val as usize) < NUM_QUEUES {
+                    self.process_queue(val as usize);
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                self.interrupt_status.fetch_and(!val, Ordering::SeqCst);
+            }
+            VIRTIO_MMIO_STATUS => {
+                if val == 0 {
+                    self.device_status = 0;
+                    self.driver_features = 0;
+                    self.queues = Default::default();
+                    self.actual = 0;
+                } else {
+                    self.device_status = val;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DESC_LOW => {
+                let q = self.current_queue_mut();
+                q.desc_table = (q.desc_table & 0xffffffff00000000) | val as u64;
+            }
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => {
+                let q = self.current_queue_mut();
+                q.desc_table = (q.desc_table & 0x00000000ffffffff) | ((val as u64) << 32);
+            }
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => {
+                let q = self.current_queue_mut();
+                q.avail_ring = (q.avail_ring & 0xffffffff00000000) | val as u64;
+            }
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => {
+                let q = self.current_queue_mut();
+                q.avail_ring = (q.avail_ring & 0x00000000ffffffff) | ((val as u64) << 32);
+            }
+            VIRTIO_MMIO_QUEUE_USED_LOW => {
+                let q = self.current_queue_mut();
+                q.used_ring = (q.used_ring & 0xffffffff00000000) | val as u64;
+            }
+            VIRTIO_MMIO_QUEUE_USED_HIGH => {
+                let q = self.current_queue_mut();
+                q.used_ring = (q.used_ring & 0x00000000ffffffff) | ((val as u64) << 32);
+            }
+            _ if offset == VIRTIO_MMIO_CONFIG + CONFIG_ACTUAL_OFFSET => self.actual = val,
+            _ => {}
+        }
+    }
+}
+
+impl MutDeviceMmio for VirtioBalloon {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.handle_mmio_read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.handle_mmio_write(offset, data);
+    }
+}
+
+impl VirtioDevice for VirtioBalloon {
+    fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+}
+
+/// Releases the balloon pages listed in an inflate buffer.
+fn release_pfns(memory: &GuestMemoryMmap, desc: &Descriptor) {
+    let capped_len = std::cmp::min(desc.len(), MAX_DESCRIPTOR_LEN) as usize;
+    let mut buf = vec![0u8; capped_len];
+    if memory.read_slice(&mut buf, desc.addr()).is_err() {
+        return;
+    }
+
+    for pfn in buf.chunks_exact(4) {
+        let pfn = u32::from_le_bytes([pfn[0], pfn[1], pfn[2], pfn[3]]) as u64;
+        release_range(
+            memory,
+            GuestAddress(pfn * BALLOON_PAGE_SIZE),
+            BALLOON_PAGE_SIZE,
+        );
+    }
+}
+
+/// Returns the host memory behind a range of guest RAM to the host.
+///
+/// The guest sees zeroes the next time it touches the range. Ranges outside
+/// guest RAM are ignored, as are failures: a page that stays resident only
+/// costs memory.
+fn release_range(memory: &GuestMemoryMmap, addr: GuestAddress, len: u64) {
+    let Some(region) = memory.find_region(addr) else {
+        return;
+    };
+    let offset = addr.unchecked_offset_from(region.start_addr());
+    if offset.checked_add(len).is_none_or(|end| end > region.len()) {
+        return;
+    }
+    let Ok(host_addr) = region.get_host_address(MemoryRegionAddress(offset)) else {
+        return;
+    };
+
+    let madvise = |advice| unsafe { libc::madvise(host_addr.cast(), len as usize, advice) };
+    // Shared memfd pages stay in the file unless removed from it, while private
+    // mappings only need to drop their pages
+    if region.file_offset().is_some() && madvise(libc::MADV_REMOVE) == 0 {
+        return;
+    }
+    madvise(libc::MADV_DONTNEED);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_ioctls::Kvm;
+    use vm_memory::GuestRegionMmap;
+
+    const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    const PFN_ARRAY: u64 = 0x4000;
+    const PAGE_ADDR: u64 = 0x8000;
+
+    fn create_test_device() -> VirtioBalloon {
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = kvm.create_vm().expect("Failed to create VM");
+        let mut device = VirtioBalloon::new(VirtioInterrupt::pin(Arc::new(vm), 10));
+
+        let region = GuestRegionMmap::new(
+            vm_memory::MmapRegion::new(0x10000).unwrap(),
+            GuestAddress(0),
+        )
+        .unwrap();
+        device.set_memory(Arc::new(
+            GuestMemoryMmap::from_regions(vec![region]).unwrap(),
+        ));
+        device.device_status = VIRTIO_STATUS_DRIVER_OK;
+        device
+    }
+
+    fn read_u32(device: &VirtioBalloon, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.handle_mmio_read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_u32(device: &mut VirtioBalloon, offset: u64, val: u32) {
+        device.handle_mmio_write(offset, &val.to_le_bytes());
+    }
+
+    /// Places a single-descriptor buffer on a queue and notifies the device.
+    fn submit_buffer(device: &mut VirtioBalloon, index: usize, addr: u64, len: u32, flags: u16) {
+        let memory = device.memory.clone().unwrap();
+        memory.write_obj(addr, GuestAddress(DESC_TABLE)).unwrap();
+        memory.write_obj(len, GuestAddress(DESC_TABLE + 8)).unwrap();
+        memory
+            .write_obj(flags, GuestAddress(DESC_TABLE + 12))
+            .unwrap();
+        memory
+            .write_obj(0u16, GuestAddress(AVAIL_RING + 4))
+            .unwrap();
+        memory
+            .write_obj(1u16, GuestAddress(AVAIL_RING + 2))
+            .unwrap();
+
+        let queue = &mut device.queues[index];
+        queue.desc_table = DESC_TABLE;
+        queue.avail_ring = AVAIL_RING;
+        queue.used_ring = USED_RING;
+        queue.ready = true;
+        write_u32(device, VIRTIO_MMIO_QUEUE_NOTIFY, index as u32);
+
+        let used_idx: u16 = memory.read_obj(GuestAddress(USED_RING + 2)).unwrap();
+        assert_eq!(used_idx, 1);
+    }
+
+    fn page_is_zero(device: &VirtioBalloon) -> bool {
+        let mut buf = [0u8; BALLOON_PAGE_SIZE as usize];
+        let memory = device.memory.clone().unwrap();
+        memory
+            .read_slice(&mut buf, GuestAddress(PAGE_ADDR))
+            .unwrap();
+        buf.iter().all(|&b| b == 0)
+    }
+
+    fn fill_page(device: &VirtioBalloon) {
+        let memory = device.memory.clone().unwrap();
+        memory
+            .write_slice(&[0xab; BALLOON_PAGE_SIZE as usize], GuestAddress(PAGE_ADDR))
+            .unwrap();
+    }
+
+    #[test]
+    fn mmio_magic_version_device_id() {
+        let device = create_test_device();
+
+        assert_eq!(
+            read_u32(&device, VIRTIO_MMIO_MAGIC),
+            VIRTIO_MMIO_MAGIC_VALUE
+        );
+        assert_eq!(read_u32(&device, VIRTIO_MMIO_VERSION), 2);
+        assert_eq!(read_u32(&device, VIRTIO_MMIO_DEVICE_ID), VIRTIO_ID_BALLOON);
+    }
+
+    #[test]
+    fn target_change_raises_config_interrupt() {
+        let mut device = create_test_device();
+
+        device.set_target_pages(256);
+        assert_eq!(read_u32(&device, VIRTIO_MMIO_CONFIG), 256);
+        assert_eq!(
+            read_u32(&device, VIRTIO_MMIO_INTERRUPT_STATUS),
+            VIRTIO_INT_CONFIG
+        );
+    }
+
+    #[test]
+    fn guest_reports_actual_size() {
+        let mut device = create_test_device();
+
+        write_u32(&mut device, VIRTIO_MMIO_CONFIG + CONFIG_ACTUAL_OFFSET, 128);
+        assert_eq!(device.actual_pages(), 128);
+        assert_eq!(
+            read_u32(&device, VIRTIO_MMIO_CONFIG + CONFIG_ACTUAL_OFFSET),
+            128
+        );
+    }
+
+    #[test]
+    fn inflate_releases_pages() {
+        let mut device = create_test_device();
+        fill_page(&device);
+
+        let memory = device.memory.clone().unwrap();
+        let pfn = (PAGE_ADDR / BALLOON_PAGE_SIZE) as u32;
+        memory.write_obj(pfn, GuestAddress(PFN_ARRAY)).unwrap();
+        submit_buffer(&mut device, INFLATE_QUEUE_INDEX, PFN_ARRAY, 4, 0);
+
+        assert!(page_is_zero(&device));
+    }
+
+    #[test]
+    fn deflate_leaves_pages_alone() {
+        let mut device = create_test_device();
+        fill_page(&device);
+
+        let memory = device.memory.clone().unwrap();
+        let pfn = (PAGE_ADDR / BALLOON_PAGE_SIZE) as u32;
+        memory.write_obj(pfn, GuestAddress(PFN_ARRAY)).unwrap();
+        submit_buffer(&mut device, DEFLATE_QUEUE_INDEX, PFN_ARRAY, 4, 0);
+
+        assert!(!page_is_zero(&device));
+    }
+
+    #[test]
+    fn reported_free_pages_are_released() {
+        let mut device = create_test_device();
+        fill_page(&device);
+
+        submit_buffer(
+            &mut device,
+            REPORTING_QUEUE_INDEX,
+            PAGE_ADDR,
+            BALLOON_PAGE_SIZE as u32,
+            VIRTQ_DESC_F_WRITE,
+        );
+
+        assert!(page_is_zero(&device));
+    }
+
+    #[test]
+    fn ranges_outside_memory_are_ignored() {
+        let device = create_test_device();
+        let memory = device.memory.clone().unwrap();
+
+        release_range(&memory, GuestAddress(0xf000), 0x2000);
+        release_range(&memory, GuestAddress(0x20000), BALLOON_PAGE_SIZE);
+    }
+}
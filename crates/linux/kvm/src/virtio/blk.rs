@@ -0,0 +1,802 @@
+//! Virtio block device implementation.
+//!
+//! Exposes a host disk image to the guest as a virtio-blk device over MMIO
+//! transport. Supports read, write, flush and get-id requests. The image format
+//! is handled by a `capsa_core::BlockBackend`.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use capsa_core::BlockBackend;
+use virtio_queue::desc::split::Descriptor;
+use virtio_queue::{Queue, QueueT};
+use vm_device::MutDeviceMmio;
+use vm_device::bus::{MmioAddress, MmioAddressOffset};
+use vm_memory::{Address, Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::common::{
+    VIRTIO_MMIO_CONFIG, VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL,
+    VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_DRIVER_FEATURES_SEL,
+    VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_MAGIC,
+    VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_QUEUE_AVAIL_HIGH, VIRTIO_MMIO_QUEUE_AVAIL_LOW,
+    VIRTIO_MMIO_QUEUE_DESC_HIGH, VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_NOTIFY,
+    VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY,
+    VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW,
+    VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION, VirtioQueueState,
+    VirtioTransportState,
+};
+use super::{MAX_DESCRIPTOR_LEN, VirtioDevice, VirtioInterrupt, validate_queue_addresses};
+
+const VIRTIO_ID_BLOCK: u32 = 2;
+
+const REQUEST_QUEUE_INDEX: usize = 0;
+const QUEUE_SIZE: u16 = 256;
+
+const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+
+const VIRTIO_INT_USED_RING: u32 = 1;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+const VIRTIO_BLK_F_SEG_MAX: u64 = 1 << 2;
+const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+const VIRTIO_BLK_F_BLK_SIZE: u64 = 1 << 6;
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+// Request types (virtio 1.0 spec section 5.2.6)
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+// Request status values written to the final descriptor
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// Sector size used by the virtio-blk protocol, independent of `blk_size`.
+const SECTOR_SIZE: u64 = 512;
+
+/// Request header: type(4) + reserved(4) + sector(8) = 16 bytes
+const REQUEST_HEADER_SIZE: usize = 16;
+
+/// Length of the device ID string returned by `VIRTIO_BLK_T_GET_ID`.
+const DEVICE_ID_LEN: usize = 20;
+
+// Config space layout: capacity(8) + size_max(4) + seg_max(4) + geometry(4) + blk_size(4)
+const CONFIG_SEG_MAX_OFFSET: usize = 12;
+const CONFIG_BLK_SIZE_OFFSET: usize = 20;
+const BLK_CONFIG_SIZE: usize = 24;
+
+/// Virtio block device using MMIO transport
+pub struct VirtioBlk {
+    device_features: u64,
+    driver_features: u64,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    device_status: u32,
+
+    queue_sel: u32,
+    queues: [VirtioQueueState; 1],
+
+    interrupt_status: AtomicU32,
+    interrupt: VirtioInterrupt,
+
+    memory: Option<Arc<GuestMemoryMmap>>,
+
+    disk: Box<dyn BlockBackend>,
+    /// Disk size in 512-byte sectors
+    capacity: u64,
+    read_only: bool,
+    device_id: [u8; DEVICE_ID_LEN],
+    config: [u8; BLK_CONFIG_SIZE],
+}
+
+impl VirtioBlk {
+    /// Create a new virtio-blk device backed by `disk`.
+    ///
+    /// The `device_id` is reported to the guest via `VIRTIO_BLK_T_GET_ID` and is
+    /// truncated to 20 bytes. When `read_only` is set, the device advertises
+    /// `VIRTIO_BLK_F_RO` and rejects write requests.
+    pub fn new(
+        disk: Box<dyn BlockBackend>,
+        read_only: bool,
+        device_id: &str,
+        interrupt: VirtioInterrupt,
+    ) -> Self {
+        let capacity = disk.size() / SECTOR_SIZE;
+
+        let mut device_features =
+            VIRTIO_F_VERSION_1 | VIRTIO_BLK_F_SEG_MAX | VIRTIO_BLK_F_BLK_SIZE | VIRTIO_BLK_F_FLUSH;
+        if read_only {
+            device_features |= VIRTIO_BLK_F_RO;
+        }
+
+        let mut id = [0u8; DEVICE_ID_LEN];
+        let id_len = device_id.len().min(DEVICE_ID_LEN);
+        id[..id_len].copy_from_slice(&device_id.as_bytes()[..id_len]);
+
+        let mut config = [0u8; BLK_CONFIG_SIZE];
+        config[..8].copy_from_slice(&capacity.to_le_bytes());
+        // The request header and status descriptors take two slots of each chain
+        config[CONFIG_SEG_MAX_OFFSET..CONFIG_SEG_MAX_OFFSET + 4]
+            .copy_from_slice(&(QUEUE_SIZE as u32 - 2).to_le_bytes());
+        config[CONFIG_BLK_SIZE_OFFSET..CONFIG_BLK_SIZE_OFFSET + 4]
+            .copy_from_slice(&(SECTOR_SIZE as u32).to_le_bytes());
+
+        Self {
+            device_features,
+            driver_features: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            device_status: 0,
+            queue_sel: 0,
+            queues: [VirtioQueueState::default()],
+            interrupt_status: AtomicU32::new(0),
+            interrupt,
+            memory: None,
+            disk,
+            capacity,
+            read_only,
+            device_id: id,
+            config,
+        }
+    }
+
+    pub fn set_memory(&mut self, memory: Arc<GuestMemoryMmap>) {
+        self.memory = Some(memory);
+    }
+
+    /// Captures the transport state for a VM snapshot.
+    pub fn save_state(&self) -> VirtioTransportState {
+        VirtioTransportState {
+            driver_features: self.driver_features,
+            device_features_sel: self.device_features_sel,
+            driver_features_sel: self.driver_features_sel,
+            device_status: self.device_status,
+            queue_sel: self.queue_sel,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            queues: self.queues.to_vec(),
+        }
+    }
+
+    /// Restores state captured by [`save_state`](Self::save_state).
+    pub fn restore_state(&mut self, state: &VirtioTransportState) -> capsa_core::Result<()> {
+        self.queues = state.queues("virtio-blk")?;
+        self.driver_features = state.driver_features;
+        self.device_features_sel = state.device_features_sel;
+        self.driver_features_sel = state.driver_features_sel;
+        self.device_status = state.device_status;
+        self.queue_sel = state.queue_sel;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Writes buffered data and metadata to the disk image.
+    ///
+    /// Snapshots do not copy disks, so the image must be consistent on disk
+    /// before the guest memory that references it is saved.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.disk.flush()
+    }
+
+    fn signal_used_queue(&self) {
+        self.interrupt_status
+            .fetch_or(VIRTIO_INT_USED_RING, Ordering::SeqCst);
+        self.interrupt.signal_used_ring();
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_status & VIRTIO_STATUS_DRIVER_OK != 0
+    }
+
+    fn current_queue(&self) -> &VirtioQueueState {
+        &self.queues[self.queue_sel as usize]
+    }
+
+    fn current_queue_mut(&mut self) -> &mut VirtioQueueState {
+        &mut self.queues[self.queue_sel as usize]
+    }
+
+    /// Process all pending requests on the request queue.
+    fn process_request_queue(&mut self) {
+        let memory = match &self.memory {
+            Some(m) => m.clone(),
+            None => return,
+        };
+        let memory = memory.as_ref();
+
+        let queue_state = &self.queues[REQUEST_QUEUE_INDEX];
+        if !queue_state.ready {
+            return;
+        }
+
+        let mut queue = Queue::new(queue_state.size).unwrap();
+        let _ = queue.try_set_desc_table_address(GuestAddress(queue_state.desc_table));
+        let _ = queue.try_set_avail_ring_address(GuestAddress(queue_state.avail_ring));
+        let _ = queue.try_set_used_ring_address(GuestAddress(queue_state.used_ring));
+        queue.set_next_avail(queue_state.next_avail);
+        queue.set_next_used(queue_state.next_used);
+        queue.set_ready(true);
+
+        let mut used_any = false;
+
+        while let Some(mut desc_chain) = queue.pop_descriptor_chain(memory) {
+            let descriptors: Vec<Descriptor> = desc_chain.by_ref().collect();
+            let len = self.handle_request(memory, &descriptors);
+
+            if queue.add_used(memory, desc_chain.head_index(), len).is_ok() {
+                used_any = true;
+            }
+        }
+
+        self.queues[REQUEST_QUEUE_INDEX].next_avail = queue.next_avail();
+        self.queues[REQUEST_QUEUE_INDEX].next_used = queue.next_used();
+
+        if used_any {
+            self.signal_used_queue();
+        }
+    }
+
+    /// Execute a single request and write its status byte.
+    ///
+    /// Returns the number of bytes written into guest memory, including the
+    /// status byte.
+    fn handle_request(&mut self, memory: &GuestMemoryMmap, descriptors: &[Descriptor]) -> u32 {
+        let Some((status_desc, rest)) = descriptors.split_last() else {
+            return 0;
+        };
+        let Some((header_desc, data_descs)) = rest.split_first() else {
+            return 0;
+        };
+        if !status_desc.is_write_only() || status_desc.len() == 0 {
+            tracing::warn!("virtio-blk: request without a writable status descriptor");
+            return 0;
+        }
+
+        let (status, len) = match self.execute_request(memory, header_desc, data_descs) {
+            Ok(len) => (VIRTIO_BLK_S_OK, len),
+            Err(status) => (status, 0),
+        };
+
+        if memory.write_obj(status, status_desc.addr()).is_err() {
+            return len;
+        }
+        len + 1
+    }
+
+    fn execute_request(
+        &mut self,
+        memory: &GuestMemoryMmap,
+        header_desc: &Descriptor,
+        data_descs: &[Descriptor],
+    ) -> Result<u32, u8> {
+        if header_desc.is_write_only() || (header_desc.len() as usize) < REQUEST_HEADER_SIZE {
+            return Err(VIRTIO_BLK_S_IOERR);
+        }
+        let mut header = [0u8; REQUEST_HEADER_SIZE];
+        memory
+            .read_slice(&mut header, header_desc.addr())
+            .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+        let request_type = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+
+        match request_type {
+            VIRTIO_BLK_T_IN => self.read_sectors(memory, sector, data_descs),
+            VIRTIO_BLK_T_OUT => self.write_sectors(memory, sector, data_descs),
+            VIRTIO_BLK_T_FLUSH => {
+                if !self.read_only {
+                    self.disk.flush().map_err(|e| {
+                        tracing::warn!("virtio-blk: flush failed: {}", e);
+                        VIRTIO_BLK_S_IOERR
+                    })?;
+                }
+                Ok(0)
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let desc = data_descs.first().ok_or(VIRTIO_BLK_S_IOERR)?;
+                if !desc.is_write_only() {
+                    return Err(VIRTIO_BLK_S_IOERR);
+                }
+                let len = (desc.len() as usize).min(DEVICE_ID_LEN);
+                memory
+                    .write_slice(&self.device_id[..len], desc.addr())
+                    .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+                Ok(len as u32)
+            }
+            _ => Err(VIRTIO_BLK_S_UNSUPP),
+        }
+    }
+
+    /// Returns the byte offset of `sector`, checking that `len` bytes from
+    /// there stay within the disk.
+    fn checked_offset(&self, sector: u64, len: u64) -> Result<u64, u8> {
+        let offset = sector.checked_mul(SECTOR_SIZE).ok_or(VIRTIO_BLK_S_IOERR)?;
+        let end = offset.checked_add(len).ok_or(VIRTIO_BLK_S_IOERR)?;
+        if end > self.capacity * SECTOR_SIZE {
+            tracing::warn!(
+                "virtio-blk: request at sector {} ({} bytes) beyond end of disk",
+                sector,
+                len
+            );
+            return Err(VIRTIO_BLK_S_IOERR);
+        }
+        Ok(offset)
+    }
+
+    fn read_sectors(
+        &mut self,
+        memory: &GuestMemoryMmap,
+        sector: u64,
+        data_descs: &[Descriptor],
+    ) -> Result<u32, u8> {
+        let total: u64 = data_descs.iter().map(|d| d.len() as u64).sum();
+        let mut offset = self.checked_offset(sector, total)?;
+        let mut written = 0u32;
+
+        for desc in data_descs {
+            if !desc.is_write_only() {
+                return Err(VIRTIO_BLK_S_IOERR);
+            }
+            // Copy through a bounded buffer so large descriptors don't cause
+            // equally large host allocations
+            let mut done = 0u64;
+            while done < desc.len() as u64 {
+                let chunk = (desc.len() as u64 - done).min(MAX_DESCRIPTOR_LEN as u64) as usize;
+                let mut buf = vec![0u8; chunk];
+                self.disk.read_at(&mut buf, offset).map_err(|e| {
+                    tracing::warn!("virtio-blk: read at offset {} failed: {}", offset, e);
+                    VIRTIO_BLK_S_IOERR
+                })?;
+                let addr = desc.addr().checked_add(done).ok_or(VIRTIO_BLK_S_IOERR)?;
+                memory
+                    .write_slice(&buf, addr)
+                    .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+                offset += chunk as u64;
+                done += chunk as u64;
+                written += chunk as u32;
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn write_sectors(
+        &mut self,
+        memory: &GuestMemoryMmap,
+        sector: u64,
+        data_descs: &[Descriptor],
+    ) -> Result<u32, u8> {
+        if self.read_only {
+            return Err(VIRTIO_BLK_S_IOERR);
+        }
+
+        let total: u64 = data_descs.iter().map(|d| d.len() as u64).sum();
+        let mut offset = self.checked_offset(sector, total)?;
+
+        for desc in data_descs {
+            if desc.is_write_only() {
+                return Err(VIRTIO_BLK_S_IOERR);
+            }
+            let mut done = 0u64;
+            while done < desc.len() as u64 {
+                let chunk = (desc.len() as u64 - done).min(MAX_DESCRIPTOR_LEN as u64) as usize;
+                let mut buf = vec![0u8; chunk];
+                let addr = desc.addr().checked_add(done).ok_or(VIRTIO_BLK_S_IOERR)?;
+                memory
+                    .read_slice(&mut buf, addr)
+                    .map_err(|_| VIRTIO_BLK_S_IOERR)?;
+                self.disk.write_at(&buf, offset).map_err(|e| {
+                    tracing::warn!("virtio-blk: write at offset {} failed: {}", offset, e);
+                    VIRTIO_BLK_S_IOERR
+                })?;
+                offset += chunk as u64;
+                done += chunk as u64;
+            }
+        }
+
+        // Out requests only write the status byte into guest memory
+        Ok(0)
+    }
+
+    fn handle_mmio_read(&self, offset: u64, data: &mut [u8]) {
+        // Config space may be read with different sizes (1, 2, 4 bytes)
+        if offset >= VIRTIO_MMIO_CONFIG && offset < VIRTIO_MMIO_CONFIG + BLK_CONFIG_SIZE as u64 {
+            let config_offset = (offset - VIRTIO_MMIO_CONFIG) as usize;
+            for (i, byte) in data.iter_mut().enumerate() {
+                *byte = self.config.get(config_offset + i).copied().unwrap_or(0);
+            }
+            return;
+        }
+
+        let val: u32 = match offset {
+            VIRTIO_MMIO_MAGIC => VIRTIO_MMIO_MAGIC_VALUE,
+            VIRTIO_MMIO_VERSION => 2, // virtio 1.0+
+            VIRTIO_MMIO_DEVICE_ID => VIRTIO_ID_BLOCK,
+            VIRTIO_MMIO_VENDOR_ID => 0x554d4551, // "QEMU" for compatibility
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                if self.device_features_sel == 0 {
+                    self.device_features as u32
+                } else {
+                    (self.device_features >> 32) as u32
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => QUEUE_SIZE as u32,
+            VIRTIO_MMIO_QUEUE_READY => {
+                if self.current_queue().ready {
+                    1
+                } else {
+                    0
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_STATUS => self.device_status,
+            _ => 0,
+        };
+
+        if data.len() >= 4 {
+            data[..4].copy_from_slice(&val.to_le_bytes());
+        }
+    }
+
+    fn handle_mmio_write(&mut self, offset: u64, data: &[u8]) {
+        if data.len() < 4 {
+            return;
+        }
+        let val = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                if self.driver_features_sel == 0 {
+                    self.driver_features = (self.driver_features & 0xffffffff00000000) | val as u64;
+                } else {
+                    self.driver_features =
+                        (self.driver_features & 0x00000000ffffffff) | ((val as u64) << 32);
+                }
+            }
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            VIRTIO_MMIO_QUEUE_SEL => {
+                if val < 1 {
+                    self.queue_sel = val;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM => {
+                self.current_queue_mut().size = val as u16;
+            }
+            VIRTIO_MMIO_QUEUE_READY => {
+                if val == 1 {
+                    let q = self.current_queue();
+                    if let Some(ref memory) = self.memory
+                        && !validate_queue_addresses(
+                            memory,
+                            q.desc_table,
+                            q.avail_ring,
+                            q.used_ring,
+                            q.size,
+                        )
+                    {
+                        return;
+                    }
+                }
+                self.current_queue_mut().ready = val == 1;
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                if self.is_activated() && val == REQUEST_QUEUE_INDEX as u32 {
+                    self.process_request_queue();
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                self.interrupt_status.fetch_and(!val, Ordering::SeqCst);
+            }
+            VIRTIO_MMIO_STATUS => {
+                if val == 0 {
+                    self.device_status = 0;
+                    self.driver_features = 0;
+                    self.queues = [VirtioQueueState::default()];
+                } else {
+                    self.device_status = val;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DESC_LOW => {
+                let q = self.current_queue_mut();
+                q.desc_table = (q.desc_table & 0xffffffff00000000) | val as u64;
+            }
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => {
+                let q = self.current_queue_mut();
+                q.desc_table = (q.desc_table & 0x00000000ffffffff) | ((val as u64) << 32);
+            }
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => {
+                let q = self.current_queue_mut();
+                q.avail_ring = (q.avail_ring & 0xffffffff00000000) | val as u64;
+            }
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => {
+                let q = self.current_queue_mut();
+                q.avail_ring = (q.avail_ring & 0x00000000ffffffff) | ((val as u64) << 32);
+            }
+            VIRTIO_MMIO_QUEUE_USED_LOW => {
+                let q = self.current_queue_mut();
+                q.used_ring = (q.used_ring & 0xffffffff00000000) | val as u64;
+            }
+            VIRTIO_MMIO_QUEUE_USED_HIGH => {
+                let q = self.current_queue_mut();
+                q.used_ring = (q.used_ring & 0x00000000ffffffff) | ((val as u64) << 32);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl MutDeviceMmio for VirtioBlk {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.handle_mmio_read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.handle_mmio_write(offset, data);
+    }
+}
+
+impl VirtioDevice for VirtioBlk {
+    fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use capsa_core::RawImage;
+    use kvm_ioctls::Kvm;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+    use vm_memory::GuestRegionMmap;
+
+    const VIRTQ_DESC_F_NEXT: u16 = 1;
+    const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    const HEADER_ADDR: u64 = 0x4000;
+    const DATA_ADDR: u64 = 0x5000;
+    const STATUS_ADDR: u64 = 0x6000;
+
+    fn create_disk(contents: &[u8]) -> NamedTempFile {
+        let mut file = NamedTempFile::new().expect("Failed to create temp file");
+        file.write_all(contents).unwrap();
+        file
+    }
+
+    fn create_test_device(disk: &NamedTempFile, read_only: bool) -> VirtioBlk {
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = kvm.create_vm().expect("Failed to create VM");
+        let image = RawImage::open(disk.path(), read_only).unwrap();
+        let mut device = VirtioBlk::new(
+            Box::new(image),
+            read_only,
+            "capsa-test",
+            VirtioInterrupt::pin(Arc::new(vm), 9),
+        );
+
+        let region = GuestRegionMmap::new(
+            vm_memory::MmapRegion::new(0x10000).unwrap(),
+            GuestAddress(0),
+        )
+        .unwrap();
+        device.set_memory(Arc::new(
+            GuestMemoryMmap::from_regions(vec![region]).unwrap(),
+        ));
+        device
+    }
+
+    fn read_u32(device: &VirtioBlk, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.handle_mmio_read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_descriptor(
+        memory: &GuestMemoryMmap,
+        idx: u16,
+        addr: u64,
+        len: u32,
+        flags: u16,
+        next: u16,
+    ) {
+        let desc_addr = DESC_TABLE + (idx as u64 * 16);
+        memory.write_obj(addr, GuestAddress(desc_addr)).unwrap();
+        memory.write_obj(len, GuestAddress(desc_addr + 8)).unwrap();
+        memory
+            .write_obj(flags, GuestAddress(desc_addr + 12))
+            .unwrap();
+        memory
+            .write_obj(next, GuestAddress(desc_addr + 14))
+            .unwrap();
+    }
+
+    /// Places a header/data/status request on the queue and processes it,
+    /// returning the status byte and the used length.
+    fn submit_request(
+        device: &mut VirtioBlk,
+        request_type: u32,
+        sector: u64,
+        data_len: u32,
+        data_write_only: bool,
+    ) -> (u8, u32) {
+        let memory = device.memory.clone().unwrap();
+
+        let mut header = [0u8; REQUEST_HEADER_SIZE];
+        header[0..4].copy_from_slice(&request_type.to_le_bytes());
+        header[8..16].copy_from_slice(&sector.to_le_bytes());
+        memory
+            .write_slice(&header, GuestAddress(HEADER_ADDR))
+            .unwrap();
+        memory.write_obj(0xffu8, GuestAddress(STATUS_ADDR)).unwrap();
+
+        let data_flags = if data_write_only {
+            VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE
+        } else {
+            VIRTQ_DESC_F_NEXT
+        };
+        write_descriptor(&memory, 0, HEADER_ADDR, 16, VIRTQ_DESC_F_NEXT, 1);
+        write_descriptor(&memory, 1, DATA_ADDR, data_len, data_flags, 2);
+        write_descriptor(&memory, 2, STATUS_ADDR, 1, VIRTQ_DESC_F_WRITE, 0);
+
+        let avail_idx: u16 = memory.read_obj(GuestAddress(AVAIL_RING + 2)).unwrap();
+        let slot = AVAIL_RING + 4 + (avail_idx as u64 % QUEUE_SIZE as u64) * 2;
+        memory.write_obj(0u16, GuestAddress(slot)).unwrap();
+        memory
+            .write_obj(avail_idx.wrapping_add(1), GuestAddress(AVAIL_RING + 2))
+            .unwrap();
+
+        let queue = &mut device.queues[REQUEST_QUEUE_INDEX];
+        queue.desc_table = DESC_TABLE;
+        queue.avail_ring = AVAIL_RING;
+        queue.used_ring = USED_RING;
+        queue.ready = true;
+        device.process_request_queue();
+
+        let used_idx: u16 = memory.read_obj(GuestAddress(USED_RING + 2)).unwrap();
+        let entry = USED_RING + 4 + (used_idx.wrapping_sub(1) as u64 % QUEUE_SIZE as u64) * 8;
+        let used_len: u32 = memory.read_obj(GuestAddress(entry + 4)).unwrap();
+        let status: u8 = memory.read_obj(GuestAddress(STATUS_ADDR)).unwrap();
+        (status, used_len)
+    }
+
+    #[test]
+    fn mmio_magic_version_device_id() {
+        let disk = create_disk(&[0u8; 4096]);
+        let device = create_test_device(&disk, false);
+
+        assert_eq!(
+            read_u32(&device, VIRTIO_MMIO_MAGIC),
+            VIRTIO_MMIO_MAGIC_VALUE
+        );
+        assert_eq!(read_u32(&device, VIRTIO_MMIO_VERSION), 2);
+        assert_eq!(read_u32(&device, VIRTIO_MMIO_DEVICE_ID), VIRTIO_ID_BLOCK);
+    }
+
+    #[test]
+    fn config_reports_capacity_in_sectors() {
+        let disk = create_disk(&[0u8; 8192]);
+        let device = create_test_device(&disk, false);
+
+        let low = read_u32(&device, VIRTIO_MMIO_CONFIG) as u64;
+        let high = read_u32(&device, VIRTIO_MMIO_CONFIG + 4) as u64;
+        assert_eq!(low | (high << 32), 16);
+        assert_eq!(
+            read_u32(&device, VIRTIO_MMIO_CONFIG + CONFIG_BLK_SIZE_OFFSET as u64),
+            512
+        );
+    }
+
+    #[test]
+    fn read_only_feature_advertised() {
+        let disk = create_disk(&[0u8; 4096]);
+
+        let device = create_test_device(&disk, false);
+        assert_eq!(
+            read_u32(&device, VIRTIO_MMIO_DEVICE_FEATURES) as u64 & VIRTIO_BLK_F_RO,
+            0
+        );
+
+        let device = create_test_device(&disk, true);
+        assert_ne!(
+            read_u32(&device, VIRTIO_MMIO_DEVICE_FEATURES) as u64 & VIRTIO_BLK_F_RO,
+            0
+        );
+    }
+
+    #[test]
+    fn read_request_copies_sectors() {
+        let mut contents = vec![0u8; 2048];
+        contents[512..1024].fill(0xab);
+        let disk = create_disk(&contents);
+        let mut device = create_test_device(&disk, false);
+
+        let (status, used_len) = submit_request(&mut device, VIRTIO_BLK_T_IN, 1, 512, true);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(used_len, 513);
+
+        let mut buf = [0u8; 512];
+        let memory = device.memory.clone().unwrap();
+        memory
+            .read_slice(&mut buf, GuestAddress(DATA_ADDR))
+            .unwrap();
+        assert!(buf.iter().all(|&b| b == 0xab));
+    }
+
+    #[test]
+    fn write_request_persists_to_disk() {
+        let disk = create_disk(&[0u8; 2048]);
+        let mut device = create_test_device(&disk, false);
+
+        let memory = device.memory.clone().unwrap();
+        memory
+            .write_slice(&[0x5a; 512], GuestAddress(DATA_ADDR))
+            .unwrap();
+
+        let (status, used_len) = submit_request(&mut device, VIRTIO_BLK_T_OUT, 2, 512, false);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(used_len, 1);
+
+        let contents = std::fs::read(disk.path()).unwrap();
+        assert!(contents[1024..1536].iter().all(|&b| b == 0x5a));
+        assert!(contents[..1024].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn read_only_rejects_writes() {
+        let disk = create_disk(&[0u8; 2048]);
+        let mut device = create_test_device(&disk, true);
+
+        let (status, _) = submit_request(&mut device, VIRTIO_BLK_T_OUT, 0, 512, false);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+
+        let contents = std::fs::read(disk.path()).unwrap();
+        assert!(contents.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn read_beyond_capacity_fails() {
+        let disk = create_disk(&[0u8; 1024]);
+        let mut device = create_test_device(&disk, false);
+
+        let (status, _) = submit_request(&mut device, VIRTIO_BLK_T_IN, 1, 1024, true);
+        assert_eq!(status, VIRTIO_BLK_S_IOERR);
+    }
+
+    #[test]
+    fn get_id_returns_device_id() {
+        let disk = create_disk(&[0u8; 1024]);
+        let mut device = create_test_device(&disk, false);
+
+        let (status, used_len) = submit_request(
+            &mut device,
+            VIRTIO_BLK_T_GET_ID,
+            0,
+            DEVICE_ID_LEN as u32,
+            true,
+        );
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(used_len, DEVICE_ID_LEN as u32 + 1);
+
+        let mut buf = [0u8; DEVICE_ID_LEN];
+        let memory = device.memory.clone().unwrap();
+        memory
+            .read_slice(&mut buf, GuestAddress(DATA_ADDR))
+            .unwrap();
+        assert_eq!(&buf[..10], b"capsa-test");
+        assert!(buf[10..].iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn unsupported_request_type() {
+        let disk = create_disk(&[0u8; 1024]);
+        let mut device = create_test_device(&disk, false);
+
+        let (status, _) = submit_request(&mut device, 0xdead, 0, 512, true);
+        assert_eq!(status, VIRTIO_BLK_S_UNSUPP);
+    }
+}
@@ -1,5 +1,8 @@
 //! Common virtio MMIO constants and types shared across all devices.
 
+use capsa_core::{Error, Result};
+use serde::{Deserialize, Serialize};
+
 /// Default queue size for virtio devices.
 pub const DEFAULT_QUEUE_SIZE: u16 = 256;
 
@@ -32,7 +35,7 @@ pub const VIRTIO_MMIO_CONFIG: u64 = 0x100;
 pub const VIRTIO_MMIO_MAGIC_VALUE: u32 = 0x74726976;
 
 /// Queue state shared by all virtio devices.
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct VirtioQueueState {
     pub ready: bool,
     pub size: u16,
@@ -62,3 +65,38 @@ impl Default for VirtioQueueState {
         Self::new(DEFAULT_QUEUE_SIZE)
     }
 }
+
+/// Guest-visible MMIO transport state, saved in VM snapshots.
+///
+/// Device features are fixed at construction, so only what the driver
+/// negotiated or configured is kept.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VirtioTransportState {
+    pub driver_features: u64,
+    pub device_features_sel: u32,
+    pub driver_features_sel: u32,
+    pub device_status: u32,
+    pub queue_sel: u32,
+    pub interrupt_status: u32,
+    pub queues: Vec<VirtioQueueState>,
+}
+
+impl VirtioTransportState {
+    /// Returns the saved queues, checking that they fit a device with `N` queues.
+    pub fn queues<const N: usize>(&self, device: &str) -> Result<[VirtioQueueState; N]> {
+        if self.queue_sel as usize >= N {
+            return Err(Error::StartFailed(format!(
+                "invalid {} snapshot: queue {} selected",
+                device, self.queue_sel
+            )));
+        }
+        self.queues.clone().try_into().map_err(|_| {
+            Error::StartFailed(format!(
+                "invalid {} snapshot: expected {} queues, found {}",
+                device,
+                N,
+                self.queues.len()
+            ))
+        })
+    }
+}
@@ -8,7 +8,7 @@ use std::io::Write;
 use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::{Arc, Mutex};
 
-use kvm_ioctls::VmFd;
+use capsa_core::Result;
 use virtio_queue::desc::split::Descriptor;
 use virtio_queue::{Queue, QueueT};
 use vm_device::MutDeviceMmio;
@@ -23,9 +23,9 @@ use super::common::{
     VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM,
     VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY, VIRTIO_MMIO_QUEUE_SEL,
     VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW, VIRTIO_MMIO_STATUS,
-    VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION, VirtioQueueState,
+    VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION, VirtioQueueState, VirtioTransportState,
 };
-use super::{MAX_DESCRIPTOR_LEN, validate_queue_addresses};
+use super::{MAX_DESCRIPTOR_LEN, VirtioDevice, VirtioInterrupt, validate_queue_addresses};
 
 const VIRTIO_ID_CONSOLE: u32 = 3;
 
@@ -55,10 +55,7 @@ pub struct VirtioConsole {
 
     // Interrupt handling
     interrupt_status: AtomicU32,
-    /// VmFd for direct interrupt injection via set_irq_line
-    vm_fd: Arc<VmFd>,
-    /// IRQ number for this device
-    irq: u32,
+    interrupt: VirtioInterrupt,
 
     // Console I/O
     output: Mutex<Box<dyn Write + Send>>,
@@ -71,9 +68,8 @@ pub struct VirtioConsole {
 impl VirtioConsole {
     /// Create a new virtio-console device.
     ///
-    /// The `vm_fd` is used for direct interrupt injection via set_irq_line.
-    /// The `irq` is the IRQ number for this device (typically 5).
-    pub fn new(output: Box<dyn Write + Send>, vm_fd: Arc<VmFd>, irq: u32) -> Self {
+    /// Console output is written to `output`.
+    pub fn new(output: Box<dyn Write + Send>, interrupt: VirtioInterrupt) -> Self {
         Self {
             device_features: VIRTIO_F_VERSION_1, // Required for virtio 1.0+
             driver_features: 0,
@@ -83,8 +79,7 @@ impl VirtioConsole {
             queue_sel: 0,
             queues: [VirtioQueueState::default(), VirtioQueueState::default()],
             interrupt_status: AtomicU32::new(0),
-            vm_fd,
-            irq,
+            interrupt,
             output: Mutex::new(output),
             input_buffer: Mutex::new(VecDeque::new()),
             memory: None,
@@ -95,6 +90,34 @@ impl VirtioConsole {
         self.memory = Some(memory);
     }
 
+    /// Captures the transport state for a VM snapshot.
+    pub fn save_state(&self) -> VirtioTransportState {
+        VirtioTransportState {
+            driver_features: self.driver_features,
+            device_features_sel: self.device_features_sel,
+            driver_features_sel: self.driver_features_sel,
+            device_status: self.device_status,
+            queue_sel: self.queue_sel,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            queues: self.queues.to_vec(),
+        }
+    }
+
+    /// Restores state captured by [`save_state`](Self::save_state).
+    ///
+    /// Characters buffered for the guest are not part of the snapshot.
+    pub fn restore_state(&mut self, state: &VirtioTransportState) -> Result<()> {
+        self.queues = state.queues("virtio-console")?;
+        self.driver_features = state.driver_features;
+        self.device_features_sel = state.device_features_sel;
+        self.driver_features_sel = state.driver_features_sel;
+        self.device_status = state.device_status;
+        self.queue_sel = state.queue_sel;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        Ok(())
+    }
+
     pub fn enqueue_input(&mut self, data: &[u8]) {
         {
             let mut input = self.input_buffer.lock().unwrap();
@@ -106,9 +129,7 @@ impl VirtioConsole {
     fn signal_used_queue(&self) {
         self.interrupt_status
             .fetch_or(VIRTIO_INT_USED_RING, Ordering::SeqCst);
-        // Edge-triggered interrupt: assert then de-assert
-        let _ = self.vm_fd.set_irq_line(self.irq, true);
-        let _ = self.vm_fd.set_irq_line(self.irq, false);
+        self.interrupt.signal_used_ring();
     }
 
     fn is_activated(&self) -> bool {
@@ -375,3 +396,9 @@ impl MutDeviceMmio for VirtioConsole {
         self.handle_mmio_write(offset, data);
     }
 }
+
+impl VirtioDevice for VirtioConsole {
+    fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+}
@@ -6,9 +6,12 @@
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::Duration;
 
-use kvm_ioctls::VmFd;
+use capsa_core::Result;
 use nix::libc;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
 use vm_device::MutDeviceMmio;
 use vm_device::bus::{MmioAddress, MmioAddressOffset};
 use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
@@ -22,18 +25,23 @@ use super::common::{
     VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY,
     VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW,
     VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION, VirtioQueueState,
+    VirtioTransportState,
 };
-use super::{MAX_DESCRIPTOR_LEN, validate_queue_addresses};
+use super::{MAX_DESCRIPTOR_LEN, VirtioDevice, VirtioInterrupt, validate_queue_addresses};
 use crate::fuse::{
-    FATTR_ATIME, FATTR_ATIME_NOW, FATTR_GID, FATTR_MODE, FATTR_MTIME, FATTR_MTIME_NOW, FATTR_SIZE,
-    FATTR_UID, FOPEN_KEEP_CACHE, FUSE_ASYNC_READ, FUSE_ATOMIC_O_TRUNC, FUSE_BIG_WRITES,
-    FUSE_EXPORT_SUPPORT, FUSE_IN_HEADER_SIZE, FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION,
-    FUSE_MAX_PAGES, FUSE_PARALLEL_DIROPS, FuseAttrOut, FuseCreateIn, FuseDirent, FuseEntryOut,
-    FuseFlushIn, FuseForgetIn, FuseFsyncIn, FuseInHeader, FuseInitIn, FuseInitOut, FuseLinkIn,
-    FuseMkdirIn, FuseOpcode, FuseOpenIn, FuseOpenOut, FuseReadIn, FuseReleaseIn, FuseRenameIn,
-    FuseSetattrIn, FuseStatfsOut, FuseWriteIn, FuseWriteOut, HandleTable, InodeTable,
-    errno_from_io, error_response, extract_name, metadata_to_attr, success_response,
-    success_response_empty,
+    CachePolicy, FATTR_ATIME, FATTR_ATIME_NOW, FATTR_GID, FATTR_MODE, FATTR_MTIME, FATTR_MTIME_NOW,
+    FATTR_SIZE, FATTR_UID, FUSE_ASYNC_READ, FUSE_ATOMIC_O_TRUNC, FUSE_BIG_WRITES,
+    FUSE_DO_READDIRPLUS, FUSE_EXPORT_SUPPORT, FUSE_FLOCK_LOCKS, FUSE_IN_HEADER_SIZE,
+    FUSE_KERNEL_MINOR_VERSION, FUSE_KERNEL_VERSION, FUSE_LK_FLOCK, FUSE_MAX_PAGES,
+    FUSE_PARALLEL_DIROPS, FUSE_POSIX_LOCKS, FUSE_READDIRPLUS_AUTO, FUSE_RELEASE_FLOCK_UNLOCK,
+    FuseAttr, FuseAttrOut, FuseCopyFileRangeIn, FuseCreateIn, FuseDirent, FuseDirentplus,
+    FuseEntryOut, FuseFallocateIn, FuseFlushIn, FuseForgetIn, FuseFsyncIn, FuseGetxattrIn,
+    FuseGetxattrOut, FuseInHeader, FuseInitIn, FuseInitOut, FuseLinkIn, FuseLkIn, FuseLkOut,
+    FuseLseekIn, FuseLseekOut, FuseMkdirIn, FuseOpcode, FuseOpenIn, FuseOpenOut, FuseReadIn,
+    FuseReleaseIn, FuseRenameIn, FuseSetattrIn, FuseSetxattrIn, FuseStatfsOut, FuseWriteIn,
+    FuseWriteOut, HandleTable, HandleTableState, IdMapper, InodeTable, InodeTableState, LockTable,
+    XattrPolicy, errno_from_io, error_response, extract_name, metadata_to_attr, success_response,
+    success_response_empty, xattr,
 };
 
 const VIRTIO_ID_FS: u32 = 26;
@@ -52,6 +60,55 @@ const FS_CONFIG_SIZE: usize = FS_TAG_SIZE + 4;
 const MAX_READ_SIZE: u32 = 1024 * 1024;
 const MAX_WRITE_SIZE: u32 = 1024 * 1024;
 
+/// How often blocked lock requests are retried while they wait. They are
+/// retried straight away when the guest drops a lock, so this only matters
+/// for locks held by host processes, which give no notice when they let go.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Most SETLKW requests that may wait for a lock at once. Each holds a
+/// descriptor chain, and the guest needs some left to release its locks.
+const MAX_BLOCKED_LOCKS: usize = QUEUE_SIZE as usize / 4;
+
+/// FUSE_INIT capabilities of the device. The guest gets those its kernel
+/// offers.
+const FUSE_INIT_FLAGS: u32 = FUSE_ASYNC_READ
+    | FUSE_BIG_WRITES
+    | FUSE_ATOMIC_O_TRUNC
+    | FUSE_EXPORT_SUPPORT
+    | FUSE_PARALLEL_DIROPS
+    | FUSE_MAX_PAGES
+    | FUSE_DO_READDIRPLUS
+    | FUSE_READDIRPLUS_AUTO
+    | FUSE_POSIX_LOCKS
+    | FUSE_FLOCK_LOCKS;
+
+/// Snapshot state of a [`VirtioFs`] device.
+///
+/// Guest inode numbers and file handles must survive a restore, so the tables
+/// are saved by host path and reopened against the same shared directory.
+/// Host file locks are not saved, but requests waiting for a lock are, and
+/// wait again after a restore.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VirtioFsState {
+    transport: VirtioTransportState,
+    fuse_initialized: bool,
+    inodes: InodeTableState,
+    handles: HandleTableState,
+    #[serde(default)]
+    blocked_locks: Vec<BlockedLock>,
+    /// Whether the share kept an owner the host refused
+    #[serde(default)]
+    owners_kept: bool,
+}
+
+/// A SETLKW request waiting for a conflicting lock to be released. Its
+/// descriptor chain stays with the device until then.
+#[derive(Clone, Serialize, Deserialize)]
+struct BlockedLock {
+    desc_idx: u16,
+    request: Vec<u8>,
+}
+
 pub struct VirtioFs {
     device_features: u64,
     driver_features: u64,
@@ -63,8 +120,7 @@ pub struct VirtioFs {
     queues: [VirtioQueueState; NUM_QUEUES],
 
     interrupt_status: AtomicU32,
-    vm_fd: Arc<VmFd>,
-    irq: u32,
+    interrupt: VirtioInterrupt,
 
     memory: Option<Arc<GuestMemoryMmap>>,
 
@@ -72,9 +128,18 @@ pub struct VirtioFs {
     #[allow(dead_code)]
     host_path: PathBuf,
     read_only: bool,
+    xattr: XattrPolicy,
+    ids: IdMapper,
+    cache: CachePolicy,
 
     inodes: InodeTable,
     handles: HandleTable,
+    locks: LockTable,
+    blocked_locks: Vec<BlockedLock>,
+    /// Set when the guest drops a lock that blocked requests may be waiting on
+    locks_released: bool,
+    /// Wakes the worker that retries blocked lock requests
+    locks_blocked: Arc<Notify>,
     fuse_initialized: bool,
 }
 
@@ -83,8 +148,10 @@ impl VirtioFs {
         host_path: PathBuf,
         tag: String,
         read_only: bool,
-        vm_fd: Arc<VmFd>,
-        irq: u32,
+        xattr: XattrPolicy,
+        ids: IdMapper,
+        cache: CachePolicy,
+        interrupt: VirtioInterrupt,
     ) -> Self {
         let device_features = VIRTIO_F_VERSION_1;
 
@@ -97,14 +164,20 @@ impl VirtioFs {
             queue_sel: 0,
             queues: Default::default(),
             interrupt_status: AtomicU32::new(0),
-            vm_fd,
-            irq,
+            interrupt,
             memory: None,
             tag,
             host_path: host_path.clone(),
             read_only,
+            xattr,
+            ids,
+            cache,
             inodes: InodeTable::new(host_path),
             handles: HandleTable::new(),
+            locks: LockTable::new(),
+            blocked_locks: Vec::new(),
+            locks_released: false,
+            locks_blocked: Arc::new(Notify::new()),
             fuse_initialized: false,
         }
     }
@@ -113,11 +186,94 @@ impl VirtioFs {
         self.memory = Some(memory);
     }
 
+    /// Returns the notifier signalled when a SETLKW request starts waiting
+    /// for a lock, for the worker that calls
+    /// [`retry_blocked_locks`](Self::retry_blocked_locks).
+    pub fn locks_blocked(&self) -> Arc<Notify> {
+        self.locks_blocked.clone()
+    }
+
+    /// Retries the SETLKW requests waiting for a lock, and completes those
+    /// that got it.
+    ///
+    /// Returns how long to wait before calling again while requests are left
+    /// waiting.
+    pub fn retry_blocked_locks(&mut self) -> Option<Duration> {
+        if self.blocked_locks.is_empty() {
+            return None;
+        }
+        let memory = self.memory.clone()?;
+
+        let completed = self.take_granted_locks();
+        self.complete_requests(&memory, completed);
+
+        (!self.blocked_locks.is_empty()).then_some(LOCK_RETRY_INTERVAL)
+    }
+
+    /// Retries the SETLKW requests waiting for a lock, returning the
+    /// responses of those that got it.
+    fn take_granted_locks(&mut self) -> Vec<(u16, Vec<u8>)> {
+        let mut granted = Vec::new();
+        for blocked in std::mem::take(&mut self.blocked_locks) {
+            match self.handle_setlkw(&blocked.request) {
+                Some(response) => granted.push((blocked.desc_idx, response)),
+                None => self.blocked_locks.push(blocked),
+            }
+        }
+        self.locks_released = false;
+        granted
+    }
+
+    /// Captures the device state for a VM snapshot.
+    pub fn save_state(&self) -> VirtioFsState {
+        VirtioFsState {
+            transport: VirtioTransportState {
+                driver_features: self.driver_features,
+                device_features_sel: self.device_features_sel,
+                driver_features_sel: self.driver_features_sel,
+                device_status: self.device_status,
+                queue_sel: self.queue_sel,
+                interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+                queues: self.queues.to_vec(),
+            },
+            fuse_initialized: self.fuse_initialized,
+            inodes: self.inodes.save_state(),
+            handles: self.handles.save_state(),
+            blocked_locks: self.blocked_locks.clone(),
+            owners_kept: self.ids.owners_kept(),
+        }
+    }
+
+    /// Restores state captured by [`save_state`](Self::save_state).
+    pub fn restore_state(&mut self, state: &VirtioFsState) -> Result<()> {
+        let transport = &state.transport;
+        self.queues = transport.queues("virtio-fs")?;
+        self.driver_features = transport.driver_features;
+        self.device_features_sel = transport.device_features_sel;
+        self.driver_features_sel = transport.driver_features_sel;
+        self.device_status = transport.device_status;
+        self.queue_sel = transport.queue_sel;
+        self.interrupt_status
+            .store(transport.interrupt_status, Ordering::SeqCst);
+
+        self.fuse_initialized = state.fuse_initialized;
+        self.ids.set_owners_kept(state.owners_kept);
+        self.inodes.restore_state(&state.inodes);
+        self.handles
+            .restore_state(&state.handles, &self.inodes, self.read_only);
+
+        self.locks.clear();
+        self.blocked_locks = state.blocked_locks.clone();
+        if !self.blocked_locks.is_empty() {
+            self.locks_blocked.notify_one();
+        }
+        Ok(())
+    }
+
     fn inject_interrupt(&self) {
         self.interrupt_status
             .fetch_or(VIRTIO_INT_USED_RING, Ordering::SeqCst);
-        let _ = self.vm_fd.set_irq_line(self.irq, true);
-        let _ = self.vm_fd.set_irq_line(self.irq, false);
+        self.interrupt.signal_used_ring();
     }
 
     fn collect_pending_requests(
@@ -183,30 +339,53 @@ impl VirtioFs {
             return;
         }
 
-        let desc_table = queue_state.desc_table;
-        let avail_ring = queue_state.avail_ring;
-        let used_ring = queue_state.used_ring;
-        let queue_size = queue_state.size;
         let mut next_avail = queue_state.next_avail;
-        let mut next_used = queue_state.next_used;
-
         let requests = Self::collect_pending_requests(
             &memory,
-            desc_table,
-            avail_ring,
-            queue_size,
+            queue_state.desc_table,
+            queue_state.avail_ring,
+            queue_state.size,
             &mut next_avail,
         );
+        self.queues[REQUEST_QUEUE_INDEX].next_avail = next_avail;
+
+        let responses = self.handle_queued_requests(requests);
+        self.complete_requests(&memory, responses);
+    }
 
-        if requests.is_empty() {
+    /// Handles a batch of requests from the queue. Requests waiting for a
+    /// lock that the batch released get their responses along with it.
+    fn handle_queued_requests(&mut self, requests: Vec<(u16, Vec<u8>)>) -> Vec<(u16, Vec<u8>)> {
+        let mut responses = Vec::with_capacity(requests.len());
+        for (desc_idx, request_data) in requests {
+            if let Some(response) = self.handle_queued_request(desc_idx, request_data) {
+                responses.push((desc_idx, response));
+            }
+        }
+        if self.locks_released && !self.blocked_locks.is_empty() {
+            responses.extend(self.take_granted_locks());
+        }
+        self.locks_released = false;
+        responses
+    }
+
+    /// Writes the responses to their descriptor chains and returns the chains
+    /// to the guest.
+    fn complete_requests(&mut self, memory: &GuestMemoryMmap, responses: Vec<(u16, Vec<u8>)>) {
+        if responses.is_empty() {
             return;
         }
 
-        for (desc_idx, request_data) in requests {
-            let response = self.handle_fuse_request(&request_data);
-            Self::write_response_to_chain(&memory, desc_table, queue_size, desc_idx, &response);
+        let queue_state = &self.queues[REQUEST_QUEUE_INDEX];
+        let desc_table = queue_state.desc_table;
+        let used_ring = queue_state.used_ring;
+        let queue_size = queue_state.size;
+        let mut next_used = queue_state.next_used;
+
+        for (desc_idx, response) in responses {
+            Self::write_response_to_chain(memory, desc_table, queue_size, desc_idx, &response);
             Self::write_used_entry(
-                &memory,
+                memory,
                 used_ring,
                 queue_size,
                 &mut next_used,
@@ -215,12 +394,35 @@ impl VirtioFs {
             );
         }
 
-        self.queues[REQUEST_QUEUE_INDEX].next_avail = next_avail;
         self.queues[REQUEST_QUEUE_INDEX].next_used = next_used;
 
         self.inject_interrupt();
     }
 
+    /// Handles a request from the queue, or returns `None` for a SETLKW
+    /// request that has to wait for its lock. Waiting requests are retried
+    /// by [`retry_blocked_locks`](Self::retry_blocked_locks), so they never
+    /// hold up the requests behind them.
+    fn handle_queued_request(&mut self, desc_idx: u16, request: Vec<u8>) -> Option<Vec<u8>> {
+        let is_setlkw = FuseInHeader::from_bytes(&request)
+            .is_some_and(|h| h.opcode == FuseOpcode::Setlkw as u32);
+        if !is_setlkw {
+            return Some(self.handle_fuse_request(&request));
+        }
+
+        let response = self.handle_setlkw(&request);
+        if response.is_some() {
+            return response;
+        }
+        if self.blocked_locks.len() >= MAX_BLOCKED_LOCKS {
+            let unique = FuseInHeader::from_bytes(&request).map_or(0, |h| h.unique);
+            return Some(error_response(unique, libc::ENOLCK));
+        }
+        self.blocked_locks.push(BlockedLock { desc_idx, request });
+        self.locks_blocked.notify_one();
+        None
+    }
+
     fn read_descriptor_chain(
         memory: &GuestMemoryMmap,
         desc_table: u64,
@@ -305,6 +507,10 @@ impl VirtioFs {
                 | FuseOpcode::Link
                 | FuseOpcode::Write
                 | FuseOpcode::Create
+                | FuseOpcode::Setxattr
+                | FuseOpcode::Removexattr
+                | FuseOpcode::Fallocate
+                | FuseOpcode::CopyFileRange
         )
     }
 
@@ -333,9 +539,9 @@ impl VirtioFs {
             FuseOpcode::Getattr => self.handle_getattr(header.unique, header.nodeid, body),
             FuseOpcode::Setattr => self.handle_setattr(header.unique, header.nodeid, body),
             FuseOpcode::Readlink => self.handle_readlink(header.unique, header.nodeid),
-            FuseOpcode::Symlink => self.handle_symlink(header.unique, header.nodeid, body),
+            FuseOpcode::Symlink => self.handle_symlink(&header, body),
             FuseOpcode::Mknod => self.handle_mknod(header.unique, header.nodeid, body),
-            FuseOpcode::Mkdir => self.handle_mkdir(header.unique, header.nodeid, body),
+            FuseOpcode::Mkdir => self.handle_mkdir(&header, body),
             FuseOpcode::Unlink => self.handle_unlink(header.unique, header.nodeid, body),
             FuseOpcode::Rmdir => self.handle_rmdir(header.unique, header.nodeid, body),
             FuseOpcode::Rename => self.handle_rename(header.unique, header.nodeid, body),
@@ -351,8 +557,20 @@ impl VirtioFs {
             FuseOpcode::Releasedir => self.handle_releasedir(header.unique, body),
             FuseOpcode::Fsyncdir => self.handle_fsyncdir(header.unique, body),
             FuseOpcode::Access => self.handle_access(header.unique, header.nodeid, body),
-            FuseOpcode::Create => self.handle_create(header.unique, header.nodeid, body),
+            FuseOpcode::Create => self.handle_create(&header, body),
             FuseOpcode::Flush => self.handle_flush(header.unique, body),
+            FuseOpcode::Getxattr => self.handle_getxattr(header.unique, header.nodeid, body),
+            FuseOpcode::Setxattr => self.handle_setxattr(header.unique, header.nodeid, body),
+            FuseOpcode::Listxattr => self.handle_listxattr(header.unique, header.nodeid, body),
+            FuseOpcode::Removexattr => self.handle_removexattr(header.unique, header.nodeid, body),
+            FuseOpcode::Readdirplus => self.handle_readdirplus(header.unique, body),
+            FuseOpcode::Lseek => self.handle_lseek(header.unique, body),
+            FuseOpcode::Fallocate => self.handle_fallocate(header.unique, body),
+            FuseOpcode::CopyFileRange => self.handle_copy_file_range(header.unique, body),
+            FuseOpcode::Getlk => self.handle_getlk(header.unique, header.nodeid, body),
+            FuseOpcode::Setlk | FuseOpcode::Setlkw => {
+                self.handle_setlk(header.unique, header.nodeid, body)
+            }
             _ => {
                 tracing::debug!("unimplemented FUSE opcode: {:?}", opcode);
                 error_response(header.unique, libc::ENOSYS)
@@ -376,12 +594,7 @@ impl VirtioFs {
             major: FUSE_KERNEL_VERSION,
             minor: FUSE_KERNEL_MINOR_VERSION,
             max_readahead: init_in.max_readahead,
-            flags: FUSE_ASYNC_READ
-                | FUSE_BIG_WRITES
-                | FUSE_ATOMIC_O_TRUNC
-                | FUSE_EXPORT_SUPPORT
-                | FUSE_PARALLEL_DIROPS
-                | FUSE_MAX_PAGES,
+            flags: init_in.flags & (FUSE_INIT_FLAGS | self.cache.init_flags()),
             max_background: 0,
             congestion_threshold: 0,
             max_write: MAX_WRITE_SIZE,
@@ -405,32 +618,11 @@ impl VirtioFs {
             None => return error_response(unique, libc::EINVAL),
         };
 
-        let ino = match self.inodes.lookup(parent, name) {
-            Ok(i) => i,
+        let out = match lookup_entry(&mut self.inodes, &self.ids, &self.cache, parent, name) {
+            Ok(o) => o,
             Err(e) => return error_response(unique, e),
         };
 
-        let path = match self.inodes.get_path(ino) {
-            Some(p) => p.to_path_buf(),
-            None => return error_response(unique, libc::ENOENT),
-        };
-
-        let metadata = match std::fs::metadata(&path) {
-            Ok(m) => m,
-            Err(e) => return error_response(unique, errno_from_io(&e)),
-        };
-
-        let attr = metadata_to_attr(ino, &metadata);
-        let out = FuseEntryOut {
-            nodeid: ino,
-            generation: 0,
-            entry_valid: 1,
-            attr_valid: 1,
-            entry_valid_nsec: 0,
-            attr_valid_nsec: 0,
-            attr,
-        };
-
         success_response(unique, &out.to_bytes())
     }
 
@@ -452,10 +644,12 @@ impl VirtioFs {
             Err(e) => return error_response(unique, errno_from_io(&e)),
         };
 
-        let attr = metadata_to_attr(nodeid, &metadata);
+        let mut attr = metadata_to_attr(nodeid, &metadata);
+        self.ids.map_attr(&path, &mut attr);
+        let (attr_valid, attr_valid_nsec) = self.cache.attr_valid();
         let out = FuseAttrOut {
-            attr_valid: 1,
-            attr_valid_nsec: 0,
+            attr_valid,
+            attr_valid_nsec,
             dummy: 0,
             attr,
         };
@@ -493,21 +687,10 @@ impl VirtioFs {
         }
 
         if (setattr.valid & (FATTR_UID | FATTR_GID)) != 0 {
-            let uid = if (setattr.valid & FATTR_UID) != 0 {
-                setattr.uid
-            } else {
-                u32::MAX
-            };
-            let gid = if (setattr.valid & FATTR_GID) != 0 {
-                setattr.gid
-            } else {
-                u32::MAX
-            };
-            let path_cstr =
-                std::ffi::CString::new(path.to_string_lossy().as_bytes()).unwrap_or_default();
-            let ret = unsafe { libc::chown(path_cstr.as_ptr(), uid, gid) };
-            if ret != 0 {
-                return error_response(unique, errno_from_io(&std::io::Error::last_os_error()));
+            let uid = ((setattr.valid & FATTR_UID) != 0).then_some(setattr.uid);
+            let gid = ((setattr.valid & FATTR_GID) != 0).then_some(setattr.gid);
+            if let Err(e) = self.ids.chown(&path, uid, gid) {
+                return error_response(unique, e);
             }
         }
 
@@ -554,7 +737,8 @@ impl VirtioFs {
         success_response(unique, target.to_string_lossy().as_bytes())
     }
 
-    fn handle_symlink(&mut self, unique: u64, parent: u64, body: &[u8]) -> Vec<u8> {
+    fn handle_symlink(&mut self, header: &FuseInHeader, body: &[u8]) -> Vec<u8> {
+        let (unique, parent) = (header.unique, header.nodeid);
         let name = match extract_name(body) {
             Some(n) => n,
             None => return error_response(unique, libc::EINVAL),
@@ -574,6 +758,7 @@ impl VirtioFs {
         if let Err(e) = std::os::unix::fs::symlink(target, &new_path) {
             return error_response(unique, errno_from_io(&e));
         }
+        self.ids.set_creator(&new_path, header.uid, header.gid);
 
         let ino = match self.inodes.lookup_path(&new_path) {
             Ok(i) => i,
@@ -585,16 +770,9 @@ impl VirtioFs {
             Err(e) => return error_response(unique, errno_from_io(&e)),
         };
 
-        let attr = metadata_to_attr(ino, &metadata);
-        let out = FuseEntryOut {
-            nodeid: ino,
-            generation: 0,
-            entry_valid: 1,
-            attr_valid: 1,
-            entry_valid_nsec: 0,
-            attr_valid_nsec: 0,
-            attr,
-        };
+        let mut attr = metadata_to_attr(ino, &metadata);
+        self.ids.map_attr(&new_path, &mut attr);
+        let out = entry_out(&self.cache, ino, attr);
 
         success_response(unique, &out.to_bytes())
     }
@@ -603,7 +781,8 @@ impl VirtioFs {
         error_response(unique, libc::ENOSYS)
     }
 
-    fn handle_mkdir(&mut self, unique: u64, parent: u64, body: &[u8]) -> Vec<u8> {
+    fn handle_mkdir(&mut self, header: &FuseInHeader, body: &[u8]) -> Vec<u8> {
+        let (unique, parent) = (header.unique, header.nodeid);
         let mkdir_in = match FuseMkdirIn::from_bytes(body) {
             Some(m) => m,
             None => return error_response(unique, libc::EINVAL),
@@ -626,6 +805,7 @@ impl VirtioFs {
         if let Err(e) = builder.create(&new_path) {
             return error_response(unique, errno_from_io(&e));
         }
+        self.ids.set_creator(&new_path, header.uid, header.gid);
 
         let ino = match self.inodes.lookup_path(&new_path) {
             Ok(i) => i,
@@ -637,16 +817,9 @@ impl VirtioFs {
             Err(e) => return error_response(unique, errno_from_io(&e)),
         };
 
-        let attr = metadata_to_attr(ino, &metadata);
-        let out = FuseEntryOut {
-            nodeid: ino,
-            generation: 0,
-            entry_valid: 1,
-            attr_valid: 1,
-            entry_valid_nsec: 0,
-            attr_valid_nsec: 0,
-            attr,
-        };
+        let mut attr = metadata_to_attr(ino, &metadata);
+        self.ids.map_attr(&new_path, &mut attr);
+        let out = entry_out(&self.cache, ino, attr);
 
         success_response(unique, &out.to_bytes())
     }
@@ -766,16 +939,9 @@ impl VirtioFs {
             Err(e) => return error_response(unique, errno_from_io(&e)),
         };
 
-        let attr = metadata_to_attr(ino, &metadata);
-        let out = FuseEntryOut {
-            nodeid: ino,
-            generation: 0,
-            entry_valid: 1,
-            attr_valid: 1,
-            entry_valid_nsec: 0,
-            attr_valid_nsec: 0,
-            attr,
-        };
+        let mut attr = metadata_to_attr(ino, &metadata);
+        self.ids.map_attr(&new_path, &mut attr);
+        let out = entry_out(&self.cache, ino, attr);
 
         success_response(unique, &out.to_bytes())
     }
@@ -801,7 +967,7 @@ impl VirtioFs {
 
         let out = FuseOpenOut {
             fh,
-            open_flags: FOPEN_KEEP_CACHE,
+            open_flags: self.cache.file_open_flags(),
             padding: 0,
         };
 
@@ -892,10 +1058,20 @@ impl VirtioFs {
             None => return error_response(unique, libc::EINVAL),
         };
 
-        self.handles.release(release_in.fh);
+        self.release_handle(&release_in);
         success_response_empty(unique)
     }
 
+    fn release_handle(&mut self, release_in: &FuseReleaseIn) {
+        // Closing the last reference to an open file drops its flock locks
+        if (release_in.release_flags & FUSE_RELEASE_FLOCK_UNLOCK) != 0
+            && let Some(handle) = self.handles.get(release_in.fh)
+        {
+            self.locks_released |= self.locks.release(handle.ino, release_in.lock_owner);
+        }
+        self.handles.release(release_in.fh);
+    }
+
     fn handle_fsync(&mut self, unique: u64, body: &[u8]) -> Vec<u8> {
         let fsync_in = match FuseFsyncIn::from_bytes(body) {
             Some(f) => f,
@@ -924,7 +1100,7 @@ impl VirtioFs {
 
         let out = FuseOpenOut {
             fh,
-            open_flags: 0,
+            open_flags: self.cache.dir_open_flags(),
             padding: 0,
         };
 
@@ -970,13 +1146,79 @@ impl VirtioFs {
         success_response(unique, &buf)
     }
 
+    fn handle_readdirplus(&mut self, unique: u64, body: &[u8]) -> Vec<u8> {
+        let read_in = match FuseReadIn::from_bytes(body) {
+            Some(r) => r,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        let parent = match self.handles.get(read_in.fh) {
+            Some(h) => h.ino,
+            None => return error_response(unique, libc::EBADF),
+        };
+
+        let entries = match self.handles.read_dir(read_in.fh, read_in.offset) {
+            Ok(e) => e,
+            Err(e) => return error_response(unique, e),
+        };
+
+        let mut buf = Vec::new();
+        let max_size = read_in.size as usize;
+
+        for (i, entry) in entries.iter().enumerate() {
+            let name_bytes = entry.name.as_bytes();
+            let entry_size = FuseDirentplus::entry_size(name_bytes.len());
+
+            // Checked before the lookup, since the guest only takes a
+            // reference on the entries it receives
+            if buf.len() + entry_size > max_size {
+                break;
+            }
+
+            // The guest ignores the attributes of "." and "..", and an entry
+            // that can't be looked up is sent without them
+            let entry_out = if entry.name == "." || entry.name == ".." {
+                FuseEntryOut::default()
+            } else {
+                lookup_entry(
+                    &mut self.inodes,
+                    &self.ids,
+                    &self.cache,
+                    parent,
+                    &entry.name,
+                )
+                .unwrap_or_default()
+            };
+
+            let dirent = FuseDirent {
+                ino: if entry_out.nodeid != 0 {
+                    entry_out.nodeid
+                } else {
+                    entry.ino
+                },
+                off: (read_in.offset as usize + i + 1) as u64,
+                namelen: name_bytes.len() as u32,
+                typ: entry.typ,
+            };
+
+            let direntplus = FuseDirentplus { entry_out, dirent };
+            buf.extend_from_slice(&direntplus.to_bytes());
+            buf.extend_from_slice(name_bytes);
+
+            let padding = entry_size - FuseDirentplus::entry_size(0) - name_bytes.len();
+            buf.extend(std::iter::repeat_n(0u8, padding));
+        }
+
+        success_response(unique, &buf)
+    }
+
     fn handle_releasedir(&mut self, unique: u64, body: &[u8]) -> Vec<u8> {
         let release_in = match FuseReleaseIn::from_bytes(body) {
             Some(r) => r,
             None => return error_response(unique, libc::EINVAL),
         };
 
-        self.handles.release(release_in.fh);
+        self.release_handle(&release_in);
         success_response_empty(unique)
     }
 
@@ -996,7 +1238,8 @@ impl VirtioFs {
         }
     }
 
-    fn handle_create(&mut self, unique: u64, parent: u64, body: &[u8]) -> Vec<u8> {
+    fn handle_create(&mut self, header: &FuseInHeader, body: &[u8]) -> Vec<u8> {
+        let (unique, parent) = (header.unique, header.nodeid);
         let create_in = match FuseCreateIn::from_bytes(body) {
             Some(c) => c,
             None => return error_response(unique, libc::EINVAL),
@@ -1017,7 +1260,9 @@ impl VirtioFs {
         let ino = match self.inodes.lookup_path(&new_path) {
             Ok(i) => i,
             Err(_) => {
-                let _ = std::fs::File::create(&new_path);
+                if std::fs::File::create(&new_path).is_ok() {
+                    self.ids.set_creator(&new_path, header.uid, header.gid);
+                }
                 match self.inodes.lookup_path(&new_path) {
                     Ok(i) => i,
                     Err(e) => return error_response(unique, e),
@@ -1038,20 +1283,13 @@ impl VirtioFs {
             Err(e) => return error_response(unique, errno_from_io(&e)),
         };
 
-        let attr = metadata_to_attr(ino, &metadata);
-        let entry = FuseEntryOut {
-            nodeid: ino,
-            generation: 0,
-            entry_valid: 1,
-            attr_valid: 1,
-            entry_valid_nsec: 0,
-            attr_valid_nsec: 0,
-            attr,
-        };
+        let mut attr = metadata_to_attr(ino, &metadata);
+        self.ids.map_attr(&new_path, &mut attr);
+        let entry = entry_out(&self.cache, ino, attr);
 
         let open = FuseOpenOut {
             fh,
-            open_flags: FOPEN_KEEP_CACHE,
+            open_flags: self.cache.file_open_flags(),
             padding: 0,
         };
 
@@ -1066,6 +1304,11 @@ impl VirtioFs {
             None => return error_response(unique, libc::EINVAL),
         };
 
+        // Closing any descriptor of a file drops the owner's POSIX locks on it
+        if let Some(handle) = self.handles.get(flush_in.fh) {
+            self.locks_released |= self.locks.release(handle.ino, flush_in.lock_owner);
+        }
+
         if let Err(e) = self.handles.flush_file(flush_in.fh) {
             return error_response(unique, e);
         }
@@ -1073,6 +1316,244 @@ impl VirtioFs {
         success_response_empty(unique)
     }
 
+    fn handle_getlk(&mut self, unique: u64, nodeid: u64, body: &[u8]) -> Vec<u8> {
+        let lk_in = match FuseLkIn::from_bytes(body) {
+            Some(l) => l,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        let path = match self.inodes.get_path(nodeid) {
+            Some(p) => p,
+            None => return error_response(unique, libc::ENOENT),
+        };
+
+        let lk = match self
+            .locks
+            .test(path, nodeid, lk_in.owner, &lk_in.lk, self.read_only)
+        {
+            Ok(l) => l,
+            Err(e) => return error_response(unique, e),
+        };
+
+        let out = FuseLkOut { lk };
+        success_response(unique, &out.to_bytes())
+    }
+
+    fn handle_setlk(&mut self, unique: u64, nodeid: u64, body: &[u8]) -> Vec<u8> {
+        if let Err(e) = self.set_lock(nodeid, body) {
+            return error_response(unique, e);
+        }
+
+        success_response_empty(unique)
+    }
+
+    /// Tries to take the lock of a SETLKW request, returning its response,
+    /// or `None` while another owner holds a conflicting lock.
+    fn handle_setlkw(&mut self, request: &[u8]) -> Option<Vec<u8>> {
+        let header = match FuseInHeader::from_bytes(request) {
+            Some(h) => h,
+            None => return Some(error_response(0, libc::EINVAL)),
+        };
+
+        match self.set_lock(header.nodeid, &request[FUSE_IN_HEADER_SIZE..]) {
+            Ok(()) => Some(success_response_empty(header.unique)),
+            Err(libc::EAGAIN) => None,
+            Err(e) => Some(error_response(header.unique, e)),
+        }
+    }
+
+    /// Sets or clears the lock of a SETLK or SETLKW request without waiting.
+    fn set_lock(&mut self, nodeid: u64, body: &[u8]) -> std::result::Result<(), i32> {
+        let lk_in = FuseLkIn::from_bytes(body).ok_or(libc::EINVAL)?;
+        let path = self.inodes.get_path(nodeid).ok_or(libc::ENOENT)?;
+
+        if (lk_in.lk_flags & FUSE_LK_FLOCK) != 0 {
+            self.locks
+                .set_flock(path, nodeid, lk_in.owner, lk_in.lk.typ, self.read_only)?;
+        } else {
+            self.locks
+                .set_posix(path, nodeid, lk_in.owner, &lk_in.lk, self.read_only)?;
+        }
+
+        // Unlocking, or downgrading a write lock, may let a waiter in
+        if lk_in.lk.typ != libc::F_WRLCK as u32 {
+            self.locks_released = true;
+        }
+        Ok(())
+    }
+
+    fn handle_lseek(&mut self, unique: u64, body: &[u8]) -> Vec<u8> {
+        let lseek_in = match FuseLseekIn::from_bytes(body) {
+            Some(l) => l,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        let offset = match self
+            .handles
+            .seek_file(lseek_in.fh, lseek_in.offset, lseek_in.whence)
+        {
+            Ok(o) => o,
+            Err(e) => return error_response(unique, e),
+        };
+
+        let out = FuseLseekOut { offset };
+        success_response(unique, &out.to_bytes())
+    }
+
+    fn handle_fallocate(&mut self, unique: u64, body: &[u8]) -> Vec<u8> {
+        let fallocate_in = match FuseFallocateIn::from_bytes(body) {
+            Some(f) => f,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        if let Err(e) = self.handles.fallocate_file(
+            fallocate_in.fh,
+            fallocate_in.offset,
+            fallocate_in.length,
+            fallocate_in.mode,
+        ) {
+            return error_response(unique, e);
+        }
+
+        success_response_empty(unique)
+    }
+
+    fn handle_copy_file_range(&mut self, unique: u64, body: &[u8]) -> Vec<u8> {
+        let copy_in = match FuseCopyFileRangeIn::from_bytes(body) {
+            Some(c) => c,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        if copy_in.flags != 0 {
+            return error_response(unique, libc::EINVAL);
+        }
+
+        // The reply holds a 32-bit length, and the guest retries short copies
+        let len = copy_in.len.min(u32::MAX as u64);
+        let n = match self.handles.copy_file_range(
+            copy_in.fh_in,
+            copy_in.off_in,
+            copy_in.fh_out,
+            copy_in.off_out,
+            len,
+        ) {
+            Ok(n) => n,
+            Err(e) => return error_response(unique, e),
+        };
+
+        let out = FuseWriteOut {
+            size: n as u32,
+            padding: 0,
+        };
+        success_response(unique, &out.to_bytes())
+    }
+
+    fn handle_getxattr(&mut self, unique: u64, nodeid: u64, body: &[u8]) -> Vec<u8> {
+        let getxattr_in = match FuseGetxattrIn::from_bytes(body) {
+            Some(g) => g,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        let name = match extract_name(&body[8..]) {
+            Some(n) => n,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        let host_name = match self.xattr.to_host(name) {
+            Ok(n) => n,
+            Err(e) => return error_response(unique, e),
+        };
+
+        let path = match self.inodes.get_path(nodeid) {
+            Some(p) => p.to_path_buf(),
+            None => return error_response(unique, libc::ENOENT),
+        };
+
+        let value = match xattr::get(&path, &host_name, getxattr_in.size as usize) {
+            Ok(v) => v,
+            Err(e) => return error_response(unique, e),
+        };
+
+        xattr_response(unique, getxattr_in.size, &value)
+    }
+
+    fn handle_setxattr(&mut self, unique: u64, nodeid: u64, body: &[u8]) -> Vec<u8> {
+        let setxattr_in = match FuseSetxattrIn::from_bytes(body) {
+            Some(s) => s,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        let name = match extract_name(&body[8..]) {
+            Some(n) => n,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        let value_start = 8 + name.len() + 1;
+        let value = match body.get(value_start..value_start + setxattr_in.size as usize) {
+            Some(v) => v,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        let host_name = match self.xattr.to_host(name) {
+            Ok(n) => n,
+            Err(e) => return error_response(unique, e),
+        };
+
+        let path = match self.inodes.get_path(nodeid) {
+            Some(p) => p.to_path_buf(),
+            None => return error_response(unique, libc::ENOENT),
+        };
+
+        if let Err(e) = xattr::set(&path, &host_name, value, setxattr_in.flags as i32) {
+            return error_response(unique, e);
+        }
+
+        success_response_empty(unique)
+    }
+
+    fn handle_listxattr(&mut self, unique: u64, nodeid: u64, body: &[u8]) -> Vec<u8> {
+        let getxattr_in = match FuseGetxattrIn::from_bytes(body) {
+            Some(g) => g,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        let path = match self.inodes.get_path(nodeid) {
+            Some(p) => p.to_path_buf(),
+            None => return error_response(unique, libc::ENOENT),
+        };
+
+        // The whole host list is read, since the guest's is a filtered subset
+        let names = match xattr::list(&path) {
+            Ok(n) => self.xattr.guest_list(&n),
+            Err(e) => return error_response(unique, e),
+        };
+
+        xattr_response(unique, getxattr_in.size, &names)
+    }
+
+    fn handle_removexattr(&mut self, unique: u64, nodeid: u64, body: &[u8]) -> Vec<u8> {
+        let name = match extract_name(body) {
+            Some(n) => n,
+            None => return error_response(unique, libc::EINVAL),
+        };
+
+        let host_name = match self.xattr.to_host(name) {
+            Ok(n) => n,
+            Err(e) => return error_response(unique, e),
+        };
+
+        let path = match self.inodes.get_path(nodeid) {
+            Some(p) => p.to_path_buf(),
+            None => return error_response(unique, libc::ENOENT),
+        };
+
+        if let Err(e) = xattr::remove(&path, &host_name) {
+            return error_response(unique, e);
+        }
+
+        success_response_empty(unique)
+    }
+
     fn handle_mmio_read(&self, offset: u64, data: &mut [u8]) {
         // Config space may be read with different sizes (1, 2, 4 bytes)
         if offset >= VIRTIO_MMIO_CONFIG && offset < VIRTIO_MMIO_CONFIG + FS_CONFIG_SIZE as u64 {
@@ -1248,6 +1729,9 @@ impl VirtioFs {
                     self.queues = Default::default();
                     self.driver_features = 0;
                     self.fuse_initialized = false;
+                    self.locks.clear();
+                    self.blocked_locks.clear();
+                    self.locks_released = false;
                 }
             }
             _ => {}
@@ -1265,9 +1749,68 @@ impl MutDeviceMmio for VirtioFs {
     }
 }
 
+impl VirtioDevice for VirtioFs {
+    fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+}
+
+/// Looks up `name` in `parent`, taking a lookup reference on the entry's
+/// inode for the guest.
+fn lookup_entry(
+    inodes: &mut InodeTable,
+    ids: &IdMapper,
+    cache: &CachePolicy,
+    parent: u64,
+    name: &str,
+) -> std::result::Result<FuseEntryOut, i32> {
+    let ino = inodes.lookup(parent, name)?;
+    let path = inodes.get_path(ino).ok_or(libc::ENOENT)?;
+    let metadata = std::fs::metadata(path).map_err(|e| errno_from_io(&e))?;
+    let mut attr = metadata_to_attr(ino, &metadata);
+    ids.map_attr(path, &mut attr);
+
+    Ok(entry_out(cache, ino, attr))
+}
+
+fn entry_out(cache: &CachePolicy, ino: u64, attr: FuseAttr) -> FuseEntryOut {
+    let (entry_valid, entry_valid_nsec) = cache.entry_valid();
+    let (attr_valid, attr_valid_nsec) = cache.attr_valid();
+    FuseEntryOut {
+        nodeid: ino,
+        generation: 0,
+        entry_valid,
+        attr_valid,
+        entry_valid_nsec,
+        attr_valid_nsec,
+        attr,
+    }
+}
+
+/// Answers a getxattr or listxattr request for a buffer of `size` bytes, or
+/// with the length of `data` when the guest asks for it with a size of zero.
+fn xattr_response(unique: u64, size: u32, data: &[u8]) -> Vec<u8> {
+    if size == 0 {
+        let out = FuseGetxattrOut {
+            size: data.len() as u32,
+            padding: 0,
+        };
+        success_response(unique, &out.to_bytes())
+    } else if data.len() > size as usize {
+        error_response(unique, libc::ERANGE)
+    } else {
+        success_response(unique, data)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::fuse::{
+        FOPEN_DIRECT_IO, FOPEN_KEEP_CACHE, FUSE_AUTO_INVAL_DATA, FUSE_ENTRY_OUT_SIZE,
+        FUSE_WRITEBACK_CACHE,
+    };
+    use capsa_core::{CacheMode, IdMapping};
     use kvm_ioctls::Kvm;
     use tempfile::TempDir;
 
@@ -1280,8 +1823,10 @@ mod tests {
             tmp_dir.path().to_path_buf(),
             tag.to_string(),
             false,
-            vm_fd,
-            8,
+            XattrPolicy::default(),
+            IdMapper::default(),
+            CachePolicy::default(),
+            VirtioInterrupt::pin(vm_fd, 8),
         );
         (device, tmp_dir)
     }
@@ -1757,8 +2302,10 @@ mod tests {
             tmp_dir.path().to_path_buf(),
             tag.to_string(),
             true, // read_only
-            vm_fd,
-            8,
+            XattrPolicy::default(),
+            IdMapper::default(),
+            CachePolicy::default(),
+            VirtioInterrupt::pin(vm_fd, 8),
         );
         (device, tmp_dir)
     }
@@ -1806,6 +2353,19 @@ mod tests {
         assert!(device.fuse_initialized);
     }
 
+    #[test]
+    fn fuse_init_negotiates_offered_flags() {
+        let (mut device, _tmp) = create_test_device("test");
+
+        let mut init_body = build_init_request(FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION);
+        let offered = FUSE_ASYNC_READ | FUSE_DO_READDIRPLUS | FUSE_WRITEBACK_CACHE;
+        init_body[12..16].copy_from_slice(&offered.to_le_bytes());
+        let response = device.handle_init(42, &init_body);
+
+        let flags = u32::from_le_bytes(response[28..32].try_into().unwrap());
+        assert_eq!(flags, FUSE_ASYNC_READ | FUSE_DO_READDIRPLUS);
+    }
+
     #[test]
     fn fuse_init_rejects_old_version() {
         let (mut device, _tmp) = create_test_device("test");
@@ -1834,6 +2394,33 @@ mod tests {
         assert!(!device.fuse_initialized);
     }
 
+    #[test]
+    fn save_restore_keeps_fuse_session() {
+        let (mut device, tmp) = create_test_device("test");
+        std::fs::write(tmp.path().join("file.txt"), "content").unwrap();
+
+        let init_body = build_init_request(FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION);
+        device.handle_init(1, &init_body);
+        write_u32(&mut device, VIRTIO_MMIO_STATUS, 0xf);
+        let ino = device.inodes.lookup(1, "file.txt").unwrap();
+        let state = device.save_state();
+
+        let mut restored = VirtioFs::new(
+            tmp.path().to_path_buf(),
+            "test".to_string(),
+            false,
+            XattrPolicy::default(),
+            IdMapper::default(),
+            CachePolicy::default(),
+            device.interrupt.clone(),
+        );
+        restored.restore_state(&state).unwrap();
+
+        assert!(restored.fuse_initialized);
+        assert_eq!(restored.device_status, 0xf);
+        assert_eq!(restored.inodes.lookup(1, "file.txt").unwrap(), ino);
+    }
+
     #[test]
     fn fuse_lookup_root() {
         let (mut device, tmp) = create_test_device("test");
@@ -2009,6 +2596,462 @@ mod tests {
         assert_eq!(error, 0, "opendir should work on read-only share");
     }
 
+    #[test]
+    fn read_only_blocks_setxattr() {
+        let (mut device, _tmp) = create_read_only_device("test");
+
+        let mut body = vec![0u8; 8]; // FuseSetxattrIn
+        body[0..4].copy_from_slice(&1u32.to_le_bytes());
+        body.extend_from_slice(b"user.comment\0x");
+        let request = build_fuse_request(FuseOpcode::Setxattr, 42, 1, &body);
+
+        let response = device.handle_fuse_request(&request);
+
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(
+            error,
+            -libc::EROFS,
+            "setxattr should fail on read-only share"
+        );
+    }
+
+    fn build_setxattr_body(name: &str, value: &[u8]) -> Vec<u8> {
+        let mut body = vec![0u8; 8];
+        body[0..4].copy_from_slice(&(value.len() as u32).to_le_bytes());
+        body.extend_from_slice(name.as_bytes());
+        body.push(0);
+        body.extend_from_slice(value);
+        body
+    }
+
+    fn build_getxattr_body(size: u32, name: &str) -> Vec<u8> {
+        let mut body = vec![0u8; 8];
+        body[0..4].copy_from_slice(&size.to_le_bytes());
+        body.extend_from_slice(name.as_bytes());
+        body.push(0);
+        body
+    }
+
+    #[test]
+    fn fuse_xattr_roundtrip() {
+        let (mut device, tmp) = create_test_device("test");
+        let body = build_setxattr_body("user.comment", b"hello");
+        let response = device.handle_setxattr(42, 1, &body);
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "setxattr should succeed");
+        assert_eq!(
+            xattr::get(tmp.path(), "user.comment", 64).unwrap(),
+            b"hello"
+        );
+
+        // A size of zero asks for the length of the value
+        let response = device.handle_getxattr(42, 1, &build_getxattr_body(0, "user.comment"));
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0);
+        assert_eq!(u32::from_le_bytes(response[16..20].try_into().unwrap()), 5);
+
+        let response = device.handle_getxattr(42, 1, &build_getxattr_body(64, "user.comment"));
+        assert_eq!(&response[16..], b"hello");
+
+        let response = device.handle_getxattr(42, 1, &build_getxattr_body(2, "user.comment"));
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, -libc::ERANGE);
+
+        let response = device.handle_removexattr(42, 1, b"user.comment\0");
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "removexattr should succeed");
+
+        let response = device.handle_getxattr(42, 1, &build_getxattr_body(64, "user.comment"));
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, -libc::ENODATA);
+    }
+
+    #[test]
+    fn fuse_security_xattrs_stay_off_the_host() {
+        let (mut device, tmp) = create_test_device("test");
+        let body = build_setxattr_body("security.selinux", b"label");
+        let response = device.handle_setxattr(42, 1, &body);
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "setxattr should succeed");
+
+        assert_eq!(
+            xattr::get(tmp.path(), "user.virtiofs.security.selinux", 64).unwrap(),
+            b"label"
+        );
+
+        let mut list_body = vec![0u8; 8];
+        list_body[0..4].copy_from_slice(&4096u32.to_le_bytes());
+        let response = device.handle_listxattr(42, 1, &list_body);
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "listxattr should succeed");
+        assert_eq!(&response[16..], b"security.selinux\0");
+    }
+
+    fn build_read_request(fh: u64, offset: u64, size: u32) -> Vec<u8> {
+        let mut body = vec![0u8; 40]; // FuseReadIn
+        body[0..8].copy_from_slice(&fh.to_le_bytes());
+        body[8..16].copy_from_slice(&offset.to_le_bytes());
+        body[16..20].copy_from_slice(&size.to_le_bytes());
+        body
+    }
+
+    fn open_test_file(device: &mut VirtioFs, name: &str, flags: i32) -> u64 {
+        let nodeid = device.inodes.lookup(1, name).unwrap();
+        let body = [(flags as u32).to_le_bytes(), [0; 4]].concat();
+        let response = device.handle_open(42, nodeid, &body);
+        u64::from_le_bytes(response[16..24].try_into().unwrap())
+    }
+
+    #[test]
+    fn fuse_readdirplus_looks_up_entries() {
+        let (mut device, tmp) = create_test_device("test");
+        std::fs::write(tmp.path().join("file.txt"), "content").unwrap();
+
+        let response = device.handle_opendir(42, 1);
+        let fh = u64::from_le_bytes(response[16..24].try_into().unwrap());
+
+        let response = device.handle_readdirplus(42, &build_read_request(fh, 0, 4096));
+        let (len, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "readdirplus should succeed");
+        assert_eq!(len as usize, 16 + 3 * FuseDirentplus::entry_size(8));
+
+        let mut entries = Vec::new();
+        let mut offset = 16;
+        while offset < response.len() {
+            let nodeid = u64::from_le_bytes(response[offset..offset + 8].try_into().unwrap());
+            let dirent = offset + FUSE_ENTRY_OUT_SIZE;
+            let namelen =
+                u32::from_le_bytes(response[dirent + 16..dirent + 20].try_into().unwrap()) as usize;
+            let name = &response[dirent + 24..dirent + 24 + namelen];
+            entries.push((String::from_utf8(name.to_vec()).unwrap(), nodeid));
+            offset += FuseDirentplus::entry_size(namelen);
+        }
+
+        let file_ino = device.inodes.lookup(1, "file.txt").unwrap();
+        assert_eq!(entries[0], (".".to_string(), 0));
+        assert_eq!(entries[1], ("..".to_string(), 0));
+        assert_eq!(entries[2], ("file.txt".to_string(), file_ino));
+
+        // A buffer too small for an entry returns nothing, and takes no
+        // lookup reference on it
+        let response = device.handle_readdirplus(42, &build_read_request(fh, 2, 64));
+        assert_eq!(response.len(), 16);
+        device.inodes.forget(file_ino, 2);
+        assert!(device.inodes.get_path(file_ino).is_none());
+    }
+
+    #[test]
+    fn fuse_fallocate_and_lseek() {
+        let (mut device, tmp) = create_test_device("test");
+        std::fs::write(tmp.path().join("file.bin"), "content").unwrap();
+        let fh = open_test_file(&mut device, "file.bin", libc::O_RDWR);
+
+        let mut body = vec![0u8; 32]; // FuseFallocateIn
+        body[0..8].copy_from_slice(&fh.to_le_bytes());
+        body[16..24].copy_from_slice(&8192u64.to_le_bytes());
+        let request = build_fuse_request(FuseOpcode::Fallocate, 42, 2, &body);
+        let (_, error, _) = parse_fuse_out_header(&device.handle_fuse_request(&request));
+        assert_eq!(error, 0, "fallocate should succeed");
+        assert_eq!(
+            std::fs::metadata(tmp.path().join("file.bin"))
+                .unwrap()
+                .len(),
+            8192
+        );
+
+        let mut body = vec![0u8; 24]; // FuseLseekIn
+        body[0..8].copy_from_slice(&fh.to_le_bytes());
+        body[16..20].copy_from_slice(&(libc::SEEK_DATA as u32).to_le_bytes());
+        let request = build_fuse_request(FuseOpcode::Lseek, 42, 2, &body);
+        let response = device.handle_fuse_request(&request);
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "lseek should succeed");
+        assert_eq!(u64::from_le_bytes(response[16..24].try_into().unwrap()), 0);
+
+        body[8..16].copy_from_slice(&16384u64.to_le_bytes());
+        let request = build_fuse_request(FuseOpcode::Lseek, 42, 2, &body);
+        let (_, error, _) = parse_fuse_out_header(&device.handle_fuse_request(&request));
+        assert_eq!(error, -libc::ENXIO, "no data past the end of the file");
+    }
+
+    #[test]
+    fn fuse_copy_file_range() {
+        let (mut device, tmp) = create_test_device("test");
+        std::fs::write(tmp.path().join("src.txt"), "hello world").unwrap();
+        std::fs::write(tmp.path().join("dst.txt"), "").unwrap();
+        let fh_in = open_test_file(&mut device, "src.txt", libc::O_RDONLY);
+        let fh_out = open_test_file(&mut device, "dst.txt", libc::O_WRONLY);
+
+        let mut body = vec![0u8; 56]; // FuseCopyFileRangeIn
+        body[0..8].copy_from_slice(&fh_in.to_le_bytes());
+        body[24..32].copy_from_slice(&fh_out.to_le_bytes());
+        body[40..48].copy_from_slice(&11u64.to_le_bytes());
+        let request = build_fuse_request(FuseOpcode::CopyFileRange, 42, 3, &body);
+        let response = device.handle_fuse_request(&request);
+
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "copy_file_range should succeed");
+        assert_eq!(u32::from_le_bytes(response[16..20].try_into().unwrap()), 11);
+        assert_eq!(
+            std::fs::read_to_string(tmp.path().join("dst.txt")).unwrap(),
+            "hello world"
+        );
+    }
+
+    fn create_configured_device(ids: IdMapper, cache: CachePolicy) -> (VirtioFs, TempDir) {
+        let tmp_dir = TempDir::new().expect("Failed to create temp dir");
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = kvm.create_vm().expect("Failed to create VM");
+        let device = VirtioFs::new(
+            tmp_dir.path().to_path_buf(),
+            "test".to_string(),
+            false,
+            XattrPolicy::default(),
+            ids,
+            cache,
+            VirtioInterrupt::pin(Arc::new(vm), 8),
+        );
+        (device, tmp_dir)
+    }
+
+    fn attr_owner(response: &[u8], attr_offset: usize) -> (u32, u32) {
+        let uid = &response[attr_offset + 68..attr_offset + 72];
+        let gid = &response[attr_offset + 72..attr_offset + 76];
+        (
+            u32::from_le_bytes(uid.try_into().unwrap()),
+            u32::from_le_bytes(gid.try_into().unwrap()),
+        )
+    }
+
+    #[test]
+    fn fuse_squashed_owner_survives_chown() {
+        let (mut device, tmp) = create_configured_device(
+            IdMapper::new(IdMapping::squash(0, 0)),
+            CachePolicy::default(),
+        );
+        std::fs::write(tmp.path().join("file.txt"), "content").unwrap();
+        let nodeid = device.inodes.lookup(1, "file.txt").unwrap();
+
+        let response = device.handle_getattr(42, nodeid, &[]);
+        assert_eq!(attr_owner(&response, 32), (0, 0));
+
+        let mut body = vec![0u8; 88]; // FuseSetattrIn
+        body[0..4].copy_from_slice(&(FATTR_UID | FATTR_GID).to_le_bytes());
+        body[76..80].copy_from_slice(&1000u32.to_le_bytes());
+        body[80..84].copy_from_slice(&100u32.to_le_bytes());
+        let request = build_fuse_request(FuseOpcode::Setattr, 42, nodeid, &body);
+        let response = device.handle_fuse_request(&request);
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "chown should succeed");
+        assert_eq!(attr_owner(&response, 32), (1000, 100));
+
+        // The saved owner is not one of the guest's attributes
+        let response = device.handle_listxattr(42, nodeid, &[0; 8]);
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "listxattr should succeed");
+        assert_eq!(u32::from_le_bytes(response[16..20].try_into().unwrap()), 0);
+    }
+
+    #[test]
+    fn fuse_range_mapping_gives_new_files_to_their_creator() {
+        let (mut device, tmp) = create_configured_device(
+            IdMapper::new(IdMapping::range(1000, 100000, 1000)),
+            CachePolicy::default(),
+        );
+
+        let mut body = vec![0u8; 8]; // FuseMkdirIn
+        body[0..4].copy_from_slice(&0o755u32.to_le_bytes());
+        body.extend_from_slice(b"dir\0");
+        let mut request = build_fuse_request(FuseOpcode::Mkdir, 42, 1, &body);
+        request[24..28].copy_from_slice(&1001u32.to_le_bytes());
+        request[28..32].copy_from_slice(&1002u32.to_le_bytes());
+        let response = device.handle_fuse_request(&request);
+
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "mkdir should succeed");
+        assert_eq!(attr_owner(&response, 16 + 40), (1001, 1002));
+        assert!(tmp.path().join("dir").is_dir());
+    }
+
+    #[test]
+    fn fuse_cache_mode_sets_timeouts_and_open_flags() {
+        for (cache, timeout, open_flags) in [
+            (CacheMode::None, 0, FOPEN_DIRECT_IO),
+            (CacheMode::Auto, 1, 0),
+            (CacheMode::Always, 86400, FOPEN_KEEP_CACHE),
+        ] {
+            let policy = CachePolicy::new(cache, None, None);
+            let (mut device, tmp) = create_configured_device(IdMapper::default(), policy);
+            std::fs::write(tmp.path().join("file.txt"), "content").unwrap();
+
+            let response = device.handle_lookup(42, 1, b"file.txt\0");
+            let entry_valid = u64::from_le_bytes(response[32..40].try_into().unwrap());
+            let attr_valid = u64::from_le_bytes(response[40..48].try_into().unwrap());
+            assert_eq!((entry_valid, attr_valid), (timeout, timeout), "{cache:?}");
+
+            let response = device.handle_getattr(42, 1, &[]);
+            let attr_valid = u64::from_le_bytes(response[16..24].try_into().unwrap());
+            assert_eq!(attr_valid, timeout, "{cache:?}");
+
+            let nodeid = device.inodes.lookup(1, "file.txt").unwrap();
+            let response = device.handle_open(42, nodeid, &[0; 8]);
+            let flags = u32::from_le_bytes(response[24..28].try_into().unwrap());
+            assert_eq!(flags, open_flags, "{cache:?}");
+        }
+    }
+
+    #[test]
+    fn fuse_auto_cache_invalidates_changed_data() {
+        let offered = FUSE_ASYNC_READ | FUSE_AUTO_INVAL_DATA;
+        for (cache, negotiated) in [
+            (CacheMode::None, FUSE_ASYNC_READ),
+            (CacheMode::Auto, offered),
+            (CacheMode::Always, FUSE_ASYNC_READ),
+        ] {
+            let policy = CachePolicy::new(cache, None, None);
+            let (mut device, _tmp) = create_configured_device(IdMapper::default(), policy);
+
+            let mut init_body = build_init_request(FUSE_KERNEL_VERSION, FUSE_KERNEL_MINOR_VERSION);
+            init_body[12..16].copy_from_slice(&offered.to_le_bytes());
+            let response = device.handle_init(42, &init_body);
+
+            let flags = u32::from_le_bytes(response[28..32].try_into().unwrap());
+            assert_eq!(flags, negotiated, "{cache:?}");
+        }
+    }
+
+    fn build_lk_body(owner: u64, typ: i32, lk_flags: u32) -> Vec<u8> {
+        let mut body = vec![0u8; 48]; // FuseLkIn
+        body[8..16].copy_from_slice(&owner.to_le_bytes());
+        body[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+        body[32..36].copy_from_slice(&(typ as u32).to_le_bytes());
+        body[40..44].copy_from_slice(&lk_flags.to_le_bytes());
+        body
+    }
+
+    #[test]
+    fn fuse_posix_locks_conflict_between_owners() {
+        let (mut device, tmp) = create_test_device("test");
+        std::fs::write(tmp.path().join("file.db"), "").unwrap();
+        let fh = open_test_file(&mut device, "file.db", libc::O_RDWR);
+        let nodeid = device.handles.get(fh).unwrap().ino;
+
+        let response = device.handle_setlk(42, nodeid, &build_lk_body(1, libc::F_WRLCK, 0));
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "setlk should succeed");
+
+        let response = device.handle_getlk(42, nodeid, &build_lk_body(2, libc::F_RDLCK, 0));
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "getlk should succeed");
+        let typ = u32::from_le_bytes(response[32..36].try_into().unwrap());
+        assert_eq!(typ, libc::F_WRLCK as u32);
+
+        let response = device.handle_setlk(42, nodeid, &build_lk_body(2, libc::F_RDLCK, 0));
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, -libc::EAGAIN);
+
+        // Closing the file as owner 1 drops its locks
+        let mut flush_body = vec![0u8; 24]; // FuseFlushIn
+        flush_body[0..8].copy_from_slice(&fh.to_le_bytes());
+        flush_body[16..24].copy_from_slice(&1u64.to_le_bytes());
+        device.handle_flush(42, &flush_body);
+
+        let response = device.handle_setlk(42, nodeid, &build_lk_body(2, libc::F_RDLCK, 0));
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "setlk should succeed once the lock is dropped");
+    }
+
+    #[test]
+    fn fuse_setlkw_waits_without_holding_up_the_queue() {
+        let (mut device, tmp) = create_test_device("test");
+        std::fs::write(tmp.path().join("file.db"), "").unwrap();
+        let nodeid = device.inodes.lookup(1, "file.db").unwrap();
+
+        let memory = create_test_memory();
+        device.set_memory(memory.clone());
+        let used_ring = 0x3000u64;
+        let queue = &mut device.queues[REQUEST_QUEUE_INDEX];
+        queue.size = 16;
+        queue.desc_table = 0x1000;
+        queue.avail_ring = 0x2000;
+        queue.used_ring = used_ring;
+        queue.ready = true;
+
+        let flock = FUSE_LK_FLOCK;
+        let response = device.handle_setlk(42, nodeid, &build_lk_body(1, libc::F_WRLCK, flock));
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "flock should succeed");
+
+        let body = build_lk_body(2, libc::F_WRLCK, flock);
+        let request = build_fuse_request(FuseOpcode::Setlkw, 43, nodeid, &body);
+        assert!(device.handle_queued_request(3, request).is_none());
+        assert_eq!(device.retry_blocked_locks(), Some(LOCK_RETRY_INTERVAL));
+
+        // Requests behind the blocked one are still answered
+        let request = build_fuse_request(FuseOpcode::Getattr, 44, nodeid, &[0; 16]);
+        assert!(device.handle_queued_request(4, request).is_some());
+
+        let response = device.handle_setlk(42, nodeid, &build_lk_body(1, libc::F_UNLCK, flock));
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "unlock should succeed");
+
+        assert_eq!(device.retry_blocked_locks(), None);
+        let used_idx: u16 = memory.read_obj(GuestAddress(used_ring + 2)).unwrap();
+        let used_id: u32 = memory.read_obj(GuestAddress(used_ring + 4)).unwrap();
+        assert_eq!(used_idx, 1);
+        assert_eq!(used_id, 3);
+    }
+
+    #[test]
+    fn fuse_unlock_grants_waiting_setlkw_at_once() {
+        let (mut device, tmp) = create_test_device("test");
+        std::fs::write(tmp.path().join("file.db"), "").unwrap();
+        let nodeid = device.inodes.lookup(1, "file.db").unwrap();
+
+        let body = build_lk_body(1, libc::F_WRLCK, 0);
+        let request = build_fuse_request(FuseOpcode::Setlk, 42, nodeid, &body);
+        let responses = device.handle_queued_requests(vec![(1, request)]);
+        assert_eq!(responses.len(), 1);
+
+        let body = build_lk_body(2, libc::F_WRLCK, 0);
+        let request = build_fuse_request(FuseOpcode::Setlkw, 43, nodeid, &body);
+        assert!(device.handle_queued_requests(vec![(2, request)]).is_empty());
+
+        // The waiter is answered with the unlock, not by the retry timer
+        let body = build_lk_body(1, libc::F_UNLCK, 0);
+        let request = build_fuse_request(FuseOpcode::Setlk, 44, nodeid, &body);
+        let responses = device.handle_queued_requests(vec![(3, request)]);
+        let completed: Vec<u16> = responses.iter().map(|(idx, _)| *idx).collect();
+        assert_eq!(completed, [3, 2]);
+        let (_, error, _) = parse_fuse_out_header(&responses[1].1);
+        assert_eq!(error, 0, "setlkw should get the lock");
+        assert!(device.blocked_locks.is_empty());
+    }
+
+    #[test]
+    fn fuse_setlkw_waiters_are_capped() {
+        let (mut device, tmp) = create_test_device("test");
+        std::fs::write(tmp.path().join("file.db"), "").unwrap();
+        let nodeid = device.inodes.lookup(1, "file.db").unwrap();
+
+        let response = device.handle_setlk(42, nodeid, &build_lk_body(1, libc::F_WRLCK, 0));
+        let (_, error, _) = parse_fuse_out_header(&response);
+        assert_eq!(error, 0, "setlk should succeed");
+
+        for owner in 0..=MAX_BLOCKED_LOCKS as u64 {
+            let body = build_lk_body(owner + 2, libc::F_WRLCK, 0);
+            let request = build_fuse_request(FuseOpcode::Setlkw, 43, nodeid, &body);
+            let response = device.handle_queued_request(owner as u16, request);
+            if owner < MAX_BLOCKED_LOCKS as u64 {
+                assert!(response.is_none(), "setlkw should wait");
+            } else {
+                let (_, error, _) = parse_fuse_out_header(&response.unwrap());
+                assert_eq!(error, -libc::ENOLCK);
+            }
+        }
+        assert_eq!(device.blocked_locks.len(), MAX_BLOCKED_LOCKS);
+    }
+
     #[test]
     fn fuse_statfs() {
         let (mut device, _tmp) = create_test_device("test");
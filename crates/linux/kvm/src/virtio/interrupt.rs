@@ -0,0 +1,53 @@
+//! Interrupts virtio devices raise towards the guest.
+
+use kvm_ioctls::VmFd;
+use std::sync::Arc;
+
+/// Delivers a virtio device's interrupts to the guest.
+///
+/// A virtio-mmio device raises every interrupt on one IOAPIC pin. A
+/// virtio-pci device has one GSI for used buffers and one for configuration
+/// changes, each routed to the MSI-X message the driver chose for it.
+#[derive(Clone)]
+pub struct VirtioInterrupt {
+    vm_fd: Arc<VmFd>,
+    used_ring_gsi: u32,
+    config_gsi: u32,
+}
+
+impl VirtioInterrupt {
+    /// Interrupts on the IOAPIC pin `irq`.
+    pub fn pin(vm_fd: Arc<VmFd>, irq: u32) -> Self {
+        Self {
+            vm_fd,
+            used_ring_gsi: irq,
+            config_gsi: irq,
+        }
+    }
+
+    /// Interrupts on two GSIs with MSI routes.
+    pub fn msi(vm_fd: Arc<VmFd>, used_ring_gsi: u32, config_gsi: u32) -> Self {
+        Self {
+            vm_fd,
+            used_ring_gsi,
+            config_gsi,
+        }
+    }
+
+    /// Tells the guest that the device has used buffers from a queue.
+    pub fn signal_used_ring(&self) {
+        self.pulse(self.used_ring_gsi);
+    }
+
+    /// Tells the guest that the device configuration changed.
+    pub fn signal_config(&self) {
+        self.pulse(self.config_gsi);
+    }
+
+    fn pulse(&self, gsi: u32) {
+        // Edge-triggered interrupt: assert then de-assert. On a GSI routed to
+        // MSI messages, asserting sends them and de-asserting does nothing.
+        let _ = self.vm_fd.set_irq_line(gsi, true);
+        let _ = self.vm_fd.set_irq_line(gsi, false);
+    }
+}
@@ -1,24 +1,48 @@
 //! Virtio device implementations.
 //!
 //! This module provides virtio-based devices for the KVM backend:
+//! - `balloon`: Virtio memory balloon for returning guest memory to the host
+//! - `blk`: Virtio block device for disk images
 //! - `console`: Virtio console for guest I/O
 //! - `net`: Virtio network device for guest networking
 //! - `vsock`: Virtio socket device for host-guest communication
 //! - `fs`: Virtio filesystem device for shared directories
+//! - `rng`: Virtio entropy device fed from the host's getrandom
+//!
+//! Devices implement the virtio-mmio register layout. `pci` presents the
+//! same devices to the guest through the virtio-pci transport instead.
 
+mod balloon;
+mod blk;
 mod common;
 mod console;
 mod fs;
+mod interrupt;
 mod net;
+mod pci;
+mod rng;
 mod vsock;
 
+pub use balloon::{BALLOON_PAGE_SIZE, VirtioBalloon, VirtioBalloonState};
+pub use blk::VirtioBlk;
+pub use common::{VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_STATUS, VirtioTransportState};
 pub use console::VirtioConsole;
-pub use fs::VirtioFs;
-pub use net::VirtioNet;
+pub use fs::{VirtioFs, VirtioFsState};
+pub use interrupt::VirtioInterrupt;
+pub use net::{VirtioNet, VirtioNetState};
+pub use pci::{VIRTIO_PCI_BAR_SIZE, VirtioPciDevice};
+pub use rng::VirtioRng;
 pub use vsock::{BridgeToDevice, DeviceToBridge, VirtioVsock};
 
+use vm_device::MutDeviceMmio;
 use vm_memory::{GuestAddress, GuestMemory, GuestMemoryMmap};
 
+/// A virtio device, accessed through its virtio-mmio registers.
+pub trait VirtioDevice: MutDeviceMmio + Send + 'static {
+    /// Number of virtqueues the device has.
+    fn num_queues(&self) -> usize;
+}
+
 // Virtio split queue structure sizes (from virtio 1.0 spec section 2.6)
 // Descriptor: addr(8) + len(4) + flags(2) + next(2) = 16 bytes
 const VIRTIO_DESC_SIZE: u64 = 16;
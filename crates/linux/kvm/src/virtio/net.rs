@@ -9,7 +9,8 @@ use std::os::fd::{AsRawFd, OwnedFd};
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
 
-use kvm_ioctls::VmFd;
+use capsa_core::Result;
+use serde::{Deserialize, Serialize};
 use virtio_queue::desc::split::Descriptor;
 use virtio_queue::{Queue, QueueT};
 use vm_device::MutDeviceMmio;
@@ -25,8 +26,9 @@ use super::common::{
     VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY,
     VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW,
     VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION, VirtioQueueState,
+    VirtioTransportState,
 };
-use super::{MAX_DESCRIPTOR_LEN, validate_queue_addresses};
+use super::{MAX_DESCRIPTOR_LEN, VirtioDevice, VirtioInterrupt, validate_queue_addresses};
 
 const VIRTIO_ID_NET: u32 = 1;
 
@@ -42,6 +44,13 @@ const VIRTIO_NET_F_MAC: u64 = 1 << 5;
 
 const VIRTIO_NET_HDR_SIZE: usize = 12;
 
+/// Snapshot state of a [`VirtioNet`] device.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VirtioNetState {
+    transport: VirtioTransportState,
+    mac: [u8; 6],
+}
+
 /// Virtio network device using MMIO transport.
 ///
 /// Uses a socketpair for frame I/O:
@@ -59,10 +68,7 @@ pub struct VirtioNet {
 
     interrupt_status: AtomicU32,
 
-    /// VmFd for direct interrupt injection via set_irq_line
-    vm_fd: Arc<VmFd>,
-    /// IRQ number for this device
-    irq: u32,
+    interrupt: VirtioInterrupt,
 
     /// File descriptor for frame I/O (our end of the socketpair)
     socket_fd: OwnedFd,
@@ -81,8 +87,7 @@ impl VirtioNet {
     ///
     /// The `socket_fd` is our end of a socketpair; the other end should be
     /// passed to the UserNatStack via SocketPairDevice.
-    /// The `vm_fd` is used for direct interrupt injection via set_irq_line.
-    pub fn new(socket_fd: OwnedFd, mac: [u8; 6], vm_fd: Arc<VmFd>, irq: u32) -> Self {
+    pub fn new(socket_fd: OwnedFd, mac: [u8; 6], interrupt: VirtioInterrupt) -> Self {
         Self {
             device_features: VIRTIO_F_VERSION_1 | VIRTIO_NET_F_MAC,
             driver_features: 0,
@@ -92,8 +97,7 @@ impl VirtioNet {
             queue_sel: 0,
             queues: [VirtioQueueState::default(), VirtioQueueState::default()],
             interrupt_status: AtomicU32::new(0),
-            vm_fd,
-            irq,
+            interrupt,
             socket_fd,
             mac,
             rx_queue: VecDeque::new(),
@@ -105,12 +109,47 @@ impl VirtioNet {
         self.memory = Some(memory);
     }
 
+    /// Captures the device state for a VM snapshot.
+    ///
+    /// Frames waiting for the guest are dropped; the guest's TCP stack
+    /// retransmits them.
+    pub fn save_state(&self) -> VirtioNetState {
+        VirtioNetState {
+            transport: VirtioTransportState {
+                driver_features: self.driver_features,
+                device_features_sel: self.device_features_sel,
+                driver_features_sel: self.driver_features_sel,
+                device_status: self.device_status,
+                queue_sel: self.queue_sel,
+                interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+                queues: self.queues.to_vec(),
+            },
+            mac: self.mac,
+        }
+    }
+
+    /// Restores state captured by [`save_state`](Self::save_state).
+    ///
+    /// The guest already configured itself with the saved MAC address, so it
+    /// replaces the one the device was created with.
+    pub fn restore_state(&mut self, state: &VirtioNetState) -> Result<()> {
+        let transport = &state.transport;
+        self.queues = transport.queues("virtio-net")?;
+        self.driver_features = transport.driver_features;
+        self.device_features_sel = transport.device_features_sel;
+        self.driver_features_sel = transport.driver_features_sel;
+        self.device_status = transport.device_status;
+        self.queue_sel = transport.queue_sel;
+        self.interrupt_status
+            .store(transport.interrupt_status, Ordering::SeqCst);
+        self.mac = state.mac;
+        Ok(())
+    }
+
     fn signal_used_queue(&self) {
         self.interrupt_status
             .fetch_or(VIRTIO_INT_USED_RING, Ordering::SeqCst);
-        // Edge-triggered interrupt: assert then de-assert
-        let _ = self.vm_fd.set_irq_line(self.irq, true);
-        let _ = self.vm_fd.set_irq_line(self.irq, false);
+        self.interrupt.signal_used_ring();
     }
 
     fn is_activated(&self) -> bool {
@@ -125,6 +164,17 @@ impl VirtioNet {
         &mut self.queues[self.queue_sel as usize]
     }
 
+    /// Processes both queues after the guest notified the device.
+    ///
+    /// Notifications arrive here instead of as MMIO writes once an ioeventfd
+    /// is registered for the QUEUE_NOTIFY register.
+    pub fn process_queues(&mut self) {
+        if self.is_activated() {
+            self.process_tx_queue();
+            self.process_rx_queue();
+        }
+    }
+
     /// Try to receive frames from the socketpair (non-blocking).
     pub fn poll_rx(&mut self) {
         let mut buf = [0u8; 1514 + VIRTIO_NET_HDR_SIZE];
@@ -422,3 +472,9 @@ impl MutDeviceMmio for VirtioNet {
         self.handle_mmio_write(offset, data);
     }
 }
+
+impl VirtioDevice for VirtioNet {
+    fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+}
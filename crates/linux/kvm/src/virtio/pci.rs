@@ -0,0 +1,856 @@
+//! Virtio over PCI (the modern virtio 1.x transport).
+//!
+//! Wraps a device that implements the virtio-mmio registers and presents it
+//! as a PCI function. Everything lives in one memory BAR:
+//!
+//! ```text
+//! Offset  Size    Contents
+//! 0x0000  0x38    Common configuration
+//! 0x1000  0x1     ISR status
+//! 0x2000  0x1000  Device-specific configuration
+//! 0x3000  0x4     Queue notifications, shared by all queues
+//! 0x4000  0x20    MSI-X table
+//! 0x5000  0x8     MSI-X pending bit array
+//! ```
+//!
+//! Common configuration accesses are translated into accesses to the
+//! device's virtio-mmio registers, apart from the fields virtio-mmio has no
+//! register to read back from, which are kept here.
+//!
+//! Devices signal used buffers without saying which queue they belong to,
+//! so the MSI-X table has two vectors: one for configuration changes and one
+//! the guest shares between all queues.
+
+use super::common::{
+    VIRTIO_MMIO_CONFIG, VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL,
+    VIRTIO_MMIO_DEVICE_ID, VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_DRIVER_FEATURES_SEL,
+    VIRTIO_MMIO_INTERRUPT_ACK, VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_QUEUE_AVAIL_HIGH,
+    VIRTIO_MMIO_QUEUE_AVAIL_LOW, VIRTIO_MMIO_QUEUE_DESC_HIGH, VIRTIO_MMIO_QUEUE_DESC_LOW,
+    VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX,
+    VIRTIO_MMIO_QUEUE_READY, VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH,
+    VIRTIO_MMIO_QUEUE_USED_LOW, VIRTIO_MMIO_STATUS,
+};
+use super::{VirtioDevice, VirtioInterrupt};
+use crate::gsi::{GsiRouting, MsiMessage};
+use crate::ioevent::NotifierRegistration;
+use crate::pci::{
+    MSIX_CONTROL_OFFSET, MSIX_ENABLE, MSIX_FUNCTION_MASK, MsixTable, PCI_CAP_ID_MSIX, PciConfig,
+    PciDevice, PciIdentity, msix_capability,
+};
+use std::sync::{Arc, Mutex};
+use vm_device::bus::MmioAddress;
+
+/// Size of the BAR that holds all virtio-pci structures.
+pub const VIRTIO_PCI_BAR_SIZE: u64 = 0x8000;
+
+/// Offset of the notification register in the BAR.
+pub const VIRTIO_PCI_NOTIFY_OFFSET: u64 = 0x3000;
+
+const COMMON_CFG_OFFSET: u64 = 0x0000;
+const COMMON_CFG_SIZE: u64 = 0x38;
+const ISR_OFFSET: u64 = 0x1000;
+const DEVICE_CFG_OFFSET: u64 = 0x2000;
+const DEVICE_CFG_SIZE: u64 = 0x1000;
+const NOTIFY_SIZE: u64 = 4;
+const MSIX_TABLE_OFFSET: u64 = 0x4000;
+const MSIX_PBA_OFFSET: u64 = 0x5000;
+const MSIX_PBA_SIZE: u64 = 8;
+
+const MSIX_VECTORS: u16 = 2;
+const VIRTIO_MSI_NO_VECTOR: u16 = 0xffff;
+
+const VIRTIO_PCI_VENDOR_ID: u16 = 0x1af4;
+const VIRTIO_PCI_DEVICE_ID_BASE: u16 = 0x1040;
+const VIRTIO_PCI_SUBSYSTEM_ID: u16 = 0x1100;
+/// Revision 1 marks a modern device without the legacy interface.
+const VIRTIO_PCI_REVISION: u8 = 1;
+
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+
+// virtio_pci_cap.cfg_type
+const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
+const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
+const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
+const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+
+// Common configuration layout (virtio 1.x section 4.1.4.3). The 64-bit
+// queue addresses are accessed as two 32-bit halves.
+const DEVICE_FEATURE_SELECT: u64 = 0x00;
+const DEVICE_FEATURE: u64 = 0x04;
+const DRIVER_FEATURE_SELECT: u64 = 0x08;
+const DRIVER_FEATURE: u64 = 0x0c;
+const CONFIG_MSIX_VECTOR: u64 = 0x10;
+const NUM_QUEUES: u64 = 0x12;
+const DEVICE_STATUS: u64 = 0x14;
+const CONFIG_GENERATION: u64 = 0x15;
+const QUEUE_SELECT: u64 = 0x16;
+const QUEUE_SIZE: u64 = 0x18;
+const QUEUE_MSIX_VECTOR: u64 = 0x1a;
+const QUEUE_ENABLE: u64 = 0x1c;
+const QUEUE_NOTIFY_OFF: u64 = 0x1e;
+const QUEUE_DESC_LO: u64 = 0x20;
+const QUEUE_DESC_HI: u64 = 0x24;
+const QUEUE_DRIVER_LO: u64 = 0x28;
+const QUEUE_DRIVER_HI: u64 = 0x2c;
+const QUEUE_DEVICE_LO: u64 = 0x30;
+const QUEUE_DEVICE_HI: u64 = 0x34;
+
+/// Start and size of each common configuration field.
+const COMMON_FIELDS: [(u64, u64); 19] = [
+    (DEVICE_FEATURE_SELECT, 4),
+    (DEVICE_FEATURE, 4),
+    (DRIVER_FEATURE_SELECT, 4),
+    (DRIVER_FEATURE, 4),
+    (CONFIG_MSIX_VECTOR, 2),
+    (NUM_QUEUES, 2),
+    (DEVICE_STATUS, 1),
+    (CONFIG_GENERATION, 1),
+    (QUEUE_SELECT, 2),
+    (QUEUE_SIZE, 2),
+    (QUEUE_MSIX_VECTOR, 2),
+    (QUEUE_ENABLE, 2),
+    (QUEUE_NOTIFY_OFF, 2),
+    (QUEUE_DESC_LO, 4),
+    (QUEUE_DESC_HI, 4),
+    (QUEUE_DRIVER_LO, 4),
+    (QUEUE_DRIVER_HI, 4),
+    (QUEUE_DEVICE_LO, 4),
+    (QUEUE_DEVICE_HI, 4),
+];
+
+/// Queue fields that virtio-mmio registers cannot be read back from.
+#[derive(Clone, Copy)]
+struct QueueConfig {
+    max_size: u16,
+    size: u16,
+    msix_vector: u16,
+    /// Descriptor table, driver area and device area, split in 32-bit halves
+    addresses: [u32; 6],
+}
+
+impl QueueConfig {
+    fn new(max_size: u16) -> Self {
+        Self {
+            max_size,
+            size: max_size,
+            msix_vector: VIRTIO_MSI_NO_VECTOR,
+            addresses: [0; 6],
+        }
+    }
+}
+
+/// A virtio device on the PCI bus.
+pub struct VirtioPciDevice<D> {
+    device: Arc<Mutex<D>>,
+    config: PciConfig,
+    /// Ioeventfd registration for the notify region
+    notifier: NotifierRegistration,
+    msix: MsixTable,
+    msix_capability: usize,
+
+    routing: Arc<GsiRouting>,
+    interrupt: VirtioInterrupt,
+    used_ring_gsi: u32,
+    config_gsi: u32,
+    /// MSI routes last given to each GSI, as (used ring, config)
+    routes: (Vec<MsiMessage>, Vec<MsiMessage>),
+
+    device_feature_select: u32,
+    driver_feature_select: u32,
+    driver_features: [u32; 2],
+    config_msix_vector: u16,
+    queue_select: u16,
+    queues: Vec<QueueConfig>,
+}
+
+impl<D: VirtioDevice> VirtioPciDevice<D> {
+    /// Places `device` on the PCI bus with its BAR at `bar`.
+    ///
+    /// `interrupt` must be the device's own MSI interrupt, on
+    /// `used_ring_gsi` and `config_gsi`; their routes follow whatever the
+    /// guest programs into the MSI-X table. `notifier` follows the notify
+    /// region wherever the guest moves the BAR.
+    pub fn new(
+        device: Arc<Mutex<D>>,
+        bar: u64,
+        routing: Arc<GsiRouting>,
+        interrupt: VirtioInterrupt,
+        used_ring_gsi: u32,
+        config_gsi: u32,
+        notifier: NotifierRegistration,
+    ) -> Self {
+        let (device_id, queues) = {
+            let mut device = device.lock().unwrap();
+            let device_id = read_register(&mut *device, VIRTIO_MMIO_DEVICE_ID);
+            let queues = (0..device.num_queues())
+                .map(|index| {
+                    write_register(&mut *device, VIRTIO_MMIO_QUEUE_SEL, index as u32);
+                    QueueConfig::new(read_register(&mut *device, VIRTIO_MMIO_QUEUE_NUM_MAX) as u16)
+                })
+                .collect();
+            write_register(&mut *device, VIRTIO_MMIO_QUEUE_SEL, 0);
+            (device_id, queues)
+        };
+
+        let mut config = PciConfig::new(&PciIdentity {
+            vendor_id: VIRTIO_PCI_VENDOR_ID,
+            device_id: VIRTIO_PCI_DEVICE_ID_BASE + device_id as u16,
+            class: pci_class(device_id),
+            revision: VIRTIO_PCI_REVISION,
+            subsystem_vendor_id: VIRTIO_PCI_VENDOR_ID,
+            subsystem_id: VIRTIO_PCI_SUBSYSTEM_ID,
+        });
+        config.set_bar(bar, VIRTIO_PCI_BAR_SIZE);
+
+        let (body, writable) = msix_capability(
+            MSIX_VECTORS,
+            0,
+            MSIX_TABLE_OFFSET as u32,
+            MSIX_PBA_OFFSET as u32,
+        );
+        let msix_capability = config.add_capability(PCI_CAP_ID_MSIX, &body, &writable);
+        for (cfg_type, offset, length) in [
+            (
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                COMMON_CFG_OFFSET,
+                COMMON_CFG_SIZE,
+            ),
+            (VIRTIO_PCI_CAP_ISR_CFG, ISR_OFFSET, 1),
+            (
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                DEVICE_CFG_OFFSET,
+                DEVICE_CFG_SIZE,
+            ),
+            (
+                VIRTIO_PCI_CAP_NOTIFY_CFG,
+                VIRTIO_PCI_NOTIFY_OFFSET,
+                NOTIFY_SIZE,
+            ),
+        ] {
+            let body = virtio_capability(cfg_type, offset as u32, length as u32);
+            config.add_capability(PCI_CAP_ID_VNDR, &body, &[]);
+        }
+
+        notifier.move_to(notify_addr(&config));
+        Self {
+            device,
+            config,
+            notifier,
+            msix: MsixTable::new(MSIX_VECTORS as usize),
+            msix_capability,
+            routing,
+            interrupt,
+            used_ring_gsi,
+            config_gsi,
+            routes: (Vec::new(), Vec::new()),
+            device_feature_select: 0,
+            driver_feature_select: 0,
+            driver_features: [0; 2],
+            config_msix_vector: VIRTIO_MSI_NO_VECTOR,
+            queue_select: 0,
+            queues,
+        }
+    }
+
+    fn read_common(&self, offset: u64, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            let offset = offset + i as u64;
+            *byte = match COMMON_FIELDS
+                .iter()
+                .find(|(start, size)| (*start..start + size).contains(&offset))
+            {
+                Some(&(start, _)) => {
+                    self.common_field(start).to_le_bytes()[(offset - start) as usize]
+                }
+                None => 0,
+            };
+        }
+    }
+
+    fn write_common(&mut self, offset: u64, data: &[u8]) {
+        // Fields are written whole, with an access of their own size
+        if !COMMON_FIELDS.contains(&(offset, data.len() as u64)) {
+            tracing::debug!(
+                "virtio-pci: ignoring {}-byte common config write at {:#x}",
+                data.len(),
+                offset
+            );
+            return;
+        }
+        let mut value = [0u8; 4];
+        value[..data.len()].copy_from_slice(data);
+        self.set_common_field(offset, u32::from_le_bytes(value));
+    }
+
+    fn common_field(&self, start: u64) -> u32 {
+        let mut device = self.device.lock().unwrap();
+        let queue = self.queues.get(self.queue_select as usize);
+        match start {
+            DEVICE_FEATURE_SELECT => self.device_feature_select,
+            DEVICE_FEATURE if self.device_feature_select < 2 => {
+                write_register(
+                    &mut *device,
+                    VIRTIO_MMIO_DEVICE_FEATURES_SEL,
+                    self.device_feature_select,
+                );
+                read_register(&mut *device, VIRTIO_MMIO_DEVICE_FEATURES)
+            }
+            DRIVER_FEATURE_SELECT => self.driver_feature_select,
+            DRIVER_FEATURE => self
+                .driver_features
+                .get(self.driver_feature_select as usize)
+                .copied()
+                .unwrap_or(0),
+            CONFIG_MSIX_VECTOR => u32::from(self.config_msix_vector),
+            NUM_QUEUES => self.queues.len() as u32,
+            DEVICE_STATUS => read_register(&mut *device, VIRTIO_MMIO_STATUS) & 0xff,
+            QUEUE_SELECT => u32::from(self.queue_select),
+            QUEUE_SIZE => queue.map_or(0, |q| u32::from(q.size)),
+            QUEUE_MSIX_VECTOR => queue.map_or(0, |q| u32::from(q.msix_vector)),
+            QUEUE_ENABLE if queue.is_some() => {
+                write_register(
+                    &mut *device,
+                    VIRTIO_MMIO_QUEUE_SEL,
+                    u32::from(self.queue_select),
+                );
+                read_register(&mut *device, VIRTIO_MMIO_QUEUE_READY)
+            }
+            QUEUE_DESC_LO..=QUEUE_DEVICE_HI => {
+                queue.map_or(0, |q| q.addresses[((start - QUEUE_DESC_LO) / 4) as usize])
+            }
+            // All queues share one notification address, and the
+            // configuration generation never changes
+            _ => 0,
+        }
+    }
+
+    fn set_common_field(&mut self, start: u64, value: u32) {
+        let device = self.device.clone();
+        let mut device = device.lock().unwrap();
+        match start {
+            DEVICE_FEATURE_SELECT => self.device_feature_select = value,
+            DRIVER_FEATURE_SELECT => self.driver_feature_select = value,
+            DRIVER_FEATURE => {
+                if let Some(features) = self
+                    .driver_features
+                    .get_mut(self.driver_feature_select as usize)
+                {
+                    *features = value;
+                    write_register(
+                        &mut *device,
+                        VIRTIO_MMIO_DRIVER_FEATURES_SEL,
+                        self.driver_feature_select,
+                    );
+                    write_register(&mut *device, VIRTIO_MMIO_DRIVER_FEATURES, value);
+                }
+            }
+            CONFIG_MSIX_VECTOR => {
+                self.config_msix_vector = msix_vector(value);
+                drop(device);
+                self.update_routes();
+            }
+            DEVICE_STATUS => {
+                write_register(&mut *device, VIRTIO_MMIO_STATUS, value);
+                if value == 0 {
+                    drop(device);
+                    self.reset_transport();
+                }
+            }
+            QUEUE_SELECT => {
+                self.queue_select = value as u16;
+                write_register(&mut *device, VIRTIO_MMIO_QUEUE_SEL, value);
+            }
+            _ => {
+                let Some(queue) = self.queues.get_mut(self.queue_select as usize) else {
+                    return;
+                };
+                let register = match start {
+                    QUEUE_SIZE => {
+                        queue.size = value as u16;
+                        VIRTIO_MMIO_QUEUE_NUM
+                    }
+                    QUEUE_MSIX_VECTOR => {
+                        queue.msix_vector = msix_vector(value);
+                        drop(device);
+                        self.update_routes();
+                        return;
+                    }
+                    // The driver only ever enables queues
+                    QUEUE_ENABLE if value == 1 => VIRTIO_MMIO_QUEUE_READY,
+                    QUEUE_DESC_LO..=QUEUE_DEVICE_HI => {
+                        queue.addresses[((start - QUEUE_DESC_LO) / 4) as usize] = value;
+                        [
+                            VIRTIO_MMIO_QUEUE_DESC_LOW,
+                            VIRTIO_MMIO_QUEUE_DESC_HIGH,
+                            VIRTIO_MMIO_QUEUE_AVAIL_LOW,
+                            VIRTIO_MMIO_QUEUE_AVAIL_HIGH,
+                            VIRTIO_MMIO_QUEUE_USED_LOW,
+                            VIRTIO_MMIO_QUEUE_USED_HIGH,
+                        ][((start - QUEUE_DESC_LO) / 4) as usize]
+                    }
+                    _ => return,
+                };
+                write_register(
+                    &mut *device,
+                    VIRTIO_MMIO_QUEUE_SEL,
+                    u32::from(self.queue_select),
+                );
+                write_register(&mut *device, register, value);
+            }
+        }
+    }
+
+    /// Clears what the driver configured, after it reset the device.
+    fn reset_transport(&mut self) {
+        self.device_feature_select = 0;
+        self.driver_feature_select = 0;
+        self.driver_features = [0; 2];
+        self.config_msix_vector = VIRTIO_MSI_NO_VECTOR;
+        self.queue_select = 0;
+        for queue in &mut self.queues {
+            *queue = QueueConfig::new(queue.max_size);
+        }
+        self.update_routes();
+    }
+
+    /// Routes the device's GSIs to the messages of the vectors the driver
+    /// assigned, leaving them unrouted while those vectors are masked.
+    fn update_routes(&mut self) {
+        let control = self
+            .config
+            .read_u16(self.msix_capability + MSIX_CONTROL_OFFSET);
+        let enabled = control & MSIX_ENABLE != 0 && control & MSIX_FUNCTION_MASK == 0;
+        let message = |vector: u16| self.msix.message(vector).filter(|_| enabled);
+
+        let mut queue_vectors: Vec<u16> = self.queues.iter().map(|q| q.msix_vector).collect();
+        queue_vectors.sort_unstable();
+        queue_vectors.dedup();
+        let used_ring: Vec<MsiMessage> = queue_vectors.into_iter().filter_map(message).collect();
+        let config: Vec<MsiMessage> = message(self.config_msix_vector).into_iter().collect();
+
+        let (old_used_ring, old_config) =
+            std::mem::replace(&mut self.routes, (used_ring.clone(), config.clone()));
+        if let Err(e) = self.routing.set_msi_routes(self.used_ring_gsi, &used_ring) {
+            tracing::warn!("virtio-pci: failed to route queue interrupts: {}", e);
+        }
+        if let Err(e) = self.routing.set_msi_routes(self.config_gsi, &config) {
+            tracing::warn!("virtio-pci: failed to route config interrupts: {}", e);
+        }
+
+        // Interrupts raised while a vector was masked were dropped. Without a
+        // pending bit array to remember them, every newly routed vector gets
+        // one, which drivers tolerate.
+        if !used_ring.is_empty() && used_ring != old_used_ring {
+            self.interrupt.signal_used_ring();
+        }
+        if !config.is_empty() && config != old_config {
+            self.interrupt.signal_config();
+        }
+    }
+}
+
+impl<D: VirtioDevice> PciDevice for VirtioPciDevice<D> {
+    fn config(&self) -> &PciConfig {
+        &self.config
+    }
+
+    fn write_config(&mut self, offset: usize, data: &[u8]) {
+        self.config.write(offset, data);
+        self.update_routes();
+        self.notifier.move_to(notify_addr(&self.config));
+    }
+
+    fn read_bar(&mut self, offset: u64, data: &mut [u8]) {
+        match offset {
+            _ if in_range(offset, data, COMMON_CFG_OFFSET, COMMON_CFG_SIZE) => {
+                self.read_common(offset - COMMON_CFG_OFFSET, data);
+            }
+            ISR_OFFSET => {
+                // Reading the ISR acknowledges the interrupt
+                let mut device = self.device.lock().unwrap();
+                let isr = read_register(&mut *device, VIRTIO_MMIO_INTERRUPT_STATUS);
+                write_register(&mut *device, VIRTIO_MMIO_INTERRUPT_ACK, isr);
+                data.fill(0);
+                data[0] = isr as u8;
+            }
+            _ if in_range(offset, data, DEVICE_CFG_OFFSET, DEVICE_CFG_SIZE) => {
+                self.device.lock().unwrap().mmio_read(
+                    MmioAddress(0),
+                    VIRTIO_MMIO_CONFIG + offset - DEVICE_CFG_OFFSET,
+                    data,
+                );
+            }
+            _ if in_range(offset, data, MSIX_TABLE_OFFSET, self.msix.size()) => {
+                self.msix.read(offset - MSIX_TABLE_OFFSET, data);
+            }
+            // Nothing is ever pending
+            _ if in_range(offset, data, MSIX_PBA_OFFSET, MSIX_PBA_SIZE) => data.fill(0),
+            _ => data.fill(0),
+        }
+    }
+
+    fn write_bar(&mut self, offset: u64, data: &[u8]) {
+        match offset {
+            _ if in_range(offset, data, COMMON_CFG_OFFSET, COMMON_CFG_SIZE) => {
+                self.write_common(offset - COMMON_CFG_OFFSET, data);
+            }
+            _ if in_range(offset, data, DEVICE_CFG_OFFSET, DEVICE_CFG_SIZE) => {
+                self.device.lock().unwrap().mmio_write(
+                    MmioAddress(0),
+                    VIRTIO_MMIO_CONFIG + offset - DEVICE_CFG_OFFSET,
+                    data,
+                );
+            }
+            // The guest writes the index of the queue it notifies
+            VIRTIO_PCI_NOTIFY_OFFSET if data.len() >= 2 => {
+                let queue = u16::from_le_bytes([data[0], data[1]]);
+                write_register(
+                    &mut *self.device.lock().unwrap(),
+                    VIRTIO_MMIO_QUEUE_NOTIFY,
+                    u32::from(queue),
+                );
+            }
+            _ if in_range(offset, data, MSIX_TABLE_OFFSET, self.msix.size()) => {
+                self.msix.write(offset - MSIX_TABLE_OFFSET, data);
+                self.update_routes();
+            }
+            _ => {}
+        }
+    }
+
+    fn reset(&mut self) {
+        self.config.reset();
+        self.notifier.move_to(notify_addr(&self.config));
+        self.msix.reset();
+        self.reset_transport();
+    }
+}
+
+/// Guest address of the notify region, while the BAR is decoded.
+fn notify_addr(config: &PciConfig) -> Option<u64> {
+    config
+        .bar_range()
+        .map(|(base, _)| base + VIRTIO_PCI_NOTIFY_OFFSET)
+}
+
+fn in_range(offset: u64, data: &[u8], start: u64, size: u64) -> bool {
+    offset >= start && offset + data.len() as u64 <= start + size
+}
+
+fn read_register<D: VirtioDevice>(device: &mut D, register: u64) -> u32 {
+    let mut data = [0u8; 4];
+    device.mmio_read(MmioAddress(0), register, &mut data);
+    u32::from_le_bytes(data)
+}
+
+fn write_register<D: VirtioDevice>(device: &mut D, register: u64, value: u32) {
+    device.mmio_write(MmioAddress(0), register, &value.to_le_bytes());
+}
+
+/// Keeps vectors the table has, and turns the rest into "no vector", which
+/// tells the driver its choice was rejected.
+fn msix_vector(value: u32) -> u16 {
+    if value < u32::from(MSIX_VECTORS) {
+        value as u16
+    } else {
+        VIRTIO_MSI_NO_VECTOR
+    }
+}
+
+/// Returns the body of a `virtio_pci_cap` after the ID and next pointer,
+/// pointing at `length` bytes at `offset` in BAR0.
+fn virtio_capability(cfg_type: u8, offset: u32, length: u32) -> Vec<u8> {
+    let notify = cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG;
+    let cap_len: u8 = if notify { 20 } else { 16 };
+
+    let mut body = vec![cap_len, cfg_type, 0, 0, 0, 0];
+    body.extend_from_slice(&offset.to_le_bytes());
+    body.extend_from_slice(&length.to_le_bytes());
+    if notify {
+        // Multiplier 0: every queue is notified at the same address
+        body.extend_from_slice(&0u32.to_le_bytes());
+    }
+    body
+}
+
+/// PCI class code for a virtio device type, so guests list the device
+/// sensibly before the virtio driver binds.
+fn pci_class(virtio_id: u32) -> u32 {
+    match virtio_id {
+        // Ethernet controller
+        1 => 0x02_00_00,
+        // SCSI storage controller
+        2 => 0x01_00_00,
+        // Communication controller
+        3 => 0x07_80_00,
+        // Unclassified device
+        _ => 0x00_ff_00,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gsi::NUM_IOAPIC_PINS;
+    use crate::virtio::VirtioBlk;
+    use capsa_core::RawImage;
+    use kvm_ioctls::Kvm;
+    use std::io::Write;
+    use tempfile::NamedTempFile;
+
+    const BAR: u64 = 0xe000_0000;
+
+    struct TestDevice {
+        pci: VirtioPciDevice<VirtioBlk>,
+        blk: Arc<Mutex<VirtioBlk>>,
+        notifier: NotifierRegistration,
+        _disk: NamedTempFile,
+    }
+
+    fn create_test_device() -> TestDevice {
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = Arc::new(kvm.create_vm().expect("Failed to create VM"));
+        vm.create_irq_chip().expect("Failed to create IRQ chip");
+        let routing = Arc::new(GsiRouting::new(vm.clone()).unwrap());
+        // The first two GSIs above the IOAPIC pins
+        let used_ring_gsi = NUM_IOAPIC_PINS as u32;
+        let config_gsi = used_ring_gsi + 1;
+        let interrupt = VirtioInterrupt::msi(vm.clone(), used_ring_gsi, config_gsi);
+
+        let notifier = NotifierRegistration::new(vm.clone());
+
+        let mut disk = NamedTempFile::new().unwrap();
+        disk.write_all(&[0u8; 8192]).unwrap();
+        let image = RawImage::open(disk.path(), false).unwrap();
+        let blk = Arc::new(Mutex::new(VirtioBlk::new(
+            Box::new(image),
+            false,
+            "capsa-test",
+            interrupt.clone(),
+        )));
+        let pci = VirtioPciDevice::new(
+            blk.clone(),
+            BAR,
+            routing,
+            interrupt,
+            used_ring_gsi,
+            config_gsi,
+            notifier.clone(),
+        );
+        TestDevice {
+            pci,
+            blk,
+            notifier,
+            _disk: disk,
+        }
+    }
+
+    fn read_bar(device: &mut VirtioPciDevice<VirtioBlk>, offset: u64, len: usize) -> u32 {
+        let mut data = [0u8; 4];
+        device.read_bar(offset, &mut data[..len]);
+        u32::from_le_bytes(data)
+    }
+
+    fn write_bar(device: &mut VirtioPciDevice<VirtioBlk>, offset: u64, len: usize, value: u32) {
+        device.write_bar(offset, &value.to_le_bytes()[..len]);
+    }
+
+    /// Walks the capability list, returning (offset, ID, cfg_type) for each.
+    fn capabilities(config: &PciConfig) -> Vec<(usize, u8, u8)> {
+        let mut data = [0u8; 4];
+        config.read(0x34, &mut data[..1]);
+        let mut offset = data[0] as usize;
+        let mut caps = Vec::new();
+        while offset != 0 {
+            config.read(offset, &mut data);
+            caps.push((offset, data[0], data[3]));
+            offset = data[1] as usize;
+        }
+        caps
+    }
+
+    #[test]
+    fn identifies_as_modern_virtio_device() {
+        let device = create_test_device();
+        let config = device.pci.config();
+
+        assert_eq!(config.read_u16(0x00), 0x1af4);
+        assert_eq!(config.read_u16(0x02), 0x1042);
+        assert_eq!(config.read_u32(0x08), 0x0100_0001);
+        assert_eq!(config.read_u32(0x10), BAR as u32 | 0x4);
+        assert_eq!(config.read_u16(0x3c) >> 8, 0, "no interrupt pin");
+    }
+
+    #[test]
+    fn lists_msix_and_virtio_capabilities() {
+        let device = create_test_device();
+        let config = device.pci.config();
+        let caps = capabilities(config);
+
+        assert_eq!(caps[0].1, PCI_CAP_ID_MSIX);
+        let msix = caps[0].0;
+        assert_eq!(config.read_u16(msix + 2), MSIX_VECTORS - 1);
+        assert_eq!(config.read_u32(msix + 4), MSIX_TABLE_OFFSET as u32);
+        assert_eq!(config.read_u32(msix + 8), MSIX_PBA_OFFSET as u32);
+
+        let virtio: Vec<u8> = caps[1..]
+            .iter()
+            .inspect(|cap| assert_eq!(cap.1, PCI_CAP_ID_VNDR))
+            .map(|cap| cap.2)
+            .collect();
+        assert_eq!(
+            virtio,
+            [
+                VIRTIO_PCI_CAP_COMMON_CFG,
+                VIRTIO_PCI_CAP_ISR_CFG,
+                VIRTIO_PCI_CAP_DEVICE_CFG,
+                VIRTIO_PCI_CAP_NOTIFY_CFG
+            ]
+        );
+        let notify = caps[4].0;
+        assert_eq!(config.read_u32(notify + 8), VIRTIO_PCI_NOTIFY_OFFSET as u32);
+        assert_eq!(config.read_u32(notify + 12), NOTIFY_SIZE as u32);
+        assert_eq!(config.read_u32(notify + 16), 0);
+    }
+
+    #[test]
+    fn negotiates_features_through_common_config() {
+        let mut device = create_test_device();
+        let pci = &mut device.pci;
+
+        write_bar(pci, DEVICE_FEATURE_SELECT, 4, 1);
+        assert_eq!(read_bar(pci, DEVICE_FEATURE, 4), 1, "VIRTIO_F_VERSION_1");
+        assert_eq!(read_bar(pci, NUM_QUEUES, 2), 1);
+
+        write_bar(pci, DRIVER_FEATURE_SELECT, 4, 1);
+        write_bar(pci, DRIVER_FEATURE, 4, 1);
+        assert_eq!(read_bar(pci, DRIVER_FEATURE, 4), 1);
+        write_bar(pci, DEVICE_STATUS, 1, 0xb);
+        assert_eq!(read_bar(pci, DEVICE_STATUS, 1), 0xb);
+        assert_eq!(
+            device.blk.lock().unwrap().save_state().driver_features,
+            1 << 32
+        );
+    }
+
+    #[test]
+    fn configures_queues_through_common_config() {
+        let mut device = create_test_device();
+        let pci = &mut device.pci;
+
+        write_bar(pci, QUEUE_SELECT, 2, 0);
+        assert_eq!(read_bar(pci, QUEUE_SIZE, 2), 256);
+        write_bar(pci, QUEUE_SIZE, 2, 128);
+        write_bar(pci, QUEUE_DESC_LO, 4, 0x1000);
+        write_bar(pci, QUEUE_DRIVER_LO, 4, 0x2000);
+        write_bar(pci, QUEUE_DEVICE_LO, 4, 0x3000);
+        write_bar(pci, QUEUE_DEVICE_HI, 4, 0);
+        assert_eq!(read_bar(pci, QUEUE_SIZE, 2), 128);
+        assert_eq!(read_bar(pci, QUEUE_DRIVER_LO, 4), 0x2000);
+        assert_eq!(read_bar(pci, QUEUE_ENABLE, 2), 0);
+
+        write_bar(pci, QUEUE_ENABLE, 2, 1);
+        assert_eq!(read_bar(pci, QUEUE_ENABLE, 2), 1);
+        let state = device.blk.lock().unwrap().save_state();
+        let queue = &state.queues[0];
+        assert_eq!(queue.size, 128);
+        assert_eq!(
+            (queue.desc_table, queue.avail_ring, queue.used_ring),
+            (0x1000, 0x2000, 0x3000)
+        );
+
+        // Queues past the last one read as absent
+        write_bar(pci, QUEUE_SELECT, 2, 1);
+        assert_eq!(read_bar(pci, QUEUE_SIZE, 2), 0);
+
+        write_bar(pci, DEVICE_STATUS, 1, 0);
+        write_bar(pci, QUEUE_SELECT, 2, 0);
+        assert_eq!(read_bar(pci, QUEUE_SIZE, 2), 256);
+        assert_eq!(read_bar(pci, QUEUE_DRIVER_LO, 4), 0);
+    }
+
+    #[test]
+    fn rejects_vectors_past_the_table() {
+        let mut device = create_test_device();
+        let pci = &mut device.pci;
+
+        assert_eq!(read_bar(pci, CONFIG_MSIX_VECTOR, 2), 0xffff);
+        write_bar(pci, CONFIG_MSIX_VECTOR, 2, 0);
+        assert_eq!(read_bar(pci, CONFIG_MSIX_VECTOR, 2), 0);
+        write_bar(pci, QUEUE_MSIX_VECTOR, 2, MSIX_VECTORS as u32);
+        assert_eq!(read_bar(pci, QUEUE_MSIX_VECTOR, 2), 0xffff);
+        write_bar(pci, QUEUE_MSIX_VECTOR, 2, 1);
+        assert_eq!(read_bar(pci, QUEUE_MSIX_VECTOR, 2), 1);
+    }
+
+    #[test]
+    fn routes_assigned_vectors_once_unmasked() {
+        let mut device = create_test_device();
+        let pci = &mut device.pci;
+        let msix = capabilities(pci.config())[0].0;
+        let message = MsiMessage {
+            address: 0xfee0_0000,
+            data: 0x41,
+        };
+
+        write_bar(pci, QUEUE_MSIX_VECTOR, 2, 1);
+        write_bar(pci, MSIX_TABLE_OFFSET + 16, 4, message.address as u32);
+        write_bar(pci, MSIX_TABLE_OFFSET + 24, 4, message.data);
+        write_bar(pci, MSIX_TABLE_OFFSET + 28, 4, 0);
+        assert!(pci.routes.0.is_empty(), "MSI-X is disabled");
+
+        pci.write_config(msix + 2, &(MSIX_ENABLE | MSIX_FUNCTION_MASK).to_le_bytes());
+        assert!(pci.routes.0.is_empty(), "function is masked");
+
+        pci.write_config(msix + 2, &MSIX_ENABLE.to_le_bytes());
+        assert_eq!(pci.routes, (vec![message], vec![]));
+
+        // Masking the entry takes the route away again
+        write_bar(pci, MSIX_TABLE_OFFSET + 28, 4, 1);
+        assert!(pci.routes.0.is_empty());
+
+        pci.reset();
+        assert_eq!(read_bar(pci, QUEUE_MSIX_VECTOR, 2), 0xffff);
+        assert_eq!(pci.config().read_u16(msix + 2), MSIX_VECTORS - 1);
+    }
+
+    #[test]
+    fn forwards_device_config_and_notifications() {
+        let mut device = create_test_device();
+        let pci = &mut device.pci;
+
+        // Capacity in 512-byte sectors
+        assert_eq!(read_bar(pci, DEVICE_CFG_OFFSET, 4), 16);
+        assert_eq!(read_bar(pci, DEVICE_CFG_OFFSET + 20, 4), 512);
+
+        write_bar(pci, MSIX_PBA_OFFSET, 4, 0xffff_ffff);
+        assert_eq!(read_bar(pci, MSIX_PBA_OFFSET, 4), 0);
+
+        // Notifying a queue of a device that was never started does nothing
+        write_bar(pci, VIRTIO_PCI_NOTIFY_OFFSET, 2, 0);
+        assert_eq!(read_bar(pci, ISR_OFFSET, 1), 0);
+    }
+
+    #[test]
+    fn notifier_follows_the_bar() {
+        let TestDevice {
+            mut pci, notifier, ..
+        } = create_test_device();
+        let memory_decoding = 1u16 << 1;
+        assert_eq!(notifier.addr(), None);
+
+        pci.write_config(0x04, &memory_decoding.to_le_bytes());
+        assert_eq!(notifier.addr(), Some(BAR + VIRTIO_PCI_NOTIFY_OFFSET));
+
+        let moved = BAR + 0x10_0000;
+        pci.write_config(0x10, &(moved as u32).to_le_bytes());
+        assert_eq!(notifier.addr(), Some(moved + VIRTIO_PCI_NOTIFY_OFFSET));
+
+        pci.write_config(0x04, &0u16.to_le_bytes());
+        assert_eq!(notifier.addr(), None);
+
+        pci.write_config(0x04, &memory_decoding.to_le_bytes());
+        pci.reset();
+        assert_eq!(notifier.addr(), None);
+    }
+}
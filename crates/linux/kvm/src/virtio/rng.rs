@@ -0,0 +1,587 @@
+//! Virtio entropy device implementation.
+//!
+//! Fills the buffers the guest places on its single queue with bytes from
+//! the host's `getrandom`. An optional rate limit caps how much entropy the
+//! guest gets per period; buffers that arrive once the budget is spent wait
+//! on the queue until the next period.
+
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+use capsa_core::{Result, RngRateLimit};
+use nix::libc;
+use virtio_queue::desc::split::Descriptor;
+use virtio_queue::{Queue, QueueT};
+use vm_device::MutDeviceMmio;
+use vm_device::bus::{MmioAddress, MmioAddressOffset};
+use vm_memory::{Bytes, GuestAddress, GuestMemoryMmap};
+
+use super::common::{
+    VIRTIO_MMIO_DEVICE_FEATURES, VIRTIO_MMIO_DEVICE_FEATURES_SEL, VIRTIO_MMIO_DEVICE_ID,
+    VIRTIO_MMIO_DRIVER_FEATURES, VIRTIO_MMIO_DRIVER_FEATURES_SEL, VIRTIO_MMIO_INTERRUPT_ACK,
+    VIRTIO_MMIO_INTERRUPT_STATUS, VIRTIO_MMIO_MAGIC, VIRTIO_MMIO_MAGIC_VALUE,
+    VIRTIO_MMIO_QUEUE_AVAIL_HIGH, VIRTIO_MMIO_QUEUE_AVAIL_LOW, VIRTIO_MMIO_QUEUE_DESC_HIGH,
+    VIRTIO_MMIO_QUEUE_DESC_LOW, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_NUM,
+    VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY, VIRTIO_MMIO_QUEUE_SEL,
+    VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW, VIRTIO_MMIO_STATUS,
+    VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION, VirtioQueueState, VirtioTransportState,
+};
+use super::{MAX_DESCRIPTOR_LEN, VirtioDevice, VirtioInterrupt, validate_queue_addresses};
+
+const VIRTIO_ID_RNG: u32 = 4;
+
+const REQUEST_QUEUE_INDEX: usize = 0;
+const NUM_QUEUES: usize = 1;
+
+const QUEUE_SIZE: u16 = 256;
+
+const VIRTIO_STATUS_DRIVER_OK: u32 = 4;
+
+const VIRTIO_INT_USED_RING: u32 = 1;
+
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// Virtio entropy device using MMIO transport
+pub struct VirtioRng {
+    device_features: u64,
+    driver_features: u64,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    device_status: u32,
+
+    queue_sel: u32,
+    queues: [VirtioQueueState; NUM_QUEUES],
+
+    interrupt_status: AtomicU32,
+    interrupt: VirtioInterrupt,
+
+    memory: Option<Arc<GuestMemoryMmap>>,
+
+    rate_limiter: Option<RateLimiter>,
+}
+
+impl VirtioRng {
+    /// Create a new virtio-rng device, optionally limited to `rate_limit`.
+    pub fn new(interrupt: VirtioInterrupt, rate_limit: Option<RngRateLimit>) -> Self {
+        Self {
+            device_features: VIRTIO_F_VERSION_1,
+            driver_features: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            device_status: 0,
+            queue_sel: 0,
+            queues: Default::default(),
+            interrupt_status: AtomicU32::new(0),
+            interrupt,
+            memory: None,
+            rate_limiter: rate_limit.map(|limit| RateLimiter::new(limit, Instant::now())),
+        }
+    }
+
+    pub fn set_memory(&mut self, memory: Arc<GuestMemoryMmap>) {
+        self.memory = Some(memory);
+    }
+
+    /// Captures the device state for a VM snapshot.
+    ///
+    /// The rate limit budget is not saved; a restored guest starts a fresh
+    /// period.
+    pub fn save_state(&self) -> VirtioTransportState {
+        VirtioTransportState {
+            driver_features: self.driver_features,
+            device_features_sel: self.device_features_sel,
+            driver_features_sel: self.driver_features_sel,
+            device_status: self.device_status,
+            queue_sel: self.queue_sel,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            queues: self.queues.to_vec(),
+        }
+    }
+
+    /// Restores state captured by [`save_state`](Self::save_state).
+    pub fn restore_state(&mut self, state: &VirtioTransportState) -> Result<()> {
+        self.queues = state.queues("virtio-rng")?;
+        self.driver_features = state.driver_features;
+        self.device_features_sel = state.device_features_sel;
+        self.driver_features_sel = state.driver_features_sel;
+        self.device_status = state.device_status;
+        self.queue_sel = state.queue_sel;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Fills the buffers the guest has queued, as far as the rate limit
+    /// allows.
+    ///
+    /// Called from the worker task, since an ioeventfd is registered for the
+    /// QUEUE_NOTIFY register. Returns how long to wait before calling again
+    /// when buffers are left waiting for the rate limit.
+    pub fn process_queue(&mut self) -> Option<Duration> {
+        if !self.is_activated() {
+            return None;
+        }
+        let memory = self.memory.clone()?;
+        let memory = memory.as_ref();
+
+        let queue_state = &self.queues[REQUEST_QUEUE_INDEX];
+        if !queue_state.ready {
+            return None;
+        }
+
+        let mut queue = Queue::new(queue_state.size).unwrap();
+        let _ = queue.try_set_desc_table_address(GuestAddress(queue_state.desc_table));
+        let _ = queue.try_set_avail_ring_address(GuestAddress(queue_state.avail_ring));
+        let _ = queue.try_set_used_ring_address(GuestAddress(queue_state.used_ring));
+        queue.set_next_avail(queue_state.next_avail);
+        queue.set_next_used(queue_state.next_used);
+        queue.set_ready(true);
+
+        let now = Instant::now();
+        let mut used_any = false;
+        let mut retry_after = None;
+
+        loop {
+            if let Some(wait) = self.rate_limiter.as_mut().and_then(|l| l.wait_time(now)) {
+                // Only worth coming back for if the guest is waiting
+                let avail_idx: u16 = memory
+                    .read_obj(GuestAddress(queue_state.avail_ring + 2))
+                    .unwrap_or(queue.next_avail());
+                if avail_idx != queue.next_avail() {
+                    retry_after = Some(wait);
+                }
+                break;
+            }
+
+            let Some(mut desc_chain) = queue.pop_descriptor_chain(memory) else {
+                break;
+            };
+
+            let mut len = 0u32;
+            for desc in desc_chain.by_ref() {
+                let desc: Descriptor = desc;
+                if !desc.is_write_only() {
+                    continue;
+                }
+
+                let wanted = std::cmp::min(desc.len(), MAX_DESCRIPTOR_LEN) as u64;
+                let granted = match &mut self.rate_limiter {
+                    Some(limiter) => limiter.take(now, wanted),
+                    None => wanted,
+                };
+                if granted == 0 {
+                    break;
+                }
+
+                let mut buf = vec![0u8; granted as usize];
+                if let Err(e) = fill_random(&mut buf) {
+                    tracing::warn!("virtio-rng: getrandom failed: {}", e);
+                    break;
+                }
+                if memory.write_slice(&buf, desc.addr()).is_err() {
+                    break;
+                }
+                len += granted as u32;
+
+                // The guest takes whatever a buffer holds, so a partly filled
+                // one can go back right away
+                if granted < wanted {
+                    break;
+                }
+            }
+
+            if queue.add_used(memory, desc_chain.head_index(), len).is_ok() {
+                used_any = true;
+            }
+        }
+
+        self.queues[REQUEST_QUEUE_INDEX].next_avail = queue.next_avail();
+        self.queues[REQUEST_QUEUE_INDEX].next_used = queue.next_used();
+
+        if used_any {
+            self.signal_used_queue();
+        }
+        retry_after
+    }
+
+    fn signal_used_queue(&self) {
+        self.interrupt_status
+            .fetch_or(VIRTIO_INT_USED_RING, Ordering::SeqCst);
+        self.interrupt.signal_used_ring();
+    }
+
+    fn is_activated(&self) -> bool {
+        self.device_status & VIRTIO_STATUS_DRIVER_OK != 0
+    }
+
+    fn current_queue(&self) -> &VirtioQueueState {
+        &self.queues[self.queue_sel as usize]
+    }
+
+    fn current_queue_mut(&mut self) -> &mut VirtioQueueState {
+        &mut self.queues[self.queue_sel as usize]
+    }
+
+    fn handle_mmio_read(&self, offset: u64, data: &mut [u8]) {
+        let val: u32 = match offset {
+            VIRTIO_MMIO_MAGIC => VIRTIO_MMIO_MAGIC_VALUE,
+            VIRTIO_MMIO_VERSION => 2, // virtio 1.0+
+            VIRTIO_MMIO_DEVICE_ID => VIRTIO_ID_RNG,
+            VIRTIO_MMIO_VENDOR_ID => 0x554d4551, // "QEMU" for compatibility
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                if self.device_features_sel == 0 {
+                    self.device_features as u32
+                } else {
+                    (self.device_features >> 32) as u32
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => QUEUE_SIZE as u32,
+            VIRTIO_MMIO_QUEUE_READY => {
+                if self.current_queue().ready {
+                    1
+                } else {
+                    0
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status.load(Ordering::SeqCst),
+            VIRTIO_MMIO_STATUS => self.device_status,
+            _ => 0,
+        };
+
+        if data.len() >= 4 {
+            data[..4].copy_from_slice(&val.to_le_bytes());
+        }
+    }
+
+    fn handle_mmio_write(&mut self, offset: u64, data: &[u8]) {
+        if data.len() < 4 {
+            return;
+        }
+        let val = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            VIRTIO_MMIO_DRIVER_FEATURES => {
+                if self.driver_features_sel == 0 {
+                    self.driver_features = (self.driver_features & 0xffffffff00000000) | val as u64;
+                } else {
+                    self.driver_features =
+                        (self.driver_features & 0x00000000ffffffff) | ((val as u64) << 32);
+                }
+            }
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            VIRTIO_MMIO_QUEUE_SEL => {
+                if val < NUM_QUEUES as u32 {
+                    self.queue_sel = val;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM => {
+                self.current_queue_mut().size = val as u16;
+            }
+            VIRTIO_MMIO_QUEUE_READY => {
+                if val == 1 {
+                    let q = self.current_queue();
+                    if let Some(ref memory) = self.memory
+                        && !validate_queue_addresses(
+                            memory,
+                            q.desc_table,
+                            q.avail_ring,
+                            q.used_ring,
+                            q.size,
+                        )
+                    {
+                        return;
+                    }
+                }
+                self.current_queue_mut().ready = val == 1;
+            }
+            VIRTIO_MMIO_QUEUE_NOTIFY => {
+                // Buffers left waiting for the rate limit are picked up by the
+                // worker task
+                if val as usize == REQUEST_QUEUE_INDEX {
+                    self.process_queue();
+                }
+            }
+            VIRTIO_MMIO_INTERRUPT_ACK => {
+                self.interrupt_status.fetch_and(!val, Ordering::SeqCst);
+            }
+            VIRTIO_MMIO_STATUS => {
+                if val == 0 {
+                    self.device_status = 0;
+                    self.driver_features = 0;
+                    self.queues = Default::default();
+                } else {
+                    self.device_status = val;
+                }
+            }
+            VIRTIO_MMIO_QUEUE_DESC_LOW => {
+                let q = self.current_queue_mut();
+                q.desc_table = (q.desc_table & 0xffffffff00000000) | val as u64;
+            }
+            VIRTIO_MMIO_QUEUE_DESC_HIGH => {
+                let q = self.current_queue_mut();
+                q.desc_table = (q.desc_table & 0x00000000ffffffff) | ((val as u64) << 32);
+            }
+            VIRTIO_MMIO_QUEUE_AVAIL_LOW => {
+                let q = self.current_queue_mut();
+                q.avail_ring = (q.avail_ring & 0xffffffff00000000) | val as u64;
+            }
+            VIRTIO_MMIO_QUEUE_AVAIL_HIGH => {
+                let q = self.current_queue_mut();
+                q.avail_ring = (q.avail_ring & 0x00000000ffffffff) | ((val as u64) << 32);
+            }
+            VIRTIO_MMIO_QUEUE_USED_LOW => {
+                let q = self.current_queue_mut();
+                q.used_ring = (q.used_ring & 0xffffffff00000000) | val as u64;
+            }
+            VIRTIO_MMIO_QUEUE_USED_HIGH => {
+                let q = self.current_queue_mut();
+                q.used_ring = (q.used_ring & 0x00000000ffffffff) | ((val as u64) << 32);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl MutDeviceMmio for VirtioRng {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        self.handle_mmio_read(offset, data);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        self.handle_mmio_write(offset, data);
+    }
+}
+
+impl VirtioDevice for VirtioRng {
+    fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+}
+
+/// Hands out a budget of bytes that is renewed every period.
+struct RateLimiter {
+    limit: RngRateLimit,
+    available: u64,
+    period_start: Instant,
+}
+
+impl RateLimiter {
+    fn new(limit: RngRateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            available: limit.bytes,
+            period_start: now,
+        }
+    }
+
+    /// Takes up to `wanted` bytes from the budget and returns how many were
+    /// granted.
+    fn take(&mut self, now: Instant, wanted: u64) -> u64 {
+        self.renew(now);
+        let granted = wanted.min(self.available);
+        self.available -= granted;
+        granted
+    }
+
+    /// Returns how long until the budget is renewed, or None if some of it
+    /// is left.
+    fn wait_time(&mut self, now: Instant) -> Option<Duration> {
+        self.renew(now);
+        (self.available == 0)
+            .then(|| (self.period_start + self.limit.period).saturating_duration_since(now))
+    }
+
+    fn renew(&mut self, now: Instant) {
+        if now.saturating_duration_since(self.period_start) >= self.limit.period {
+            self.period_start = now;
+            self.available = self.limit.bytes;
+        }
+    }
+}
+
+/// Fills `buf` from the host's random number generator, which blocks only
+/// until the host pool is initialized.
+fn fill_random(buf: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let rest = &mut buf[filled..];
+        let ret = unsafe { libc::getrandom(rest.as_mut_ptr().cast(), rest.len(), 0) };
+        if ret < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        filled += ret as usize;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use kvm_ioctls::Kvm;
+    use vm_memory::GuestRegionMmap;
+
+    const VIRTQ_DESC_F_WRITE: u16 = 2;
+
+    const DESC_TABLE: u64 = 0x1000;
+    const AVAIL_RING: u64 = 0x2000;
+    const USED_RING: u64 = 0x3000;
+    const BUFFER: u64 = 0x4000;
+
+    fn create_test_device(rate_limit: Option<RngRateLimit>) -> VirtioRng {
+        let kvm = Kvm::new().expect("Failed to open /dev/kvm");
+        let vm = kvm.create_vm().expect("Failed to create VM");
+        let mut device = VirtioRng::new(VirtioInterrupt::pin(Arc::new(vm), 10), rate_limit);
+
+        let region = GuestRegionMmap::new(
+            vm_memory::MmapRegion::new(0x10000).unwrap(),
+            GuestAddress(0),
+        )
+        .unwrap();
+        device.set_memory(Arc::new(
+            GuestMemoryMmap::from_regions(vec![region]).unwrap(),
+        ));
+        device.device_status = VIRTIO_STATUS_DRIVER_OK;
+
+        let queue = &mut device.queues[REQUEST_QUEUE_INDEX];
+        queue.size = QUEUE_SIZE;
+        queue.desc_table = DESC_TABLE;
+        queue.avail_ring = AVAIL_RING;
+        queue.used_ring = USED_RING;
+        queue.ready = true;
+        device
+    }
+
+    fn read_u32(device: &VirtioRng, offset: u64) -> u32 {
+        let mut data = [0u8; 4];
+        device.handle_mmio_read(offset, &mut data);
+        u32::from_le_bytes(data)
+    }
+
+    /// Places a write-only buffer of `len` bytes on the queue, at descriptor
+    /// and avail ring slot `index`.
+    fn queue_buffer(device: &VirtioRng, index: u16, len: u32) {
+        let memory = device.memory.clone().unwrap();
+        let desc = DESC_TABLE + index as u64 * 16;
+        memory
+            .write_obj(BUFFER + index as u64 * 0x1000, GuestAddress(desc))
+            .unwrap();
+        memory.write_obj(len, GuestAddress(desc + 8)).unwrap();
+        memory
+            .write_obj(VIRTQ_DESC_F_WRITE, GuestAddress(desc + 12))
+            .unwrap();
+        memory
+            .write_obj(index, GuestAddress(AVAIL_RING + 4 + index as u64 * 2))
+            .unwrap();
+        memory
+            .write_obj(index + 1, GuestAddress(AVAIL_RING + 2))
+            .unwrap();
+    }
+
+    /// Returns the length the device reported for used ring entry `index`.
+    fn used_len(device: &VirtioRng, index: u16) -> Option<u32> {
+        let memory = device.memory.clone().unwrap();
+        let used_idx: u16 = memory.read_obj(GuestAddress(USED_RING + 2)).unwrap();
+        (index < used_idx).then(|| {
+            memory
+                .read_obj(GuestAddress(USED_RING + 4 + index as u64 * 8 + 4))
+                .unwrap()
+        })
+    }
+
+    fn buffer(device: &VirtioRng, index: u16, len: usize) -> Vec<u8> {
+        let memory = device.memory.clone().unwrap();
+        let mut buf = vec![0u8; len];
+        memory
+            .read_slice(&mut buf, GuestAddress(BUFFER + index as u64 * 0x1000))
+            .unwrap();
+        buf
+    }
+
+    #[test]
+    fn mmio_magic_version_device_id() {
+        let device = create_test_device(None);
+
+        assert_eq!(
+            read_u32(&device, VIRTIO_MMIO_MAGIC),
+            VIRTIO_MMIO_MAGIC_VALUE
+        );
+        assert_eq!(read_u32(&device, VIRTIO_MMIO_VERSION), 2);
+        assert_eq!(read_u32(&device, VIRTIO_MMIO_DEVICE_ID), VIRTIO_ID_RNG);
+    }
+
+    #[test]
+    fn fills_buffers_with_random_bytes() {
+        let mut device = create_test_device(None);
+        queue_buffer(&device, 0, 64);
+
+        assert_eq!(device.process_queue(), None);
+        assert_eq!(used_len(&device, 0), Some(64));
+        assert!(buffer(&device, 0, 64).iter().any(|&b| b != 0));
+        assert_eq!(
+            read_u32(&device, VIRTIO_MMIO_INTERRUPT_STATUS),
+            VIRTIO_INT_USED_RING
+        );
+    }
+
+    #[test]
+    fn rate_limit_leaves_buffers_queued() {
+        let mut device = create_test_device(Some(RngRateLimit {
+            bytes: 48,
+            period: Duration::from_secs(60),
+        }));
+        queue_buffer(&device, 0, 32);
+        queue_buffer(&device, 1, 32);
+        queue_buffer(&device, 2, 32);
+
+        // The second buffer gets what is left of the budget, and the third
+        // waits for the next period
+        let wait = device.process_queue().expect("buffer left waiting");
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(60));
+        assert_eq!(used_len(&device, 0), Some(32));
+        assert_eq!(used_len(&device, 1), Some(16));
+        assert_eq!(used_len(&device, 2), None);
+    }
+
+    #[test]
+    fn exhausted_rate_limit_with_empty_queue_needs_no_retry() {
+        let mut device = create_test_device(Some(RngRateLimit {
+            bytes: 32,
+            period: Duration::from_secs(60),
+        }));
+        queue_buffer(&device, 0, 32);
+
+        assert_eq!(device.process_queue(), None);
+        assert_eq!(used_len(&device, 0), Some(32));
+    }
+
+    #[test]
+    fn rate_limiter_renews_budget_each_period() {
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(
+            RngRateLimit {
+                bytes: 100,
+                period: Duration::from_secs(1),
+            },
+            start,
+        );
+
+        assert_eq!(limiter.take(start, 60), 60);
+        assert_eq!(limiter.wait_time(start), None);
+        assert_eq!(limiter.take(start, 60), 40);
+        assert_eq!(
+            limiter.wait_time(start + Duration::from_millis(250)),
+            Some(Duration::from_millis(750))
+        );
+
+        let next = start + Duration::from_secs(1);
+        assert_eq!(limiter.wait_time(next), None);
+        assert_eq!(limiter.take(next, 500), 100);
+    }
+}
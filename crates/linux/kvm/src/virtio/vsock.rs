@@ -7,7 +7,7 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, Ordering};
 
-use kvm_ioctls::VmFd;
+use capsa_core::Result;
 use tokio::sync::mpsc;
 use virtio_queue::desc::split::Descriptor;
 use virtio_queue::{Queue, QueueT};
@@ -24,12 +24,14 @@ use super::common::{
     VIRTIO_MMIO_QUEUE_NUM, VIRTIO_MMIO_QUEUE_NUM_MAX, VIRTIO_MMIO_QUEUE_READY,
     VIRTIO_MMIO_QUEUE_SEL, VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW,
     VIRTIO_MMIO_STATUS, VIRTIO_MMIO_VENDOR_ID, VIRTIO_MMIO_VERSION, VirtioQueueState,
+    VirtioTransportState,
 };
 
 const VIRTIO_ID_VSOCK: u32 = 19;
 
 const RX_QUEUE_INDEX: usize = 0;
 const TX_QUEUE_INDEX: usize = 1;
+const EVENT_QUEUE_INDEX: usize = 2;
 const QUEUE_SIZE: u16 = 256;
 const NUM_QUEUES: usize = 3;
 
@@ -41,7 +43,10 @@ const VIRTIO_F_VERSION_1: u64 = 1 << 32;
 // Vsock header size (44 bytes)
 const VSOCK_HDR_SIZE: usize = 44;
 
-use super::{MAX_DESCRIPTOR_LEN, MAX_VSOCK_CONNECTIONS, validate_queue_addresses};
+use super::{
+    MAX_DESCRIPTOR_LEN, MAX_VSOCK_CONNECTIONS, VirtioDevice, VirtioInterrupt,
+    validate_queue_addresses,
+};
 
 // Vsock operation codes
 const VSOCK_OP_REQUEST: u16 = 1;
@@ -52,6 +57,9 @@ const VSOCK_OP_RW: u16 = 5;
 const VSOCK_OP_CREDIT_UPDATE: u16 = 6;
 const VSOCK_OP_CREDIT_REQUEST: u16 = 7;
 
+// Event sent on the event queue when host connections were lost
+const VSOCK_EVENT_TRANSPORT_RESET: u32 = 0;
+
 // Vsock type (stream only)
 const VSOCK_TYPE_STREAM: u16 = 1;
 
@@ -175,8 +183,7 @@ pub struct VirtioVsock {
 
     interrupt_status: AtomicU32,
 
-    vm_fd: Arc<VmFd>,
-    irq: u32,
+    interrupt: VirtioInterrupt,
 
     /// Guest CID (reported in config space)
     guest_cid: u64,
@@ -197,18 +204,13 @@ pub struct VirtioVsock {
     /// Channel to send messages to bridge
     bridge_tx: mpsc::UnboundedSender<DeviceToBridge>,
 
-    /// Channel to receive messages from bridge
-    bridge_rx: mpsc::UnboundedReceiver<BridgeToDevice>,
-
     memory: Option<Arc<GuestMemoryMmap>>,
 }
 
 impl VirtioVsock {
     pub fn new(
-        vm_fd: Arc<VmFd>,
-        irq: u32,
+        interrupt: VirtioInterrupt,
         bridge_tx: mpsc::UnboundedSender<DeviceToBridge>,
-        bridge_rx: mpsc::UnboundedReceiver<BridgeToDevice>,
     ) -> Self {
         Self {
             device_features: VIRTIO_F_VERSION_1,
@@ -223,15 +225,13 @@ impl VirtioVsock {
                 VirtioQueueState::default(),
             ],
             interrupt_status: AtomicU32::new(0),
-            vm_fd,
-            irq,
+            interrupt,
             guest_cid: VSOCK_GUEST_CID,
             connections: HashMap::new(),
             pending_host_connects: HashMap::new(),
             next_ephemeral_port: 49152, // Start of dynamic/private port range
             rx_queue: std::collections::VecDeque::new(),
             bridge_tx,
-            bridge_rx,
             memory: None,
         }
     }
@@ -240,11 +240,89 @@ impl VirtioVsock {
         self.memory = Some(memory);
     }
 
+    /// Captures the transport state for a VM snapshot.
+    pub fn save_state(&self) -> VirtioTransportState {
+        VirtioTransportState {
+            driver_features: self.driver_features,
+            device_features_sel: self.device_features_sel,
+            driver_features_sel: self.driver_features_sel,
+            device_status: self.device_status,
+            queue_sel: self.queue_sel,
+            interrupt_status: self.interrupt_status.load(Ordering::SeqCst),
+            queues: self.queues.to_vec(),
+        }
+    }
+
+    /// Restores state captured by [`save_state`](Self::save_state).
+    ///
+    /// Connections are not restored; call
+    /// [`send_transport_reset`](Self::send_transport_reset) once the guest
+    /// is running so it drops its side of them.
+    pub fn restore_state(&mut self, state: &VirtioTransportState) -> Result<()> {
+        self.queues = state.queues("virtio-vsock")?;
+        self.driver_features = state.driver_features;
+        self.device_features_sel = state.device_features_sel;
+        self.driver_features_sel = state.driver_features_sel;
+        self.device_status = state.device_status;
+        self.queue_sel = state.queue_sel;
+        self.interrupt_status
+            .store(state.interrupt_status, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Tells the guest that all connections were lost.
+    ///
+    /// The guest driver resets its sockets and re-reads the guest CID.
+    pub fn send_transport_reset(&mut self) {
+        let memory = match &self.memory {
+            Some(m) => m.as_ref(),
+            None => return,
+        };
+
+        let queue_state = &self.queues[EVENT_QUEUE_INDEX];
+        if !queue_state.ready || !self.is_activated() {
+            return;
+        }
+
+        let mut queue = Queue::new(queue_state.size).unwrap();
+        let _ = queue.try_set_desc_table_address(GuestAddress(queue_state.desc_table));
+        let _ = queue.try_set_avail_ring_address(GuestAddress(queue_state.avail_ring));
+        let _ = queue.try_set_used_ring_address(GuestAddress(queue_state.used_ring));
+        queue.set_next_avail(queue_state.next_avail);
+        queue.set_next_used(queue_state.next_used);
+        queue.set_ready(true);
+
+        let Some(mut desc_chain) = queue.pop_descriptor_chain(memory) else {
+            tracing::warn!("vsock: no event buffer available for transport reset");
+            return;
+        };
+
+        let event = VSOCK_EVENT_TRANSPORT_RESET.to_le_bytes();
+        let mut written = 0u32;
+        for desc in desc_chain.by_ref() {
+            let desc: Descriptor = desc;
+            if desc.is_write_only() && desc.len() as usize >= event.len() {
+                if memory.write_slice(&event, desc.addr()).is_ok() {
+                    written = event.len() as u32;
+                }
+                break;
+            }
+        }
+
+        if queue
+            .add_used(memory, desc_chain.head_index(), written)
+            .is_ok()
+        {
+            self.queues[EVENT_QUEUE_INDEX].next_avail = queue.next_avail();
+            self.queues[EVENT_QUEUE_INDEX].next_used = queue.next_used();
+            self.signal_used_queue();
+        }
+    }
+
     fn signal_used_queue(&self) {
         self.interrupt_status
             .fetch_or(VIRTIO_INT_USED_RING, Ordering::SeqCst);
-        let _ = self.vm_fd.set_irq_line(self.irq, true);
-        let _ = self.vm_fd.set_irq_line(self.irq, false);
+        self.interrupt.signal_used_ring();
     }
 
     fn is_activated(&self) -> bool {
@@ -259,22 +337,32 @@ impl VirtioVsock {
         &mut self.queues[self.queue_sel as usize]
     }
 
-    /// Poll for messages from bridge and queue responses.
-    pub fn poll(&mut self) {
-        while let Ok(msg) = self.bridge_rx.try_recv() {
-            match msg {
-                BridgeToDevice::Data { local_port, data } => {
-                    self.handle_bridge_data(local_port, data);
-                }
-                BridgeToDevice::Closed { local_port } => {
-                    self.handle_bridge_closed(local_port);
-                }
-                BridgeToDevice::Connect { local_port } => {
-                    self.handle_bridge_connect(local_port);
-                }
+    /// Queues the packets for a message from the bridge.
+    ///
+    /// The packets reach the guest on the next
+    /// [`process_queues`](Self::process_queues).
+    pub fn handle_bridge_message(&mut self, msg: BridgeToDevice) {
+        match msg {
+            BridgeToDevice::Data { local_port, data } => {
+                self.handle_bridge_data(local_port, data);
+            }
+            BridgeToDevice::Closed { local_port } => {
+                self.handle_bridge_closed(local_port);
+            }
+            BridgeToDevice::Connect { local_port } => {
+                self.handle_bridge_connect(local_port);
             }
         }
+    }
 
+    /// Processes guest packets and delivers queued packets to the guest.
+    ///
+    /// Guest notifications arrive here instead of as MMIO writes once an
+    /// ioeventfd is registered for the QUEUE_NOTIFY register.
+    pub fn process_queues(&mut self) {
+        if self.is_activated() {
+            self.process_tx_queue();
+        }
         if !self.rx_queue.is_empty() {
             self.process_rx_queue();
         }
@@ -909,3 +997,9 @@ impl MutDeviceMmio for VirtioVsock {
         self.handle_mmio_write(offset, data);
     }
 }
+
+impl VirtioDevice for VirtioVsock {
+    fn num_queues(&self) -> usize {
+        self.queues.len()
+    }
+}
@@ -1,29 +1,52 @@
 use crate::arch::{
-    BOOT_PARAMS_ADDR, KERNEL_LOAD_ADDR, RTC_INDEX_PORT, RtcDevice, SERIAL_IRQ, SERIAL_PORT_BASE,
-    SERIAL_PORT_END, VIRTIO_CONSOLE_IRQ, VIRTIO_FS_IRQ, VIRTIO_FS_MMIO_BASE, VIRTIO_MMIO_BASE,
-    VIRTIO_MMIO_SIZE, VIRTIO_NET_IRQ, VIRTIO_NET_MMIO_BASE, VIRTIO_VSOCK_IRQ,
-    VIRTIO_VSOCK_MMIO_BASE, create_guest_memory, initrd_load_addr, run_vcpu, setup_boot_params,
-    setup_mptable, setup_regs, setup_sregs,
+    ACPI_PM_PORT_BASE, ACPI_PM_PORT_SIZE, AcpiPmDevice, BOOT_PARAMS_ADDR, I8042_COMMAND_PORT,
+    I8042_DATA_PORT, I8042Device, IB700_START_PORT, IB700_STOP_PORT, Ib700Device, KERNEL_LOAD_ADDR,
+    MAX_MEMORY_MB, PCI_MMIO_SIZE, PCI_MMIO_START, PVPANIC_PORT, PvPanicDevice, RTC_INDEX_PORT,
+    RtcDevice, SERIAL_IRQ, SERIAL_PORT_BASE, SERIAL_PORT_END, Topology, VIRTIO_MMIO_SIZE,
+    apply_cpu_model, create_guest_memory, create_guest_memory_from_file, initrd_load_addr,
+    restore_vcpu_state, restore_vm_state, run_vcpu, save_vcpu_state, save_vm_state,
+    setup_acpi_tables, setup_boot_params, setup_mptable, setup_regs, setup_sregs,
+    snapshot_msr_indices,
 };
-use crate::handle::KvmVmHandle;
+use crate::crash::GuestCrash;
+use crate::fuse::{CachePolicy, IdMapper, XattrPolicy};
+use crate::gdb::{GdbControl, GdbSocket, GdbStub, VcpuDebug};
+use crate::gsi::GsiRouting;
+use crate::handle::{KvmVmHandle, VmComponents};
+use crate::ioevent::{NotifierRegistration, QueueNotifier};
+use crate::pause::PauseControl;
+use crate::pci::{PCI_CONFIG_IO_PORT, PCI_CONFIG_IO_SIZE, PciBus, PciConfigIo};
+use crate::reboot::{BootState, RebootControl};
+use crate::resources::ResourceAllocator;
 use crate::serial::{SerialDevice, create_console_pipes};
-use crate::virtio::{VirtioConsole, VirtioFs, VirtioNet, VirtioVsock};
+use crate::snapshot::{Snapshot, VmDevices, check_compatible};
+use crate::virtio::{
+    BridgeToDevice, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_PCI_BAR_SIZE, VirtioBalloon, VirtioBlk,
+    VirtioConsole, VirtioDevice, VirtioFs, VirtioInterrupt, VirtioNet, VirtioPciDevice, VirtioRng,
+    VirtioVsock,
+};
 use crate::vsock_bridge::VsockBridge;
+use crate::watchdog::Watchdog;
 use capsa_core::{
-    BackendVmHandle, BootMethod, Error, MountMode, NetworkMode, Result, ShareMechanism, VmConfig,
+    BackendVmHandle, BootMethod, DiskImage, Error, MountMode, NetworkMode, Result, ShareMechanism,
+    VirtioTransport, VmConfig, open_disk,
 };
 use capsa_net::{SocketPairDevice, StackConfig, UserNatStack};
 use kvm_bindings::{
-    KVM_IRQ_ROUTING_IRQCHIP, KVM_IRQCHIP_IOAPIC, kvm_irq_routing_entry, kvm_pit_config,
+    KVM_CAP_X2APIC_API, KVM_MAX_CPUID_ENTRIES, KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK,
+    KVM_X2APIC_API_USE_32BIT_IDS, kvm_enable_cap, kvm_pit_config,
 };
-use kvm_ioctls::{Kvm, VmFd};
+use kvm_ioctls::{Kvm, VcpuFd, VmFd};
 use linux_loader::loader::KernelLoader;
 use linux_loader::loader::bzimage::BzImage;
 use nix::fcntl::{FcntlArg, OFlag, fcntl};
+use nix::sched::{CpuSet, sched_getaffinity, sched_setaffinity};
 use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal, sigaction};
+use nix::unistd::Pid;
 use std::fs::File;
 use std::io::{Read, Write};
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 use tokio::io::Interest;
@@ -35,41 +58,6 @@ use vm_memory::{Address, Bytes, GuestAddress, GuestMemory, GuestMemoryMmap, Gues
 
 static SIGNAL_HANDLER_INSTALLED: AtomicBool = AtomicBool::new(false);
 
-const NUM_IOAPIC_PINS: usize = 24;
-
-#[repr(C)]
-struct KvmIrqRouting {
-    nr: u32,
-    flags: u32,
-    entries: [kvm_irq_routing_entry; NUM_IOAPIC_PINS],
-}
-
-fn setup_gsi_routing(vm_fd: &VmFd) -> Result<()> {
-    let mut routing = KvmIrqRouting {
-        nr: NUM_IOAPIC_PINS as u32,
-        flags: 0,
-        entries: [kvm_irq_routing_entry::default(); NUM_IOAPIC_PINS],
-    };
-
-    // Route all GSIs through IOAPIC to match MP table configuration
-    for gsi in 0..NUM_IOAPIC_PINS {
-        routing.entries[gsi].gsi = gsi as u32;
-        routing.entries[gsi].type_ = KVM_IRQ_ROUTING_IRQCHIP;
-        routing.entries[gsi].u.irqchip.irqchip = KVM_IRQCHIP_IOAPIC;
-        routing.entries[gsi].u.irqchip.pin = gsi as u32;
-    }
-
-    let routing_ptr = &routing as *const KvmIrqRouting as *const kvm_bindings::kvm_irq_routing;
-    unsafe {
-        vm_fd
-            .set_gsi_routing(&*routing_ptr)
-            .map_err(|e| Error::StartFailed(format!("failed to set GSI routing: {}", e)))?;
-    }
-
-    tracing::debug!("GSI routing configured for {} IOAPIC pins", NUM_IOAPIC_PINS);
-    Ok(())
-}
-
 /// Installs a no-op SIGUSR1 handler to interrupt blocking vcpu.run() calls.
 ///
 /// When we need to stop a VM, we send SIGUSR1 to each vCPU thread. This causes
@@ -94,7 +82,73 @@ fn install_signal_handler() -> Result<()> {
     Ok(())
 }
 
+/// Switches the VM to 32-bit x2APIC IDs, needed once APIC IDs exceed the
+/// xAPIC range.
+fn enable_x2apic_ids(vm_fd: &VmFd) -> Result<()> {
+    let mut cap = kvm_enable_cap {
+        cap: KVM_CAP_X2APIC_API,
+        ..Default::default()
+    };
+    cap.args[0] = (KVM_X2APIC_API_USE_32BIT_IDS | KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK) as u64;
+    vm_fd
+        .enable_cap(&cap)
+        .map_err(|e| Error::StartFailed(format!("failed to enable x2APIC IDs: {}", e)))
+}
+
+/// Checks that `pinning` has one host CPU per vCPU, each of which this
+/// process may run on.
+fn check_pinning(pinning: &[usize], cpus: u32) -> Result<()> {
+    if pinning.is_empty() {
+        return Ok(());
+    }
+    if pinning.len() != cpus as usize {
+        return Err(Error::InvalidConfig(format!(
+            "vCPU pinning lists {} host CPUs for {} vCPUs",
+            pinning.len(),
+            cpus
+        )));
+    }
+    let allowed = sched_getaffinity(Pid::from_raw(0))
+        .map_err(|e| Error::Io(std::io::Error::from_raw_os_error(e as i32)))?;
+    for &host_cpu in pinning {
+        if !allowed.is_set(host_cpu).unwrap_or(false) {
+            return Err(Error::InvalidConfig(format!(
+                "host CPU {} is not available for vCPU pinning",
+                host_cpu
+            )));
+        }
+    }
+    Ok(())
+}
+
+fn pin_current_thread(host_cpu: usize) -> nix::Result<()> {
+    let mut cpu_set = CpuSet::new();
+    cpu_set.set(host_cpu)?;
+    sched_setaffinity(Pid::from_raw(0), &cpu_set)
+}
+
 pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
+    launch(config, None).await
+}
+
+/// Resumes a VM from a snapshot instead of booting it.
+///
+/// `config` must match the snapshot's hardware; see [`check_compatible`].
+pub async fn restore_vm(path: &Path, config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
+    let snapshot = Snapshot::open(path)?;
+    check_compatible(&snapshot.config, config)?;
+    if snapshot.state.vcpus.len() != config.resources.cpus as usize {
+        return Err(Error::StartFailed(format!(
+            "invalid snapshot {}: expected {} vCPUs, found {}",
+            path.display(),
+            config.resources.cpus,
+            snapshot.state.vcpus.len()
+        )));
+    }
+    launch(config, Some(snapshot)).await
+}
+
+async fn launch(config: &VmConfig, snapshot: Option<Snapshot>) -> Result<Box<dyn BackendVmHandle>> {
     let (kernel_path, initrd_path, mut cmdline) = match &config.boot {
         BootMethod::LinuxDirect {
             kernel,
@@ -109,47 +163,34 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
         }
     };
 
-    // Add virtio-console MMIO device to cmdline if console is enabled
-    if config.console_enabled {
-        cmdline.push_str(&format!(
-            " virtio_mmio.device=0x{:x}@0x{:x}:{}",
-            VIRTIO_MMIO_SIZE, VIRTIO_MMIO_BASE, VIRTIO_CONSOLE_IRQ
-        ));
+    if config.resources.memory_mb > MAX_MEMORY_MB {
+        return Err(Error::InvalidConfig(format!(
+            "requested {} MB memory but backend supports at most {} MB",
+            config.resources.memory_mb, MAX_MEMORY_MB
+        )));
     }
 
-    // Add virtio-net MMIO device to cmdline if networking is enabled
-    if matches!(
-        &config.network,
-        NetworkMode::UserNat(_) | NetworkMode::Cluster(_)
-    ) {
-        cmdline.push_str(&format!(
-            " virtio_mmio.device=0x{:x}@0x{:x}:{}",
-            VIRTIO_MMIO_SIZE, VIRTIO_NET_MMIO_BASE, VIRTIO_NET_IRQ
-        ));
+    let cpus = config.resources.cpus;
+    let topology = Topology::new(cpus, config.resources.cpu.topology.as_ref());
+    if topology.cpus() != cpus {
+        return Err(Error::InvalidConfig(format!(
+            "CPU topology has {} vCPUs but {} were requested",
+            topology.cpus(),
+            cpus
+        )));
     }
+    let apic_ids = topology.apic_ids();
+    check_pinning(&config.resources.cpu.pinning, cpus)?;
 
-    // Add virtio-vsock MMIO device to cmdline if vsock is enabled
+    // Block devices are named in probe order, root disk first so it becomes
+    // /dev/vda. virtio-mmio devices probe in the order their slots are
+    // allocated, and virtio-pci devices in slot order.
+    let disks: Vec<&DiskImage> = config.root_disk.iter().chain(config.disks.iter()).collect();
     let vsock_enabled = config.vsock.is_enabled();
-    if vsock_enabled {
-        cmdline.push_str(&format!(
-            " virtio_mmio.device=0x{:x}@0x{:x}:{}",
-            VIRTIO_MMIO_SIZE, VIRTIO_VSOCK_MMIO_BASE, VIRTIO_VSOCK_IRQ
-        ));
-    }
-
-    // Add virtio-fs MMIO devices to cmdline for each shared directory
-    for (i, _share) in config.shares.iter().enumerate() {
-        let base = VIRTIO_FS_MMIO_BASE + (i as u64 * VIRTIO_MMIO_SIZE);
-        let irq = VIRTIO_FS_IRQ + i as u32;
-        cmdline.push_str(&format!(
-            " virtio_mmio.device=0x{:x}@0x{:x}:{}",
-            VIRTIO_MMIO_SIZE, base, irq
-        ));
-    }
 
-    let cpus = config.resources.cpus;
     let memory_mb = config.resources.memory_mb as u64;
     let console_enabled = config.console_enabled;
+    let crash = Arc::new(GuestCrash::default());
 
     let (host_read, host_write, guest_read, serial, virtio_console_fd) = if console_enabled {
         let (guest_read, host_write, host_read, guest_write) = create_console_pipes()
@@ -162,7 +203,7 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
             .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
             .map_err(|e| Error::StartFailed(format!("failed to dup console fd: {}", e)))?;
 
-        let writer = ConsolePipeWriter(guest_write);
+        let writer = crash.tap(ConsolePipeWriter(guest_write));
         // Wrap in Arc<Mutex> for IoManager registration and sharing with console input task
         let serial = Arc::new(Mutex::new(SerialDevice::new(Box::new(writer))));
         (
@@ -185,14 +226,39 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
     );
     let vm_fd_ref = vm_fd.as_ref();
 
+    if cpus as usize > kvm.get_max_vcpus() {
+        return Err(Error::InvalidConfig(format!(
+            "requested {} vCPUs but KVM supports at most {}",
+            cpus,
+            kvm.get_max_vcpus()
+        )));
+    }
+    if apic_ids
+        .iter()
+        .any(|&id| id as usize >= kvm.get_max_vcpu_id())
+    {
+        return Err(Error::InvalidConfig(
+            "CPU topology needs APIC IDs beyond what KVM supports".into(),
+        ));
+    }
+    let supported_cpuid = kvm
+        .get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+        .map_err(|e| Error::StartFailed(format!("failed to get supported CPUID: {}", e)))?;
+    let cpuid = apply_cpu_model(&supported_cpuid, &config.resources.cpu)?;
+
+    // Has to be enabled before the vCPUs exist, and before restoring their
+    // LAPIC state, whose ID register layout it changes
+    if topology.needs_x2apic() {
+        enable_x2apic_ids(vm_fd_ref)?;
+    }
+
     // Set up IRQ chip (PIC + IOAPIC) - required for x86_64
     vm_fd_ref
         .create_irq_chip()
         .map_err(|e| Error::StartFailed(format!("failed to create IRQ chip: {}", e)))?;
 
-    // Set up GSI routing for IOAPIC
-    // This explicitly routes GSIs to IOAPIC pins, which is required for irqfd to work correctly
-    setup_gsi_routing(vm_fd_ref)?;
+    // Set up GSI routing for IOAPIC, and MSI routes for virtio-pci devices
+    let routing = Arc::new(GsiRouting::new(vm_fd.clone())?);
 
     // Set up PIT (Programmable Interval Timer)
     let pit_config = kvm_pit_config::default();
@@ -200,47 +266,64 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
         .create_pit2(pit_config)
         .map_err(|e| Error::StartFailed(format!("failed to create PIT: {}", e)))?;
 
-    let memory = create_guest_memory(memory_mb)
-        .map_err(|e| Error::StartFailed(format!("failed to create guest memory: {}", e)))?;
+    // Power-on state the guest goes back to when it reboots in place
+    let (boot_vm_state, msr_indices) = if config.reboot_in_place {
+        let vm_state = save_vm_state(vm_fd_ref)
+            .map_err(|e| Error::StartFailed(format!("failed to save VM state: {}", e)))?;
+        let msr_indices = snapshot_msr_indices(&kvm)
+            .map_err(|e| Error::StartFailed(format!("failed to list MSRs: {}", e)))?;
+        (Some(vm_state), msr_indices)
+    } else {
+        (None, Vec::new())
+    };
+    let mut boot_vcpu_states = Vec::new();
+
+    let memory = match &snapshot {
+        Some(snapshot) => {
+            let regions: Vec<(u64, u64)> = snapshot
+                .state
+                .memory_regions
+                .iter()
+                .map(|r| (r.guest_addr, r.size))
+                .collect();
+            create_guest_memory_from_file(&snapshot.file, snapshot.memory_offset, &regions)
+        }
+        None => create_guest_memory(memory_mb, &config.resources.memory_backing),
+    }
+    .map_err(|e| Error::StartFailed(format!("failed to create guest memory: {}", e)))?;
     let memory = Arc::new(memory);
 
     setup_memory_regions(vm_fd_ref, &memory)?;
 
-    let (kernel_entry, kernel_header) = load_kernel(&memory, &kernel_path)?;
-    tracing::debug!("Kernel loaded at entry point: 0x{:x}", kernel_entry);
-
-    let initrd_addr = initrd_load_addr(kernel_entry);
-    let initrd_size = load_initrd(&memory, &initrd_path, initrd_addr)?;
-    tracing::debug!(
-        "Initrd loaded at 0x{:x}, size: {} bytes",
-        initrd_addr,
-        initrd_size
-    );
-
-    tracing::debug!("Kernel cmdline: {}", cmdline);
-    setup_boot_params(
-        &memory,
-        &cmdline,
-        kernel_header,
-        initrd_addr,
-        initrd_size,
-        memory_mb * 1024 * 1024,
-    )
-    .map_err(|e| Error::StartFailed(format!("failed to setup boot params: {}", e)))?;
-
-    // Set up MP table for IOAPIC interrupt routing
-    // This is required for Linux to properly handle interrupts from virtio-mmio devices
-    setup_mptable(&memory, cpus as u8)
-        .map_err(|e| Error::StartFailed(format!("failed to setup MP table: {}", e)))?;
-    tracing::debug!("MP table set up for {} CPUs", cpus);
+    // A restored guest already has its kernel, boot params and MP table in
+    // memory, so only the in-kernel devices need their state back
+    if let Some(snapshot) = &snapshot {
+        restore_vm_state(vm_fd_ref, &snapshot.state.vm)
+            .map_err(|e| Error::StartFailed(format!("failed to restore VM state: {}", e)))?;
+    }
 
     install_signal_handler()?;
 
+    let gdb_socket = config.gdb.as_ref().map(GdbSocket::bind).transpose()?;
+
     let running = Arc::new(AtomicBool::new(true));
+    let pause = Arc::new(PauseControl::new());
+    let gdb = gdb_socket
+        .is_some()
+        .then(|| Arc::new(GdbControl::new(pause.clone())));
     let (exit_tx, exit_rx) = mpsc::channel(1);
+    let reboot = Arc::new(RebootControl::new(
+        config.reboot_in_place,
+        running.clone(),
+        pause.clone(),
+        exit_tx.clone(),
+    ));
+    let mut devices = VmDevices::default();
 
     // Create I/O manager and register devices
     let mut io_manager = IoManager::new();
+    let mut resources = ResourceAllocator::new();
+    let mut virtio_bus = VirtioBus::new(vm_fd.clone(), routing, config.virtio_transport);
 
     // Register serial device (shared with console input task)
     let serial_for_io = serial
@@ -279,23 +362,90 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
         "RTC device",
     )?;
 
+    // Register ACPI power management registers
+    let pm = Arc::new(Mutex::new(AcpiPmDevice::new(
+        vm_fd.clone(),
+        running.clone(),
+        exit_tx.clone(),
+    )));
+    register_pio_device(
+        &mut io_manager,
+        ACPI_PM_PORT_BASE,
+        ACPI_PM_PORT_SIZE,
+        pm.clone(),
+        "ACPI PM device",
+    )?;
+    devices.pm = Some(pm);
+
+    // Register the pvpanic device the guest reports kernel panics to
+    register_pio_device(
+        &mut io_manager,
+        PVPANIC_PORT,
+        1,
+        Arc::new(Mutex::new(PvPanicDevice::new(
+            crash.clone(),
+            running.clone(),
+            exit_tx.clone(),
+        ))),
+        "pvpanic device",
+    )?;
+
+    // Register the keyboard controller the guest reboots through
+    let i8042 = Arc::new(Mutex::new(I8042Device::new(reboot.clone())));
+    register_pio_device(
+        &mut io_manager,
+        I8042_DATA_PORT,
+        1,
+        i8042.clone(),
+        "i8042 data port",
+    )?;
+    register_pio_device(
+        &mut io_manager,
+        I8042_COMMAND_PORT,
+        1,
+        i8042,
+        "i8042 command port",
+    )?;
+
+    // Register the watchdog timer card the guest pings
+    if let Some(config) = config.watchdog {
+        let watchdog = Arc::new(Watchdog::new(
+            config,
+            crash.clone(),
+            reboot.clone(),
+            running.clone(),
+            exit_tx.clone(),
+        ));
+        let ib700 = Arc::new(Mutex::new(Ib700Device::new(watchdog.clone())));
+        register_pio_device(
+            &mut io_manager,
+            IB700_STOP_PORT,
+            1,
+            ib700.clone(),
+            "watchdog stop port",
+        )?;
+        register_pio_device(
+            &mut io_manager,
+            IB700_START_PORT,
+            1,
+            ib700,
+            "watchdog start port",
+        )?;
+        devices.watchdog = Some(watchdog);
+    }
+
     // Register virtio-console device if console is enabled
     let virtio_console = if let Some(fd) = virtio_console_fd {
-        let writer = ConsolePipeWriter(fd);
+        let writer = crash.tap(ConsolePipeWriter(fd));
+        let slot = virtio_bus.slot(&mut resources)?;
         let console = Arc::new(Mutex::new(VirtioConsole::new(
             Box::new(writer),
-            vm_fd.clone(),
-            VIRTIO_CONSOLE_IRQ,
+            slot.interrupt.clone(),
         )));
         console.lock().unwrap().set_memory(memory.clone());
 
-        register_mmio_device(
-            &mut io_manager,
-            VIRTIO_MMIO_BASE,
-            VIRTIO_MMIO_SIZE,
-            console.clone(),
-            "virtio-console",
-        )?;
+        virtio_bus.attach(&mut io_manager, &slot, console.clone(), "virtio-console")?;
+        devices.console = Some(console.clone());
 
         Some(console)
     } else {
@@ -303,7 +453,7 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
     };
 
     // Set up virtio-net device if networking is configured
-    let network_task = match &config.network {
+    let (network_task, network_stack_task) = match &config.network {
         NetworkMode::UserNat(user_nat_config) => {
             // Create socketpair for frame I/O between virtio-net and UserNatStack
             let (host_device, guest_fd) = SocketPairDevice::new().map_err(|e| {
@@ -313,46 +463,36 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
             // Default MAC address for the guest (52:54:00:xx:xx:xx is QEMU convention)
             let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
 
+            let socket = clone_fd(&guest_fd, "network socket")?;
             // VirtioNet uses direct interrupt injection via set_irq_line
+            let slot = virtio_bus.slot(&mut resources)?;
             let virtio_net = Arc::new(Mutex::new(VirtioNet::new(
                 guest_fd,
                 mac,
-                vm_fd.clone(),
-                VIRTIO_NET_IRQ,
+                slot.interrupt.clone(),
             )));
             virtio_net.lock().unwrap().set_memory(memory.clone());
 
-            register_mmio_device(
-                &mut io_manager,
-                VIRTIO_NET_MMIO_BASE,
-                VIRTIO_MMIO_SIZE,
-                virtio_net.clone(),
-                "virtio-net",
-            )?;
+            virtio_bus.attach(&mut io_manager, &slot, virtio_net.clone(), "virtio-net")?;
 
             tracing::debug!("virtio-net device registered for UserNat");
+            devices.net = Some(virtio_net.clone());
 
             // Spawn the UserNatStack to handle NAT with port forwards and policy from config
             let stack_config = StackConfig::from(user_nat_config);
             let stack = UserNatStack::new(host_device, stack_config);
-            tokio::spawn(async move {
+            let task = spawn_net_worker(
+                virtio_net,
+                virtio_bus.notifier(&slot)?,
+                socket,
+                pause.clone(),
+            )?;
+            let stack_task = tokio::spawn(async move {
                 if let Err(e) = stack.run().await {
                     tracing::error!("UserNat stack error: {:?}", e);
                 }
             });
-
-            // Spawn a task to poll for incoming frames from the network
-            let virtio_net_for_rx = virtio_net.clone();
-            let task = tokio::spawn(async move {
-                let mut interval = tokio::time::interval(std::time::Duration::from_millis(1));
-                loop {
-                    interval.tick().await;
-                    if let Ok(mut net) = virtio_net_for_rx.try_lock() {
-                        net.poll_rx();
-                    }
-                }
-            });
-            Some(task)
+            (Some(task), Some(stack_task))
         }
         NetworkMode::Cluster(_) => {
             // For Cluster mode, the guest fd is pre-created by the backend wrapper
@@ -381,39 +521,30 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
                 ]
             };
 
+            let socket = clone_fd(&guest_fd, "network socket")?;
+            let slot = virtio_bus.slot(&mut resources)?;
             let virtio_net = Arc::new(Mutex::new(VirtioNet::new(
                 guest_fd,
                 mac,
-                vm_fd.clone(),
-                VIRTIO_NET_IRQ,
+                slot.interrupt.clone(),
             )));
             virtio_net.lock().unwrap().set_memory(memory.clone());
 
-            register_mmio_device(
-                &mut io_manager,
-                VIRTIO_NET_MMIO_BASE,
-                VIRTIO_MMIO_SIZE,
-                virtio_net.clone(),
-                "virtio-net",
-            )?;
+            virtio_bus.attach(&mut io_manager, &slot, virtio_net.clone(), "virtio-net")?;
 
             tracing::debug!("virtio-net device registered for Cluster");
-
-            // Spawn a task to poll for incoming frames from the network
-            // (bridge_to_switch is handled by the backend wrapper)
-            let virtio_net_for_rx = virtio_net.clone();
-            let task = tokio::spawn(async move {
-                let mut interval = tokio::time::interval(std::time::Duration::from_millis(1));
-                loop {
-                    interval.tick().await;
-                    if let Ok(mut net) = virtio_net_for_rx.try_lock() {
-                        net.poll_rx();
-                    }
-                }
-            });
-            Some(task)
+            devices.net = Some(virtio_net.clone());
+
+            // bridge_to_switch is handled by the backend wrapper
+            let task = spawn_net_worker(
+                virtio_net,
+                virtio_bus.notifier(&slot)?,
+                socket,
+                pause.clone(),
+            )?;
+            (Some(task), None)
         }
-        _ => None,
+        _ => (None, None),
     };
 
     // Set up virtio-vsock device if vsock is enabled
@@ -423,23 +554,17 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
         let (bridge_to_device_tx, bridge_to_device_rx) = mpsc::unbounded_channel();
 
         // Create the vsock device
+        let slot = virtio_bus.slot(&mut resources)?;
         let virtio_vsock = Arc::new(Mutex::new(VirtioVsock::new(
-            vm_fd.clone(),
-            VIRTIO_VSOCK_IRQ,
+            slot.interrupt.clone(),
             device_to_bridge_tx,
-            bridge_to_device_rx,
         )));
         virtio_vsock.lock().unwrap().set_memory(memory.clone());
 
-        register_mmio_device(
-            &mut io_manager,
-            VIRTIO_VSOCK_MMIO_BASE,
-            VIRTIO_MMIO_SIZE,
-            virtio_vsock.clone(),
-            "virtio-vsock",
-        )?;
+        virtio_bus.attach(&mut io_manager, &slot, virtio_vsock.clone(), "virtio-vsock")?;
 
         tracing::debug!("virtio-vsock device registered");
+        devices.vsock = Some(virtio_vsock.clone());
 
         // Spawn the bridge task
         let bridge = VsockBridge::new(config.vsock.ports.clone());
@@ -447,32 +572,36 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
             bridge.run(bridge_to_device_tx, device_to_bridge_rx).await;
         });
 
-        // Spawn a task to poll the vsock device for bridge messages
-        let task = tokio::spawn(async move {
-            let mut interval = tokio::time::interval(std::time::Duration::from_millis(1));
-            loop {
-                interval.tick().await;
-                if let Ok(mut vsock) = virtio_vsock.try_lock() {
-                    vsock.poll();
-                }
-            }
-        });
+        let notifier = virtio_bus.notifier(&slot)?;
+        let task = tokio::spawn(run_vsock_worker(
+            virtio_vsock,
+            notifier,
+            bridge_to_device_rx,
+            pause.clone(),
+        ));
         Some(task)
     } else {
         None
     };
 
     // Set up virtio-fs devices for each shared directory
+    let mut fs_tasks = Vec::with_capacity(config.shares.len());
     for (i, share) in config.shares.iter().enumerate() {
-        let base = VIRTIO_FS_MMIO_BASE + (i as u64 * VIRTIO_MMIO_SIZE);
-        let irq = VIRTIO_FS_IRQ + i as u32;
-
-        let tag = match &share.mechanism {
-            ShareMechanism::VirtioFs(cfg) => {
-                cfg.tag.clone().unwrap_or_else(|| format!("share{}", i))
-            }
-            _ => format!("share{}", i),
+        let slot = virtio_bus.slot(&mut resources)?;
+
+        let (tag, xattr, cache) = match &share.mechanism {
+            ShareMechanism::VirtioFs(cfg) => (
+                cfg.tag.clone().unwrap_or_else(|| format!("share{}", i)),
+                XattrPolicy::new(cfg.xattr_namespaces.clone()),
+                CachePolicy::new(cfg.cache, cfg.entry_timeout, cfg.attr_timeout),
+            ),
+            _ => (
+                format!("share{}", i),
+                XattrPolicy::default(),
+                CachePolicy::default(),
+            ),
         };
+        let ids = IdMapper::new(share.id_mapping);
 
         let read_only = matches!(share.mode, MountMode::ReadOnly);
 
@@ -480,18 +609,21 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
             share.host_path.clone(),
             tag.clone(),
             read_only,
-            vm_fd.clone(),
-            irq,
+            xattr,
+            ids,
+            cache,
+            slot.interrupt.clone(),
         )));
         virtio_fs.lock().unwrap().set_memory(memory.clone());
 
-        register_mmio_device(
+        virtio_bus.attach(
             &mut io_manager,
-            base,
-            VIRTIO_MMIO_SIZE,
-            virtio_fs,
+            &slot,
+            virtio_fs.clone(),
             &format!("virtio-fs-{}", tag),
         )?;
+        devices.fs.push(virtio_fs.clone());
+        fs_tasks.push(tokio::spawn(run_fs_lock_worker(virtio_fs, pause.clone())));
 
         tracing::debug!(
             "virtio-fs device '{}' registered for {} ({})",
@@ -501,36 +633,180 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
         );
     }
 
+    // Set up virtio-blk devices for the root disk and additional disks
+    for (i, disk) in disks.iter().enumerate() {
+        let slot = virtio_bus.slot(&mut resources)?;
+
+        let image = open_disk(disk).map_err(|e| {
+            Error::StartFailed(format!(
+                "failed to open disk image {}: {}",
+                disk.path.display(),
+                e
+            ))
+        })?;
+
+        let device_id = format!("capsa-disk{}", i);
+        let virtio_blk = Arc::new(Mutex::new(VirtioBlk::new(
+            image,
+            disk.read_only,
+            &device_id,
+            slot.interrupt.clone(),
+        )));
+        virtio_blk.lock().unwrap().set_memory(memory.clone());
+
+        virtio_bus.attach(
+            &mut io_manager,
+            &slot,
+            virtio_blk.clone(),
+            &format!("virtio-blk-{}", i),
+        )?;
+        devices.blk.push(virtio_blk);
+
+        tracing::debug!(
+            "virtio-blk device {} registered for {} ({})",
+            i,
+            disk.path.display(),
+            if disk.read_only {
+                "read-only"
+            } else {
+                "read-write"
+            }
+        );
+    }
+
+    if config.resources.balloon {
+        let slot = virtio_bus.slot(&mut resources)?;
+        let virtio_balloon = Arc::new(Mutex::new(VirtioBalloon::new(slot.interrupt.clone())));
+        virtio_balloon.lock().unwrap().set_memory(memory.clone());
+
+        virtio_bus.attach(
+            &mut io_manager,
+            &slot,
+            virtio_balloon.clone(),
+            "virtio-balloon",
+        )?;
+        devices.balloon = Some(virtio_balloon);
+
+        tracing::debug!("virtio-balloon device registered");
+    }
+
+    let rng_task = if let Some(rng) = &config.resources.rng {
+        let slot = virtio_bus.slot(&mut resources)?;
+        let virtio_rng = Arc::new(Mutex::new(VirtioRng::new(
+            slot.interrupt.clone(),
+            rng.rate_limit,
+        )));
+        virtio_rng.lock().unwrap().set_memory(memory.clone());
+
+        virtio_bus.attach(&mut io_manager, &slot, virtio_rng.clone(), "virtio-rng")?;
+        devices.rng = Some(virtio_rng.clone());
+
+        tracing::debug!("virtio-rng device registered");
+
+        let notifier = virtio_bus.notifier(&slot)?;
+        Some(tokio::spawn(run_rng_worker(
+            virtio_rng,
+            notifier,
+            pause.clone(),
+        )))
+    } else {
+        None
+    };
+
+    devices.pci = virtio_bus.finish(&mut io_manager)?;
+
+    // virtio-mmio devices are declared on the cmdline, and the MP table only
+    // describes the IOAPIC pins that are wired to something
+    cmdline.push_str(&resources.virtio_mmio_cmdline());
+    let boot_image = BootImage {
+        kernel: kernel_path,
+        initrd: initrd_path,
+        cmdline,
+        memory_size: memory_mb * 1024 * 1024,
+        apic_ids: apic_ids.clone(),
+        ioapic_pins: resources.ioapic_pins(),
+    };
+    let kernel_entry = match &snapshot {
+        Some(_) => None,
+        None => Some(boot_image.load(&memory)?),
+    };
+
+    if let Some(snapshot) = &snapshot {
+        devices.restore_state(&snapshot.state.devices)?;
+    }
+
     let io_manager = Arc::new(io_manager);
 
+    // The guest stays halted until a debugger attaches and continues it
+    if let Some(listener) = &config.gdb {
+        tracing::info!("waiting for debugger on {}", listener);
+        pause.request();
+    }
+
+    let mut vcpus = Vec::new();
     let mut vcpu_handles = Vec::new();
     let mut vcpu_thread_ids = Vec::new();
 
-    for vcpu_id in 0..cpus {
+    // KVM uses the vCPU ID as the initial APIC ID
+    for (vcpu_id, &apic_id) in apic_ids.iter().enumerate() {
         let vcpu = vm_fd_ref
-            .create_vcpu(vcpu_id as u64)
+            .create_vcpu(apic_id as u64)
             .map_err(|e| Error::StartFailed(format!("failed to create vCPU {}: {}", vcpu_id, e)))?;
 
-        crate::arch::init_vcpu(&vcpu, &kvm)
+        crate::arch::init_vcpu(&vcpu, &cpuid, &topology, apic_id)
             .map_err(|e| Error::StartFailed(format!("failed to init vCPU {}: {}", vcpu_id, e)))?;
 
-        setup_sregs(&vcpu, memory_mb * 1024 * 1024)
-            .map_err(|e| Error::StartFailed(format!("failed to setup vCPU sregs: {}", e)))?;
+        if boot_vm_state.is_some() {
+            boot_vcpu_states.push(save_vcpu_state(&vcpu, &msr_indices).map_err(|e| {
+                Error::StartFailed(format!("failed to save vCPU {} state: {}", vcpu_id, e))
+            })?);
+        }
 
-        if vcpu_id == 0 {
-            setup_regs(&vcpu, kernel_entry, BOOT_PARAMS_ADDR).map_err(|e| {
-                Error::StartFailed(format!("failed to setup vCPU registers: {}", e))
+        if let Some(snapshot) = &snapshot {
+            restore_vcpu_state(&vcpu, &snapshot.state.vcpus[vcpu_id]).map_err(|e| {
+                Error::StartFailed(format!("failed to restore vCPU {}: {}", vcpu_id, e))
             })?;
+        } else if let Some(kernel_entry) = kernel_entry {
+            boot_image.setup_vcpu(&vcpu, vcpu_id, kernel_entry)?;
         }
 
+        let vcpu = Arc::new(Mutex::new(vcpu));
+        vcpus.push(vcpu.clone());
+
         let io_manager_clone = io_manager.clone();
         let running_clone = running.clone();
+        let pause_clone = pause.clone();
+        let reboot_clone = reboot.clone();
+        let debug = gdb.clone().map(|control| VcpuDebug {
+            index: vcpu_id,
+            control,
+        });
         let exit_tx_clone = exit_tx.clone();
 
+        let host_cpu = config.resources.cpu.pinning.get(vcpu_id).copied();
+
         let (tid_tx, tid_rx) = std::sync::mpsc::channel();
         let handle = std::thread::spawn(move || {
             let _ = tid_tx.send(nix::sys::pthread::pthread_self());
-            run_vcpu(vcpu, io_manager_clone, running_clone, exit_tx_clone);
+            if let Some(host_cpu) = host_cpu
+                && let Err(e) = pin_current_thread(host_cpu)
+            {
+                tracing::warn!(
+                    "failed to pin vCPU {} to host CPU {}: {}",
+                    vcpu_id,
+                    host_cpu,
+                    e
+                );
+            }
+            run_vcpu(
+                vcpu,
+                io_manager_clone,
+                running_clone,
+                pause_clone,
+                reboot_clone,
+                debug,
+                exit_tx_clone,
+            );
         });
         if let Ok(tid) = tid_rx.recv() {
             vcpu_thread_ids.push(tid);
@@ -538,10 +814,31 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
         vcpu_handles.push(handle);
     }
 
+    if let (Some(control), Some(socket)) = (gdb, gdb_socket) {
+        GdbStub {
+            control,
+            vcpus: vcpus.clone(),
+            vcpu_threads: vcpu_thread_ids.clone(),
+            memory: memory.clone(),
+            running: running.clone(),
+        }
+        .spawn(socket);
+    }
+
+    // Host-side vsock connections did not survive the snapshot, so the guest
+    // has to drop its end of them. This must follow the LAPIC restore above,
+    // which would otherwise discard the interrupt.
+    if snapshot.is_some()
+        && let Some(vsock) = &devices.vsock
+    {
+        vsock.lock().unwrap().send_transport_reset();
+    }
+
     let console_input_task = if let (Some(serial), Some(guest_read)) = (&serial, guest_read) {
         let serial_clone = serial.clone();
         let virtio_console_clone = virtio_console.clone();
         let running_clone = running.clone();
+        let pause_clone = pause.clone();
 
         // Set the fd to non-blocking mode (required for AsyncFd)
         let flags = fcntl(guest_read.as_raw_fd(), FcntlArg::F_GETFL)
@@ -576,6 +873,8 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
                         break;
                     }
                     Ok(Ok(n)) => {
+                        // Input typed while the VM is paused is delivered on resume
+                        pause_clone.wait_resumed().await;
                         // Forward input to virtio-console (primary) and serial (fallback)
                         if let Some(ref vc) = virtio_console_clone
                             && let Ok(mut vc) = vc.lock()
@@ -598,8 +897,21 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
         None
     };
 
+    let components = VmComponents {
+        vm_fd,
+        config: config.clone(),
+        vcpus,
+        devices,
+    };
+    let boot = boot_vm_state.map(|vm| BootState {
+        image: boot_image,
+        vm,
+        vcpus: boot_vcpu_states,
+    });
+
     Ok(Box::new(KvmVmHandle::new(
         running,
+        pause,
         exit_rx,
         vcpu_handles,
         vcpu_thread_ids,
@@ -608,12 +920,83 @@ pub async fn start_vm(config: &VmConfig) -> Result<Box<dyn BackendVmHandle>> {
         host_write,
         console_enabled,
         memory,
+        components,
+        crash,
+        reboot,
+        boot,
         network_task,
+        network_stack_task,
         serial_irq_task,
         vsock_task,
+        rng_task,
+        fs_tasks,
     )))
 }
 
+/// Kernel, initrd and boot tables the guest is booted from.
+pub struct BootImage {
+    kernel: PathBuf,
+    initrd: PathBuf,
+    cmdline: String,
+    memory_size: u64,
+    apic_ids: Vec<u32>,
+    /// IOAPIC pins the MP table describes
+    ioapic_pins: Vec<u32>,
+}
+
+impl BootImage {
+    /// Writes the kernel, initrd, boot params, MP table and ACPI tables to
+    /// guest memory and returns the kernel entry point.
+    pub fn load(&self, memory: &GuestMemoryMmap) -> Result<u64> {
+        let (kernel_entry, kernel_header) = load_kernel(memory, &self.kernel)?;
+        tracing::debug!("Kernel loaded at entry point: 0x{:x}", kernel_entry);
+
+        let initrd_addr = initrd_load_addr(kernel_entry);
+        let initrd_size = load_initrd(memory, &self.initrd, initrd_addr)?;
+        tracing::debug!(
+            "Initrd loaded at 0x{:x}, size: {} bytes",
+            initrd_addr,
+            initrd_size
+        );
+
+        tracing::debug!("Kernel cmdline: {}", self.cmdline);
+        setup_boot_params(
+            memory,
+            &self.cmdline,
+            kernel_header,
+            initrd_addr,
+            initrd_size,
+            self.memory_size,
+        )
+        .map_err(|e| Error::StartFailed(format!("failed to setup boot params: {}", e)))?;
+
+        // Set up MP table for IOAPIC interrupt routing
+        // This is required for Linux to properly handle interrupts from virtio-mmio devices
+        setup_mptable(memory, &self.apic_ids, &self.ioapic_pins)
+            .map_err(|e| Error::StartFailed(format!("failed to setup MP table: {}", e)))?;
+        tracing::debug!("MP table set up for {} CPUs", self.apic_ids.len());
+
+        // ACPI tables describe the power button and the S5 soft-off state
+        setup_acpi_tables(memory, &self.apic_ids)
+            .map_err(|e| Error::StartFailed(format!("failed to setup ACPI tables: {}", e)))?;
+
+        Ok(kernel_entry)
+    }
+
+    /// Switches a vCPU in its power-on state to long mode, pointing the boot
+    /// processor at the kernel entry point.
+    pub fn setup_vcpu(&self, vcpu: &VcpuFd, index: usize, kernel_entry: u64) -> Result<()> {
+        setup_sregs(vcpu, self.memory_size)
+            .map_err(|e| Error::StartFailed(format!("failed to setup vCPU sregs: {}", e)))?;
+        if index == 0 {
+            setup_regs(vcpu, kernel_entry, BOOT_PARAMS_ADDR).map_err(|e| {
+                Error::StartFailed(format!("failed to setup vCPU registers: {}", e))
+            })?;
+        }
+        Ok(())
+    }
+}
+
 fn setup_memory_regions(vm_fd: &VmFd, memory: &GuestMemoryMmap) -> Result<()> {
     for (index, region) in memory.iter().enumerate() {
         let mem_region = kvm_bindings::kvm_userspace_memory_region {
@@ -647,6 +1030,128 @@ fn load_kernel(
     Ok((entry_64, result.setup_header))
 }
 
+/// Starts the task that processes virtio-net queues.
+///
+/// The task wakes when `notifier` sees the guest notify a queue or frames
+/// arrive on `socket`, a duplicate of the device's end of the network
+/// socketpair.
+fn spawn_net_worker(
+    net: Arc<Mutex<VirtioNet>>,
+    notifier: QueueNotifier,
+    socket: OwnedFd,
+    pause: Arc<PauseControl>,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let socket = AsyncFd::with_interest(socket, Interest::READABLE)
+        .map_err(|e| Error::StartFailed(format!("failed to watch network socket: {}", e)))?;
+
+    Ok(tokio::spawn(async move {
+        loop {
+            // Also runs once at startup, for notifications lost in a snapshot
+            pause
+                .run_resumed(&net, |net| {
+                    net.process_queues();
+                    net.poll_rx();
+                })
+                .await;
+
+            tokio::select! {
+                result = notifier.notified() => {
+                    if let Err(e) = result {
+                        tracing::error!("virtio-net notifier failed: {}", e);
+                        return;
+                    }
+                }
+                result = socket.readable() => match result {
+                    // poll_rx drains the socket
+                    Ok(mut guard) => guard.clear_ready(),
+                    Err(e) => {
+                        tracing::error!("virtio-net socket failed: {}", e);
+                        return;
+                    }
+                },
+            }
+        }
+    }))
+}
+
+/// Processes virtio-vsock queues when the guest notifies the device, and
+/// delivers messages from the bridge as they arrive.
+async fn run_vsock_worker(
+    vsock: Arc<Mutex<VirtioVsock>>,
+    notifier: QueueNotifier,
+    mut bridge_rx: mpsc::UnboundedReceiver<BridgeToDevice>,
+    pause: Arc<PauseControl>,
+) {
+    let mut msg = None;
+    loop {
+        // Also runs once at startup, for notifications lost in a snapshot
+        pause
+            .run_resumed(&vsock, |vsock| {
+                if let Some(msg) = msg.take() {
+                    vsock.handle_bridge_message(msg);
+                }
+                while let Ok(msg) = bridge_rx.try_recv() {
+                    vsock.handle_bridge_message(msg);
+                }
+                vsock.process_queues();
+            })
+            .await;
+
+        tokio::select! {
+            Some(next) = bridge_rx.recv() => msg = Some(next),
+            result = notifier.notified() => {
+                if let Err(e) = result {
+                    tracing::error!("virtio-vsock notifier failed: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Fills virtio-rng buffers when the guest notifies the device, and again
+/// once the rate limit allows buffers that had to wait.
+async fn run_rng_worker(
+    rng: Arc<Mutex<VirtioRng>>,
+    notifier: QueueNotifier,
+    pause: Arc<PauseControl>,
+) {
+    loop {
+        // Also runs once at startup, for notifications lost in a snapshot
+        let retry_after = pause.run_resumed(&rng, |rng| rng.process_queue()).await;
+
+        tokio::select! {
+            result = notifier.notified() => {
+                if let Err(e) = result {
+                    tracing::error!("virtio-rng notifier failed: {}", e);
+                    return;
+                }
+            }
+            _ = tokio::time::sleep(retry_after.unwrap_or_default()), if retry_after.is_some() => {}
+        }
+    }
+}
+
+/// Retries the virtio-fs requests waiting for a file lock until each gets
+/// its lock. The device retries them itself when the guest drops a lock, so
+/// this is for locks held by host processes.
+async fn run_fs_lock_worker(fs: Arc<Mutex<VirtioFs>>, pause: Arc<PauseControl>) {
+    let blocked = fs.lock().unwrap().locks_blocked();
+    loop {
+        // Also runs once at startup, for requests blocked when a snapshot
+        // was taken
+        match pause.run_resumed(&fs, |fs| fs.retry_blocked_locks()).await {
+            Some(retry_after) => tokio::time::sleep(retry_after).await,
+            None => blocked.notified().await,
+        }
+    }
+}
+
+fn clone_fd(fd: &OwnedFd, name: &str) -> Result<OwnedFd> {
+    fd.try_clone()
+        .map_err(|e| Error::StartFailed(format!("failed to duplicate {}: {}", name, e)))
+}
+
 fn register_pio_device<D: vm_device::MutDevicePio + Send + 'static>(
     io_manager: &mut IoManager,
     base: u16,
@@ -662,11 +1167,11 @@ fn register_pio_device<D: vm_device::MutDevicePio + Send + 'static>(
     Ok(())
 }
 
-fn register_mmio_device<D: vm_device::MutDeviceMmio + Send + 'static>(
+fn register_mmio_device<D: vm_device::DeviceMmio + Send + Sync + 'static>(
     io_manager: &mut IoManager,
     base: u64,
     size: u64,
-    device: Arc<Mutex<D>>,
+    device: Arc<D>,
     name: &str,
 ) -> Result<()> {
     let range = MmioRange::new(MmioAddress(base), size).map_err(|e| {
@@ -678,6 +1183,139 @@ fn register_mmio_device<D: vm_device::MutDeviceMmio + Send + 'static>(
     Ok(())
 }
 
+/// Attaches virtio devices through the transport the VM config asks for.
+///
+/// virtio-mmio devices get a register slot and an IOAPIC pin, which the
+/// kernel cmdline announces. virtio-pci devices get a BAR in the PCI window
+/// and two MSI GSIs each instead.
+struct VirtioBus {
+    vm_fd: Arc<VmFd>,
+    routing: Arc<GsiRouting>,
+    pci: Option<PciBus>,
+}
+
+/// Where a virtio device sits, and how it interrupts the guest.
+struct VirtioSlot {
+    interrupt: VirtioInterrupt,
+    location: SlotLocation,
+}
+
+enum SlotLocation {
+    Mmio(u64),
+    Pci {
+        bar: u64,
+        used_ring_gsi: u32,
+        config_gsi: u32,
+        /// Follows the notify region when the guest moves the BAR
+        notifier: NotifierRegistration,
+    },
+}
+
+impl VirtioBus {
+    fn new(vm_fd: Arc<VmFd>, routing: Arc<GsiRouting>, transport: VirtioTransport) -> Self {
+        let pci = match transport {
+            VirtioTransport::Mmio => None,
+            VirtioTransport::Pci => Some(PciBus::new()),
+        };
+        Self {
+            vm_fd,
+            routing,
+            pci,
+        }
+    }
+
+    /// Reserves a slot and interrupts for the next device from `resources`.
+    fn slot(&self, resources: &mut ResourceAllocator) -> Result<VirtioSlot> {
+        if self.pci.is_none() {
+            let (mmio_base, irq) = resources.allocate_virtio_mmio()?;
+            return Ok(VirtioSlot {
+                interrupt: VirtioInterrupt::pin(self.vm_fd.clone(), irq),
+                location: SlotLocation::Mmio(mmio_base),
+            });
+        }
+        let bar = resources.allocate_pci_bar(VIRTIO_PCI_BAR_SIZE)?;
+        let used_ring_gsi = resources.allocate_msi_gsi()?;
+        let config_gsi = resources.allocate_msi_gsi()?;
+        Ok(VirtioSlot {
+            interrupt: VirtioInterrupt::msi(self.vm_fd.clone(), used_ring_gsi, config_gsi),
+            location: SlotLocation::Pci {
+                bar,
+                used_ring_gsi,
+                config_gsi,
+                notifier: NotifierRegistration::new(self.vm_fd.clone()),
+            },
+        })
+    }
+
+    /// Registers an ioeventfd for the queue notification register of the
+    /// device in `slot`, for a worker task to wait on.
+    fn notifier(&self, slot: &VirtioSlot) -> Result<QueueNotifier> {
+        match &slot.location {
+            SlotLocation::Mmio(base) => {
+                QueueNotifier::new(&self.vm_fd, base + VIRTIO_MMIO_QUEUE_NOTIFY)
+            }
+            SlotLocation::Pci { notifier, .. } => QueueNotifier::with_registration(notifier),
+        }
+    }
+
+    fn attach<D: VirtioDevice>(
+        &mut self,
+        io_manager: &mut IoManager,
+        slot: &VirtioSlot,
+        device: Arc<Mutex<D>>,
+        name: &str,
+    ) -> Result<()> {
+        match &slot.location {
+            SlotLocation::Mmio(base) => {
+                register_mmio_device(io_manager, *base, VIRTIO_MMIO_SIZE, device, name)
+            }
+            SlotLocation::Pci {
+                bar,
+                used_ring_gsi,
+                config_gsi,
+                notifier,
+            } => {
+                let pci = self.pci.as_mut().expect("PCI slots come from the PCI bus");
+                let device = VirtioPciDevice::new(
+                    device,
+                    *bar,
+                    self.routing.clone(),
+                    slot.interrupt.clone(),
+                    *used_ring_gsi,
+                    *config_gsi,
+                    notifier.clone(),
+                );
+                let number = pci.add_device(Arc::new(Mutex::new(device)))?;
+                tracing::debug!("{} attached to PCI slot {}", name, number);
+                Ok(())
+            }
+        }
+    }
+
+    /// Registers the PCI bus, if the devices are on one, and returns it.
+    fn finish(self, io_manager: &mut IoManager) -> Result<Option<Arc<PciBus>>> {
+        let Some(pci) = self.pci else {
+            return Ok(None);
+        };
+        let pci = Arc::new(pci);
+        register_pio_device(
+            io_manager,
+            PCI_CONFIG_IO_PORT,
+            PCI_CONFIG_IO_SIZE,
+            Arc::new(Mutex::new(PciConfigIo::new(pci.clone()))),
+            "PCI configuration ports",
+        )?;
+        register_mmio_device(
+            io_manager,
+            PCI_MMIO_START,
+            PCI_MMIO_SIZE,
+            pci.clone(),
+            "PCI window",
+        )?;
+        Ok(Some(pci))
+    }
+}
+
 fn load_initrd(
     memory: &GuestMemoryMmap,
     initrd_path: &std::path::Path,
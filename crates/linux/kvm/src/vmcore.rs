@@ -0,0 +1,214 @@
+//! Guest memory dumps in ELF core format.
+//!
+//! A dump is laid out like the vmcore QEMU writes with `dump-guest-memory`:
+//!
+//! ```text
+//! ELF header
+//! Program headers    PT_NOTE, then one PT_LOAD per guest memory region
+//! Notes              NT_PRSTATUS with the registers of each vCPU
+//! page aligned       Guest memory, one region after another
+//! ```
+//!
+//! Segments are addressed by guest physical address, which `crash` resolves
+//! through the kernel's own page tables.
+
+use crate::arch::ELF_MACHINE;
+use crate::snapshot::write_memory;
+use capsa_core::{Error, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use vm_memory::{Address, GuestMemory, GuestMemoryMmap, GuestMemoryRegion};
+
+const ELF_HEADER_SIZE: u64 = 64;
+const PROGRAM_HEADER_SIZE: u64 = 56;
+
+const ET_CORE: u16 = 4;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_RWX: u32 = 0x7;
+const NT_PRSTATUS: u32 = 1;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Writes guest memory and the `NT_PRSTATUS` note of each vCPU to `path`.
+pub fn write_vmcore(path: &Path, memory: &GuestMemoryMmap, prstatus: &[Vec<u8>]) -> Result<()> {
+    write_vmcore_file(path, memory, prstatus).map_err(|e| {
+        Error::Io(std::io::Error::new(
+            e.kind(),
+            format!("failed to write memory dump {}: {}", path.display(), e),
+        ))
+    })
+}
+
+fn write_vmcore_file(
+    path: &Path,
+    memory: &GuestMemoryMmap,
+    prstatus: &[Vec<u8>],
+) -> std::io::Result<()> {
+    let notes: Vec<u8> = prstatus
+        .iter()
+        .flat_map(|desc| encode_note(b"CORE", NT_PRSTATUS, desc))
+        .collect();
+
+    let regions: Vec<(u64, u64)> = memory
+        .iter()
+        .map(|region| (region.start_addr().raw_value(), region.len()))
+        .collect();
+    let phnum = u16::try_from(regions.len() + 1)
+        .map_err(|_| std::io::Error::other("too many guest memory regions"))?;
+
+    let notes_offset = ELF_HEADER_SIZE + u64::from(phnum) * PROGRAM_HEADER_SIZE;
+    let memory_offset = (notes_offset + notes.len() as u64).div_ceil(PAGE_SIZE) * PAGE_SIZE;
+
+    let mut file = BufWriter::new(File::create(path)?);
+    file.write_all(&encode_elf_header(phnum))?;
+    file.write_all(&encode_program_header(
+        PT_NOTE,
+        0,
+        notes_offset,
+        0,
+        notes.len() as u64,
+        0,
+    ))?;
+    let mut offset = memory_offset;
+    for &(guest_addr, size) in &regions {
+        file.write_all(&encode_program_header(
+            PT_LOAD, PF_RWX, offset, guest_addr, size, PAGE_SIZE,
+        ))?;
+        offset += size;
+    }
+    file.write_all(&notes)?;
+    let padding = memory_offset - notes_offset - notes.len() as u64;
+    file.write_all(&vec![0u8; padding as usize])?;
+
+    write_memory(&mut file, memory)?;
+    file.flush()
+}
+
+fn encode_elf_header(phnum: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(ELF_HEADER_SIZE as usize);
+    // Magic, 64-bit, little endian, ELF version 1, System V ABI
+    header.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0]);
+    header.extend_from_slice(&[0; 8]);
+    header.extend_from_slice(&ET_CORE.to_le_bytes());
+    header.extend_from_slice(&ELF_MACHINE.to_le_bytes());
+    header.extend_from_slice(&1u32.to_le_bytes());
+    // No entry point or section headers
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&ELF_HEADER_SIZE.to_le_bytes());
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    header.extend_from_slice(&phnum.to_le_bytes());
+    header.extend_from_slice(&[0; 6]);
+    header
+}
+
+fn encode_program_header(
+    p_type: u32,
+    flags: u32,
+    offset: u64,
+    paddr: u64,
+    size: u64,
+    align: u64,
+) -> Vec<u8> {
+    let mut header = Vec::with_capacity(PROGRAM_HEADER_SIZE as usize);
+    header.extend_from_slice(&p_type.to_le_bytes());
+    header.extend_from_slice(&flags.to_le_bytes());
+    header.extend_from_slice(&offset.to_le_bytes());
+    // Guest virtual addresses depend on the guest's page tables
+    header.extend_from_slice(&0u64.to_le_bytes());
+    header.extend_from_slice(&paddr.to_le_bytes());
+    header.extend_from_slice(&size.to_le_bytes());
+    header.extend_from_slice(&size.to_le_bytes());
+    header.extend_from_slice(&align.to_le_bytes());
+    header
+}
+
+/// Encodes an ELF note, padding the name and descriptor to 4 bytes.
+fn encode_note(name: &[u8], note_type: u32, desc: &[u8]) -> Vec<u8> {
+    let padded = |len: usize| len.div_ceil(4) * 4;
+    // The name is NUL terminated
+    let name_size = name.len() + 1;
+
+    let mut note = Vec::with_capacity(12 + padded(name_size) + padded(desc.len()));
+    note.extend_from_slice(&(name_size as u32).to_le_bytes());
+    note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    note.extend_from_slice(&note_type.to_le_bytes());
+    note.extend_from_slice(name);
+    note.resize(12 + padded(name_size), 0);
+    note.extend_from_slice(desc);
+    note.resize(12 + padded(name_size) + padded(desc.len()), 0);
+    note
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+    use vm_memory::{Bytes, GuestAddress};
+
+    fn read_u16(data: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+    }
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn read_u64(data: &[u8], offset: usize) -> u64 {
+        u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+    }
+
+    #[test]
+    fn pads_notes() {
+        let note = encode_note(b"CORE", NT_PRSTATUS, &[1, 2, 3, 4, 5]);
+        assert_eq!(note.len(), 12 + 8 + 8);
+        assert_eq!(read_u32(&note, 0), 5);
+        assert_eq!(read_u32(&note, 4), 5);
+        assert_eq!(read_u32(&note, 8), NT_PRSTATUS);
+        assert_eq!(&note[12..20], b"CORE\0\0\0\0");
+        assert_eq!(&note[20..28], &[1, 2, 3, 4, 5, 0, 0, 0]);
+    }
+
+    #[test]
+    fn writes_loadable_segments() {
+        let memory = GuestMemoryMmap::from_ranges(&[
+            (GuestAddress(0), 0x2000),
+            (GuestAddress(0x100000000), 0x1000),
+        ])
+        .unwrap();
+        memory.write_obj(0xabu8, GuestAddress(0x1000)).unwrap();
+        memory.write_obj(0xcdu8, GuestAddress(0x100000000)).unwrap();
+
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("vmcore");
+        write_vmcore(&path, &memory, &[vec![0x11; 336], vec![0x22; 336]]).unwrap();
+        let dump = std::fs::read(&path).unwrap();
+
+        assert_eq!(&dump[..4], b"\x7fELF");
+        assert_eq!(read_u16(&dump, 16), ET_CORE);
+        assert_eq!(read_u16(&dump, 18), ELF_MACHINE);
+        assert_eq!(read_u16(&dump, 56), 3);
+
+        let phdr = |index: usize| 64 + index * 56;
+        assert_eq!(read_u32(&dump, phdr(0)), PT_NOTE);
+        let notes_size = read_u64(&dump, phdr(0) + 32);
+        assert_eq!(notes_size, 2 * (12 + 8 + 336));
+
+        assert_eq!(read_u32(&dump, phdr(1)), PT_LOAD);
+        let low = read_u64(&dump, phdr(1) + 8) as usize;
+        assert_eq!(low % PAGE_SIZE as usize, 0);
+        assert_eq!(read_u64(&dump, phdr(1) + 24), 0);
+        assert_eq!(read_u64(&dump, phdr(1) + 32), 0x2000);
+        assert_eq!(dump[low + 0x1000], 0xab);
+
+        let high = read_u64(&dump, phdr(2) + 8) as usize;
+        assert_eq!(high, low + 0x2000);
+        assert_eq!(read_u64(&dump, phdr(2) + 24), 0x100000000);
+        assert_eq!(dump[high], 0xcd);
+        assert_eq!(dump.len(), high + 0x1000);
+    }
+}
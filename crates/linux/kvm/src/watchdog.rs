@@ -0,0 +1,180 @@
+//! Hardware watchdog policy.
+//!
+//! The guest pings the watchdog through an emulated timer card. When and how
+//! the watchdog acts is decided on the host: the configured timeout applies
+//! whatever the guest programs into the card. Like on real hardware, the
+//! watchdog stays disarmed until the guest starts it, and a reset disarms it
+//! again. It is not saved in snapshots, so a restored guest arms it on its
+//! next ping.
+
+use crate::crash::GuestCrash;
+use crate::reboot::RebootControl;
+use capsa_core::{ExitReason, WatchdogAction, WatchdogConfig};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{Notify, mpsc};
+
+/// Countdown of the guest's watchdog and the action taken when it expires.
+pub struct Watchdog {
+    config: WatchdogConfig,
+    /// When the watchdog expires, while the guest has it started
+    deadline: Mutex<Option<Instant>>,
+    changed: Notify,
+    crash: Arc<GuestCrash>,
+    reboot: Arc<RebootControl>,
+    running: Arc<AtomicBool>,
+    exit_tx: mpsc::Sender<ExitReason>,
+}
+
+impl Watchdog {
+    pub fn new(
+        config: WatchdogConfig,
+        crash: Arc<GuestCrash>,
+        reboot: Arc<RebootControl>,
+        running: Arc<AtomicBool>,
+        exit_tx: mpsc::Sender<ExitReason>,
+    ) -> Self {
+        Self {
+            config,
+            deadline: Mutex::new(None),
+            changed: Notify::new(),
+            crash,
+            reboot,
+            running,
+            exit_tx,
+        }
+    }
+
+    /// Starts the watchdog, or restarts its countdown if it is running.
+    pub fn ping(&self) {
+        self.set_deadline(Some(Instant::now() + self.config.timeout));
+    }
+
+    /// Disarms the watchdog until the guest starts it again.
+    pub fn stop(&self) {
+        self.set_deadline(None);
+    }
+
+    /// Gives a started watchdog a full timeout again, for when the guest
+    /// could not ping it, such as while the VM was paused.
+    pub fn restart(&self) {
+        let mut deadline = self.deadline.lock().unwrap();
+        if deadline.is_some() {
+            *deadline = Some(Instant::now() + self.config.timeout);
+            self.changed.notify_one();
+        }
+    }
+
+    /// Returns when the watchdog expires, if the guest has started it.
+    pub fn deadline(&self) -> Option<Instant> {
+        *self.deadline.lock().unwrap()
+    }
+
+    /// Waits until the guest pings or stops the watchdog.
+    pub async fn changed(&self) {
+        self.changed.notified().await;
+    }
+
+    /// Takes the configured action, and disarms the watchdog.
+    pub fn expire(&self) {
+        self.stop();
+        match self.config.action {
+            WatchdogAction::Reset => self.reboot.reset(ExitReason::Watchdog),
+            WatchdogAction::Poweroff => self.stop_vm(),
+            WatchdogAction::Fail => {
+                self.crash.set_watchdog_expired();
+                self.stop_vm();
+            }
+        }
+    }
+
+    fn stop_vm(&self) {
+        let _ = self.exit_tx.try_send(ExitReason::Watchdog);
+        self.running.store(false, Ordering::Relaxed);
+    }
+
+    fn set_deadline(&self, deadline: Option<Instant>) {
+        *self.deadline.lock().unwrap() = deadline;
+        self.changed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pause::PauseControl;
+    use std::time::Duration;
+
+    fn create_watchdog(
+        action: WatchdogAction,
+        reboot_in_place: bool,
+    ) -> (Watchdog, Arc<GuestCrash>, mpsc::Receiver<ExitReason>) {
+        let crash = Arc::new(GuestCrash::default());
+        let running = Arc::new(AtomicBool::new(true));
+        let (exit_tx, exit_rx) = mpsc::channel(1);
+        let reboot = Arc::new(RebootControl::new(
+            reboot_in_place,
+            running.clone(),
+            Arc::new(PauseControl::new()),
+            exit_tx.clone(),
+        ));
+        let watchdog = Watchdog::new(
+            WatchdogConfig::new(Duration::from_secs(30), action),
+            crash.clone(),
+            reboot,
+            running,
+            exit_tx,
+        );
+        (watchdog, crash, exit_rx)
+    }
+
+    #[test]
+    fn disarmed_until_pinged() {
+        let (watchdog, _, _) = create_watchdog(WatchdogAction::Reset, false);
+        assert_eq!(watchdog.deadline(), None);
+
+        watchdog.restart();
+        assert_eq!(watchdog.deadline(), None);
+
+        let before = Instant::now();
+        watchdog.ping();
+        let deadline = watchdog.deadline().unwrap();
+        assert!(deadline >= before + Duration::from_secs(30));
+
+        watchdog.stop();
+        assert_eq!(watchdog.deadline(), None);
+    }
+
+    #[test]
+    fn poweroff_stops_the_vm() {
+        let (watchdog, crash, mut exit_rx) = create_watchdog(WatchdogAction::Poweroff, false);
+        watchdog.ping();
+        watchdog.expire();
+
+        assert!(!watchdog.running.load(Ordering::Relaxed));
+        assert_eq!(exit_rx.try_recv().unwrap(), ExitReason::Watchdog);
+        assert!(crash.report().is_none());
+        assert_eq!(watchdog.deadline(), None);
+    }
+
+    #[test]
+    fn fail_reports_a_crash() {
+        let (watchdog, crash, mut exit_rx) = create_watchdog(WatchdogAction::Fail, false);
+        watchdog.expire();
+
+        assert!(!watchdog.running.load(Ordering::Relaxed));
+        assert_eq!(exit_rx.try_recv().unwrap(), ExitReason::Watchdog);
+        assert_eq!(crash.report().unwrap(), "guest watchdog expired");
+    }
+
+    #[test]
+    fn reset_reboots_in_place() {
+        let (watchdog, crash, mut exit_rx) = create_watchdog(WatchdogAction::Reset, true);
+        watchdog.expire();
+
+        assert!(watchdog.running.load(Ordering::Relaxed));
+        assert!(exit_rx.try_recv().is_err());
+        assert!(crash.report().is_none());
+    }
+}
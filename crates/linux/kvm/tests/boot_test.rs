@@ -1,5 +1,6 @@
 use capsa_core::{
-    BootMethod, Error, HypervisorBackend, NetworkMode, ResourceConfig, VmConfig, VsockConfig,
+    BootMethod, Error, HypervisorBackend, NetworkMode, ResourceConfig, VirtioTransport, VmConfig,
+    VsockConfig,
 };
 use capsa_linux_kvm::KvmBackend;
 use std::path::PathBuf;
@@ -19,6 +20,7 @@ fn create_config(kernel: PathBuf, initrd: PathBuf, cpus: u32, console_enabled: b
         resources: ResourceConfig {
             cpus,
             memory_mb: 256,
+            ..Default::default()
         },
         network: NetworkMode::None,
         root_disk: None,
@@ -26,6 +28,10 @@ fn create_config(kernel: PathBuf, initrd: PathBuf, cpus: u32, console_enabled: b
         shares: vec![],
         vsock: VsockConfig::default(),
         console_enabled,
+        reboot_in_place: false,
+        gdb: None,
+        virtio_transport: VirtioTransport::default(),
+        watchdog: None,
         cluster_network_fd: None,
     }
 }
@@ -176,6 +182,7 @@ async fn test_kvm_uefi_boot_unsupported() {
         resources: ResourceConfig {
             cpus: 1,
             memory_mb: 256,
+            ..Default::default()
         },
         network: NetworkMode::None,
         root_disk: None,
@@ -183,6 +190,10 @@ async fn test_kvm_uefi_boot_unsupported() {
         shares: vec![],
         vsock: VsockConfig::default(),
         console_enabled: true,
+        reboot_in_place: false,
+        gdb: None,
+        virtio_transport: VirtioTransport::default(),
+        watchdog: None,
         cluster_network_fd: None,
     };
 
@@ -3,8 +3,8 @@
 //! These tests verify that UserNat networking works correctly with the KVM hypervisor.
 
 use capsa_core::{
-    BootMethod, ConsoleIo, HypervisorBackend, NetworkMode, ResourceConfig, UserNatConfig, VmConfig,
-    VsockConfig,
+    BootMethod, ConsoleIo, HypervisorBackend, NetworkMode, ResourceConfig, UserNatConfig,
+    VirtioTransport, VmConfig, VsockConfig,
 };
 use capsa_linux_kvm::KvmBackend;
 use std::path::PathBuf;
@@ -39,6 +39,7 @@ fn create_network_config(kernel: PathBuf, initrd: PathBuf) -> VmConfig {
         resources: ResourceConfig {
             cpus: 1,
             memory_mb: 256,
+            ..Default::default()
         },
         network: NetworkMode::UserNat(UserNatConfig::default()),
         root_disk: None,
@@ -46,6 +47,10 @@ fn create_network_config(kernel: PathBuf, initrd: PathBuf) -> VmConfig {
         shares: vec![],
         vsock: VsockConfig::default(),
         console_enabled: true,
+        reboot_in_place: false,
+        gdb: None,
+        virtio_transport: VirtioTransport::default(),
+        watchdog: None,
         cluster_network_fd: None,
     }
 }
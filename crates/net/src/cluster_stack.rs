@@ -9,7 +9,8 @@
 
 use crate::device::SmoltcpDevice;
 use crate::dhcp::DhcpServer;
-use crate::frame_io::FrameIO;
+use crate::error::NetError;
+use crate::frame_io::{FrameIO, is_closed, is_transient};
 
 use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
 use smoltcp::socket::udp::{self, PacketBuffer, PacketMetadata};
@@ -133,16 +134,30 @@ impl<F: FrameIO> ClusterStack<F> {
     }
 
     /// Run the cluster stack.
-    pub async fn run(mut self) {
-        let mut interval = tokio::time::interval(Duration::from_millis(1));
-
+    ///
+    /// Sleeps until a frame arrives or a smoltcp timer is due. Runs until the
+    /// frame I/O is closed or fails with an error that would not clear by
+    /// itself.
+    pub async fn run(mut self) -> Result<(), NetError> {
         loop {
-            interval.tick().await;
-
-            {
-                let waker = futures::task::noop_waker();
-                let mut cx = std::task::Context::from_waker(&waker);
-                self.device.poll_recv(&mut cx);
+            let poll_delay = self
+                .iface
+                .poll_delay(smoltcp_now(self.start_time), &self.sockets)
+                .map(|delay| Duration::from_micros(delay.total_micros()));
+
+            tokio::select! {
+                result = std::future::poll_fn(|cx| self.device.poll_recv(cx)) => match result {
+                    Ok(()) => {}
+                    Err(e) if is_closed(&e) => {
+                        tracing::debug!("Cluster frame I/O closed, stopping cluster stack");
+                        return Ok(());
+                    }
+                    Err(e) if is_transient(&e) => {
+                        tracing::debug!("Transient cluster frame receive error: {}", e);
+                    }
+                    Err(e) => return Err(e.into()),
+                },
+                _ = tokio::time::sleep(poll_delay.unwrap_or_default()), if poll_delay.is_some() => {}
             }
 
             {
@@ -181,3 +196,27 @@ fn smoltcp_now(start: std::time::Instant) -> Instant {
     let elapsed = start.elapsed();
     Instant::from_millis(elapsed.as_millis() as i64)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame_io::FailingFrameIO;
+
+    #[tokio::test]
+    async fn fails_on_persistent_receive_error() {
+        let device = FailingFrameIO::new(vec![
+            std::io::ErrorKind::WouldBlock.into(),
+            std::io::ErrorKind::ConnectionReset.into(),
+        ]);
+        let polls = device.polls();
+        let stack = ClusterStack::new(device, ClusterStackConfig::default());
+
+        let result = tokio::time::timeout(Duration::from_secs(5), stack.run())
+            .await
+            .expect("cluster stack kept polling a failing frame I/O");
+        assert!(
+            matches!(result, Err(NetError::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionReset)
+        );
+        assert_eq!(polls.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+}
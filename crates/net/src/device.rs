@@ -23,19 +23,27 @@ impl<F: FrameIO> SmoltcpDevice<F> {
     }
 
     /// Poll for incoming frames. Call this before each smoltcp poll.
-    pub fn poll_recv(&mut self, cx: &mut Context<'_>) {
+    ///
+    /// Ready once a frame is pending; otherwise `cx` is woken when one arrives.
+    /// Fails with [`std::io::ErrorKind::UnexpectedEof`] once the other end of
+    /// the frame I/O is closed.
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
         if self.rx_len.is_some() {
-            return; // Already have a pending frame
+            return Poll::Ready(Ok(())); // Already have a pending frame
         }
 
         match self.frame_io.poll_recv(cx, &mut self.rx_buffer) {
+            // No ethernet frame is empty, so this is the peer hanging up
+            Poll::Ready(Ok(0)) => Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "frame I/O closed",
+            ))),
             Poll::Ready(Ok(len)) => {
                 self.rx_len = Some(len);
+                Poll::Ready(Ok(()))
             }
-            Poll::Ready(Err(e)) => {
-                tracing::warn!("Frame receive error: {}", e);
-            }
-            Poll::Pending => {}
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
         }
     }
 
@@ -20,3 +20,75 @@ pub trait FrameIO: Send + 'static {
     /// Send an ethernet frame.
     fn send(&mut self, frame: &[u8]) -> io::Result<()>;
 }
+
+/// Whether a receive error means the other end of the frame I/O was closed.
+pub(crate) fn is_closed(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::BrokenPipe
+    )
+}
+
+/// Whether a receive error clears by itself, so polling again makes sense.
+/// Any other error would come straight back, leaving a stack spinning on it.
+pub(crate) fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    ) || e.raw_os_error() == Some(libc::ENOBUFS)
+}
+
+/// Frame I/O that fails receives with the given errors in turn, then keeps
+/// failing with the last one. Sends are dropped.
+#[cfg(test)]
+pub(crate) struct FailingFrameIO {
+    errors: std::collections::VecDeque<io::Error>,
+    polls: std::sync::Arc<std::sync::atomic::AtomicUsize>,
+}
+
+#[cfg(test)]
+impl FailingFrameIO {
+    pub(crate) fn new(errors: Vec<io::Error>) -> Self {
+        Self {
+            errors: errors.into(),
+            polls: Default::default(),
+        }
+    }
+
+    /// Counts the receive polls made through this frame I/O.
+    pub(crate) fn polls(&self) -> std::sync::Arc<std::sync::atomic::AtomicUsize> {
+        self.polls.clone()
+    }
+}
+
+#[cfg(test)]
+impl FrameIO for FailingFrameIO {
+    fn poll_recv(&mut self, _cx: &mut Context<'_>, _buf: &mut [u8]) -> Poll<io::Result<usize>> {
+        self.polls.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let e = if self.errors.len() > 1 {
+            self.errors.pop_front().unwrap()
+        } else {
+            let last = &self.errors[0];
+            io::Error::new(last.kind(), last.to_string())
+        };
+        Poll::Ready(Err(e))
+    }
+
+    fn send(&mut self, _frame: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_passing_errors_are_transient() {
+        assert!(is_transient(&io::ErrorKind::Interrupted.into()));
+        assert!(is_transient(&io::ErrorKind::WouldBlock.into()));
+        assert!(is_transient(&io::Error::from_raw_os_error(libc::ENOBUFS)));
+        assert!(!is_transient(&io::ErrorKind::PermissionDenied.into()));
+        assert!(!is_transient(&io::Error::from_raw_os_error(libc::EBADF)));
+    }
+}
@@ -3,7 +3,7 @@ use crate::dhcp::DhcpServer;
 use crate::dns_cache::DnsCache;
 use crate::dns_proxy::DnsProxy;
 use crate::error::NetError;
-use crate::frame_io::FrameIO;
+use crate::frame_io::{FrameIO, is_closed, is_transient};
 use crate::nat::{FrameReceiver, NatTable, craft_tcp_rst, frame_channel};
 use crate::policy::{PacketProtocol, PolicyChecker, PolicyResult};
 use crate::port_forward::PortForwarder;
@@ -23,9 +23,8 @@ use std::net::SocketAddrV4;
 use std::net::Ipv4Addr;
 use std::time::Duration;
 
-/// How often to run NAT cleanup (in milliseconds).
-/// With 1ms polling intervals, 10000 means every 10 seconds.
-const NAT_CLEANUP_INTERVAL_MS: u32 = 10_000;
+/// How often to run NAT cleanup.
+const NAT_CLEANUP_INTERVAL: Duration = Duration::from_secs(10);
 
 /// Parsed DNS query information extracted from a frame.
 struct DnsQueryInfo {
@@ -200,7 +199,9 @@ impl<F: FrameIO> UserNatStack<F> {
     /// Run the network stack.
     ///
     /// This is an async function that should be spawned as a task.
-    /// It runs until an error occurs or the frame I/O is closed.
+    /// It runs until the frame I/O is closed or fails with an error that
+    /// would not clear by itself. The stack sleeps until a frame arrives
+    /// from either side or a timer is due, so an idle guest costs no CPU.
     pub async fn run(mut self) -> Result<(), NetError> {
         // Start port forward listeners
         if let Some(ref mut pf) = self.port_forwarder {
@@ -225,24 +226,52 @@ impl<F: FrameIO> UserNatStack<F> {
             }
         }
 
-        let mut interval = tokio::time::interval(Duration::from_millis(1));
-        let mut cleanup_counter = 0u32;
+        let mut cleanup = tokio::time::interval_at(
+            tokio::time::Instant::now() + NAT_CLEANUP_INTERVAL,
+            NAT_CLEANUP_INTERVAL,
+        );
 
         loop {
-            interval.tick().await;
+            let poll_delay = self
+                .iface
+                .poll_delay(smoltcp_now(self.start_time), &self.sockets)
+                .map(|delay| Duration::from_micros(delay.total_micros()));
 
-            // Receive frames from guest
-            {
-                let waker = futures::task::noop_waker();
-                let mut cx = std::task::Context::from_waker(&waker);
-                self.device.poll_recv(&mut cx);
+            let mut nat_frames_to_send = Vec::new();
+            tokio::select! {
+                // Receive frames from guest
+                result = std::future::poll_fn(|cx| self.device.poll_recv(cx)) => match result {
+                    Ok(()) => {}
+                    Err(e) if is_closed(&e) => {
+                        tracing::debug!("Guest frame I/O closed, stopping network stack");
+                        return Ok(());
+                    }
+                    Err(e) if is_transient(&e) => {
+                        tracing::debug!("Transient frame receive error: {}", e);
+                    }
+                    Err(e) => return Err(e.into()),
+                },
+                Some(frame) = self.nat_rx.recv() => nat_frames_to_send.push(frame),
+                _ = tokio::time::sleep(poll_delay.unwrap_or_default()), if poll_delay.is_some() => {}
+                _ = cleanup.tick() => {
+                    // Periodic cleanup of idle NAT entries and expired DNS cache
+                    // TODO: Handle RwLock poison gracefully instead of unwrap() to avoid
+                    // crashing the network stack if another thread panics while holding the lock.
+                    self.nat.cleanup();
+                    self.dns_cache.write().unwrap().cleanup();
+                }
             }
 
-            // Collect NAT frames to send after smoltcp poll
-            let mut nat_frames_to_send = Vec::new();
+            // Send NAT responses before handling the guest frame, which may
+            // skip the rest of this iteration
             while let Ok(frame) = self.nat_rx.try_recv() {
                 nat_frames_to_send.push(frame);
             }
+            for frame in nat_frames_to_send {
+                if let Err(e) = self.device.send_frame(&frame) {
+                    tracing::warn!("Failed to send NAT response frame: {}", e);
+                }
+            }
 
             // Check if we have a frame destined to gateway (potential port forward response)
             if let Some(frame) = self.device.peek_rx() {
@@ -315,22 +344,6 @@ impl<F: FrameIO> UserNatStack<F> {
                     .poll(timestamp, &mut self.device, &mut self.sockets);
                 self.process_dhcp();
             }
-
-            // Send collected NAT frames after smoltcp poll
-            for frame in nat_frames_to_send {
-                if let Err(e) = self.device.send_frame(&frame) {
-                    tracing::warn!("Failed to send NAT response frame: {}", e);
-                }
-            }
-
-            // Periodic cleanup of idle NAT entries and expired DNS cache
-            // TODO: Handle RwLock poison gracefully instead of unwrap() to avoid
-            // crashing the network stack if another thread panics while holding the lock.
-            cleanup_counter = cleanup_counter.wrapping_add(1);
-            if cleanup_counter.is_multiple_of(NAT_CLEANUP_INTERVAL_MS) {
-                self.nat.cleanup();
-                self.dns_cache.write().unwrap().cleanup();
-            }
         }
     }
 
@@ -626,6 +639,7 @@ fn smoltcp_now(start: std::time::Instant) -> Instant {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::frame_io::FailingFrameIO;
     use capsa_core::{NetworkPolicy, PolicyAction, PortForward, Protocol, UserNatConfig};
 
     #[test]
@@ -695,4 +709,40 @@ mod tests {
         assert_eq!(stack_config.port_forwards.len(), 0);
         assert!(stack_config.policy.is_none());
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_stack_stops_at_end_of_frame_io() {
+        let (device, guest_fd) = crate::SocketPairDevice::new().unwrap();
+        let stack = UserNatStack::new(device, StackConfig::default());
+
+        // An empty read is how the frame I/O reports that it was closed
+        let guest = std::os::fd::AsRawFd::as_raw_fd(&guest_fd);
+        let n = unsafe { libc::send(guest, std::ptr::null(), 0, 0) };
+        assert_eq!(n, 0);
+
+        let result = tokio::time::timeout(Duration::from_secs(5), stack.run())
+            .await
+            .expect("stack kept running after the frame I/O closed");
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stack_fails_on_persistent_receive_error() {
+        let device = FailingFrameIO::new(vec![
+            std::io::ErrorKind::Interrupted.into(),
+            std::io::Error::from_raw_os_error(libc::ENOBUFS),
+            std::io::ErrorKind::PermissionDenied.into(),
+        ]);
+        let polls = device.polls();
+        let stack = UserNatStack::new(device, StackConfig::default());
+
+        let result = tokio::time::timeout(Duration::from_secs(5), stack.run())
+            .await
+            .expect("stack kept polling a failing frame I/O");
+        assert!(
+            matches!(result, Err(NetError::Io(e)) if e.kind() == std::io::ErrorKind::PermissionDenied)
+        );
+        assert_eq!(polls.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
 }
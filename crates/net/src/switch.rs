@@ -203,7 +203,7 @@ impl FrameIO for SwitchPort {
         1500
     }
 
-    fn poll_recv(&mut self, _cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+    fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<usize>> {
         // Try to get from pending first
         {
             let mut pending = self.pending_frame.lock().unwrap();
@@ -214,23 +214,17 @@ impl FrameIO for SwitchPort {
             }
         }
 
-        // Try non-blocking receive
-        let mut rx = match self.rx.try_lock() {
-            Ok(rx) => rx,
-            Err(_) => return Poll::Pending,
-        };
-
-        match rx.try_recv() {
-            Ok(frame) => {
+        match self.rx.get_mut().poll_recv(cx) {
+            Poll::Ready(Some(frame)) => {
                 let len = frame.len().min(buf.len());
                 buf[..len].copy_from_slice(&frame[..len]);
                 Poll::Ready(Ok(len))
             }
-            Err(mpsc::error::TryRecvError::Empty) => Poll::Pending,
-            Err(mpsc::error::TryRecvError::Disconnected) => Poll::Ready(Err(io::Error::new(
+            Poll::Ready(None) => Poll::Ready(Err(io::Error::new(
                 io::ErrorKind::BrokenPipe,
                 "switch closed",
             ))),
+            Poll::Pending => Poll::Pending,
         }
     }
 